@@ -0,0 +1,5 @@
+fn main() {
+    // Node-API addons link to symbols that Node provides when it loads them
+    #[cfg(feature = "node")]
+    napi_build::setup();
+}
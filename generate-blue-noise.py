@@ -0,0 +1,110 @@
+#!/usr/bin/env python3
+#
+# Generate the blue-noise threshold texture used for dithering (src/blue_noise.bin).
+#
+# This is Ulichney's void-and-cluster method on a 64x64 torus. Each output byte is a pixel's
+# rank scaled to 0..255, stored row-major. Output is deterministic for a given seed.
+
+import math
+import random
+import sys
+
+SIZE = 64
+N = SIZE * SIZE
+SIGMA = 1.5
+SEED = 0x8C
+
+
+def gaussian_table():
+    """Energy contribution of a set pixel to every offset, wrapping around the edges."""
+    table = [0.0] * N
+    for dy in range(SIZE):
+        for dx in range(SIZE):
+            wx = min(dx, SIZE - dx)
+            wy = min(dy, SIZE - dy)
+            table[dy * SIZE + dx] = math.exp(-(wx * wx + wy * wy) / (2 * SIGMA * SIGMA))
+    return table
+
+
+KERNEL = gaussian_table()
+
+
+class Pattern:
+    def __init__(self, bits):
+        self.bits = list(bits)
+        self.energy = [0.0] * N
+        for i, b in enumerate(self.bits):
+            if b:
+                self._update(i, 1.0)
+
+    def _update(self, index, sign):
+        y, x = divmod(index, SIZE)
+        energy = self.energy
+        for j in range(N):
+            jy, jx = divmod(j, SIZE)
+            energy[j] += sign * KERNEL[((jy - y) % SIZE) * SIZE + (jx - x) % SIZE]
+
+    def set(self, index, value):
+        if self.bits[index] != value:
+            self.bits[index] = value
+            self._update(index, 1.0 if value else -1.0)
+
+    def tightest_cluster(self):
+        return max((i for i in range(N) if self.bits[i]), key=lambda i: self.energy[i])
+
+    def largest_void(self):
+        return min((i for i in range(N) if not self.bits[i]), key=lambda i: self.energy[i])
+
+
+def main(out_path):
+    rng = random.Random(SEED)
+
+    # Initial binary pattern: randomly place ~10% of pixels, then redistribute them
+    # until moving the tightest cluster into the largest void changes nothing.
+    initial = [False] * N
+    for i in rng.sample(range(N), N // 10):
+        initial[i] = True
+    pattern = Pattern(initial)
+    while True:
+        cluster = pattern.tightest_cluster()
+        pattern.set(cluster, False)
+        void = pattern.largest_void()
+        if void == cluster:
+            pattern.set(cluster, True)
+            break
+        pattern.set(void, True)
+
+    prototype = list(pattern.bits)
+    ones = sum(prototype)
+    rank = [0] * N
+
+    # Phase 1: rank the prototype's pixels by removing tightest clusters
+    phase = Pattern(prototype)
+    for r in range(ones - 1, -1, -1):
+        cluster = phase.tightest_cluster()
+        phase.set(cluster, False)
+        rank[cluster] = r
+
+    # Phase 2: fill the largest voids up to half full
+    phase = Pattern(prototype)
+    for r in range(ones, N // 2):
+        void = phase.largest_void()
+        phase.set(void, True)
+        rank[void] = r
+
+    # Phase 3: the remaining zeros are the minority, so fill their tightest clusters
+    inverse = Pattern(not b for b in phase.bits)
+    for r in range(N // 2, N):
+        cluster = inverse.tightest_cluster()
+        inverse.set(cluster, False)
+        rank[cluster] = r
+
+    with open(out_path, 'wb') as f:
+        f.write(bytes(r * 256 // N for r in rank))
+
+
+if __name__ == '__main__':
+    if len(sys.argv) != 2:
+        print("Usage: {} <output file>".format(sys.argv[0]))
+        sys.exit(1)
+    main(sys.argv[1])
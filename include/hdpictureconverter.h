@@ -0,0 +1,52 @@
+/* C interface to hdpictureconverter, built with `cargo build --release --features ffi`. */
+#ifndef HDPICTURECONVERTER_H
+#define HDPICTURECONVERTER_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define HDPC_OK 0
+/* An argument was null or invalid. */
+#define HDPC_INVALID_ARGUMENT (-1)
+/* The image couldn't be read or converted. */
+#define HDPC_CONVERSION_FAILED (-2)
+
+/* Options for hdpc_convert, all of which are the default when zeroed. */
+typedef struct HdpcOptions {
+    /* Nonzero to map colors to the nearest in the palette without dithering. */
+    int no_dither;
+    /* 0 for zx0, 1 for DEFLATE, 2 for run-length encoding or 3 for none. */
+    int compression;
+    /* Nonzero to store the appvars in RAM rather than archive. */
+    int unarchived;
+} HdpcOptions;
+
+/* Bytes owned by the caller until passed to hdpc_free. */
+typedef struct HdpcBuffer {
+    uint8_t *data;
+    size_t len;
+} HdpcBuffer;
+
+/*
+ * Convert the image file at path to a group file holding its tile and palette appvars, which is
+ * stored in out_buf. prefix is the two-letter var prefix, and options may be NULL for the
+ * defaults. Returns HDPC_OK or a negative error code described by hdpc_last_error.
+ */
+int hdpc_convert(const char *path, const char *prefix, const HdpcOptions *options,
+                 HdpcBuffer *out_buf);
+
+/* Release a buffer filled in by hdpc_convert, leaving it empty. */
+void hdpc_free(HdpcBuffer *buf);
+
+/* Describe the last error on this thread, until the next call to hdpc_convert on it. */
+const char *hdpc_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,162 @@
+//! Color adjustments applied to images before quantization
+use crate::Image;
+
+impl Image {
+    /// Replace each red, green and blue component with its entry in a lookup table.
+    fn map_components(&mut self, table: &[u8; 256]) {
+        for pixel in self.input.pixels_mut() {
+            for c in &mut pixel.0[..3] {
+                *c = table[*c as usize];
+            }
+        }
+    }
+
+    /// Apply gamma correction, where values above 1 brighten midtones and values below 1
+    /// darken them.
+    ///
+    /// Black and white are unchanged.
+    pub fn adjust_gamma(&mut self, gamma: f32) {
+        assert!(gamma > 0., "gamma must be positive");
+        let mut table = [0; 256];
+        for (i, out) in table.iter_mut().enumerate() {
+            *out = ((i as f32 / 255.).powf(1. / gamma) * 255.).round() as u8;
+        }
+        self.map_components(&table);
+    }
+
+    /// Shift brightness by a fraction of the full range from -1 to 1, and scale contrast around
+    /// mid-gray by a factor where 1 is unchanged.
+    pub fn adjust_brightness_contrast(&mut self, brightness: f32, contrast: f32) {
+        assert!(contrast >= 0., "contrast must not be negative");
+        let mut table = [0; 256];
+        for (i, out) in table.iter_mut().enumerate() {
+            let x = (i as f32 / 255. - 0.5) * contrast + 0.5 + brightness;
+            *out = (x.clamp(0., 1.) * 255.).round() as u8;
+        }
+        self.map_components(&table);
+    }
+
+    /// Scale saturation by a factor where 0 is grayscale and 1 is unchanged, and rotate hues by
+    /// some number of degrees.
+    ///
+    /// These are the `saturate` and `hueRotate` color matrices from SVG, which keep luminance
+    /// roughly constant.
+    pub fn adjust_saturation_hue(&mut self, saturation: f32, hue_degrees: f32) {
+        assert!(saturation >= 0., "saturation must not be negative");
+        let s = saturation;
+        let saturate = [
+            [0.213 + 0.787 * s, 0.715 - 0.715 * s, 0.072 - 0.072 * s],
+            [0.213 - 0.213 * s, 0.715 + 0.285 * s, 0.072 - 0.072 * s],
+            [0.213 - 0.213 * s, 0.715 - 0.715 * s, 0.072 + 0.928 * s],
+        ];
+        let (sin, cos) = hue_degrees.to_radians().sin_cos();
+        let rotate = [
+            [
+                0.213 + cos * 0.787 - sin * 0.213,
+                0.715 - cos * 0.715 - sin * 0.715,
+                0.072 - cos * 0.072 + sin * 0.928,
+            ],
+            [
+                0.213 - cos * 0.213 + sin * 0.143,
+                0.715 + cos * 0.285 + sin * 0.140,
+                0.072 - cos * 0.072 - sin * 0.283,
+            ],
+            [
+                0.213 - cos * 0.213 - sin * 0.787,
+                0.715 - cos * 0.715 + sin * 0.715,
+                0.072 + cos * 0.928 + sin * 0.072,
+            ],
+        ];
+
+        // Combine them so each pixel is only transformed once
+        let mut matrix = [[0f32; 3]; 3];
+        for (i, row) in matrix.iter_mut().enumerate() {
+            for (j, out) in row.iter_mut().enumerate() {
+                *out = (0..3).map(|k| saturate[i][k] * rotate[k][j]).sum();
+            }
+        }
+
+        for pixel in self.input.pixels_mut() {
+            let rgb = [pixel[0], pixel[1], pixel[2]].map(|c| c as f32);
+            for (c, row) in pixel.0[..3].iter_mut().zip(&matrix) {
+                let x: f32 = row.iter().zip(&rgb).map(|(m, c)| m * c).sum();
+                *c = x.round().clamp(0., 255.) as u8;
+            }
+        }
+    }
+
+    /// Convert the image to shades of gray with the same luminance.
+    pub fn grayscale(&mut self) {
+        self.adjust_saturation_hue(0., 0.);
+    }
+}
+
+/// Gamma above 1 brightens midtones but leaves the extremes alone.
+#[test]
+fn gamma_brightens_midtones() {
+    use image::{Rgba, RgbaImage};
+
+    let mut input = RgbaImage::new(3, 1);
+    input.put_pixel(0, 0, Rgba([0, 0, 0, 255]));
+    input.put_pixel(1, 0, Rgba([128, 128, 128, 100]));
+    input.put_pixel(2, 0, Rgba([255, 255, 255, 255]));
+    let mut image = Image::from_rgba(input, "GAMMA", "AA");
+
+    image.adjust_gamma(2.);
+    assert_eq!(image.input.get_pixel(0, 0).0, [0, 0, 0, 255]);
+    assert_eq!(image.input.get_pixel(1, 0).0, [181, 181, 181, 100]);
+    assert_eq!(image.input.get_pixel(2, 0).0, [255, 255, 255, 255]);
+}
+
+/// Contrast spreads values away from mid-gray, and brightness shifts them all.
+#[test]
+fn brightness_and_contrast() {
+    use image::{Rgba, RgbaImage};
+
+    let image = |value| {
+        Image::from_rgba(
+            RgbaImage::from_pixel(1, 1, Rgba([value, value, value, 255])),
+            "CONTRAST",
+            "AA",
+        )
+    };
+    let adjusted = |value, brightness, contrast| {
+        let mut image = image(value);
+        image.adjust_brightness_contrast(brightness, contrast);
+        image.input.get_pixel(0, 0)[0]
+    };
+
+    assert_eq!(adjusted(100, 0., 1.), 100);
+    assert_eq!(adjusted(64, 0., 2.), 1);
+    assert_eq!(adjusted(192, 0., 2.), 255);
+    assert_eq!(adjusted(100, 0.5, 1.), 228);
+    assert_eq!(adjusted(100, -1., 1.), 0);
+}
+
+/// Removing saturation makes gray, and a half turn of hue turns red toward cyan.
+#[test]
+fn saturation_and_hue() {
+    use image::{Rgba, RgbaImage};
+
+    let red = || {
+        Image::from_rgba(
+            RgbaImage::from_pixel(1, 1, Rgba([200, 0, 0, 255])),
+            "HUE",
+            "AA",
+        )
+    };
+
+    let mut image = red();
+    image.adjust_saturation_hue(1., 0.);
+    assert_eq!(image.input.get_pixel(0, 0).0, [200, 0, 0, 255]);
+
+    let mut image = red();
+    image.adjust_saturation_hue(0., 0.);
+    let [r, g, b, _] = image.input.get_pixel(0, 0).0;
+    assert!(r == g && g == b, "{:?} isn't gray", (r, g, b));
+
+    let mut image = red();
+    image.adjust_saturation_hue(1., 180.);
+    let [r, g, b, _] = image.input.get_pixel(0, 0).0;
+    assert!(g > r && b > r, "{:?} isn't cyan", (r, g, b));
+}
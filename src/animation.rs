@@ -0,0 +1,250 @@
+//! Converting animations frame by frame
+//!
+//! Each frame of an animation is converted like any other image, with the frame number in the
+//! names of its tile and palette appvars. One more appvar records how many frames there are and
+//! how long each is shown, so a player knows what to load.
+//!
+//! Animated GIFs, PNGs and WebPs are all decoded to whole frames before quantization, with each
+//! frame's disposal and blending applied to the canvas it's drawn on.
+use std::io::{BufRead, Result as IoResult, Seek, Write};
+
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use image::{AnimationDecoder, DynamicImage, ImageFormat};
+use tifiles::VariableType;
+
+use crate::{DecodeOptions, Image, QuantizedImage};
+
+/// One frame of an animation.
+pub struct Frame {
+    pub image: Image,
+    /// How long the frame is shown, in milliseconds.
+    pub delay_ms: u32,
+}
+
+impl Frame {
+    /// Decode the only frame of a still image.
+    fn still<'a>(
+        decoder: impl image::ImageDecoder<'a>,
+        name: &str,
+        var_prefix: &str,
+    ) -> IoResult<Vec<Frame>> {
+        let image = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+        Ok(vec![Frame {
+            image: Image::from_rgba(image.into_rgba8(), name, var_prefix),
+            delay_ms: 0,
+        }])
+    }
+}
+
+fn decode_error(e: impl std::fmt::Display) -> std::io::Error {
+    std::io::Error::other(format!("Unable to decode image: {}", e))
+}
+
+impl Image {
+    /// Decode every frame of an animated GIF, PNG or WebP, or the only frame of any other image.
+    ///
+    /// Each frame is the whole image as it's shown at that point in the animation, with earlier
+    /// frames already drawn beneath it. Still images have a delay of 0. A PNG's default image is
+    /// skipped if it isn't part of the animation.
+    pub fn frames<R: BufRead + Seek>(
+        data: R,
+        name: &str,
+        var_prefix: &str,
+    ) -> IoResult<Vec<Frame>> {
+        Self::frames_with(data, name, var_prefix, &DecodeOptions::default())
+    }
+
+    /// Decode every frame of an image file as specified by `options`, like
+    /// [`frames`](Image::frames).
+    pub fn frames_with<R: BufRead + Seek>(
+        mut data: R,
+        name: &str,
+        var_prefix: &str,
+        options: &DecodeOptions,
+    ) -> IoResult<Vec<Frame>> {
+        if let Some(image) = crate::decode_other(&mut data, options)? {
+            return Ok(vec![Frame {
+                image: Image::from_rgba(image, name, var_prefix),
+                delay_ms: 0,
+            }]);
+        }
+        let reader = image::io::Reader::new(data).with_guessed_format()?;
+        let frames = match reader.format() {
+            Some(ImageFormat::Gif) => GifDecoder::new(reader.into_inner())
+                .map_err(decode_error)?
+                .into_frames(),
+            Some(ImageFormat::Png) => {
+                let decoder = PngDecoder::new(reader.into_inner()).map_err(decode_error)?;
+                if !decoder.is_apng() {
+                    return Frame::still(decoder, name, var_prefix);
+                }
+                decoder.apng().into_frames()
+            }
+            Some(ImageFormat::WebP) => {
+                let decoder = WebPDecoder::new(reader.into_inner()).map_err(decode_error)?;
+                if !decoder.has_animation() {
+                    return Frame::still(decoder, name, var_prefix);
+                }
+                decoder.into_frames()
+            }
+            _ => {
+                return Ok(vec![Frame {
+                    image: Image::new_with(reader.into_inner(), name, var_prefix, options)?,
+                    delay_ms: 0,
+                }])
+            }
+        };
+
+        frames
+            .map(|frame| {
+                let frame = frame.map_err(decode_error)?;
+                let (numer, denom) = frame.delay().numer_denom_ms();
+                Ok(Frame {
+                    delay_ms: (numer + denom / 2) / denom.max(1),
+                    image: Image::from_rgba(frame.into_buffer(), name, var_prefix),
+                })
+            })
+            .collect()
+    }
+}
+
+impl QuantizedImage {
+    /// Return the name of the appvar describing the animation this image is a frame of.
+    pub fn animation_appvar_name(&self) -> String {
+        format!("HA{:2}0000", self.var_prefix)
+    }
+
+    /// Write the appvar describing an animation with frames shown for `delays_ms` milliseconds
+    /// each, of which this image is one.
+    ///
+    /// `shared_palette` records that every frame uses the palette appvar of the first, rather
+    /// than each having its own.
+    pub fn write_animation_appvar<W: Write + Seek>(
+        &self,
+        delays_ms: &[u32],
+        shared_palette: bool,
+        mut out: W,
+    ) -> IoResult<W> {
+        let frames = u16::try_from(delays_ms.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "animations may have at most 65535 frames",
+            )
+        })?;
+
+        let start = out.stream_position()?;
+        let mut writer = tifiles::Writer::new(
+            out,
+            VariableType::AppVar,
+            &self.animation_appvar_name(),
+            self.archived,
+        )?;
+
+        // Header like the palette's: signature, 8-character image name, 2-character var prefix
+        // and index of the last tile of each frame.
+        write!(
+            writer,
+            "HDANIMV1{:8}{:2}{:03}{:03}",
+            self.name,
+            self.var_prefix,
+            self.width_tiles() - 1,
+            self.height_tiles() - 1,
+        )?;
+
+        // Frame count, flags (bit 0 set if the palette is shared), then the delay of each frame
+        // in milliseconds, all little-endian.
+        writer.write_all(&frames.to_le_bytes())?;
+        writer.write_all(&[shared_palette as u8])?;
+        for &delay in delays_ms {
+            writer.write_all(&u16::try_from(delay).unwrap_or(u16::MAX).to_le_bytes())?;
+        }
+        self.finish_var(writer, start)
+    }
+}
+
+/// Every frame of a GIF is decoded in order with its delay.
+#[test]
+fn gif_frames_are_decoded() {
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    let mut gif = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut gif);
+        let first = RgbaImage::from_pixel(4, 4, Rgba([255, 0, 0, 255]));
+        let second = RgbaImage::from_pixel(4, 4, Rgba([0, 0, 255, 255]));
+        for (pixels, delay) in [(first, 100), (second, 250)] {
+            let delay = Delay::from_numer_denom_ms(delay, 1);
+            encoder
+                .encode_frame(image::Frame::from_parts(pixels, 0, 0, delay))
+                .unwrap();
+        }
+    }
+
+    let frames = Image::frames(Cursor::new(gif), "test", "TS").unwrap();
+    assert_eq!(
+        frames.iter().map(|f| f.delay_ms).collect::<Vec<_>>(),
+        [100, 250]
+    );
+    assert_eq!(
+        frames[0].image.input.get_pixel(1, 1),
+        &Rgba([255, 0, 0, 255])
+    );
+    assert_eq!(
+        frames[1].image.input.get_pixel(1, 1),
+        &Rgba([0, 0, 255, 255])
+    );
+}
+
+/// APNG frames are drawn over the canvas left by the frame before, as their disposal and
+/// blending say.
+#[test]
+fn apng_frames_are_composited() {
+    use image::Rgba;
+    use std::io::Cursor;
+
+    let mut apng = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut apng, 4, 4);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_animated(2, 0).unwrap();
+        let mut writer = encoder.write_header().unwrap();
+        writer.set_frame_delay(1, 10).unwrap();
+        writer.set_dispose_op(png::DisposeOp::None).unwrap();
+        writer
+            .write_image_data(&[255, 0, 0, 255].repeat(16))
+            .unwrap();
+
+        // A half-transparent 2x2 frame blended over the middle of the first
+        writer.set_frame_delay(1, 4).unwrap();
+        writer.set_frame_dimension(2, 2).unwrap();
+        writer.set_frame_position(1, 1).unwrap();
+        writer.set_blend_op(png::BlendOp::Over).unwrap();
+        writer
+            .write_image_data(&[0, 0, 255, 128].repeat(4))
+            .unwrap();
+        writer.finish().unwrap();
+    }
+
+    let frames = Image::frames(Cursor::new(apng), "test", "TS").unwrap();
+    assert_eq!(
+        frames.iter().map(|f| f.delay_ms).collect::<Vec<_>>(),
+        [100, 250]
+    );
+    let second = &frames[1].image.input;
+    assert_eq!(second.get_pixel(0, 0), &Rgba([255, 0, 0, 255]));
+    let blended = second.get_pixel(1, 1);
+    assert!(blended[0] > 100 && blended[2] > 100, "{:?}", blended);
+}
+
+/// Still lossy WebPs, with or without alpha, are a single frame.
+#[test]
+fn lossy_webp_is_one_frame() {
+    let webp = include_bytes!("../N.webp");
+    let frames = Image::frames(std::io::Cursor::new(webp), "N", "NN").unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].image.dimensions(), (1200, 1200));
+}
@@ -0,0 +1,25 @@
+//! Sending converted images to the CEmu emulator
+//!
+//! CEmu is started with `--send` for each file. An instance that's already running is handed the
+//! files instead of another opening, so each conversion shows up in the same emulator, and
+//! otherwise the new one is left running without waiting for it to be closed.
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use log::info;
+
+/// Transfer files to CEmu, which is run as `program`.
+pub fn send(program: &Path, files: &[PathBuf]) -> Result<(), String> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    info!("Sending {} files to CEmu", files.len());
+    let mut command = Command::new(program);
+    for file in files {
+        command.arg("--send").arg(file);
+    }
+    command
+        .spawn()
+        .map_err(|e| format!("Unable to run {:?}: {}", program, e))?;
+    Ok(())
+}
@@ -0,0 +1,38 @@
+//! The `completions` subcommand, which prints shell completion scripts
+//!
+//! Scripts are generated by clap_complete from the definitions of every option and subcommand,
+//! so they stay up to date as options are added.
+use clap::{Arg, ArgMatches, Command};
+use clap_complete::Shell;
+
+pub fn command() -> Command {
+    Command::new("completions")
+        .about("Print a script that completes options in a shell")
+        .long_about(
+            "Print a script that completes options in a shell. For example, load it in bash with \
+             'source <(cli completions bash)', or save the output in a directory listed in zsh's \
+             $fpath as _cli, fish's completions directory as cli.fish or run it from a \
+             PowerShell profile.",
+        )
+        .arg(
+            Arg::new("shell")
+                .required(true)
+                .value_parser(clap::value_parser!(Shell))
+                .help("Shell to complete options in"),
+        )
+        .arg(
+            Arg::new("bin_name")
+                .long("bin-name")
+                .value_name("name")
+                .default_value("cli")
+                .help("Name the converter is run by"),
+        )
+}
+
+/// Print the completion script for the shell named in `m`, covering `command`.
+pub fn run(m: &ArgMatches, command: &Command) -> Result<(), Box<dyn std::error::Error>> {
+    let shell = *m.get_one::<Shell>("shell").unwrap();
+    let bin: &String = m.get_one("bin_name").unwrap();
+    clap_complete::generate(shell, &mut command.clone(), bin, &mut std::io::stdout());
+    Ok(())
+}
@@ -0,0 +1,203 @@
+//! Options given by configuration files and environment variables
+//!
+//! Every option can also be set by an environment variable named for it, like
+//! `HDPC_NO_FIT_SCREEN=true` or `HDPC_QUANTIZER=neuquant`, which take precedence over
+//! configuration files but not the command line.
+//!
+//! A configuration file is TOML whose keys are the long names of options, like
+//! `quantizer = "neuquant"` or `no-fit-screen = true`. Keys at the top level apply to every
+//! conversion, and tables under `profile` group more of them under a name to choose with
+//! `--profile`, taking precedence over the top level:
+//!
+//! ```toml
+//! dither = "none"
+//!
+//! [profile.poster]
+//! dither = "floyd-steinberg"
+//! no-fit-screen = true
+//! ```
+//!
+//! Options from the file are given to the parser ahead of those on the command line, which
+//! take precedence over them, leaving out any set by environment variables.
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use clap::builder::BoolishValueParser;
+use clap::{Arg, ArgAction, Command};
+use toml_edit::{Document, Item, TableLike, Value};
+
+/// Return the name of the environment variable that sets the option with a long name.
+fn env_name(long: &str) -> String {
+    format!("HDPC_{}", long.to_uppercase().replace('-', "_"))
+}
+
+/// Let every option of a command and its subcommands be set by an environment variable.
+pub fn with_env(command: Command) -> Command {
+    let subcommands: Vec<String> = command
+        .get_subcommands()
+        .map(|subcommand| subcommand.get_name().into())
+        .collect();
+    let command = command.mut_args(|arg| match arg.get_long().map(env_name) {
+        // Flags accept anything like yes or no from the environment, as well as true or false
+        Some(name) if matches!(arg.get_action(), ArgAction::SetTrue) => {
+            arg.env(name).value_parser(BoolishValueParser::new())
+        }
+        Some(name) => arg.env(name),
+        None => arg,
+    });
+    subcommands.iter().fold(command, |command, name| {
+        command.mut_subcommand(name, with_env)
+    })
+}
+
+pub fn args() -> [Arg; 2] {
+    [
+        Arg::new("config")
+            .long("config")
+            .value_name("file")
+            .value_parser(clap::value_parser!(PathBuf))
+            .help("Read default options from this TOML file")
+            .long_help(
+                "Read default options from this TOML file, where each key is the long name of \
+                 an option, like quantizer = \"neuquant\" or no-fit-screen = true. Options \
+                 given on the command line take precedence.",
+            ),
+        Arg::new("profile")
+            .long("profile")
+            .value_name("name")
+            .requires("config")
+            .help("Also use the options in the [profile.NAME] table of the configuration file"),
+    ]
+}
+
+/// Turn the options in a table into arguments, replacing those already in `options` with the
+/// same name.
+fn add_options(
+    command: &Command,
+    path: &Path,
+    table: &dyn TableLike,
+    options: &mut Vec<(String, Vec<OsString>)>,
+) -> Result<(), String> {
+    for (key, item) in table.iter() {
+        let is_option = command
+            .get_arguments()
+            .any(|arg| arg.get_long() == Some(key) && !arg.is_positional());
+        if !is_option || key == "config" || key == "profile" {
+            return Err(format!("{}: {} is not an option", path.display(), key));
+        }
+        if std::env::var_os(env_name(key)).is_some() {
+            continue;
+        }
+        let values: Vec<&Value> = match item.as_value() {
+            Some(Value::Array(array)) => array.iter().collect(),
+            Some(value) => vec![value],
+            None => return Err(format!("{}: {} must be a value", path.display(), key)),
+        };
+
+        let mut args = Vec::new();
+        for value in values {
+            let value = match value {
+                Value::String(s) => s.value().clone(),
+                Value::Integer(i) => i.value().to_string(),
+                Value::Float(f) => f.value().to_string(),
+                Value::Boolean(b) => {
+                    if *b.value() {
+                        args.push(format!("--{}", key).into());
+                    }
+                    continue;
+                }
+                _ => {
+                    return Err(format!(
+                        "{}: {} must be a string, number or boolean",
+                        path.display(),
+                        key
+                    ))
+                }
+            };
+            args.push(format!("--{}={}", key, value).into());
+        }
+        options.retain(|(name, _)| name != key);
+        options.push((key.into(), args));
+    }
+    Ok(())
+}
+
+/// Insert the options from any configuration file given in `args` ahead of the others.
+///
+/// Arguments for subcommands, which don't read configuration files, are returned as they are,
+/// as are any that don't parse so the parser can report the problem.
+pub fn apply(command: &Command, mut args: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let m = match command.clone().try_get_matches_from(&args) {
+        Ok(m) if m.subcommand().is_none() => m,
+        _ => return Ok(args),
+    };
+    let Some(path) = m.get_one::<PathBuf>("config") else {
+        return Ok(args);
+    };
+
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let document = text
+        .parse::<Document>()
+        .map_err(|e| format!("Unable to parse {}: {}", path.display(), e))?;
+
+    let mut defaults = document.as_table().clone();
+    let profiles = defaults.remove("profile");
+    let mut options = Vec::new();
+    add_options(command, path, &defaults, &mut options)?;
+    if let Some(name) = m.get_one::<String>("profile") {
+        let profile = profiles
+            .as_ref()
+            .and_then(Item::as_table_like)
+            .and_then(|profiles| profiles.get(name))
+            .and_then(Item::as_table_like)
+            .ok_or_else(|| format!("{}: there is no profile named {}", path.display(), name))?;
+        add_options(command, path, profile, &mut options)?;
+    }
+
+    args.splice(1..1, options.into_iter().flat_map(|(_, args)| args));
+    Ok(args)
+}
+
+/// Options from a configuration file go ahead of the command line's, with a profile's replacing
+/// the top level's and false flags left out, and keys that aren't options are refused.
+#[test]
+fn config_options_are_spliced() {
+    let path = std::env::temp_dir().join(format!("hdpc-config-{}.toml", std::process::id()));
+    let args = |extra: &[&str]| -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec!["cli".into(), "--config".into(), path.clone().into()];
+        args.extend(extra.iter().map(OsString::from));
+        args
+    };
+    // The arguments with options from the file inserted after the program's name
+    let spliced = |options: &[&str], extra: &[&str]| {
+        let mut spliced = args(extra);
+        spliced.splice(1..1, options.iter().map(OsString::from));
+        spliced
+    };
+    let command = super::command();
+
+    std::fs::write(
+        &path,
+        "dither = \"none\"\ncolors = 64\nfit-screen = false\n\n\
+         [profile.poster]\ndither = \"floyd-steinberg\"\nno-fit-screen = true\n",
+    )
+    .unwrap();
+    assert_eq!(
+        apply(&command, args(&["a.png"])).unwrap(),
+        spliced(&["--dither=none", "--colors=64"], &["a.png"])
+    );
+    let profile = ["--profile", "poster", "a.png"];
+    assert_eq!(
+        apply(&command, args(&profile)).unwrap(),
+        spliced(
+            &["--colors=64", "--dither=floyd-steinberg", "--no-fit-screen"],
+            &profile
+        )
+    );
+    assert!(apply(&command, args(&["--profile", "print", "a.png"])).is_err());
+
+    std::fs::write(&path, "size = 3\n").unwrap();
+    assert!(apply(&command, args(&["a.png"])).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,108 @@
+//! The `decode` subcommand, which turns appvars back into images
+use std::path::{Path, PathBuf};
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use log::{info, warn};
+
+use hdpictureconverter::decode::{self, DecodedImage};
+use hdpictureconverter::group::{self, Var};
+use hdpictureconverter::NameTemplate;
+
+use super::{ExistingOutput, STDIN_IMAGE_NAME};
+
+pub fn command() -> Command {
+    Command::new("decode")
+        .about("Rebuild images from their appvars and save them as PNGs")
+        .long_about(
+            "Rebuild images from their appvars and save them as PNGs. Every palette appvar found \
+             among the input files is decoded along with its tile appvars, showing the image as \
+             the calculator would, including padding.",
+        )
+        .args([
+            Arg::new("files")
+                .value_name("file")
+                .num_args(1..)
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Variable or group files containing palette and tile appvars"),
+            Arg::new("out_dir")
+                .short('o')
+                .long("outdir")
+                .default_value(".")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write images to this directory"),
+            Arg::new("name_template")
+                .long("name-template")
+                .value_name("template")
+                .value_parser(NameTemplate::parse)
+                .help("How tile appvars are named, if not the default"),
+            Arg::new("force")
+                .long("force")
+                .action(ArgAction::SetTrue)
+                .help("Overwrite images that already exist"),
+        ])
+}
+
+/// Read every variable in variable or group files.
+pub fn read_vars(files: &[&PathBuf]) -> Result<Vec<Var>, String> {
+    let mut vars = Vec::new();
+    for &path in files {
+        let data = std::fs::read(path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        let contents =
+            group::read(&data).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        if !contents.checksum_valid {
+            warn!("{:?} has an incorrect checksum", path);
+        }
+        vars.extend(contents.vars);
+    }
+    Ok(vars)
+}
+
+/// Return the name of the file a decoded image is saved as.
+fn file_name(image: &DecodedImage, animated: bool) -> String {
+    let mut name = image.name.trim_end_matches('_').to_string();
+    if name.is_empty() {
+        name = STDIN_IMAGE_NAME.into();
+    }
+    if animated {
+        name = format!("{}-{:02}", name, image.frame);
+    }
+    name + ".png"
+}
+
+pub fn run(m: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let files: Vec<&PathBuf> = m.get_many::<PathBuf>("files").unwrap().collect();
+    let out_dir: &Path = m.get_one::<PathBuf>("out_dir").unwrap();
+    let existing = if m.get_flag("force") {
+        ExistingOutput::Overwrite
+    } else {
+        ExistingOutput::Refuse
+    };
+
+    let vars = read_vars(&files)?;
+    let images = decode::images(&vars, m.get_one::<NameTemplate>("name_template"))?;
+    if images.is_empty() {
+        return Err("no palette appvars were found, so there are no images to decode".into());
+    }
+
+    let animated = images.iter().any(|image| image.frame > 0);
+    let paths: Vec<PathBuf> = images
+        .iter()
+        .map(|image| out_dir.join(file_name(image, animated)))
+        .collect();
+    existing.may_write(&paths)?;
+    for (image, path) in images.iter().zip(&paths) {
+        info!(
+            "Writing {}x{} image {} to {:?}",
+            image.image.width(),
+            image.image.height(),
+            image.name.trim_end_matches('_'),
+            path
+        );
+        image
+            .image
+            .save(path)
+            .map_err(|e| format!("Unable to write {:?}: {}", path, e))?;
+    }
+    Ok(())
+}
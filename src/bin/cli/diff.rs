@@ -0,0 +1,184 @@
+//! The `diff` subcommand, which compares the variables in two sets of files
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use clap::{Arg, ArgMatches, Command};
+use image::RgbaImage;
+
+use hdpictureconverter::decode::{self, DecodedImage, Palette, Tile};
+use hdpictureconverter::group::Var;
+use hdpictureconverter::NameTemplate;
+
+use super::decode::read_vars;
+
+pub fn command() -> Command {
+    Command::new("diff")
+        .about("Compare the appvars of two conversions")
+        .long_about(
+            "Compare the appvars of two conversions, listing those that were added, removed or \
+             changed. Changed tiles are compared pixel by pixel, counting how many pixels are a \
+             different color, so it's clear which parts of an image a change of settings affected.",
+        )
+        .args([
+            Arg::new("old")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Variable or group file to compare from"),
+            Arg::new("new")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Variable or group file to compare to"),
+            Arg::new("name_template")
+                .long("name-template")
+                .value_name("template")
+                .value_parser(NameTemplate::parse)
+                .help("How tile appvars are named, if not the default"),
+        ])
+}
+
+/// The variables of one side of the comparison and the images they make up.
+struct Side {
+    vars: Vec<Var>,
+    images: Vec<DecodedImage>,
+}
+
+impl Side {
+    fn read(path: &PathBuf, template: Option<&NameTemplate>) -> Result<Self, String> {
+        let vars = read_vars(&[path])?;
+        // Tiles are compared by their indices if the images can't be put together
+        let images = decode::images(&vars, template).unwrap_or_default();
+        Ok(Side { vars, images })
+    }
+
+    /// Read the tile in `var`, along with the colors of its pixels if the image it's part of
+    /// was decoded.
+    fn tile(&self, var: &Var, template: &NameTemplate) -> Option<(Tile, Option<Vec<u32>>)> {
+        let tile = Tile::read(&var.data).ok()?;
+        let colors = template.locate(&var.name).and_then(|position| {
+            let image = self.images.iter().find(|image| {
+                image.var_prefix == position.var_prefix && image.frame == position.frame
+            })?;
+            let (x, y) = (position.column * tile.width, position.row * tile.height);
+            colors(&image.image, x, y, tile.width, tile.height)
+        });
+        Some((tile, colors))
+    }
+}
+
+/// Return the colors of a region of an image, if it's all within the image.
+fn colors(image: &RgbaImage, x: u32, y: u32, width: u32, height: u32) -> Option<Vec<u32>> {
+    if x + width > image.width() || y + height > image.height() {
+        return None;
+    }
+    Some(
+        (y..y + height)
+            .flat_map(|y| (x..x + width).map(move |x| (x, y)))
+            .map(|(x, y)| u32::from_le_bytes(image.get_pixel(x, y).0))
+            .collect(),
+    )
+}
+
+/// Describe how one variable changed.
+fn describe_change(old: &Side, new: &Side, a: &Var, b: &Var, template: &NameTemplate) -> String {
+    let mut changes = Vec::new();
+    if a.ty != b.ty {
+        changes.push(format!("type {:?} -> {:?}", a.ty, b.ty));
+    }
+    if a.archived != b.archived {
+        changes.push(if b.archived { "archived" } else { "unarchived" }.to_string());
+    }
+    if a.data != b.data {
+        changes.push(if Tile::is_tile(&a.data) && Tile::is_tile(&b.data) {
+            match (old.tile(a, template), new.tile(b, template)) {
+                (Some((ta, _)), Some((tb, _)))
+                    if (ta.width, ta.height) != (tb.width, tb.height) =>
+                {
+                    format!(
+                        "tile size {}x{} -> {}x{}",
+                        ta.width, ta.height, tb.width, tb.height
+                    )
+                }
+                (Some((_, Some(ca))), Some((_, Some(cb)))) => {
+                    let differ = ca.iter().zip(&cb).filter(|(a, b)| a != b).count();
+                    format!("{} of {} pixels differ", differ, ca.len())
+                }
+                (Some((ta, _)), Some((tb, _))) => {
+                    let differ = ta.pixels.iter().zip(&tb.pixels).filter(|(a, b)| a != b);
+                    format!(
+                        "{} of {} palette indices differ",
+                        differ.count(),
+                        ta.pixels.len()
+                    )
+                }
+                _ => "tile data changed".into(),
+            }
+        } else if Palette::is_palette(&a.data) && Palette::is_palette(&b.data) {
+            match (Palette::read(&a.data), Palette::read(&b.data)) {
+                (Ok(pa), Ok(pb)) => {
+                    let differ = pa.colors.iter().zip(&pb.colors).filter(|(a, b)| a != b);
+                    format!(
+                        "{} of {} palette colors differ, {} -> {} colors",
+                        differ.count(),
+                        pa.colors.len().min(pb.colors.len()),
+                        pa.colors.len(),
+                        pb.colors.len()
+                    )
+                }
+                _ => "palette changed".into(),
+            }
+        } else {
+            format!("{} -> {} bytes of data", a.data.len(), b.data.len())
+        });
+    }
+    changes.join(", ")
+}
+
+pub fn run(m: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let template = m.get_one::<NameTemplate>("name_template");
+    let old = Side::read(m.get_one("old").unwrap(), template)?;
+    let new = Side::read(m.get_one("new").unwrap(), template)?;
+    // Default templates begin with the var prefix
+    let template_for = |var: &Var| match template {
+        Some(template) => template.clone(),
+        None => decode::default_template(&new.vars, var.name.get(..2).unwrap_or("")),
+    };
+    let by_name = |side: &Side| -> BTreeMap<String, usize> {
+        side.vars
+            .iter()
+            .enumerate()
+            .map(|(i, var)| (var.name.clone(), i))
+            .collect()
+    };
+    let (old_names, new_names) = (by_name(&old), by_name(&new));
+
+    let (mut added, mut removed, mut changed, mut unchanged) = (0, 0, 0, 0);
+    for (name, &a) in &old_names {
+        let a = &old.vars[a];
+        match new_names.get(name).map(|&b| &new.vars[b]) {
+            None => {
+                println!("- {}", name);
+                removed += 1;
+            }
+            Some(b) if a.ty == b.ty && a.archived == b.archived && a.data == b.data => {
+                unchanged += 1;
+            }
+            Some(b) => {
+                let change = describe_change(&old, &new, a, b, &template_for(b));
+                println!("~ {}: {}", name, change);
+                changed += 1;
+            }
+        }
+    }
+    for name in new_names
+        .keys()
+        .filter(|name| !old_names.contains_key(*name))
+    {
+        println!("+ {}", name);
+        added += 1;
+    }
+    println!(
+        "{} added, {} removed, {} changed, {} unchanged",
+        added, removed, changed, unchanged
+    );
+    Ok(())
+}
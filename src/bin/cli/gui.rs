@@ -0,0 +1,376 @@
+//! The `gui` subcommand, a window that converts images dropped on it
+//!
+//! The preview is decoded from the converted appvars, so it's what HD Picture Viewer will show.
+//! Images convert on another thread whenever the options change, and the group file is only
+//! written when it's saved.
+use std::collections::HashSet;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+
+use clap::{Arg, ArgMatches, Command, ValueEnum};
+use eframe::egui;
+
+use hdpictureconverter::{
+    decode, group, DecodeOptions, Dither, Image, QuantizeOptions, SCREEN_HEIGHT, SCREEN_WIDTH,
+};
+
+use super::{
+    derive_var_prefix, var_prefix_str, CompressionChoice, DitherChoice, QuantizerChoice,
+    GROUP_EXTENSION,
+};
+
+pub fn command() -> Command {
+    Command::new("gui")
+        .about("Open a window that converts images dropped on it")
+        .arg(
+            Arg::new("image")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Image to open in the window"),
+        )
+}
+
+pub fn run(m: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let mut gui = Gui::default();
+    if let Some(path) = m.get_one::<PathBuf>("image") {
+        gui.open(path);
+    }
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default()
+            .with_inner_size([960., 600.])
+            .with_drag_and_drop(true),
+        ..Default::default()
+    };
+    eframe::run_native(
+        "HD Picture Converter",
+        options,
+        Box::new(|_| Ok(Box::new(gui))),
+    )
+    .map_err(|e| format!("Unable to open a window: {}", e).into())
+}
+
+/// The options that can be changed in the window, which are a few of the command line's.
+#[derive(Clone, Debug, PartialEq)]
+struct Options {
+    var_prefix: String,
+    fit_screen: bool,
+    brightness: f32,
+    contrast: f32,
+    saturation: f32,
+    quantizer: QuantizerChoice,
+    colors: u32,
+    dither: DitherChoice,
+    dither_strength: f32,
+    compression: CompressionChoice,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        let quantize = QuantizeOptions::default();
+        Options {
+            var_prefix: "AA".to_string(),
+            fit_screen: true,
+            brightness: 0.,
+            contrast: 1.,
+            saturation: 1.,
+            quantizer: QuantizerChoice(quantize.quantizer),
+            colors: quantize.max_colors,
+            dither: DitherChoice(quantize.dither),
+            dither_strength: quantize.dither_strength,
+            compression: CompressionChoice(Default::default()),
+        }
+    }
+}
+
+/// An image converted with some options.
+struct Converted {
+    /// The image decoded from its appvars.
+    preview: image::RgbaImage,
+    group: Vec<u8>,
+    colors: usize,
+}
+
+/// Convert an image as the options specify, returning the group file of its appvars.
+fn convert(mut image: Image, options: &Options) -> Result<Converted, String> {
+    var_prefix_str(&options.var_prefix)?;
+    image.set_var_prefix(&options.var_prefix);
+    if options.fit_screen {
+        image.downscale_to_fit(SCREEN_WIDTH, SCREEN_HEIGHT);
+    }
+    if options.brightness != 0. || options.contrast != 1. {
+        image.adjust_brightness_contrast(options.brightness, options.contrast);
+    }
+    if options.saturation != 1. {
+        image.adjust_saturation_hue(options.saturation, 0.);
+    }
+    let quantize = QuantizeOptions {
+        quantizer: options.quantizer.0,
+        max_colors: options.colors,
+        dither: options.dither.0,
+        dither_strength: options.dither_strength,
+        ..Default::default()
+    };
+    let (width, height) = image.dimensions();
+    let mut quantized = image.quantize_with(&quantize).map_err(|e| e.to_string())?;
+    quantized.set_compression(options.compression.0);
+    let colors = quantized.stored_palette().len();
+    let group = quantized
+        .write_group(Vec::new())
+        .map_err(|e| e.to_string())?;
+
+    let vars = group::read(&group).map_err(|e| e.to_string())?.vars;
+    let decoded = decode::images(&vars, None)
+        .map_err(|e| e.to_string())?
+        .swap_remove(0)
+        .image;
+    // Without the padding that fills out the last tiles
+    let preview = image::imageops::crop_imm(&decoded, 0, 0, width, height).to_image();
+    Ok(Converted {
+        preview,
+        group,
+        colors,
+    })
+}
+
+#[derive(Default)]
+struct Gui {
+    /// The image being converted and where it came from.
+    source: Option<(PathBuf, Image)>,
+    options: Options,
+    /// The options of the latest conversion, whether or not it's finished.
+    converted_options: Option<Options>,
+    converting: Option<Receiver<Result<Converted, String>>>,
+    converted: Option<Converted>,
+    preview: Option<egui::TextureHandle>,
+    /// What happened last, and whether it went wrong.
+    status: Option<(String, bool)>,
+}
+
+impl Gui {
+    fn open(&mut self, path: &Path) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let var_prefix = match derive_var_prefix(path, &HashSet::new()) {
+            Ok(var_prefix) => var_prefix,
+            Err(e) => {
+                self.status = Some((e, true));
+                return;
+            }
+        };
+        let loaded = std::fs::File::open(path).and_then(|file| {
+            Image::new_with(
+                BufReader::new(file),
+                &name,
+                &var_prefix,
+                &DecodeOptions::default(),
+            )
+        });
+        match loaded {
+            Ok(image) => {
+                self.source = Some((path.to_path_buf(), image));
+                self.options.var_prefix = var_prefix;
+                // A conversion of the last image is no use any more
+                self.converting = None;
+                self.converted_options = None;
+                self.converted = None;
+                self.preview = None;
+                self.status = None;
+            }
+            Err(e) => self.status = Some((format!("Unable to open {:?}: {}", path, e), true)),
+        }
+    }
+
+    /// Start converting the image if the options have changed since it was last converted.
+    fn convert_if_changed(&mut self, ctx: &egui::Context) {
+        let Some((_, image)) = &self.source else {
+            return;
+        };
+        if self.converting.is_some() || self.converted_options.as_ref() == Some(&self.options) {
+            return;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let (image, options, ctx) = (image.clone(), self.options.clone(), ctx.clone());
+        std::thread::spawn(move || {
+            // The window may have closed first, leaving nobody to receive this
+            let _ = sender.send(convert(image, &options));
+            ctx.request_repaint();
+        });
+        self.converting = Some(receiver);
+        self.converted_options = Some(self.options.clone());
+    }
+
+    fn receive_conversion(&mut self, ctx: &egui::Context) {
+        let Some(result) = self.converting.as_ref().and_then(|r| r.try_recv().ok()) else {
+            return;
+        };
+        self.converting = None;
+        match result {
+            Ok(converted) => {
+                let (width, height) = converted.preview.dimensions();
+                let pixels = egui::ColorImage::from_rgba_unmultiplied(
+                    [width as usize, height as usize],
+                    converted.preview.as_raw(),
+                );
+                self.preview =
+                    Some(ctx.load_texture("preview", pixels, egui::TextureOptions::NEAREST));
+                self.status = Some((
+                    format!(
+                        "{}x{} pixels, {} colors, {} byte group file",
+                        width,
+                        height,
+                        converted.colors,
+                        converted.group.len()
+                    ),
+                    false,
+                ));
+                self.converted = Some(converted);
+            }
+            Err(e) => {
+                self.converted = None;
+                self.status = Some((e, true));
+            }
+        }
+    }
+
+    fn save(&mut self) {
+        let (Some((path, _)), Some(converted)) = (&self.source, &self.converted) else {
+            return;
+        };
+        let out = path.with_extension(GROUP_EXTENSION);
+        self.status = Some(match std::fs::write(&out, &converted.group) {
+            Ok(()) => (format!("Saved {:?}", out), false),
+            Err(e) => (format!("Unable to save {:?}: {}", out, e), true),
+        });
+    }
+
+    fn options_ui(&mut self, ui: &mut egui::Ui) {
+        let options = &mut self.options;
+        ui.horizontal(|ui| {
+            ui.label("Var prefix");
+            ui.add(egui::TextEdit::singleline(&mut options.var_prefix).desired_width(32.));
+        });
+        ui.checkbox(&mut options.fit_screen, "Shrink to fit the screen");
+        ui.add(egui::Slider::new(&mut options.brightness, -1.0..=1.0).text("Brightness"));
+        ui.add(egui::Slider::new(&mut options.contrast, 0.0..=3.0).text("Contrast"));
+        ui.add(egui::Slider::new(&mut options.saturation, 0.0..=3.0).text("Saturation"));
+        ui.separator();
+        choice_ui(ui, "Quantizer", &mut options.quantizer);
+        ui.add(egui::Slider::new(&mut options.colors, 2..=256).text("Colors"));
+        choice_ui(ui, "Dither", &mut options.dither);
+        ui.add_enabled(
+            options.dither.0 != Dither::None,
+            egui::Slider::new(&mut options.dither_strength, 0.0..=1.0).text("Dither strength"),
+        );
+        choice_ui(ui, "Compression", &mut options.compression);
+        ui.separator();
+        if ui
+            .add_enabled(
+                self.converted.is_some(),
+                egui::Button::new("Save group file"),
+            )
+            .clicked()
+        {
+            self.save();
+        }
+    }
+}
+
+/// Show a menu choosing one of the values a command-line option takes.
+fn choice_ui<T: ValueEnum + PartialEq>(ui: &mut egui::Ui, label: &str, choice: &mut T) {
+    let name = |value: &T| value.to_possible_value().unwrap().get_name().to_string();
+    egui::ComboBox::from_label(label)
+        .selected_text(name(choice))
+        .show_ui(ui, |ui| {
+            for value in T::value_variants() {
+                let possible = value.to_possible_value().unwrap();
+                let response = ui.selectable_label(choice == value, possible.get_name());
+                let response = match possible.get_help() {
+                    Some(help) => response.on_hover_text(help.to_string()),
+                    None => response,
+                };
+                if response.clicked() {
+                    *choice = value.clone();
+                }
+            }
+        });
+}
+
+impl eframe::App for Gui {
+    fn ui(&mut self, ui: &mut egui::Ui, _frame: &mut eframe::Frame) {
+        let ctx = ui.ctx().clone();
+        let dropped = ctx.input(|input| input.raw.dropped_files.first().cloned());
+        if let Some(file) = dropped {
+            self.open(file.path());
+        }
+        self.receive_conversion(&ctx);
+        self.convert_if_changed(&ctx);
+
+        egui::Panel::left("options")
+            .resizable(false)
+            .show(ui, |ui| {
+                ui.add_space(4.);
+                self.options_ui(ui);
+            });
+        egui::Panel::bottom("status").show(ui, |ui| match &self.status {
+            Some((message, true)) => ui.colored_label(ui.visuals().error_fg_color, message),
+            Some((message, false)) => ui.label(message),
+            None if self.converting.is_some() => ui.label("Converting..."),
+            None => ui.label(""),
+        });
+        egui::CentralPanel::default().show(ui, |ui| {
+            let hovering = ctx.input(|input| !input.raw.hovered_files.is_empty());
+            match &self.preview {
+                Some(preview) if !hovering => {
+                    // Show whole calculator pixels, as large as fits
+                    let size = preview.size_vec2();
+                    let available = ui.available_size();
+                    let scale = (available.x / size.x).min(available.y / size.y).floor();
+                    ui.centered_and_justified(|ui| {
+                        ui.image((preview.id(), size * scale.max(1.)));
+                    });
+                }
+                _ => {
+                    ui.centered_and_justified(|ui| {
+                        ui.heading(if hovering {
+                            "Drop the image to convert it"
+                        } else {
+                            "Drop an image here to convert it"
+                        });
+                    });
+                }
+            }
+        });
+    }
+}
+
+/// Previews are decoded from the group file the options produce.
+#[test]
+fn conversions_are_previewed() {
+    use image::{Rgba, RgbaImage};
+
+    let source = RgbaImage::from_fn(640, 100, |x, y| Rgba([(x / 3) as u8, y as u8, 200, 255]));
+    let image = Image::from_rgba(source, "GRADIENT.png", "AA");
+    let options = Options {
+        var_prefix: "GR".to_string(),
+        colors: 4,
+        dither: DitherChoice(Dither::None),
+        ..Default::default()
+    };
+    let converted = convert(image.clone(), &options).unwrap();
+    assert_eq!(converted.preview.dimensions(), (320, 50));
+    assert!(converted.colors <= 4);
+    let vars = group::read(&converted.group).unwrap().vars;
+    assert!(vars.iter().all(|var| var.name.contains("GR")));
+
+    let full_size = Options {
+        fit_screen: false,
+        ..options.clone()
+    };
+    let converted = convert(image.clone(), &full_size).unwrap();
+    assert_eq!(converted.preview.dimensions(), (640, 100));
+
+    let bad_prefix = Options {
+        var_prefix: "G".to_string(),
+        ..options
+    };
+    assert!(convert(image, &bad_prefix).is_err());
+}
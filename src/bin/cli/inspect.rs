@@ -0,0 +1,205 @@
+//! The `inspect` subcommand, which describes the variables in variable and group files
+use std::path::PathBuf;
+
+use clap::{Arg, ArgMatches, Command};
+
+use hdpictureconverter::decode::{self, Animation, Palette, Tile};
+use hdpictureconverter::group::{self, Var};
+use hdpictureconverter::index::Index;
+use hdpictureconverter::metadata::Metadata;
+use hdpictureconverter::thumbnail::Thumbnail;
+use hdpictureconverter::{BitDepth, NameTemplate, PICTURE_HEIGHT, PICTURE_WIDTH};
+use tifiles::VariableType;
+
+pub fn command() -> Command {
+    Command::new("inspect")
+        .about("Describe the variables in variable and group files")
+        .long_about(
+            "Describe the variables in variable and group files: each one's name, type, whether \
+             it's archived and the length of its data, along with what's in palette, animation, \
+             index, metadata, thumbnail and tile appvars. Files with an incorrect checksum are \
+             reported but still read.",
+        )
+        .args([
+            Arg::new("files")
+                .value_name("file")
+                .num_args(1..)
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Variable or group files to describe"),
+            Arg::new("name_template")
+                .long("name-template")
+                .value_name("template")
+                .value_parser(NameTemplate::parse)
+                .help("How tile appvars are named, if not the default"),
+        ])
+}
+
+/// Describe how pixels are packed, if not one to a byte.
+fn packing(depth: BitDepth) -> String {
+    match depth {
+        BitDepth::Eight => String::new(),
+        _ => format!(", {}-bit pixels", depth.bits()),
+    }
+}
+
+/// Describe what an appvar or picture made by the converter contains.
+fn describe(var: &Var, template: &NameTemplate) -> String {
+    let data = &var.data;
+    if var.ty == VariableType::Picture {
+        // Picture names are a token whose second byte counts from Pic1 up to Pic0, and might
+        // have been trimmed off along with the padding when zero
+        let number = match var.name.as_bytes() {
+            [0x60] => Some(0),
+            [0x60, number @ 0..=9] => Some(*number),
+            _ => None,
+        };
+        return match number {
+            Some(number) if data.len() == 756 => format!(
+                "monochrome picture Pic{}, {}x{} pixels",
+                (number + 1) % 10,
+                PICTURE_WIDTH,
+                PICTURE_HEIGHT
+            ),
+            _ => "not made by this converter".into(),
+        };
+    }
+    let result = if Palette::is_palette(data) {
+        Palette::read(data).map(|p| {
+            format!(
+                "palette of {} ({}), {}x{} tiles, {} colors{}{}",
+                p.name.trim_end_matches('_'),
+                p.var_prefix,
+                p.columns,
+                p.rows,
+                p.colors.len(),
+                packing(p.bit_depth),
+                match p.lcd_scale.factors() {
+                    (1, 1) => String::new(),
+                    (x, y) => format!(", shown with pixels {}x{} on the LCD", x, y),
+                }
+            )
+        })
+    } else if Animation::is_animation(data) {
+        Animation::read(data).map(|a| {
+            format!(
+                "animation of {} ({}), {}x{} tiles, {} frames{}, delays {:?} ms",
+                a.name.trim_end_matches('_'),
+                a.var_prefix,
+                a.columns,
+                a.rows,
+                a.delays_ms.len(),
+                if a.shared_palette {
+                    " sharing a palette"
+                } else {
+                    ""
+                },
+                a.delays_ms
+            )
+        })
+    } else if Index::is_index(data) {
+        Index::read(data).map(|index| {
+            let images: Vec<String> = index
+                .entries
+                .iter()
+                .map(|e| format!("{} ({})", e.name.trim_end_matches('_'), e.var_prefix))
+                .collect();
+            format!(
+                "slideshow index of {} images: {}",
+                images.len(),
+                images.join(", ")
+            )
+        })
+    } else if Thumbnail::is_thumbnail(data) {
+        Thumbnail::read(data).map(|t| {
+            format!(
+                "thumbnail of {} ({}), {}x{} pixels, {} colors",
+                t.name.trim_end_matches('_'),
+                t.var_prefix,
+                t.width,
+                t.height,
+                t.palette.len()
+            )
+        })
+    } else if Metadata::is_metadata(data) {
+        Metadata::read(data).map(|m| {
+            format!(
+                "metadata of {} ({}), {}x{} pixels in {}x{} tiles of {}x{}, {}-bit pixels, \
+                 {:?} compression",
+                m.name.trim_end_matches('_'),
+                m.var_prefix,
+                m.width,
+                m.height,
+                m.columns,
+                m.rows,
+                m.tile_width,
+                m.tile_height,
+                m.bits,
+                m.compression
+            )
+        })
+    } else if Tile::is_tile(data) {
+        Tile::read(data).map(|t| {
+            let position = match template.locate(&var.name) {
+                Some(p) if template.has_frame() => format!(
+                    "column {}, row {} of frame {} of {}",
+                    p.column, p.row, p.frame, p.var_prefix
+                ),
+                Some(p) => format!("column {}, row {} of {}", p.column, p.row, p.var_prefix),
+                None => "unknown position".into(),
+            };
+            format!(
+                "tile at {} in {}, {}x{} pixels, {:?} compression{}",
+                position,
+                t.name.trim_end_matches('_'),
+                t.width,
+                t.height,
+                t.compression,
+                match t.colors {
+                    Some(_) => ", 16-bit color".into(),
+                    None => packing(t.bit_depth),
+                }
+            )
+        })
+    } else {
+        return "not made by this converter".into();
+    };
+    result.unwrap_or_else(|e| format!("corrupt: {}", e))
+}
+
+pub fn run(m: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    for path in m.get_many::<PathBuf>("files").unwrap() {
+        let file = std::fs::read(path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        let contents =
+            group::read(&file).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+
+        println!(
+            "{}: {} variable{}, checksum {}",
+            path.display(),
+            contents.vars.len(),
+            if contents.vars.len() == 1 { "" } else { "s" },
+            if contents.checksum_valid {
+                "valid"
+            } else {
+                "INCORRECT"
+            }
+        );
+        println!("  comment: {}", contents.comment.trim_end());
+        // Default templates begin with the var prefix
+        let template = |var: &Var| match m.get_one::<NameTemplate>("name_template") {
+            Some(template) => template.clone(),
+            None => decode::default_template(&contents.vars, var.name.get(..2).unwrap_or("")),
+        };
+        for var in &contents.vars {
+            println!(
+                "  {:8}  {:?}{}, {} bytes: {}",
+                var.name,
+                var.ty,
+                if var.archived { ", archived" } else { "" },
+                var.data.len(),
+                describe(var, &template(var))
+            );
+        }
+    }
+    Ok(())
+}
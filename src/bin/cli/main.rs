@@ -0,0 +1,3662 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use clap::builder::{PossibleValue, PossibleValuesParser, TypedValueParser};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, Command};
+use glob::Pattern;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, error, info, trace, warn, Level, LevelFilter, Log, Metadata, Record};
+use notify::{RecursiveMode, Watcher};
+use rayon::prelude::*;
+use zip::ZipWriter;
+
+use hdpictureconverter::{
+    group, index, index::Index, palette, screen, viewer, BitDepth, ColorMetric, ColorSpace,
+    Compression, DecodeOptions, Dither, Frame, Image, LcdScale, NameTemplate, PictureOptions,
+    QuantizeOptions, QuantizedImage, Quantizer, Rotation, ScaleMode, StreamingImage, Tile,
+    MAX_DIRECT_COLOR_PIXELS, MAX_ZX0_LEN, NUMWORKS_HEIGHT, NUMWORKS_WIDTH, OTHER_EXTENSIONS,
+    PICTURE_HEIGHT, PICTURE_WIDTH, SCREEN_HEIGHT, SCREEN_WIDTH, TI_PYTHON_HEIGHT, TI_PYTHON_WIDTH,
+};
+use image::{DynamicImage, ImageOutputFormat};
+use rgb::RGBA8 as RGBA;
+use serde::Serialize;
+
+mod cemu;
+mod completions;
+mod config;
+mod decode;
+mod diff;
+#[cfg(feature = "gui")]
+mod gui;
+mod inspect;
+mod project;
+mod serve;
+#[cfg(feature = "usb")]
+mod usb;
+mod verify;
+
+fn var_prefix_str(s: &str) -> Result<String, String> {
+    let len = s.chars().count();
+    if len != 2 {
+        return Err(format!(
+            "var_prefix must be exactly two characters, but is {}",
+            len
+        ));
+    }
+
+    for (i, c) in s.chars().enumerate() {
+        if !c.is_ascii_alphabetic() {
+            return Err(format!(
+                "{:?} at var_prefix position {} is not an alphabetic character",
+                c, i
+            ));
+        }
+    }
+
+    Ok(s.into())
+}
+
+fn dither_strength(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if (0. ..=1.).contains(&x) => Ok(x),
+        Ok(x) => Err(format!("{} is not between 0 and 1", x)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse a quality range written as `MIN-MAX`, or just `MAX` for no minimum.
+fn quality(s: &str) -> Result<(u8, u8), String> {
+    let invalid = || format!("{:?} is not a quality range like 60-90", s);
+    let (min, max) = s.split_once('-').unwrap_or(("0", s));
+    match (min.parse::<u8>(), max.parse::<u8>()) {
+        (Ok(min), Ok(max)) if max <= 100 && min <= max => Ok((min, max)),
+        (Ok(_), Ok(_)) => Err(format!(
+            "{:?} must be between 0 and 100 with the minimum first",
+            s
+        )),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parse dimensions written as `WxH`, like `320x240`.
+fn dimensions(s: &str) -> Result<(u32, u32), String> {
+    let invalid = || format!("{:?} is not a size like 320x240", s);
+    let (width, height) = s.split_once('x').ok_or_else(invalid)?;
+    match (width.parse::<u32>(), height.parse::<u32>()) {
+        (Ok(w), Ok(h)) if w > 0 && h > 0 => Ok((w, h)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parse a crop region written as `X,Y,WxH`.
+fn crop_region(s: &str) -> Result<(u32, u32, u32, u32), String> {
+    let invalid = || format!("{:?} is not a region like 0,0,320x240", s);
+    let mut parts = s.splitn(3, ',');
+    let mut coordinate = || parts.next().and_then(|c| c.parse::<u32>().ok());
+    let (x, y) = coordinate().zip(coordinate()).ok_or_else(invalid)?;
+    let (width, height) = parts.next().ok_or_else(invalid).and_then(dimensions)?;
+    Ok((x, y, width, height))
+}
+
+fn gamma(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if x > 0. && x.is_finite() => Ok(x),
+        Ok(x) => Err(format!("{} is not a positive number", x)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse a density in dots per inch.
+#[cfg(any(feature = "pdf", feature = "svg"))]
+fn density(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if x > 0. && x.is_finite() => Ok(x),
+        Ok(x) => Err(format!("{} is not a positive number", x)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn brightness(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if (-1. ..=1.).contains(&x) => Ok(x),
+        Ok(x) => Err(format!("{} is not between -1 and 1", x)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Parse a factor for an adjustment like contrast, which may not be negative.
+fn adjustment_factor(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(x) if x >= 0. && x.is_finite() => Ok(x),
+        Ok(x) => Err(format!("{} is not a non-negative number", x)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn tile_size(s: &str) -> Result<(u32, u32), String> {
+    match dimensions(s)? {
+        (w, h) if w <= 255 && h <= 255 => Ok((w, h)),
+        _ => Err(format!("{:?} is larger than 255x255", s)),
+    }
+}
+
+fn comment(s: &str) -> Result<String, String> {
+    if !s.is_ascii() {
+        return Err(format!("{:?} contains non-ASCII characters", s));
+    }
+    if s.len() > group::COMMENT_LEN {
+        return Err(format!(
+            "comment is {} characters long but may not be more than {}",
+            s.len(),
+            group::COMMENT_LEN
+        ));
+    }
+    Ok(s.into())
+}
+
+/// Parse a number of bytes, optionally followed by `K` or `M` for kibibytes or mebibytes.
+fn byte_count(s: &str) -> Result<usize, String> {
+    let (digits, multiplier) = match s.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1 << 10),
+        None => match s.strip_suffix(['M', 'm']) {
+            Some(digits) => (digits, 1 << 20),
+            None => (s, 1),
+        },
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("{:?} is not a number of bytes", s))
+}
+
+fn glob_pattern(s: &str) -> Result<Pattern, String> {
+    Pattern::new(s).map_err(|e| format!("{:?} is not a valid pattern: {}", s, e))
+}
+
+/// Where to get a fixed palette from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum PaletteSource {
+    /// The graphx default palette, which needs no palette appvar.
+    Xlibc,
+    File(PathBuf),
+}
+
+impl PaletteSource {
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "xlibc" => Ok(PaletteSource::Xlibc),
+            _ => Ok(PaletteSource::File(s.into())),
+        }
+    }
+
+    fn load(&self) -> Result<Vec<RGBA>, String> {
+        match self {
+            PaletteSource::Xlibc => Ok(hdpictureconverter::palette::xlibc()),
+            PaletteSource::File(path) => std::fs::File::open(path)
+                .map(BufReader::new)
+                .and_then(hdpictureconverter::palette::read)
+                .map_err(|e| format!("Unable to read palette {:?}: {}", path, e)),
+        }
+    }
+}
+
+/// Quantizer choices, wrapping the library's [`Quantizer`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct QuantizerChoice(Quantizer);
+
+impl clap::ValueEnum for QuantizerChoice {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            #[cfg(feature = "imagequant")]
+            Self(Quantizer::ImageQuant),
+            Self(Quantizer::NeuQuant),
+            Self(Quantizer::MedianCut),
+            Self(Quantizer::Octree),
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self.0 {
+            #[cfg(feature = "imagequant")]
+            Quantizer::ImageQuant => Some(
+                PossibleValue::new("imagequant")
+                    .alias("libimagequant")
+                    .help("libimagequant, which gives the best palettes"),
+            ),
+            Quantizer::NeuQuant => Some(
+                PossibleValue::new("neuquant").help("NeuQuant, which is faster on large images"),
+            ),
+            Quantizer::MedianCut => Some(
+                PossibleValue::new("median-cut")
+                    .help("Median cut, which is fastest and suits images with few colors"),
+            ),
+            Quantizer::Octree => {
+                Some(PossibleValue::new("octree").help("Octree, which suits gradients and photos"))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "imagequant")]
+const DEFAULT_QUANTIZER: &str = "imagequant";
+#[cfg(not(feature = "imagequant"))]
+const DEFAULT_QUANTIZER: &str = "neuquant";
+
+/// Color space choices, wrapping the library's [`ColorSpace`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct ColorSpaceChoice(ColorSpace);
+
+impl clap::ValueEnum for ColorSpaceChoice {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self(ColorSpace::Rgb), Self(ColorSpace::Oklab)]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self.0 {
+            ColorSpace::Rgb => Some(PossibleValue::new("rgb").help("sRGB as stored")),
+            ColorSpace::Oklab => {
+                Some(PossibleValue::new("oklab").help("Oklab, which is perceptually uniform"))
+            }
+        }
+    }
+}
+
+/// Color metric choices, wrapping the library's [`ColorMetric`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct ColorMetricChoice(ColorMetric);
+
+impl clap::ValueEnum for ColorMetricChoice {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self(ColorMetric::Rgb),
+            Self(ColorMetric::WeightedRgb),
+            Self(ColorMetric::Lab),
+            Self(ColorMetric::Ciede2000),
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self.0 {
+            ColorMetric::Rgb => Some(PossibleValue::new("rgb").help("Distance in sRGB")),
+            ColorMetric::WeightedRgb => Some(
+                PossibleValue::new("weighted-rgb")
+                    .help("Distance in sRGB, weighting green most and red least"),
+            ),
+            ColorMetric::Lab => Some(
+                PossibleValue::new("lab")
+                    .alias("cie76")
+                    .help("Distance in CIELAB"),
+            ),
+            ColorMetric::Ciede2000 => Some(
+                PossibleValue::new("ciede2000").help("CIEDE2000 difference, which is much slower"),
+            ),
+        }
+    }
+}
+
+/// Dithering algorithm choices, wrapping the library's [`Dither`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct DitherChoice(Dither);
+
+impl clap::ValueEnum for DitherChoice {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self(Dither::None),
+            Self(Dither::FloydSteinberg),
+            Self(Dither::Atkinson),
+            Self(Dither::Sierra),
+            Self(Dither::JarvisJudiceNinke),
+            Self(Dither::Bayer),
+            Self(Dither::BlueNoise),
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self.0 {
+            Dither::None => Some(PossibleValue::new("none").help("Use the nearest palette color")),
+            Dither::FloydSteinberg => Some(
+                PossibleValue::new("fs")
+                    .alias("floyd-steinberg")
+                    .help("Floyd-Steinberg error diffusion"),
+            ),
+            Dither::Atkinson => {
+                Some(PossibleValue::new("atkinson").help("Atkinson error diffusion"))
+            }
+            Dither::Sierra => Some(PossibleValue::new("sierra").help("Sierra error diffusion")),
+            Dither::JarvisJudiceNinke => Some(
+                PossibleValue::new("jjn")
+                    .alias("jarvis-judice-ninke")
+                    .help("Jarvis-Judice-Ninke error diffusion"),
+            ),
+            Dither::Bayer => Some(
+                PossibleValue::new("bayer")
+                    .alias("ordered")
+                    .help("Ordered dithering with an 8x8 Bayer matrix"),
+            ),
+            Dither::BlueNoise => {
+                Some(PossibleValue::new("blue-noise").help("Ordered dithering with blue noise"))
+            }
+        }
+    }
+}
+
+/// Scale mode choices, wrapping the library's [`ScaleMode`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct ScaleModeChoice(ScaleMode);
+
+impl clap::ValueEnum for ScaleModeChoice {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self(ScaleMode::Fit),
+            Self(ScaleMode::Fill),
+            Self(ScaleMode::Stretch),
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self.0 {
+            ScaleMode::Fit => PossibleValue::new("fit").help("Scale to fit inside, adding bars"),
+            ScaleMode::Fill => PossibleValue::new("fill").help("Scale to cover, cropping edges"),
+            ScaleMode::Stretch => PossibleValue::new("stretch").help("Scale to exactly the size"),
+        })
+    }
+}
+
+/// Compression choices, wrapping the library's [`Compression`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct CompressionChoice(Compression);
+
+impl clap::ValueEnum for CompressionChoice {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self(Compression::Zx0),
+            Self(Compression::Deflate),
+            Self(Compression::Rle),
+            Self(Compression::None),
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        Some(match self.0 {
+            Compression::Zx0 => {
+                PossibleValue::new("zx0").help("The format HD Picture Viewer reads")
+            }
+            Compression::Deflate => {
+                PossibleValue::new("deflate").help("Raw DEFLATE, preceded by the original size")
+            }
+            Compression::Rle => {
+                PossibleValue::new("rle").help("Run-length encoding, for flat images")
+            }
+            Compression::None => PossibleValue::new("none").help("Uncompressed"),
+        })
+    }
+}
+
+/// How generated appvars are packaged for output.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// A single group file containing every appvar.
+    Group,
+    /// One 8xv file per appvar.
+    Loose,
+    /// A zip archive of 8xv files.
+    Zip,
+    /// One bin file per appvar, holding its data without the TI variable header.
+    Raw,
+    /// A Python appvar that draws the image, in place of tiles.
+    TiPython,
+}
+
+impl clap::ValueEnum for OutputFormat {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self::Group,
+            Self::Loose,
+            Self::Zip,
+            Self::Raw,
+            Self::TiPython,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Group => Some(PossibleValue::new("group").help("One 8xg containing all appvars")),
+            Self::Loose => Some(PossibleValue::new("loose").help("Separate 8xv for each appvar")),
+            Self::Zip => Some(PossibleValue::new("zip").help("Zip archive of 8xv appvars")),
+            Self::Raw => {
+                Some(PossibleValue::new("raw").help("Separate bin of each appvar's data alone"))
+            }
+            Self::TiPython => Some(
+                PossibleValue::new("ti-python")
+                    .help("Python appvar drawing the image with ti_draw"),
+            ),
+        }
+    }
+}
+
+/// Programs that can be generated to show converted images.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ViewerKind {
+    /// A TI-BASIC launcher that says which image to choose and runs HD Picture Viewer, since
+    /// TI-BASIC can't read appvars to show the image itself.
+    Basic,
+    /// eZ80 assembly source for a program that shows the image by itself, which has to be
+    /// assembled before it can be run.
+    Asm,
+}
+
+impl clap::ValueEnum for ViewerKind {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Basic, Self::Asm]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Basic => {
+                Some(PossibleValue::new("basic").help("TI-BASIC launcher for HD Picture Viewer"))
+            }
+            Self::Asm => Some(PossibleValue::new("asm").help("eZ80 source to assemble with fasmg")),
+        }
+    }
+}
+
+/// Calculators that images can be converted for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Target {
+    /// The TI-84 Plus CE, with tile appvars for HD Picture Viewer.
+    Ce,
+    /// The TI-83 Premium CE, which is the TI-84 Plus CE sold in France and runs the same
+    /// programs.
+    ///
+    /// Its OS restricts appvar names the same way, which name templates are already checked
+    /// against, and variable files have no header field naming the model, so its files are
+    /// identical to the CE's.
+    PremiumCe,
+    /// The monochrome TI-83 Plus and TI-84 Plus, with picture variables.
+    Monochrome,
+    /// The HP Prime, with a PNG for an app to load with `AFiles`.
+    ///
+    /// The Prime shows images in full color, so they're only resized to fit its screen.
+    HpPrime,
+    /// The Casio fx-CG50, with a BMP for add-ins to show on its 384x216 screen.
+    ///
+    /// Casio's own `g3p` pictures aren't documented well enough to write, but BMPs with 16-bit
+    /// pixels match the screen and are what add-in image viewers read.
+    Cg50,
+    /// The TI-Nspire CX, with a raw framebuffer for Ndless programs to show on its screen.
+    ///
+    /// Files must end in `tns` for the Nspire to list them, but have no header of their own: the
+    /// image is always 320x240, so viewers can read its 16-bit pixels straight into the screen.
+    Nspire,
+    /// NumWorks calculators, with a Python script that draws the image using `kandinsky`.
+    ///
+    /// The image is quantized as for the CE, so fewer colors make shorter scripts.
+    NumWorks,
+}
+
+impl Target {
+    /// Return the file extension of the variables or images generated for this target.
+    fn file_extension(self) -> &'static str {
+        match self {
+            Target::Ce | Target::PremiumCe => "8xv",
+            Target::Monochrome => "8xi",
+            Target::HpPrime => "png",
+            Target::Cg50 => "bmp",
+            Target::Nspire => "tns",
+            Target::NumWorks => "py",
+        }
+    }
+
+    /// Return whether images for this target are split into tile appvars.
+    fn has_tiles(self) -> bool {
+        matches!(self, Target::Ce | Target::PremiumCe)
+    }
+
+    /// Return whether images for this target are a single file holding the whole screen.
+    fn is_screen(self) -> bool {
+        !self.has_tiles() && self != Target::Monochrome
+    }
+
+    /// Return the name that chooses this target.
+    fn name(self) -> &'static str {
+        match self {
+            Target::Ce => "ce",
+            Target::PremiumCe => "83pce",
+            Target::Monochrome => "83p",
+            Target::HpPrime => "hp-prime",
+            Target::Cg50 => "cg50",
+            Target::Nspire => "nspire",
+            Target::NumWorks => "numworks",
+        }
+    }
+}
+
+impl clap::ValueEnum for Target {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self::Ce,
+            Self::PremiumCe,
+            Self::Monochrome,
+            Self::HpPrime,
+            Self::Cg50,
+            Self::Nspire,
+            Self::NumWorks,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        let help = match self {
+            Self::Ce => "TI-84 Plus CE appvars for HD Picture Viewer",
+            Self::PremiumCe => {
+                "TI-83 Premium CE appvars, identical to ce's since it reads the same files"
+            }
+            Self::Monochrome => "TI-83 Plus and TI-84 Plus monochrome pictures",
+            Self::HpPrime => "HP Prime full color PNG for an app's files",
+            Self::Cg50 => "Casio fx-CG50 16-bit BMP for add-ins",
+            Self::Nspire => "TI-Nspire CX raw 16-bit screen image for Ndless viewers",
+            Self::NumWorks => "NumWorks Python script drawing the image",
+        };
+        Some(PossibleValue::new(self.name()).help(help))
+    }
+}
+
+/// Choose a prefix for an image that wasn't given one, avoiding any already in use.
+///
+/// The first two letters of the file name are preferred, falling back to the first unused
+/// pair of letters. This fails if every pair is in use.
+fn derive_var_prefix(image_file: &Path, used: &HashSet<String>) -> Result<String, String> {
+    let preferred: String = image_file
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .chars()
+        .filter(char::is_ascii_alphabetic)
+        .map(|c| c.to_ascii_uppercase())
+        .take(2)
+        .collect();
+    if preferred.len() == 2 && !used.contains(&preferred) {
+        return Ok(preferred);
+    }
+
+    ('A'..='Z')
+        .flat_map(|a| ('A'..='Z').map(move |b| format!("{}{}", a, b)))
+        .find(|p| !used.contains(p))
+        .ok_or_else(|| {
+            format!(
+                "every var prefix is in use, so {:?} can't have one",
+                image_file
+            )
+        })
+}
+
+/// Split command-line inputs into paths, each with the var prefix that followed it if any.
+fn parse_inputs(inputs: &[String]) -> Result<Vec<(PathBuf, Option<String>)>, String> {
+    let mut paths: Vec<(PathBuf, Option<String>)> = Vec::new();
+    for input in inputs {
+        // Anything that could be a prefix is one, unless it names a file
+        let is_prefix = var_prefix_str(input).is_ok() && !Path::new(input).exists();
+        match paths.last_mut() {
+            Some((path, prefix @ None)) if is_prefix && !path.is_dir() => {
+                *prefix = Some(input.clone())
+            }
+            _ if is_prefix => {
+                return Err(format!(
+                    "var_prefix {:?} must follow the image file it applies to",
+                    input
+                ))
+            }
+            _ => paths.push((input.into(), None)),
+        }
+    }
+    Ok(paths)
+}
+
+/// Which files found by searching directories will be converted.
+struct DirectoryFilter {
+    recursive: bool,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+}
+
+impl DirectoryFilter {
+    /// Return whether a file at `path` (relative to the searched directory) should be converted.
+    fn accepts(&self, path: &Path) -> bool {
+        let other = path.extension().is_some_and(|ext| {
+            OTHER_EXTENSIONS
+                .iter()
+                .any(|other| ext.eq_ignore_ascii_case(other))
+        });
+        let supported = other
+            || image::ImageFormat::from_path(path)
+                .map(|f| f.can_read())
+                .unwrap_or(false);
+        let included = if self.include.is_empty() {
+            supported
+        } else {
+            self.include.iter().any(|p| p.matches_path(path))
+        };
+
+        included && !self.exclude.iter().any(|p| p.matches_path(path))
+    }
+
+    /// Find all the files under `dir` that should be converted, in a stable order.
+    fn find_images(&self, dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+        let mut found = Vec::new();
+        let mut pending = vec![dir.to_path_buf()];
+        while let Some(current) = pending.pop() {
+            for entry in std::fs::read_dir(&current)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    if self.recursive {
+                        pending.push(path);
+                    }
+                } else if self.accepts(path.strip_prefix(dir).unwrap()) {
+                    found.push(path);
+                }
+            }
+        }
+
+        found.sort();
+        Ok(found)
+    }
+}
+
+/// Pair each image to convert with the var prefix to use for it.
+///
+/// Directories are replaced with the images found in them. Images without a prefix get one
+/// derived from their file name.
+fn assign_var_prefixes(
+    inputs: Vec<(PathBuf, Option<String>)>,
+    filter: &DirectoryFilter,
+) -> Result<Vec<(PathBuf, String)>, Box<dyn std::error::Error>> {
+    let mut images: Vec<(PathBuf, Option<String>)> = Vec::new();
+    for (path, prefix) in inputs {
+        if path.is_dir() {
+            let found = filter
+                .find_images(&path)
+                .map_err(|e| format!("Unable to search directory {:?}: {}", path, e))?;
+            if found.is_empty() {
+                warn!("No images found in {:?}", path);
+            }
+            images.extend(found.into_iter().map(|f| (f, None)));
+        } else {
+            images.push((path, prefix));
+        }
+    }
+
+    if images.iter().filter(|(path, _)| is_stdio(path)).count() > 1 {
+        return Err("stdin can only be read once, but '-' was given more than once".into());
+    }
+
+    let mut used: HashSet<String> = images.iter().filter_map(|(_, p)| p.clone()).collect();
+    if used.len() != images.iter().filter(|(_, p)| p.is_some()).count() {
+        return Err("the same var_prefix was given for more than one image".into());
+    }
+
+    let mut assigned = Vec::with_capacity(images.len());
+    for (image_file, prefix) in images {
+        let prefix = match prefix {
+            Some(prefix) => prefix,
+            None => {
+                let derived = derive_var_prefix(&image_file, &used)?;
+                info!("Using var prefix {} for {:?}", derived, image_file);
+                used.insert(derived.clone());
+                derived
+            }
+        };
+        assigned.push((image_file, prefix));
+    }
+    Ok(assigned)
+}
+
+thread_local! {
+    /// Messages logged by this thread that are being held back, if they are.
+    static LOG_BUFFER: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Run `f`, returning the messages it logs instead of writing them out.
+fn capture_log<R>(f: impl FnOnce() -> R) -> (R, Vec<String>) {
+    LOG_BUFFER.with(|buffer| *buffer.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let messages = LOG_BUFFER.with(|buffer| buffer.borrow_mut().take().unwrap());
+    (result, messages)
+}
+
+/// Run `work` on every item using up to `jobs` threads, returning the results in order.
+///
+/// With more than one job, each item's log messages are held back until it's done so those of
+/// items being worked on at the same time aren't interleaved.
+fn run_jobs<T: Send, R: Send>(jobs: usize, items: Vec<T>, work: impl Fn(T) -> R + Sync) -> Vec<R> {
+    if jobs <= 1 {
+        return items.into_iter().map(work).collect();
+    }
+
+    let count = items.len();
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<Option<R>>>());
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(count) {
+            scope.spawn(|| loop {
+                let Some((i, item)) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                let (result, messages) = capture_log(|| work(item));
+                let mut stderr = std::io::stderr().lock();
+                for message in messages {
+                    let _ = writeln!(stderr, "{}", message);
+                }
+                results.lock().unwrap()[i] = Some(result);
+            });
+        }
+    });
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+/// Writes this program's log messages to stderr, labelled with their level unless they're
+/// ordinary progress information.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level() && metadata.target().starts_with(module_path!())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = match record.level() {
+            Level::Info => record.args().to_string(),
+            Level::Warn => format!("warning: {}", record.args()),
+            level => format!("{}: {}", level.as_str().to_lowercase(), record.args()),
+        };
+        LOG_BUFFER.with(|buffer| match &mut *buffer.borrow_mut() {
+            Some(buffer) => buffer.push(message),
+            None => eprintln!("{}", message),
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+/// Return the command line interface, with options that can also be set by the environment.
+fn command() -> Command {
+    let command = Command::new("HD picture converter")
+        .subcommand(decode::command())
+        .subcommand(inspect::command())
+        .subcommand(diff::command())
+        .subcommand(verify::command())
+        .subcommand(serve::command())
+        .subcommand(completions::command())
+        .args_conflicts_with_subcommands(true)
+        .args_override_self(true)
+        .args([
+            Arg::new("inputs")
+                .value_name("image_file [var_prefix]")
+                .num_args(1..)
+                .required_unless_present("project")
+                .help("Images to convert, each optionally followed by the var prefix to use for it")
+                .long_help(
+                    "Images to convert, each optionally followed by the two-letter var prefix \
+                     to use for it. Images without a prefix get one derived from their file name. \
+                     Directories are searched for images to convert, and '-' reads an image \
+                     from stdin.",
+                ),
+            Arg::new("project")
+                .long("project")
+                .value_name("file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("watch")
+                .help("Convert the images described by a convimg YAML project file")
+                .long_help(
+                    "Convert the images described by a convimg YAML project file, instead of \
+                     images given on the command line. Each of its converts is converted on its \
+                     own, with its images sharing a palette and options set from the convert, \
+                     its palette and the outputs that list it; options on the command line take \
+                     precedence. Image paths are relative to the project file.",
+                ),
+            Arg::new("recursive")
+                .short('r')
+                .long("recursive")
+                .action(ArgAction::SetTrue)
+                .help("Also search subdirectories of input directories"),
+            Arg::new("include")
+                .long("include")
+                .value_name("glob")
+                .action(ArgAction::Append)
+                .value_parser(glob_pattern)
+                .help("Only convert files found in directories that match this pattern")
+                .long_help(
+                    "Only convert files found in directories that match this pattern, \
+                     relative to the searched directory. May be given more than once. \
+                     By default all files with a supported image file extension are converted.",
+                ),
+            Arg::new("exclude")
+                .long("exclude")
+                .value_name("glob")
+                .action(ArgAction::Append)
+                .value_parser(glob_pattern)
+                .help("Skip files found in directories that match this pattern"),
+            Arg::new("out_dir")
+                .short('o')
+                .long("outdir")
+                .default_value(".")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write output files to this directory, or a bundle to stdout if '-'"),
+            Arg::new("manifest")
+                .long("manifest")
+                .value_name("file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Describe the generated appvars as JSON in this file, or stdout if '-'"),
+            Arg::new("index")
+                .long("index")
+                .value_name("name")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value(index::DEFAULT_NAME)
+                .value_parser(appvar_name)
+                .help("Also write an appvar listing every image, for slideshow viewers")
+                .long_help(
+                    "Also write an index appvar listing every image's name, var prefix, size and \
+                     number of tiles, so a viewer can show a list of pictures without knowing \
+                     their names. It's named HDINDEX unless another name is given, and written \
+                     to the output directory as a separate 8xv file whatever the --format.",
+                ),
+            Arg::new("append_index")
+                .long("append-index")
+                .action(ArgAction::SetTrue)
+                .requires("index")
+                .help("Add to the index already in the output directory instead of replacing it")
+                .long_help(
+                    "Add to the index appvar already in the output directory instead of \
+                     replacing it, so images converted separately can be shown together. \
+                     Images with the same var prefix as one already listed replace it.",
+                ),
+            Arg::new("emit_viewer")
+                .long("emit-viewer")
+                .value_name("kind")
+                .value_parser(clap::value_parser!(ViewerKind))
+                .help("Also write a program for showing each image")
+                .long_help(
+                    "Also write a program for showing each image, named after it in capitals, to \
+                     the output directory as a separate file whatever the --format. The basic \
+                     viewer is only a launcher, an 8xp TI-BASIC program that says which image \
+                     to choose and runs HD Picture Viewer (prgmHDPICV), which must also be on \
+                     the calculator and does the showing. The asm viewer isn't a finished \
+                     program but eZ80 source written to an asm file, which must be assembled \
+                     into an 8xp with fasmg and the CE toolchain's include files; it shows the \
+                     image by itself until a key is pressed, and can't read DEFLATE-compressed \
+                     tiles, packed pixels, direct color or --half-res.",
+                ),
+            Arg::new("emit_ice")
+                .long("emit-ice")
+                .action(ArgAction::SetTrue)
+                .help("Also write ICE source defining each image's palette and appvar names")
+                .long_help(
+                    "Also write ICE source for each image, named after the input with an ice \
+                     extension, which stores its size, tile geometry, palette and the names of \
+                     its palette and tile appvars in variables. It's text to tokenize into a \
+                     program, for example with SourceCoder or TokenIDE, and include with prgm \
+                     in an ICE program.",
+                ),
+            Arg::new("emit_c")
+                .long("emit-c")
+                .action(ArgAction::SetTrue)
+                .help("Also write a C header and source declaring each image's palette and tiles")
+                .long_help(
+                    "Also write a C header and source for each image, named after the input with \
+                     h and c extensions, in the style of convimg. They define its size and tile \
+                     geometry, its palette for gfx_SetPalette and a table of its tiles' appvar \
+                     names and positions, for CE C programs that open the tiles with fileioc.",
+                ),
+            Arg::new("emit_inc")
+                .long("emit-inc")
+                .action(ArgAction::SetTrue)
+                .help("Also write an assembly include with equates for each image's tiles")
+                .long_help(
+                    "Also write an assembly include for each image, named after the input with \
+                     an inc extension, that fasmg and spasm-ng both assemble. It has equates for \
+                     the image's size, tile grid and palette length, and the names of its \
+                     palette and tile appvars ready to copy to OP1.",
+                ),
+            Arg::new("format")
+                .short('f')
+                .long("format")
+                .default_value("group")
+                .value_parser(clap::value_parser!(OutputFormat))
+                .help("How to package the generated appvars")
+                .long_help(
+                    "How to package the generated appvars. raw writes only the data of each \
+                     appvar, to embed in other programs or ROMs: tiles are just their pixels, \
+                     compressed with their dimensions, without the TI variable header or HD \
+                     Picture Viewer's signature. ti-python writes a Python appvar \
+                     for the CE's Python edition in place of tiles, which draws the image with \
+                     ti_draw. Scripts hold runs of one color, so photos need few --colors and \
+                     --dither none to fit.",
+                ),
+            Arg::new("target")
+                .long("target")
+                .default_value("ce")
+                .value_parser(clap::value_parser!(Target))
+                .help("Calculator to convert images for")
+                .long_help(
+                    "Calculator to convert images for. The TI-83 Premium CE restricts appvar \
+                     names like the TI-84 Plus CE and its files have no field naming the model, \
+                     so 83pce writes exactly what ce does. Images for the monochrome calculators \
+                     shrink to fit their 96x63 picture variables and are mapped to black and \
+                     white, with each written as a single picture variable instead of tile \
+                     appvars, in its own 8xi file unless --format is given. Images for the HP \
+                     Prime shrink to fit its 320x240 screen and are written in full color as \
+                     PNG files, which an app loads with AFiles once they're added to its files.",
+                ),
+            Arg::new("picture_number")
+                .long("picture-number")
+                .value_name("N")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u8).range(0..=9))
+                .help("Store the first monochrome picture as PicN, and following ones after it"),
+            Arg::new("mono_dither")
+                .long("mono-dither")
+                .value_name("algorithm")
+                .default_value("atkinson")
+                .value_parser(clap::value_parser!(DitherChoice))
+                .help("How monochrome pictures are dithered to black and white")
+                .long_help(
+                    "How monochrome pictures are dithered to black and white, independently of \
+                     --dither for color images. Error diffusion alternates direction on each \
+                     row, and Atkinson keeps the most contrast at such a small size.",
+                ),
+            Arg::new("mono_threshold")
+                .long("mono-threshold")
+                .value_name("level")
+                .default_value("128")
+                .value_parser(clap::value_parser!(u8))
+                .help("Lightness from 0 to 255 below which monochrome pixels are dark"),
+            Arg::new("quantizer")
+                .long("quantizer")
+                .default_value(DEFAULT_QUANTIZER)
+                .value_parser(clap::value_parser!(QuantizerChoice))
+                .help("Algorithm for generating palettes"),
+            Arg::new("quality")
+                .long("quality")
+                .value_name("MIN-MAX")
+                .default_value("0-100")
+                .value_parser(quality)
+                .help("Fail below MIN quality and stop improving the palette at MAX (0-100)")
+                .long_help(
+                    "Fail below MIN quality and stop improving the palette at MAX, both from 0 \
+                     to 100. Without a minimum, only MAX may be given. Only libimagequant uses \
+                     this.",
+                ),
+            Arg::new("speed")
+                .long("speed")
+                .value_name("N")
+                .default_value("4")
+                .value_parser(clap::value_parser!(u8).range(1..=10))
+                .help("libimagequant speed, from 1 (slowest, best palettes) to 10 (fastest)"),
+            Arg::new("refine_palette")
+                .long("refine-palette")
+                .value_name("PASSES")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_value("0")
+                .default_missing_value("10")
+                .value_parser(clap::value_parser!(u32))
+                .help("Refine generated palettes with k-means, for up to 10 passes by default")
+                .long_help(
+                    "Refine generated palettes with k-means clustering, which improves photos \
+                     but takes about as long as mapping the image to the palette for each pass. \
+                     Refinement stops after PASSES passes (10 if not given) or once the palette \
+                     stops changing.",
+                ),
+            Arg::new("color_space")
+                .long("color-space")
+                .default_value("rgb")
+                .value_parser(clap::value_parser!(ColorSpaceChoice))
+                .help("Color space to generate palettes and match colors in"),
+            Arg::new("color_metric")
+                .long("color-metric")
+                .value_parser(clap::value_parser!(ColorMetricChoice))
+                .help("Measure of color difference to match colors by, instead of the color space"),
+            Arg::new("dither")
+                .short('d')
+                .long("dither")
+                .default_value("fs")
+                .value_parser(clap::value_parser!(DitherChoice))
+                .help("How to dither the image when applying its palette"),
+            Arg::new("dither_strength")
+                .long("dither-strength")
+                .value_name("0.0-1.0")
+                .default_value("1.0")
+                .value_parser(dither_strength)
+                .help("How strongly to dither; weaker dithering compresses better"),
+            Arg::new("colors")
+                .short('c')
+                .long("colors")
+                .value_name("N")
+                .default_value("256")
+                .value_parser(clap::value_parser!(u32).range(2..=256))
+                .help("Maximum number of colors in the palette"),
+            Arg::new("palette")
+                .short('p')
+                .long("palette")
+                .value_name("file|xlibc")
+                .value_parser(PaletteSource::parse)
+                .help("Map images to the colors in this palette file instead of generating one")
+                .long_help(
+                    "Map images to the colors in this palette file instead of generating one. \
+                     GIMP (.gpl) and JASC (.pal) palettes are supported, as are plain lists of \
+                     hex colors. 'xlibc' selects the graphx default palette, which programs \
+                     already have loaded, so no palette appvar is written for it.",
+                ),
+            Arg::new("reserve_colors")
+                .long("reserve-colors")
+                .value_name("#RRGGBB,..")
+                .action(ArgAction::Append)
+                .value_delimiter(',')
+                .value_parser(hdpictureconverter::palette::parse_hex_color)
+                .help("Put these colors at the start of the palette without using them")
+                .long_help(
+                    "Put these colors at the start of the palette in the order given, without \
+                     mapping any pixels to them. This keeps palette entries free for other uses \
+                     like user interface colors. Reserved colors count toward --colors.",
+                ),
+            Arg::new("half_res")
+                .long("half-res")
+                .value_name("width|both")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("width")
+                .value_parser(PossibleValuesParser::new(["width", "both"]).map(|halved| {
+                    match &*halved {
+                        "width" => LcdScale::HalfWidth,
+                        _ => LcdScale::Half,
+                    }
+                }))
+                .help("Halve the resolution for viewers that double pixels on the LCD")
+                .long_help(
+                    "Halve the resolution for viewers that put the LCD in a mode doubling each \
+                     pixel, so a 160x240 image fills the screen, or 160x120 with 'both'. This \
+                     happens after resizing, and halves the size of the appvars. The mode is \
+                     recorded in the palette appvar, which gets a different signature that HD \
+                     Picture Viewer doesn't read.",
+                ),
+            #[cfg(any(feature = "pdf", feature = "svg"))]
+            Arg::new("density")
+                .long("density")
+                .value_name("dpi")
+                .value_parser(density)
+                .help("Rasterize SVG drawings and PDF pages at this density, in dots per inch")
+                .long_help(
+                    "Rasterize SVG drawings and PDF pages at this density, in dots per inch. \
+                     SVG drawings are meant to be shown at 96. By default they're rasterized \
+                     to fit the 320x240 calculator screen.",
+                ),
+            #[cfg(feature = "pdf")]
+            Arg::new("page")
+                .long("page")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("Convert this page of PDF documents, counting from 1 [default: 1]"),
+            Arg::new("rotate")
+                .long("rotate")
+                .value_name("degrees")
+                .value_parser(["90", "180", "270"])
+                .help("Rotate images clockwise"),
+            Arg::new("flip_h")
+                .long("flip-h")
+                .action(ArgAction::SetTrue)
+                .help("Mirror images left to right, after rotating"),
+            Arg::new("flip_v")
+                .long("flip-v")
+                .action(ArgAction::SetTrue)
+                .help("Mirror images top to bottom, after rotating"),
+            Arg::new("crop")
+                .long("crop")
+                .value_name("X,Y,WxH")
+                .value_parser(crop_region)
+                .help("Convert only this region of each image")
+                .long_help(
+                    "Convert only the region of each image with its top left corner at X,Y \
+                     and the given size. Cropping happens after rotating and flipping but \
+                     before resizing.",
+                ),
+            Arg::new("fit_screen")
+                .long("fit-screen")
+                .action(ArgAction::SetTrue)
+                .help("Shrink images to fit the 320x240 calculator screen, which is the default")
+                .long_help(
+                    "Shrink images larger than the 320x240 calculator screen to fit it, \
+                     preserving their aspect ratio. This is the default; the option undoes an \
+                     earlier --no-fit-screen, such as one from a config file.",
+                ),
+            Arg::new("no_fit_screen")
+                .long("no-fit-screen")
+                .action(ArgAction::SetTrue)
+                .overrides_with("fit_screen")
+                .help("Convert images at full resolution instead of shrinking them")
+                .long_help(
+                    "Convert images at full resolution instead of shrinking them to fit the \
+                     screen, so the viewer can pan and zoom around them. Images are also kept \
+                     at full resolution with --scale-mode, which resizes them itself, and \
+                     --stream.",
+                ),
+            Arg::new("scale_mode")
+                .long("scale-mode")
+                .value_parser(clap::value_parser!(ScaleModeChoice))
+                .conflicts_with("fit_screen")
+                .help("Resize images to the --scale-to size this way"),
+            Arg::new("scale_to")
+                .long("scale-to")
+                .value_name("WxH")
+                .default_value("320x240")
+                .value_parser(dimensions)
+                .help("Size to resize images to when --scale-mode is given"),
+            Arg::new("gamma")
+                .long("gamma")
+                .value_name("gamma")
+                .value_parser(gamma)
+                .help("Apply gamma correction; values above 1 brighten midtones"),
+            Arg::new("brightness")
+                .long("brightness")
+                .value_name("-1.0-1.0")
+                .default_value("0")
+                .allow_negative_numbers(true)
+                .value_parser(brightness)
+                .help("Brighten or darken images by this fraction of the full range"),
+            Arg::new("contrast")
+                .long("contrast")
+                .value_name("factor")
+                .default_value("1")
+                .value_parser(adjustment_factor)
+                .help("Scale contrast by this factor; above 1 increases it"),
+            Arg::new("saturation")
+                .long("saturation")
+                .value_name("factor")
+                .default_value("1")
+                .value_parser(adjustment_factor)
+                .help("Scale saturation by this factor; 0 is grayscale"),
+            Arg::new("hue_shift")
+                .long("hue-shift")
+                .value_name("degrees")
+                .default_value("0")
+                .allow_negative_numbers(true)
+                .value_parser(clap::value_parser!(f32))
+                .help("Rotate the hues of images"),
+            Arg::new("grayscale")
+                .long("grayscale")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["palette", "shared_palette"])
+                .help("Convert images to shades of gray")
+                .long_help(
+                    "Convert images to shades of gray, mapped to an evenly spaced ramp of as \
+                     many grays as --colors allows. The calculator can only display 32 \
+                     distinct grays, so no more are used.",
+                ),
+            Arg::new("tile_size")
+                .long("tile-size")
+                .value_name("WxH")
+                .value_parser(tile_size)
+                .help("Split images into tiles of this size instead of 80x80")
+                .long_help(
+                    "Split images into tiles of this size instead of 80x80, up to 255x255. \
+                     HD Picture Viewer expects 80x80 tiles, so other sizes are for other \
+                     viewers. Compressing a tile with zx0 takes time growing with the square \
+                     of its size, so zx0 tiles may hold at most 16 KiB of pixels, such as \
+                     128x128 with 8-bit pixels or 128x64 with --direct-color; larger tiles \
+                     need another --compression.",
+                ),
+            Arg::new("compression")
+                .long("compression")
+                .visible_alias("compress")
+                .default_value("zx0")
+                .value_parser(clap::value_parser!(CompressionChoice))
+                .help("How to compress tile pixel data")
+                .long_help(
+                    "How to compress tile pixel data. HD Picture Viewer only reads zx0; other \
+                     choices write tiles with a different signature followed by the image name \
+                     and a byte identifying the compression.",
+                ),
+            Arg::new("bit_depth")
+                .long("bit-depth")
+                .value_name("bits")
+                .default_value("8")
+                .value_parser(
+                    PossibleValuesParser::new(["8", "4", "2", "1"])
+                        .map(|bits| BitDepth::from_bits(bits.parse().unwrap()).unwrap()),
+                )
+                .help("Bits each tile pixel takes; fewer make smaller appvars")
+                .long_help(
+                    "Bits each tile pixel takes. HD Picture Viewer only reads 8. Fewer pack \
+                     several pixels into each byte, allowing 16 colors at 4 bits, 4 at 2 bits \
+                     and 2 at 1 bit, which becomes the default for --colors. 1 or 2 bits suit \
+                     line art and scanned documents. Packed tiles and their palettes have \
+                     different signatures and record the depth.",
+                ),
+            Arg::new("direct_color")
+                .long("direct-color")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "colors",
+                    "palette",
+                    "grayscale",
+                    "reserve_colors",
+                    "transparent_color",
+                    "transparent_index",
+                    "bit_depth",
+                    "shared_palette",
+                    "global_palette",
+                    "stream",
+                ])
+                .help("Store 16-bit colors in tiles instead of quantizing to a palette")
+                .long_help(
+                    "Store every pixel as a 16-bit RGB565 color instead of quantizing images to \
+                     a palette, so there's no palette appvar. Tiles are twice the size but show \
+                     colors exactly. HD Picture Viewer doesn't read them; they have the packed \
+                     tile signature with 16 bits per pixel. Tiles may have at most 32749 \
+                     pixels, like 255x128, so they fit in an appvar uncompressed.",
+                ),
+            Arg::new("no_archive")
+                .long("no-archive")
+                .action(ArgAction::SetTrue)
+                .help("Don't mark appvars to be sent to archive")
+                .long_help(
+                    "Don't mark appvars to be sent to archive, so link software puts them in \
+                     RAM instead. Most images are too large to fit in RAM.",
+                ),
+            Arg::new("name_template")
+                .long("name-template")
+                .value_name("template")
+                .value_parser(NameTemplate::parse)
+                .help("How to name tile appvars, like {prefix}{col:03}{row:03}")
+                .long_help(
+                    "How to name tile appvars. {prefix} is replaced with the image's var \
+                     prefix, {col} and {row} with the tile's column and row, and {frame} with \
+                     the animation frame. Numbers can be zero-padded to a width like {row:02}. \
+                     The default is {prefix}{col:03}{row:03}, which is what HD Picture Viewer \
+                     expects, or {prefix}{frame:02}{col:02}{row:02} for animations, whose \
+                     templates must include {frame}.",
+                ),
+            Arg::new("force")
+                .long("force")
+                .action(ArgAction::SetTrue)
+                .help("Overwrite output files that already exist"),
+            Arg::new("skip_existing")
+                .long("skip-existing")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("force")
+                .help("Don't convert images whose output files already exist"),
+            Arg::new("watch")
+                .long("watch")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("check_existing")
+                .help("Convert images again whenever they change")
+                .long_help(
+                    "After converting, keep watching the input images and convert them again \
+                     whenever they change, replacing the files written before. Directories are \
+                     searched again each time, so images added to them are converted too.",
+                ),
+            Arg::new("send_to_cemu")
+                .long("send-to-cemu")
+                .value_name("program")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("CEmu")
+                .value_parser(clap::value_parser!(PathBuf))
+                .conflicts_with("dry_run")
+                .help("Transfer the output files to the CEmu emulator")
+                .long_help(
+                    "Transfer the output files to the CEmu emulator after converting, running \
+                     it as this program, or CEmu if not given. They're sent to CEmu if it's \
+                     already running, and otherwise it's started with them.",
+                ),
+            #[cfg(feature = "usb")]
+            Arg::new("send")
+                .long("send")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("dry_run")
+                .help("Transfer the output files to a TI-84 Plus CE connected over USB"),
+            Arg::new("check_existing")
+                .long("check-existing")
+                .action(ArgAction::SetTrue)
+                .help("Also refuse appvar names of 8xv files already in the output directory")
+                .long_help(
+                    "Refuse to convert images that would generate appvars with the same names \
+                     as 8xv files already in the output directory, in addition to names used \
+                     by other images being converted.",
+                ),
+            Arg::new("flash_budget")
+                .long("flash-budget")
+                .value_name("bytes")
+                .default_value("3M")
+                .value_parser(byte_count)
+                .help("Warn if all the appvars together need more archive space than this")
+                .long_help(
+                    "Warn if all the generated appvars together need more archive space than \
+                     this many bytes, which may be followed by K or M. The default is roughly \
+                     the archive space of an empty TI-84 Plus CE.",
+                ),
+            Arg::new("strict_size")
+                .long("strict-size")
+                .action(ArgAction::SetTrue)
+                .help("Fail to convert images that would exceed the flash budget"),
+            Arg::new("dry_run")
+                .short('n')
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Convert images and report the sizes of their appvars without writing them"),
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("verbose")
+                .global(true)
+                .help("Only report warnings and errors, without progress bars"),
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .action(ArgAction::Count)
+                .global(true)
+                .help("Report more details of the conversion; give twice for even more"),
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Convert this many images at once"),
+            Arg::new("deterministic")
+                .long("deterministic")
+                .action(ArgAction::SetTrue)
+                .help("Always produce identical output from the same input")
+                .long_help(
+                    "Always produce identical output from the same input. Quantization runs \
+                     on several threads that can combine their results in different orders, \
+                     slightly changing the palette; this uses only one thread instead, which \
+                     is slower. Several images can still be converted at once with --jobs, \
+                     since each image's palette only depends on that image.",
+                ),
+            Arg::new("comment")
+                .long("comment")
+                .value_parser(comment)
+                .help("Comment to put in the header of output files, up to 42 characters"),
+            Arg::new("background")
+                .short('b')
+                .long("background")
+                .value_name("#RRGGBB")
+                .default_value("#000000")
+                .value_parser(hdpictureconverter::palette::parse_hex_color)
+                .help("Blend transparent pixels over this color, which also fills padding"),
+            Arg::new("transparent_color")
+                .long("transparent-color")
+                .value_name("#RRGGBB")
+                .value_parser(hdpictureconverter::palette::parse_hex_color)
+                .help("Reserve a palette entry of this color for transparent pixels")
+                .long_help(
+                    "Reserve a palette entry of this color, following any other reserved \
+                     colors, and map fully transparent pixels to it. Padding around images \
+                     that aren't a whole number of tiles is also transparent.",
+                ),
+            Arg::new("transparent_index")
+                .long("transparent-index")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u8))
+                .conflicts_with("transparent_color")
+                .help("Map transparent pixels to this reserved color's palette index"),
+            Arg::new("stream")
+                .long("stream")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all([
+                    "rotate",
+                    "flip_h",
+                    "flip_v",
+                    "crop",
+                    "fit_screen",
+                    "scale_mode",
+                    "gamma",
+                    "brightness",
+                    "contrast",
+                    "saturation",
+                    "hue_shift",
+                    "grayscale",
+                    "half_res",
+                    "shared_palette",
+                    "thumbnails",
+                ])
+                .help("Convert images a row of tiles at a time to use less memory")
+                .long_help(
+                    "Convert images a row of tiles at a time to use less memory, reading each one \
+                     twice. PNGs are also decoded a row at a time. Images can't be transformed \
+                     or adjusted, and error diffusion dithering restarts in each row.",
+                ),
+            Arg::new("shared_palette")
+                .long("shared-palette")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("palette")
+                .help("Generate one palette for all images")
+                .long_help(
+                    "Generate one palette for all images and map each of them to it, so they \
+                     can be displayed one after another without changing colors. Every image \
+                     still has its own palette appvar, since that's how the viewer finds images, \
+                     but they all contain the same colors.",
+                ),
+            Arg::new("global_palette")
+                .long("global-palette")
+                .action(ArgAction::SetTrue)
+                .help("Generate one palette for every frame of an animation")
+                .long_help(
+                    "Generate one palette for every frame of an animated GIF, PNG or WebP, \
+                     which is written once instead of with each frame. Frames otherwise each get \
+                     a palette of their own.",
+                ),
+            Arg::new("thumbnails")
+                .long("thumbnails")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("half_res")
+                .help("Also write a small preview of each image, for galleries")
+                .long_help(
+                    "Also write a thumbnail appvar for each image, named HT followed by its var \
+                     prefix and 0000. Thumbnails are 80x60 pixels with their own palette, \
+                     uncompressed, so a gallery can show them without reading the image's \
+                     tiles. Animations get a thumbnail of their first frame.",
+                ),
+            Arg::new("metadata")
+                .long("metadata")
+                .action(ArgAction::SetTrue)
+                .help("Also write an appvar describing each image's size, tiles and encoding")
+                .long_help(
+                    "Also write a metadata appvar for each image, named HM followed by its var \
+                     prefix and 0000. It records the image's width, height, tile size, number \
+                     of columns and rows of tiles, bits per pixel and compression, so viewers \
+                     don't have to assume them.",
+                ),
+            Arg::new("export_palette")
+                .long("export-palette")
+                .action(ArgAction::SetTrue)
+                .help("Also write each image's palette as a GIMP palette and for convimg")
+                .long_help(
+                    "Also write each image's palette, as the calculator shows it, named after the \
+                     input with a gpl extension as a GIMP palette and a yaml extension as a \
+                     convimg project defining it, so other assets can be converted against the \
+                     same colors. The convimg palette is named after the image with _palette, \
+                     and fixes every color at its index.",
+                ),
+            Arg::new("no_palette_appvar")
+                .long("no-palette-appvar")
+                .action(ArgAction::SetTrue)
+                .help("Don't write the palette appvar"),
+        ])
+        .args(config::args());
+    #[cfg(feature = "gui")]
+    let command = command.subcommand(gui::command());
+    config::with_env(command)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let command = command();
+    let args = config::apply(&command, std::env::args_os().collect())?;
+    let m = command.clone().get_matches_from(args);
+
+    log::set_logger(&StderrLogger).unwrap();
+    log::set_max_level(match (m.get_flag("quiet"), m.get_count("verbose")) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    });
+    match m.subcommand() {
+        Some(("decode", m)) => return decode::run(m),
+        Some(("inspect", m)) => return inspect::run(m),
+        Some(("diff", m)) => return diff::run(m),
+        Some(("verify", m)) => return verify::run(m),
+        Some(("serve", m)) => return serve::run(m),
+        #[cfg(feature = "gui")]
+        Some(("gui", m)) => return gui::run(m),
+        Some(("completions", m)) => return completions::run(m, &command),
+        _ => {}
+    }
+
+    // The global pool can only be set up once, so not for each of a project's conversions
+    if m.get_flag("deterministic") {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build_global()
+            .map_err(|e| format!("Unable to limit quantization to one thread: {}", e))?;
+    }
+
+    let Some(project) = m.get_one::<PathBuf>("project") else {
+        return convert_command(&m);
+    };
+    if m.contains_id("inputs") {
+        return Err("images can't be given on the command line with --project".into());
+    }
+    for args in project::conversions(project, std::env::args_os().collect())? {
+        let args = config::apply(&command, args)?;
+        convert_command(&command.clone().get_matches_from(args))?;
+    }
+    Ok(())
+}
+
+/// Convert the images given on the command line as it specifies.
+fn convert_command(m: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let inputs: Vec<String> = m.get_many::<String>("inputs").unwrap().cloned().collect();
+    let palette_source = m.get_one::<PaletteSource>("palette");
+    let grayscale = m.get_flag("grayscale");
+    let bit_depth = *m.get_one::<BitDepth>("bit_depth").unwrap();
+    let mut max_colors = *m.get_one::<u32>("colors").unwrap();
+    let depth_colors = bit_depth.max_colors() as u32;
+    if m.value_source("colors") == Some(ValueSource::DefaultValue) {
+        max_colors = max_colors.min(depth_colors);
+    } else if max_colors > depth_colors {
+        return Err(format!(
+            "--colors {} is more than {}-bit pixels can use, which is {}",
+            max_colors,
+            bit_depth.bits(),
+            depth_colors
+        )
+        .into());
+    }
+    let mut settings = Settings {
+        decode: decode_options(m),
+        rotation: m
+            .get_one::<String>("rotate")
+            .map(|degrees| match degrees.as_str() {
+                "90" => Rotation::Rotate90,
+                "180" => Rotation::Rotate180,
+                _ => Rotation::Rotate270,
+            }),
+        flip_horizontal: m.get_flag("flip_h"),
+        flip_vertical: m.get_flag("flip_v"),
+        crop: m.get_one::<(u32, u32, u32, u32)>("crop").copied(),
+        fit_screen: !m.get_flag("no_fit_screen")
+            && !m.contains_id("scale_mode")
+            && !m.get_flag("stream"),
+        gamma: m.get_one::<f32>("gamma").copied(),
+        brightness: *m.get_one::<f32>("brightness").unwrap(),
+        contrast: *m.get_one::<f32>("contrast").unwrap(),
+        saturation: *m.get_one::<f32>("saturation").unwrap(),
+        hue_shift: *m.get_one::<f32>("hue_shift").unwrap(),
+        grayscale,
+        tile_size: m.get_one::<(u32, u32)>("tile_size").copied(),
+        compression: m.get_one::<CompressionChoice>("compression").unwrap().0,
+        bit_depth,
+        lcd_scale: m
+            .get_one::<LcdScale>("half_res")
+            .copied()
+            .unwrap_or_default(),
+        archived: !m.get_flag("no_archive"),
+        comment: m.get_one::<String>("comment").cloned(),
+        existing: if m.get_flag("force") || m.get_flag("dry_run") {
+            ExistingOutput::Overwrite
+        } else if m.get_flag("skip_existing") {
+            ExistingOutput::Skip
+        } else {
+            ExistingOutput::Refuse
+        },
+        name_template: m.get_one::<NameTemplate>("name_template").cloned(),
+        scale: m.get_one::<ScaleModeChoice>("scale_mode").map(|mode| {
+            let (width, height) = *m.get_one::<(u32, u32)>("scale_to").unwrap();
+            (width, height, mode.0)
+        }),
+        out_dir: m.get_one::<PathBuf>("out_dir").unwrap().clone(),
+        format: *m.get_one::<OutputFormat>("format").unwrap(),
+        quantize: QuantizeOptions {
+            quantizer: m.get_one::<QuantizerChoice>("quantizer").unwrap().0,
+            quality: *m.get_one::<(u8, u8)>("quality").unwrap(),
+            speed: *m.get_one::<u8>("speed").unwrap(),
+            refine_passes: *m.get_one::<u32>("refine_palette").unwrap(),
+            color_space: m.get_one::<ColorSpaceChoice>("color_space").unwrap().0,
+            color_metric: m.get_one::<ColorMetricChoice>("color_metric").map(|c| c.0),
+            dither: m.get_one::<DitherChoice>("dither").unwrap().0,
+            dither_strength: *m.get_one::<f32>("dither_strength").unwrap(),
+            max_colors,
+            palette: if grayscale {
+                let levels = (max_colors as usize).min(hdpictureconverter::palette::MAX_GRAYS);
+                Some(hdpictureconverter::palette::gray_ramp(levels))
+            } else {
+                palette_source.map(PaletteSource::load).transpose()?
+            },
+            reserved_colors: m
+                .get_many::<RGBA>("reserve_colors")
+                .unwrap_or_default()
+                .copied()
+                .collect(),
+            background: *m.get_one::<RGBA>("background").unwrap(),
+            ..Default::default()
+        },
+        global_palette: m.get_flag("global_palette"),
+        direct_color: m.get_flag("direct_color"),
+        palette_appvar: !m.get_flag("no_palette_appvar")
+            && !m.get_flag("direct_color")
+            && palette_source != Some(&PaletteSource::Xlibc),
+        target: *m.get_one::<Target>("target").unwrap(),
+        first_picture: *m.get_one::<u8>("picture_number").unwrap(),
+        picture: PictureOptions {
+            dither: m.get_one::<DitherChoice>("mono_dither").unwrap().0,
+            threshold: *m.get_one::<u8>("mono_threshold").unwrap() as f32 / 255.,
+            background: match m.value_source("background") {
+                // Pictures are drawn on a white screen, so transparent pixels stay white
+                Some(ValueSource::DefaultValue) => PictureOptions::default().background,
+                _ => *m.get_one::<RGBA>("background").unwrap(),
+            },
+        },
+        progress: !m.get_flag("quiet"),
+        dry_run: m.get_flag("dry_run"),
+        stream: m.get_flag("stream"),
+        thumbnails: m.get_flag("thumbnails"),
+        emit_ice: m.get_flag("emit_ice"),
+        emit_c: m.get_flag("emit_c"),
+        emit_inc: m.get_flag("emit_inc"),
+        export_palette: m.get_flag("export_palette"),
+        metadata: m.get_flag("metadata"),
+        flash_budget: *m.get_one::<usize>("flash_budget").unwrap(),
+        strict_size: m.get_flag("strict_size"),
+    };
+
+    if let Some(&color) = m.get_one::<RGBA>("transparent_color") {
+        let reserved = &mut settings.quantize.reserved_colors;
+        reserved.push(color);
+        settings.quantize.transparent_index = Some(reserved.len() as u8 - 1);
+    }
+    if let Some(&index) = m.get_one::<u8>("transparent_index") {
+        let reserved = settings.quantize.reserved_colors.len();
+        if index as usize >= reserved {
+            return Err(format!(
+                "transparent index {} must be one of the {} reserved colors",
+                index, reserved
+            )
+            .into());
+        }
+        settings.quantize.transparent_index = Some(index);
+    }
+
+    // Pictures are usually sent on their own
+    let default_format = m.value_source("format") == Some(ValueSource::DefaultValue);
+    if settings.target == Target::Monochrome && default_format {
+        settings.format = OutputFormat::Loose;
+    }
+    if settings.format == OutputFormat::TiPython {
+        if !settings.target.has_tiles() {
+            return Err(format!(
+                "--format ti-python doesn't apply to {}",
+                settings.target.name()
+            )
+            .into());
+        }
+    } else if settings.target.is_screen() && !default_format {
+        return Err(format!("--format doesn't apply to {}", settings.target.name()).into());
+    }
+
+    let reserved = settings.quantize.reserved_colors.len();
+    let available = match &settings.quantize.palette {
+        Some(palette) => hdpictureconverter::palette::MAX_COLORS - palette.len(),
+        None => settings.quantize.max_colors as usize - 2,
+    };
+    if reserved > available {
+        return Err(format!(
+            "{} colors can't be reserved, since only {} palette entries are available",
+            reserved, available
+        )
+        .into());
+    }
+
+    let filter = DirectoryFilter {
+        recursive: m.get_flag("recursive"),
+        include: m.get_many("include").unwrap_or_default().cloned().collect(),
+        exclude: m.get_many("exclude").unwrap_or_default().cloned().collect(),
+    };
+
+    let jobs = *m.get_one::<u64>("jobs").unwrap() as usize;
+    if jobs > 1 {
+        // Bars for several images at once would draw over each other
+        settings.progress = false;
+    }
+
+    if !m.get_flag("watch") {
+        return convert_all(m, &inputs, &filter, &settings, jobs);
+    }
+    if inputs.iter().any(|input| is_stdio(Path::new(input))) {
+        return Err("images from stdin can't be watched for changes".into());
+    }
+    loop {
+        let before = modified_times(&inputs, &filter);
+        if let Err(e) = convert_all(m, &inputs, &filter, &settings, jobs) {
+            error!("{}", e);
+        }
+        // Later conversions replace what earlier ones wrote
+        settings.existing = ExistingOutput::Overwrite;
+        info!("Watching for changes..");
+        wait_for_changes(&inputs, &filter, &before)?;
+    }
+}
+
+/// How long watched images must go without changing before they're converted again, so saving
+/// a file in several writes converts it once.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Wait until the images that would be converted differ from `before`.
+///
+/// The directories holding the inputs are watched rather than the files themselves, since many
+/// editors save by replacing a file, and files written beside the images (such as the outputs)
+/// are ignored because they don't change the images' modification times.
+fn wait_for_changes(
+    inputs: &[String],
+    filter: &DirectoryFilter,
+    before: &[(PathBuf, Option<SystemTime>)],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)
+        .map_err(|e| format!("Unable to watch for changes: {}", e))?;
+    for (path, _) in parse_inputs(inputs)? {
+        let (path, mode) = if path.is_dir() && filter.recursive {
+            (path, RecursiveMode::Recursive)
+        } else if path.is_dir() {
+            (path, RecursiveMode::NonRecursive)
+        } else {
+            let parent = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty());
+            (
+                parent.unwrap_or(Path::new(".")).to_owned(),
+                RecursiveMode::NonRecursive,
+            )
+        };
+        watcher
+            .watch(&path, mode)
+            .map_err(|e| format!("Unable to watch {:?} for changes: {}", path, e))?;
+    }
+
+    loop {
+        let event = receiver.recv()?;
+        if matches!(event, Ok(ref event) if event.kind.is_access()) {
+            continue;
+        }
+        // Wait for things to settle before looking at what changed
+        while receiver.recv_timeout(WATCH_DEBOUNCE).is_ok() {}
+        if modified_times(inputs, filter) != before {
+            return Ok(());
+        }
+    }
+}
+
+/// Return the images that would be converted and when each was last modified, to tell when
+/// they've changed.
+fn modified_times(
+    inputs: &[String],
+    filter: &DirectoryFilter,
+) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let images = parse_inputs(inputs)
+        .map_err(Into::into)
+        .and_then(|inputs| assign_var_prefixes(inputs, filter))
+        .unwrap_or_default();
+    images
+        .into_iter()
+        .map(|(path, _)| {
+            let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect()
+}
+
+/// Convert every image given as input.
+fn convert_all(
+    m: &clap::ArgMatches,
+    inputs: &[String],
+    filter: &DirectoryFilter,
+    settings: &Settings,
+    jobs: usize,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut settings = settings.clone();
+    let images = assign_var_prefixes(parse_inputs(inputs)?, filter)?;
+    if is_stdio(&settings.out_dir) {
+        if m.get_one::<PathBuf>("manifest")
+            .is_some_and(|path| is_stdio(path))
+        {
+            return Err("the manifest and appvars can't both be written to stdout".into());
+        }
+        if matches!(settings.format, OutputFormat::Loose | OutputFormat::Raw) {
+            return Err("loose and raw appvars can't be written to stdout".into());
+        }
+        if images.len() != 1 {
+            return Err(format!(
+                "only one image can be written to stdout, but there are {}",
+                images.len()
+            )
+            .into());
+        }
+    }
+    if !settings.has_tiles() && (m.get_flag("shared_palette") || settings.stream) {
+        return Err(format!(
+            "images for {} can't share a palette or be streamed",
+            settings.output_name()
+        )
+        .into());
+    }
+    if !settings.has_tiles() && settings.lcd_scale != LcdScale::Full {
+        return Err(format!("--half-res doesn't apply to {}", settings.output_name()).into());
+    }
+    if settings.target == Target::Monochrome && images.len() > 10 {
+        return Err(format!(
+            "only 10 pictures can be stored, but there are {} images",
+            images.len()
+        )
+        .into());
+    }
+    if settings.thumbnails && !settings.has_tiles() {
+        return Err(format!("{} images can't have thumbnails", settings.output_name()).into());
+    }
+    if settings.metadata && !settings.has_tiles() {
+        return Err(format!("{} images have no metadata appvar", settings.output_name()).into());
+    }
+    if settings.emit_ice && !settings.has_tiles() {
+        return Err(format!("{} images can't be used from ICE", settings.output_name()).into());
+    }
+    if settings.emit_ice && is_stdio(&settings.out_dir) {
+        return Err("ICE source can't be written to stdout".into());
+    }
+    if settings.emit_c && !settings.has_tiles() {
+        return Err(format!("{} images can't be used from C", settings.output_name()).into());
+    }
+    if settings.emit_c && is_stdio(&settings.out_dir) {
+        return Err("C source can't be written to stdout".into());
+    }
+    if settings.emit_inc && !settings.has_tiles() {
+        return Err(format!(
+            "{} images can't be used from assembly",
+            settings.output_name()
+        )
+        .into());
+    }
+    if settings.emit_inc && is_stdio(&settings.out_dir) {
+        return Err("assembly includes can't be written to stdout".into());
+    }
+    if settings.export_palette && (!settings.has_tiles() || settings.direct_color) {
+        return Err("only images with a palette appvar can have their palette exported".into());
+    }
+    if settings.export_palette && is_stdio(&settings.out_dir) {
+        return Err("palettes can't be exported to stdout".into());
+    }
+    if let Some((width, height)) = settings.tile_size {
+        let row_len = if settings.direct_color {
+            width as usize * 2
+        } else {
+            settings.bit_depth.packed_len(width as usize)
+        };
+        let len = row_len * height as usize;
+        if settings.compression == Compression::Zx0 && len > MAX_ZX0_LEN {
+            return Err(format!(
+                "{}x{} tiles hold {} bytes of pixels, more than the {} zx0 can compress in \
+                 reasonable time; use smaller tiles or another --compression",
+                width, height, len, MAX_ZX0_LEN
+            )
+            .into());
+        }
+        let pixels = width as usize * height as usize;
+        if settings.direct_color && pixels > MAX_DIRECT_COLOR_PIXELS {
+            return Err(format!(
+                "{}x{} direct color tiles have {} pixels, more than the {} an appvar can hold; \
+                 use smaller tiles",
+                width, height, pixels, MAX_DIRECT_COLOR_PIXELS
+            )
+            .into());
+        }
+    }
+    let viewer = m.get_one::<ViewerKind>("emit_viewer").copied();
+    if viewer.is_some() && !settings.has_tiles() {
+        return Err(format!("{} images can't have a viewer", settings.output_name()).into());
+    }
+    if viewer.is_some() && is_stdio(&settings.out_dir) {
+        return Err("viewers can't be written to stdout".into());
+    }
+    if viewer == Some(ViewerKind::Asm)
+        && (settings.compression == Compression::Deflate
+            || settings.bit_depth != BitDepth::Eight
+            || settings.direct_color
+            || settings.lcd_scale != LcdScale::Full)
+    {
+        return Err(
+            "the asm viewer only reads tiles with 8-bit palette indices, compressed \
+                    with zx0, rle or none"
+                .into(),
+        );
+    }
+    let index_name = m.get_one::<String>("index");
+    if index_name.is_some() && !settings.has_tiles() {
+        return Err(format!(
+            "{} images can't be listed in an index",
+            settings.output_name()
+        )
+        .into());
+    }
+    if index_name.is_some() && is_stdio(&settings.out_dir) {
+        return Err("an index can't be written to stdout".into());
+    }
+    let send_to_cemu = m.get_one::<PathBuf>("send_to_cemu");
+    if send_to_cemu.is_some() && !settings.has_tiles() {
+        return Err("CEmu only emulates the CE, so only CE targets can be sent to it".into());
+    }
+    if send_to_cemu.is_some()
+        && (is_stdio(&settings.out_dir)
+            || matches!(settings.format, OutputFormat::Zip | OutputFormat::Raw))
+    {
+        return Err("only group files and loose appvars can be sent to CEmu".into());
+    }
+    let send = m.try_get_one::<bool>("send").ok().flatten() == Some(&true);
+    if send && !settings.target.has_tiles() {
+        return Err("only CE targets can be sent to a calculator".into());
+    }
+    if send
+        && (is_stdio(&settings.out_dir)
+            || matches!(settings.format, OutputFormat::Zip | OutputFormat::Raw))
+    {
+        return Err("only group files and loose appvars can be sent to a calculator".into());
+    }
+
+    let mut batch = Batch::default();
+    if m.get_flag("check_existing") && !is_stdio(&settings.out_dir) {
+        batch
+            .used_names
+            .add_existing(&settings.out_dir)
+            .map_err(|e| format!("Unable to list {:?}: {}", settings.out_dir, e))?;
+    }
+    let batch = Mutex::new(batch);
+
+    // A single failed image shouldn't prevent converting the others
+    let mut manifest = Manifest { images: Vec::new() };
+    let failures = AtomicUsize::new(0);
+    let report = |image_file: &Path, e: &dyn std::fmt::Display| {
+        error!("Failed to convert {:?}: {}", image_file, e);
+        failures.fetch_add(1, Ordering::Relaxed);
+    };
+
+    if m.get_flag("shared_palette") {
+        // Every image must be loaded and prepared before any can be mapped to the palette, which
+        // should only hold the colors left after resizing and adjustments
+        let mut loaded = Vec::new();
+        for (image_file, var_prefix) in &images {
+            let image = load_image(image_file, var_prefix, &settings.decode, settings.progress)
+                .and_then(|mut image| prepare(&mut image, &settings).map(|()| image));
+            match image {
+                Ok(image) => loaded.push((image_file, image)),
+                Err(e) => report(image_file, &e),
+            }
+        }
+
+        info!("Generating palette shared by {} images..", loaded.len());
+        let palette = with_spinner(settings.progress, "Generating palette", || {
+            Image::shared_palette(loaded.iter().map(|(_, image)| image), &settings.quantize)
+        })
+        .map_err(|e| format!("Unable to generate shared palette: {}", e))?;
+        settings.quantize.palette = Some(palette);
+        let converted = run_jobs(jobs, loaded, |(image_file, image)| {
+            convert(image_file, image, &settings, &batch).unwrap_or_else(|e| {
+                report(image_file, &e);
+                None
+            })
+        });
+        manifest.images.extend(converted.into_iter().flatten());
+    } else {
+        let converted = run_jobs(jobs, images.iter().enumerate().collect(), |(i, image)| {
+            let (image_file, var_prefix) = image;
+            let result = if settings.target == Target::Monochrome {
+                // Pictures are numbered in order, following Pic9 with Pic0
+                let number = (settings.first_picture as usize + i) % 10;
+                load_image(image_file, var_prefix, &settings.decode, settings.progress)
+                    .map_err(Into::into)
+                    .and_then(|image| {
+                        convert_picture(image_file, image, number as u8, &settings, &batch)
+                    })
+            } else if settings.target.is_screen() || settings.format == OutputFormat::TiPython {
+                load_image(image_file, var_prefix, &settings.decode, settings.progress)
+                    .map_err(Into::into)
+                    .and_then(|image| convert_screen(image_file, image, &settings))
+            } else if settings.stream {
+                convert_streaming(image_file, var_prefix, &settings, &batch)
+            } else {
+                load_frames(image_file, var_prefix, &settings.decode, settings.progress)
+                    .map_err(Into::into)
+                    .and_then(|mut frames| {
+                        if frames.len() > 1 {
+                            convert_animation(image_file, frames, &settings, &batch)
+                        } else {
+                            let mut image = frames.remove(0).image;
+                            prepare(&mut image, &settings)?;
+                            convert(image_file, image, &settings, &batch)
+                        }
+                    })
+            };
+            result.unwrap_or_else(|e| {
+                report(image_file, &e);
+                None
+            })
+        });
+        manifest.images.extend(converted.into_iter().flatten());
+    }
+
+    let flash_used = batch.into_inner().unwrap().flash_used;
+    if flash_used > settings.flash_budget {
+        warn!(
+            "Appvars need {} bytes of archive, more than the {} byte flash budget",
+            flash_used, settings.flash_budget
+        );
+    }
+
+    if settings.dry_run && manifest.images.len() > 1 {
+        let appvars = manifest.images.iter().flat_map(|image| &image.appvars);
+        info!(
+            "Would write {} appvars totalling {} bytes for {} images",
+            appvars.clone().count(),
+            appvars.map(|appvar| appvar.size).sum::<usize>(),
+            manifest.images.len()
+        );
+    }
+
+    if let Some(name) = index_name {
+        write_index(name, m.get_flag("append_index"), &manifest, &settings)?;
+    }
+    if let Some(kind) = viewer {
+        write_viewers(kind, &manifest, &settings)?;
+    }
+
+    if let Some(path) = m.get_one::<PathBuf>("manifest") {
+        manifest
+            .write(path)
+            .map_err(|e| format!("Unable to write manifest {:?}: {}", path, e))?;
+    }
+
+    if let Some(program) = send_to_cemu {
+        cemu::send(program, &output_files(&manifest, &settings))?;
+    }
+    #[cfg(feature = "usb")]
+    if send {
+        usb::send(&output_files(&manifest, &settings))?;
+    }
+
+    let failures = failures.into_inner();
+    if failures > 0 {
+        return Err(format!("{} of {} images failed to convert", failures, images.len()).into());
+    }
+    Ok(())
+}
+
+/// Return the files written for every image in `manifest`.
+fn output_files(manifest: &Manifest, settings: &Settings) -> Vec<PathBuf> {
+    manifest
+        .images
+        .iter()
+        .flat_map(|image| match &image.output {
+            Some(output) => vec![PathBuf::from(output)],
+            None => image
+                .appvars
+                .iter()
+                .map(|appvar| {
+                    let extension = settings.target.file_extension();
+                    settings
+                        .out_dir
+                        .join(format!("{}.{}", appvar.name, extension))
+                })
+                .collect(),
+        })
+        .collect()
+}
+
+/// Write an index appvar named `name` listing every image in `manifest`, adding them to the one
+/// already in the output directory if `append` is set.
+fn write_index(
+    name: &str,
+    append: bool,
+    manifest: &Manifest,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = settings.out_dir.join(name).with_extension("8xv");
+    let mut index = Index::default();
+    if append && path.exists() {
+        let file = std::fs::read(&path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        let contents =
+            group::read(&file).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        let var = contents
+            .vars
+            .iter()
+            .find(|var| Index::is_index(&var.data))
+            .ok_or_else(|| format!("{:?} isn't an index appvar", path))?;
+        index = Index::read(&var.data).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+    } else if !settings.existing.may_write(std::slice::from_ref(&path))? {
+        return Ok(());
+    }
+
+    for image in &manifest.images {
+        if let Some(entry) = &image.index_entry {
+            index.add(entry.clone());
+        }
+    }
+    let data = index
+        .write(Cursor::new(Vec::new()), name, settings.archived)?
+        .into_inner();
+    if settings.dry_run {
+        info!(
+            "Would write index of {} images to {} ({} bytes)",
+            index.entries.len(),
+            path.display(),
+            data.len()
+        );
+    } else {
+        info!(
+            "Writing index of {} images to {}",
+            index.entries.len(),
+            path.display()
+        );
+        std::fs::write(&path, data)?;
+    }
+    Ok(())
+}
+
+/// Write a program of the given kind for every image in `manifest` to show it.
+fn write_viewers(
+    kind: ViewerKind,
+    manifest: &Manifest,
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut names = HashSet::new();
+    for image in &manifest.images {
+        let Some(entry) = &image.index_entry else {
+            continue;
+        };
+        let name = viewer::program_name(entry);
+        if !names.insert(name.clone()) {
+            return Err(format!("more than one image would have a viewer named {}", name).into());
+        }
+        let extension = match kind {
+            ViewerKind::Basic => "8xp",
+            ViewerKind::Asm => "asm",
+        };
+        let path = settings.out_dir.join(&name).with_extension(extension);
+        if !settings.existing.may_write(std::slice::from_ref(&path))? {
+            continue;
+        }
+        let data = match kind {
+            ViewerKind::Basic => {
+                viewer::write_basic_viewer(entry, settings.archived, Cursor::new(Vec::new()))?
+                    .into_inner()
+            }
+            ViewerKind::Asm => {
+                let template = match &settings.name_template {
+                    Some(template) => template.clone(),
+                    None if image.animation.is_some() => NameTemplate::default_for_frames(),
+                    None => NameTemplate::default(),
+                };
+                viewer::write_asm_viewer(entry, &template, settings.compression, Vec::new())?
+            }
+        };
+        if settings.dry_run {
+            info!(
+                "Would write viewer to {} ({} bytes)",
+                path.display(),
+                data.len()
+            );
+        } else {
+            info!("Writing viewer to {}", path.display());
+            std::fs::write(&path, data)?;
+            if kind == ViewerKind::Asm {
+                info!(
+                    "Assemble it into a program with `fasmg {} {}`",
+                    path.display(),
+                    path.with_extension("8xp").display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Appvar names that have already been used, each with a description of what used it.
+#[derive(Default)]
+struct UsedNames(HashMap<String, String>);
+
+impl UsedNames {
+    /// Record the names of appvars already present in a directory.
+    fn add_existing(&mut self, dir: &Path) -> std::io::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let is_var = path
+                .extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("8xv"));
+            if let (true, Some(stem)) = (is_var, path.file_stem()) {
+                self.0.insert(
+                    stem.to_string_lossy().into_owned(),
+                    format!("existing file {:?}", path),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Record the names of all the appvars generated for an image, unless any is already taken.
+    fn claim<'a, I: IntoIterator<Item = &'a str>>(
+        &mut self,
+        names: I,
+        image_file: &Path,
+    ) -> Result<(), String> {
+        let names: Vec<&str> = names.into_iter().collect();
+        if let Some((name, owner)) = names
+            .iter()
+            .find_map(|&name| self.0.get(name).map(|owner| (name, owner)))
+        {
+            return Err(format!(
+                "appvar {} would be the same as one from {}",
+                name, owner
+            ));
+        }
+
+        let owner = format!("{:?}", image_file);
+        self.0.extend(
+            names
+                .into_iter()
+                .map(|name| (name.to_string(), owner.clone())),
+        );
+        Ok(())
+    }
+}
+
+/// Description of the output for every image converted, written with `--manifest`.
+#[derive(Serialize)]
+struct Manifest {
+    images: Vec<ManifestImage>,
+}
+
+#[derive(Serialize)]
+struct ManifestImage {
+    /// Path of the input image.
+    source: String,
+    /// Path of the bundle containing the appvars, if they were written to one.
+    output: Option<String>,
+    /// Width of the image in pixels, including padding to fill whole tiles.
+    width: u32,
+    /// Height of the image in pixels, including padding to fill whole tiles.
+    height: u32,
+    tile_width: u32,
+    tile_height: u32,
+    columns: u32,
+    rows: u32,
+    /// Name of the palette appvar, or of the first frame's palette appvar for animations, if it
+    /// was written.
+    palette: Option<String>,
+    transparent_index: Option<u8>,
+    /// Name of the animation appvar, for animations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    animation: Option<String>,
+    /// How long each frame of an animation is shown, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame_delays: Option<Vec<u32>>,
+    /// How the image is listed in an index appvar, for images with tiles.
+    #[serde(skip)]
+    index_entry: Option<index::Entry>,
+    appvars: Vec<ManifestAppvar>,
+}
+
+#[derive(Serialize)]
+struct ManifestAppvar {
+    name: String,
+    /// Size of the 8xv file in bytes.
+    size: usize,
+    /// Tile column, which the palette appvar doesn't have.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    column: Option<u32>,
+    /// Tile row, which the palette appvar doesn't have.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    row: Option<u32>,
+    /// Animation frame of a tile or palette, which still images don't have.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frame: Option<u32>,
+}
+
+impl Manifest {
+    fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut json = serde_json::to_string_pretty(self)?;
+        json.push('\n');
+        if is_stdio(path) {
+            std::io::stdout().lock().write_all(json.as_bytes())
+        } else {
+            std::fs::write(path, json)
+        }
+    }
+}
+
+/// State shared by all the images being converted.
+#[derive(Default)]
+struct Batch {
+    used_names: UsedNames,
+    /// Archive space needed by the appvars generated so far.
+    flash_used: usize,
+}
+
+/// Name given to images read from stdin, which have no file name.
+const STDIN_IMAGE_NAME: &str = "image";
+const GROUP_EXTENSION: &str = "8xg";
+const ZIP_EXTENSION: &str = "zip";
+const RAW_EXTENSION: &str = "bin";
+
+/// Return the path of the bundle file written for an image, which is named after it.
+fn bundle_path(image_file: &Path, out_dir: &Path, extension: &str) -> PathBuf {
+    let mut out_path = out_dir.to_path_buf();
+    if is_stdio(image_file) {
+        out_path.push(STDIN_IMAGE_NAME);
+    } else {
+        out_path.push(image_file.file_stem().unwrap_or("image".as_ref()));
+    }
+    out_path.set_extension(extension);
+    out_path
+}
+
+/// What to do when output files already exist.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ExistingOutput {
+    /// Fail to convert the image.
+    Refuse,
+    /// Replace the existing files.
+    Overwrite,
+    /// Leave the existing files alone and don't convert the image.
+    Skip,
+}
+
+impl ExistingOutput {
+    /// Return whether files should be written to these paths, or an error if they must not be.
+    fn may_write(self, paths: &[PathBuf]) -> Result<bool, String> {
+        let Some(existing) = paths.iter().find(|path| path.exists()) else {
+            return Ok(true);
+        };
+        match self {
+            ExistingOutput::Overwrite => Ok(true),
+            ExistingOutput::Skip => {
+                info!("Skipping because {:?} already exists", existing);
+                Ok(false)
+            }
+            ExistingOutput::Refuse => Err(format!(
+                "{:?} already exists; use --force to overwrite it",
+                existing
+            )),
+        }
+    }
+}
+
+/// Parse the name of an appvar, which is up to 8 letters and digits beginning with a letter.
+fn appvar_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > 8 {
+        Err(format!("{:?} isn't from 1 to 8 characters long", s))
+    } else if !s.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        Err(format!("{:?} doesn't begin with a letter", s))
+    } else if !s.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Err(format!(
+            "{:?} has characters other than letters and digits",
+            s
+        ))
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+/// Return whether a path is `-`, which refers to stdin or stdout rather than a file.
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+/// Options applying to the conversion of every image.
+#[derive(Clone)]
+struct Settings {
+    decode: DecodeOptions,
+    rotation: Option<Rotation>,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+    /// Position and size of the region of images to keep.
+    crop: Option<(u32, u32, u32, u32)>,
+    /// Whether to shrink images to fit the screen.
+    fit_screen: bool,
+    /// Dimensions and mode to resize images with.
+    scale: Option<(u32, u32, ScaleMode)>,
+    gamma: Option<f32>,
+    brightness: f32,
+    contrast: f32,
+    saturation: f32,
+    /// Hue rotation in degrees.
+    hue_shift: f32,
+    grayscale: bool,
+    tile_size: Option<(u32, u32)>,
+    compression: Compression,
+    bit_depth: BitDepth,
+    lcd_scale: LcdScale,
+    archived: bool,
+    comment: Option<String>,
+    name_template: Option<NameTemplate>,
+    existing: ExistingOutput,
+    out_dir: PathBuf,
+    format: OutputFormat,
+    quantize: QuantizeOptions,
+    /// Whether tiles hold 16-bit colors rather than indices into a palette.
+    direct_color: bool,
+    /// Whether to write the palette appvar.
+    palette_appvar: bool,
+    /// Whether every frame of an animation shares one palette.
+    global_palette: bool,
+    /// Whether to write a thumbnail appvar for each image.
+    thumbnails: bool,
+    /// Whether to write a metadata appvar for each image.
+    metadata: bool,
+    /// Whether to write ICE source describing each image.
+    emit_ice: bool,
+    /// Whether to write a C header and source describing each image.
+    emit_c: bool,
+    /// Whether to write an assembly include describing each image.
+    emit_inc: bool,
+    /// Whether to write each image's palette as GIMP and convimg palettes.
+    export_palette: bool,
+    target: Target,
+    /// Number of the picture variable the first image is stored in, for monochrome targets.
+    first_picture: u8,
+    picture: PictureOptions,
+    /// Whether to show progress bars.
+    progress: bool,
+    /// Whether to only report what would be written.
+    dry_run: bool,
+    /// Whether to convert images a band at a time.
+    stream: bool,
+    /// Archive space available for appvars, in bytes.
+    flash_budget: usize,
+    /// Whether exceeding the flash budget is an error.
+    strict_size: bool,
+}
+
+impl Settings {
+    /// Return whether images are split into tile appvars, which the TI-Python format replaces
+    /// with a script even for CE targets.
+    fn has_tiles(&self) -> bool {
+        self.target.has_tiles() && self.format != OutputFormat::TiPython
+    }
+
+    /// Return the name of what images are converted to, for messages about options that don't
+    /// apply to it.
+    fn output_name(&self) -> &'static str {
+        if self.format == OutputFormat::TiPython {
+            "ti-python"
+        } else {
+            self.target.name()
+        }
+    }
+}
+
+/// Estimate how much archive space the variable in a variable file takes, which is about the
+/// size of its entry in the file.
+fn flash_size(file: &[u8]) -> usize {
+    file.len() - group::HEADER_LEN - 2
+}
+
+/// Run `work` while showing a spinner with `message`, if `show` is set.
+///
+/// Like all progress bars, nothing is drawn if stderr isn't a terminal.
+fn with_spinner<T>(show: bool, message: &str, work: impl FnOnce() -> T) -> T {
+    let spinner = if show {
+        ProgressBar::new_spinner()
+    } else {
+        ProgressBar::hidden()
+    };
+    spinner.set_message(message.to_string());
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    let result = work();
+    spinner.finish_and_clear();
+    result
+}
+
+/// Return how images are decoded, as options that are only present with some features specify.
+fn decode_options(m: &clap::ArgMatches) -> DecodeOptions {
+    DecodeOptions {
+        density: m.try_get_one::<f32>("density").ok().flatten().copied(),
+        page: m
+            .try_get_one::<u32>("page")
+            .ok()
+            .flatten()
+            .map_or(0, |&page| page as usize - 1),
+    }
+}
+
+/// Load every frame of one image for conversion, of which only animations have more than one.
+fn load_frames(
+    image_file: &Path,
+    var_prefix: &str,
+    options: &DecodeOptions,
+    progress: bool,
+) -> std::io::Result<Vec<Frame>> {
+    if is_stdio(image_file) {
+        info!("Reading image from stdin");
+        // stdin can't seek, so buffer it all; the format is guessed from the data itself.
+        let mut data = Vec::new();
+        std::io::stdin().lock().read_to_end(&mut data)?;
+        with_spinner(progress, "Decoding", || {
+            Image::frames_with(Cursor::new(data), STDIN_IMAGE_NAME, var_prefix, options)
+        })
+    } else {
+        info!("Opening image file {:?}", &image_file);
+        let f = std::fs::File::open(image_file)?;
+        with_spinner(progress, "Decoding", || {
+            Image::frames_with(
+                BufReader::new(f),
+                &image_file.file_name().unwrap().to_string_lossy(),
+                var_prefix,
+                options,
+            )
+        })
+    }
+}
+
+/// Load one image for conversion, keeping only the first frame of animations.
+fn load_image(
+    image_file: &Path,
+    var_prefix: &str,
+    options: &DecodeOptions,
+    progress: bool,
+) -> std::io::Result<Image> {
+    let mut frames = load_frames(image_file, var_prefix, options, progress)?;
+    if frames.len() > 1 {
+        warn!("Only converting the first of {} frames", frames.len());
+    }
+    Ok(frames.swap_remove(0).image)
+}
+
+/// Return the extension of the bundle an image's appvars go in, if they go in one.
+fn bundle_extension(format: OutputFormat) -> Option<&'static str> {
+    match format {
+        OutputFormat::Group => Some(GROUP_EXTENSION),
+        OutputFormat::Zip => Some(ZIP_EXTENSION),
+        OutputFormat::Loose | OutputFormat::Raw | OutputFormat::TiPython => None,
+    }
+}
+
+/// Check whether an image's bundle may be written, before doing any work since its name doesn't
+/// depend on the conversion.
+fn may_write_bundle(image_file: &Path, settings: &Settings) -> Result<bool, String> {
+    match bundle_extension(settings.format) {
+        Some(extension) if !is_stdio(&settings.out_dir) => settings
+            .existing
+            .may_write(&[bundle_path(image_file, &settings.out_dir, extension)]),
+        _ => Ok(true),
+    }
+}
+
+/// Apply the output settings to a quantized image.
+fn configure(image: &mut QuantizedImage, settings: &Settings) -> std::io::Result<()> {
+    image.set_compression(settings.compression);
+    image.set_bit_depth(settings.bit_depth)?;
+    image.set_lcd_scale(settings.lcd_scale);
+    image.set_archived(settings.archived);
+    if let Some(comment) = &settings.comment {
+        image.set_comment(comment);
+    }
+    if let Some(template) = &settings.name_template {
+        image.set_name_template(template.clone())?;
+    }
+    Ok(())
+}
+
+/// Create a progress bar for packaging some number of tiles.
+fn packaging_bar(settings: &Settings, tiles: u32) -> ProgressBar {
+    let bar = if settings.progress {
+        ProgressBar::new(tiles as u64)
+    } else {
+        ProgressBar::hidden()
+    };
+    bar.set_style(
+        ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len} tiles")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    bar.set_message("Packaging");
+    bar
+}
+
+/// Appvars generated for an image so far, with their manifest entries.
+#[derive(Default)]
+struct Appvars {
+    files: Vec<(String, Vec<u8>)>,
+    manifest: Vec<ManifestAppvar>,
+    /// Name of the palette appvar, or of the first frame's if the image is animated.
+    palette: Option<String>,
+    /// Name of the animation appvar and the delay of each frame in milliseconds.
+    animation: Option<(String, Vec<u32>)>,
+    /// Whether the appvars are for frames of an animation.
+    animated: bool,
+}
+
+impl Appvars {
+    /// Generate the appvars of an image's tiles, advancing `bar` as each is done.
+    fn add_tiles(&mut self, image: &QuantizedImage, bar: &ProgressBar) -> std::io::Result<()> {
+        // Tiles are independent, so they can be compressed in parallel
+        let tiles: Vec<Tile> = image.tiles().collect();
+        let encoded = tiles
+            .par_iter()
+            .map(|tile| {
+                // Written to memory because the writer needs to seek
+                let data = tile.write_appvar(Cursor::new(Vec::new()))?.into_inner();
+                bar.inc(1);
+                Ok(data)
+            })
+            .collect::<std::io::Result<Vec<Vec<u8>>>>()?;
+
+        for (tile, data) in tiles.iter().zip(encoded) {
+            let (column, row) = tile.index();
+            debug!(
+                "Tile {} at column {} row {} is {} bytes",
+                tile.appvar_name(),
+                column,
+                row,
+                data.len()
+            );
+            self.manifest.push(ManifestAppvar {
+                name: tile.appvar_name().to_string(),
+                size: data.len(),
+                column: Some(column),
+                row: Some(row),
+                frame: self.animated.then_some(image.frame()),
+            });
+            self.files.push((tile.appvar_name().to_string(), data));
+        }
+        Ok(())
+    }
+
+    /// Generate an image's palette appvar.
+    fn add_palette(&mut self, image: &QuantizedImage) -> std::io::Result<()> {
+        let data = image
+            .write_palette_appvar(Cursor::new(Vec::new()))?
+            .into_inner();
+        self.palette
+            .get_or_insert_with(|| image.palette_appvar_name());
+        self.push(
+            image.palette_appvar_name(),
+            data,
+            self.animated.then_some(image.frame()),
+        );
+        Ok(())
+    }
+
+    /// Generate the thumbnail appvar of an image that hasn't been quantized yet.
+    fn add_thumbnail(
+        &mut self,
+        image: &Image,
+        options: &QuantizeOptions,
+        settings: &Settings,
+    ) -> std::io::Result<()> {
+        let data = image
+            .write_thumbnail_appvar(options, settings.archived, Cursor::new(Vec::new()))?
+            .into_inner();
+        self.push(image.thumbnail_appvar_name(), data, None);
+        Ok(())
+    }
+
+    /// Generate the appvar describing an image's geometry and encoding.
+    fn add_metadata(&mut self, image: &QuantizedImage) -> std::io::Result<()> {
+        let data = image
+            .write_metadata_appvar(Cursor::new(Vec::new()))?
+            .into_inner();
+        self.push(image.metadata_appvar_name(), data, None);
+        Ok(())
+    }
+
+    /// Generate the appvar describing an animation that `image` is a frame of.
+    fn add_animation(
+        &mut self,
+        image: &QuantizedImage,
+        delays_ms: Vec<u32>,
+        shared_palette: bool,
+    ) -> std::io::Result<()> {
+        let data = image
+            .write_animation_appvar(&delays_ms, shared_palette, Cursor::new(Vec::new()))?
+            .into_inner();
+        self.animation = Some((image.animation_appvar_name(), delays_ms));
+        self.push(image.animation_appvar_name(), data, None);
+        Ok(())
+    }
+
+    /// Add an appvar that isn't a tile.
+    fn push(&mut self, name: String, data: Vec<u8>, frame: Option<u32>) {
+        self.manifest.push(ManifestAppvar {
+            name: name.clone(),
+            size: data.len(),
+            column: None,
+            row: None,
+            frame,
+        });
+        self.files.push((name, data));
+    }
+}
+
+/// Apply the transformations and adjustments in `settings` to a loaded image.
+fn prepare(image: &mut Image, settings: &Settings) -> std::io::Result<()> {
+    if let Some(rotation) = settings.rotation {
+        trace!("Rotating by {:?}", rotation);
+        image.rotate(rotation);
+    }
+    if settings.flip_horizontal {
+        trace!("Flipping horizontally");
+        image.flip_horizontal();
+    }
+    if settings.flip_vertical {
+        trace!("Flipping vertically");
+        image.flip_vertical();
+    }
+    if let Some((x, y, width, height)) = settings.crop {
+        trace!("Cropping to {}x{} at {},{}", width, height, x, y);
+        image.crop(x, y, width, height)?;
+    }
+    if settings.fit_screen {
+        trace!("Shrinking to fit {}x{}", SCREEN_WIDTH, SCREEN_HEIGHT);
+        image.downscale_to_fit(SCREEN_WIDTH, SCREEN_HEIGHT);
+    }
+    if let Some((width, height, mode)) = settings.scale {
+        trace!("Resizing to {}x{} with {:?}", width, height, mode);
+        image.resize(width, height, mode);
+    }
+    if settings.lcd_scale != LcdScale::Full {
+        trace!(
+            "Shrinking for the LCD scaled {:?}",
+            settings.lcd_scale.factors()
+        );
+        image.scale_for_lcd(settings.lcd_scale);
+    }
+    if let Some(gamma) = settings.gamma {
+        trace!("Adjusting gamma by {}", gamma);
+        image.adjust_gamma(gamma);
+    }
+    if settings.brightness != 0. || settings.contrast != 1. {
+        trace!(
+            "Adjusting brightness by {} and contrast by {}",
+            settings.brightness,
+            settings.contrast
+        );
+        image.adjust_brightness_contrast(settings.brightness, settings.contrast);
+    }
+    if settings.saturation != 1. || settings.hue_shift != 0. {
+        trace!(
+            "Adjusting saturation by {} and rotating hue by {} degrees",
+            settings.saturation,
+            settings.hue_shift
+        );
+        image.adjust_saturation_hue(settings.saturation, settings.hue_shift);
+    }
+    if settings.grayscale {
+        trace!("Converting to grayscale");
+        image.grayscale();
+    }
+    if let Some((width, height)) = settings.tile_size {
+        image.set_tile_size(width, height);
+    }
+    Ok(())
+}
+
+/// Quantize a prepared image with `options`, or convert it to direct color if `settings` ask for
+/// that instead.
+fn quantize(
+    image: Image,
+    options: &QuantizeOptions,
+    settings: &Settings,
+) -> std::io::Result<QuantizedImage> {
+    if settings.direct_color {
+        Ok(image.direct_color(options.background))
+    } else {
+        with_spinner(settings.progress, "Quantizing", || {
+            image.quantize_with(options)
+        })
+    }
+}
+
+/// Convert one loaded image that [`prepare`] has already transformed, writing its appvars as
+/// specified by `settings`.
+fn convert(
+    image_file: &Path,
+    image: Image,
+    settings: &Settings,
+    batch: &Mutex<Batch>,
+) -> Result<Option<ManifestImage>, Box<dyn std::error::Error>> {
+    if !may_write_bundle(image_file, settings)? {
+        return Ok(None);
+    }
+
+    let (width, height) = image.dimensions();
+    debug!("Prepared image is {}x{} pixels", width, height);
+    // Every format needs complete variable files, so generate them all up front
+    let mut appvars = Appvars::default();
+    if settings.thumbnails {
+        appvars.add_thumbnail(&image, &settings.quantize, settings)?;
+    }
+    let mut image = quantize(image, &settings.quantize, settings)?;
+    configure(&mut image, settings)?;
+
+    let bar = packaging_bar(settings, image.width_tiles() * image.height_tiles());
+    appvars.add_tiles(&image, &bar)?;
+    bar.finish_and_clear();
+    if settings.palette_appvar {
+        appvars.add_palette(&image)?;
+    }
+    package(image_file, &image, appvars, settings, batch)
+}
+
+/// Convert one loaded image to a monochrome picture stored as `PicN`, writing it as specified by
+/// `settings`.
+fn convert_picture(
+    image_file: &Path,
+    mut image: Image,
+    number: u8,
+    settings: &Settings,
+    batch: &Mutex<Batch>,
+) -> Result<Option<ManifestImage>, Box<dyn std::error::Error>> {
+    if !may_write_bundle(image_file, settings)? {
+        return Ok(None);
+    }
+
+    let (width, height) = image.dimensions();
+    debug!("Image is {}x{} pixels", width, height);
+    prepare(&mut image, settings)?;
+    let mut picture = image.to_picture(&settings.picture, number);
+    if let Some(comment) = &settings.comment {
+        picture.set_comment(comment);
+    }
+    let file = picture.write(Cursor::new(Vec::new()))?.into_inner();
+
+    let manifest = ManifestImage {
+        source: image_file.display().to_string(),
+        output: None,
+        width: PICTURE_WIDTH,
+        height: PICTURE_HEIGHT,
+        tile_width: PICTURE_WIDTH,
+        tile_height: PICTURE_HEIGHT,
+        columns: 1,
+        rows: 1,
+        palette: None,
+        transparent_index: None,
+        animation: None,
+        frame_delays: None,
+        index_entry: None,
+        appvars: vec![ManifestAppvar {
+            name: picture.var_name(),
+            size: file.len(),
+            column: None,
+            row: None,
+            frame: None,
+        }],
+    };
+    write_vars(
+        image_file,
+        manifest,
+        vec![(picture.var_name(), file)],
+        settings,
+        batch,
+    )
+}
+
+/// Size in bytes above which NumWorks scripts risk not fitting in the calculator's storage, which
+/// holds about 32 KiB of scripts on older models.
+const NUMWORKS_SCRIPT_LIMIT: usize = 32 * 1024;
+
+/// Convert one loaded image for a calculator that shows it on the whole screen, writing a single
+/// file named after it.
+fn convert_screen(
+    image_file: &Path,
+    mut image: Image,
+    settings: &Settings,
+) -> Result<Option<ManifestImage>, Box<dyn std::error::Error>> {
+    let extension = settings.target.file_extension();
+    let out_path = bundle_path(image_file, &settings.out_dir, extension);
+    if !is_stdio(&settings.out_dir)
+        && !settings
+            .existing
+            .may_write(std::slice::from_ref(&out_path))?
+    {
+        return Ok(None);
+    }
+
+    let (width, height) = image.dimensions();
+    debug!("Image is {}x{} pixels", width, height);
+    prepare(&mut image, settings)?;
+    let (width, height) = match settings.target {
+        _ if settings.format == OutputFormat::TiPython => (TI_PYTHON_WIDTH, TI_PYTHON_HEIGHT),
+        Target::HpPrime | Target::Nspire => (SCREEN_WIDTH, SCREEN_HEIGHT),
+        Target::Cg50 => (screen::CG50_WIDTH, screen::CG50_HEIGHT),
+        Target::NumWorks => (NUMWORKS_WIDTH, NUMWORKS_HEIGHT),
+        _ => unreachable!("{:?} has no full color screen", settings.target),
+    };
+    let data = if settings.target == Target::NumWorks {
+        let script = image.write_numworks_script(&settings.quantize, Vec::new())?;
+        if script.len() > NUMWORKS_SCRIPT_LIMIT {
+            warn!(
+                "{} is a {} byte script, which may not fit on the calculator; try fewer --colors",
+                out_path.display(),
+                script.len()
+            );
+        }
+        script
+    } else if settings.format == OutputFormat::TiPython {
+        image
+            .write_ti_python_appvar(
+                &settings.quantize,
+                settings.archived,
+                Cursor::new(Vec::new()),
+            )?
+            .into_inner()
+    } else {
+        let canvas = image.to_screen(width, height, settings.quantize.background);
+        match settings.target {
+            Target::Cg50 => screen::bmp(&canvas),
+            Target::Nspire => screen::raw_rgb565(&canvas),
+            _ => {
+                let mut data = Cursor::new(Vec::new());
+                DynamicImage::ImageRgba8(canvas)
+                    .into_rgb8()
+                    .write_to(&mut data, ImageOutputFormat::Png)?;
+                data.into_inner()
+            }
+        }
+    };
+
+    let manifest = ManifestImage {
+        source: image_file.display().to_string(),
+        output: (!is_stdio(&settings.out_dir)).then(|| out_path.display().to_string()),
+        width,
+        height,
+        tile_width: width,
+        tile_height: height,
+        columns: 1,
+        rows: 1,
+        palette: None,
+        transparent_index: None,
+        animation: None,
+        frame_delays: None,
+        index_entry: None,
+        appvars: Vec::new(),
+    };
+    if settings.dry_run {
+        info!("Would write {} ({} bytes)", out_path.display(), data.len());
+    } else if is_stdio(&settings.out_dir) {
+        let mut out = std::io::stdout().lock();
+        out.write_all(&data)?;
+        out.flush()?;
+    } else {
+        info!("Writing {}", out_path.display());
+        std::fs::write(&out_path, data)?;
+    }
+    Ok(Some(manifest))
+}
+
+/// Convert every frame of an animation, writing their appvars as specified by `settings`.
+///
+/// Each frame has its own tiles and, unless they share one, palette, followed by an appvar
+/// describing the whole animation.
+fn convert_animation(
+    image_file: &Path,
+    frames: Vec<Frame>,
+    settings: &Settings,
+    batch: &Mutex<Batch>,
+) -> Result<Option<ManifestImage>, Box<dyn std::error::Error>> {
+    if !may_write_bundle(image_file, settings)? {
+        return Ok(None);
+    }
+    if let Some(template) = &settings.name_template {
+        if !template.has_frame() {
+            return Err("the name template must include {frame} to convert animations".into());
+        }
+    }
+
+    let (width, height) = frames[0].image.dimensions();
+    debug!(
+        "Animation is {} frames of {}x{} pixels",
+        frames.len(),
+        width,
+        height
+    );
+    let mut delays = Vec::with_capacity(frames.len());
+    let mut images = Vec::with_capacity(frames.len());
+    for Frame {
+        mut image,
+        delay_ms,
+    } in frames
+    {
+        prepare(&mut image, settings)?;
+        delays.push(delay_ms);
+        images.push(image);
+    }
+
+    let mut options = settings.quantize.clone();
+    let shared_palette = settings.global_palette || options.palette.is_some();
+    if options.palette.is_none() && settings.global_palette {
+        let palette = with_spinner(settings.progress, "Generating palette", || {
+            Image::shared_palette(&images, &options)
+        })?;
+        options.palette = Some(palette);
+    }
+
+    let mut appvars = Appvars {
+        animated: true,
+        ..Default::default()
+    };
+    if settings.thumbnails {
+        appvars.add_thumbnail(&images[0], &options, settings)?;
+    }
+    let bar = packaging_bar(settings, 0);
+    let mut first = None;
+    for (frame, image) in images.into_iter().enumerate() {
+        trace!("Quantizing frame {}", frame);
+        let mut image = quantize(image, &options, settings)?;
+        configure(&mut image, settings)?;
+        if settings.name_template.is_none() {
+            image.set_name_template(NameTemplate::default_for_frames())?;
+        }
+        image.set_frame(frame as u32)?;
+
+        bar.inc_length((image.width_tiles() * image.height_tiles()) as u64);
+        appvars.add_tiles(&image, &bar)?;
+        if settings.palette_appvar && (!shared_palette || first.is_none()) {
+            appvars.add_palette(&image)?;
+        }
+        first.get_or_insert(image);
+    }
+    bar.finish_and_clear();
+    // The first frame describes the rest, which are the same size
+    let image = first.expect("animations always have at least one frame");
+    appvars.add_animation(&image, delays, shared_palette)?;
+    package(image_file, &image, appvars, settings, batch)
+}
+
+/// Convert one image a band of tiles at a time, writing its appvars as specified by `settings`.
+///
+/// This can't apply any transformations or adjustments, only quantize the image as it is.
+fn convert_streaming(
+    image_file: &Path,
+    var_prefix: &str,
+    settings: &Settings,
+    batch: &Mutex<Batch>,
+) -> Result<Option<ManifestImage>, Box<dyn std::error::Error>> {
+    if is_stdio(image_file) {
+        return Err(
+            "images can't be streamed from stdin, since they're read more than once".into(),
+        );
+    }
+    if !may_write_bundle(image_file, settings)? {
+        return Ok(None);
+    }
+
+    info!("Opening image file {:?}", image_file);
+    let open = || std::fs::File::open(image_file).map(BufReader::new);
+    let mut image = StreamingImage::new(
+        open,
+        &image_file.file_name().unwrap().to_string_lossy(),
+        var_prefix,
+    )?;
+    let (width, height) = image.dimensions();
+    debug!("Image is {}x{} pixels", width, height);
+    let (tile_width, tile_height) = settings
+        .tile_size
+        .unwrap_or((Image::DEFAULT_TILE_SIZE, Image::DEFAULT_TILE_SIZE));
+    image.set_tile_size(tile_width, tile_height);
+
+    let mut options = settings.quantize.clone();
+    if options.palette.is_none() {
+        let palette = with_spinner(settings.progress, "Generating palette", || {
+            image.palette(&options)
+        })?;
+        options.palette = Some(palette);
+    }
+
+    let mut appvars = Appvars::default();
+    let bar = packaging_bar(
+        settings,
+        width.div_ceil(tile_width) * height.div_ceil(tile_height),
+    );
+    let mut last_band = None;
+    image.quantize_bands(&options, |mut band| {
+        configure(&mut band, settings)?;
+        appvars.add_tiles(&band, &bar)?;
+        last_band = Some(band);
+        Ok(())
+    })?;
+    bar.finish_and_clear();
+    // Every band describes the whole image
+    let image = last_band.expect("images always have at least one row of tiles");
+    if settings.palette_appvar {
+        appvars.add_palette(&image)?;
+    }
+    package(image_file, &image, appvars, settings, batch)
+}
+
+/// Write the appvars of a converted image along with its palette.
+fn package(
+    image_file: &Path,
+    image: &QuantizedImage,
+    mut appvars: Appvars,
+    settings: &Settings,
+    batch: &Mutex<Batch>,
+) -> Result<Option<ManifestImage>, Box<dyn std::error::Error>> {
+    if settings.metadata {
+        appvars.add_metadata(image)?;
+    }
+    if let Some(index) = image.transparent_index() {
+        info!("Transparent pixels use palette index {}", index);
+    }
+    debug!(
+        "Quantized to {}x{} pixels in {}x{} tiles of {}x{}",
+        image.width(),
+        image.height(),
+        image.width_tiles(),
+        image.height_tiles(),
+        image.tile_size().0,
+        image.tile_size().1
+    );
+
+    let Appvars {
+        files: appvars,
+        manifest: manifest_appvars,
+        palette,
+        animation,
+        ..
+    } = appvars;
+    let (animation, frame_delays) = animation.unzip();
+    let manifest = ManifestImage {
+        source: image_file.display().to_string(),
+        output: None,
+        width: image.width(),
+        height: image.height(),
+        tile_width: image.tile_size().0,
+        tile_height: image.tile_size().1,
+        columns: image.width_tiles(),
+        rows: image.height_tiles(),
+        palette,
+        transparent_index: image.transparent_index(),
+        animation,
+        frame_delays,
+        index_entry: Some(index::Entry::new(image)),
+        appvars: manifest_appvars,
+    };
+    let written = write_vars(image_file, manifest, appvars, settings, batch)?;
+    if written.is_some() && settings.emit_ice {
+        let source = image.write_ice_source(Vec::new())?;
+        write_source(
+            &bundle_path(image_file, &settings.out_dir, "ice"),
+            &source,
+            settings,
+        )?;
+    }
+    if written.is_some() && settings.emit_c {
+        let header_path = bundle_path(image_file, &settings.out_dir, "h");
+        let header_name = header_path.file_name().unwrap().to_string_lossy();
+        let source = image.write_c_source(&header_name, Vec::new())?;
+        write_source(&header_path, &image.write_c_header(Vec::new())?, settings)?;
+        write_source(
+            &bundle_path(image_file, &settings.out_dir, "c"),
+            &source,
+            settings,
+        )?;
+    }
+    if written.is_some() && settings.emit_inc {
+        let source = image.write_asm_include(Vec::new())?;
+        write_source(
+            &bundle_path(image_file, &settings.out_dir, "inc"),
+            &source,
+            settings,
+        )?;
+    }
+    if written.is_some() && settings.export_palette {
+        let colors = image.stored_palette();
+        let name = index::Entry::new(image).name;
+        let name = name.trim_end_matches('_').to_ascii_lowercase();
+        let gpl = palette::write_gpl(&colors, &name, Vec::new())?;
+        write_source(
+            &bundle_path(image_file, &settings.out_dir, "gpl"),
+            &gpl,
+            settings,
+        )?;
+        let yaml = palette::write_convimg(&colors, &format!("{}_palette", name), Vec::new())?;
+        write_source(
+            &bundle_path(image_file, &settings.out_dir, "yaml"),
+            &yaml,
+            settings,
+        )?;
+    }
+    Ok(written)
+}
+
+/// Write generated source code for an image to `path`, as specified by `settings`.
+fn write_source(
+    path: &Path,
+    source: &[u8],
+    settings: &Settings,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if !settings.existing.may_write(&[path.to_path_buf()])? {
+        return Ok(());
+    }
+    if settings.dry_run {
+        info!(
+            "Would write source to {} ({} bytes)",
+            path.display(),
+            source.len()
+        );
+    } else {
+        info!("Writing source to {}", path.display());
+        std::fs::write(path, source)?;
+    }
+    Ok(())
+}
+
+/// Write the variable files generated for an image as specified by `settings`, returning its
+/// manifest entry unless they were skipped.
+fn write_vars(
+    image_file: &Path,
+    mut manifest: ManifestImage,
+    appvars: Vec<(String, Vec<u8>)>,
+    settings: &Settings,
+    batch: &Mutex<Batch>,
+) -> Result<Option<ManifestImage>, Box<dyn std::error::Error>> {
+    let out_dir = &settings.out_dir;
+    let extension = if settings.format == OutputFormat::Raw {
+        RAW_EXTENSION
+    } else {
+        settings.target.file_extension()
+    };
+    // Other images being converted at the same time mustn't claim names or flash in between
+    let mut batch = batch.lock().unwrap();
+    let size: usize = appvars.iter().map(|(_, data)| flash_size(data)).sum();
+    if settings.strict_size && batch.flash_used + size > settings.flash_budget {
+        return Err(format!(
+            "appvars need {} bytes of archive, but only {} of the {} byte flash budget remain",
+            size,
+            settings.flash_budget.saturating_sub(batch.flash_used),
+            settings.flash_budget
+        )
+        .into());
+    }
+
+    // Raw data is only what programs read, so tiles don't have their signature either
+    let appvars = if settings.format == OutputFormat::Raw {
+        appvars
+            .into_iter()
+            .map(|(name, file)| {
+                let data = group::read(&file)?.vars.remove(0).data;
+                if !hdpictureconverter::decode::Tile::is_tile(&data) {
+                    return Ok((name, data));
+                }
+                let payload = hdpictureconverter::decode::Tile::payload(&data)?;
+                Ok((name, payload.to_vec()))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?
+    } else {
+        appvars
+    };
+    if matches!(settings.format, OutputFormat::Loose | OutputFormat::Raw) {
+        let paths: Vec<PathBuf> = appvars
+            .iter()
+            .map(|(name, _)| out_dir.join(format!("{}.{}", name, extension)))
+            .collect();
+        if !settings.existing.may_write(&paths)? {
+            return Ok(None);
+        }
+    }
+
+    // Only appvars that will be written take their names from others
+    batch
+        .used_names
+        .claim(appvars.iter().map(|(name, _)| name.as_str()), image_file)?;
+    batch.flash_used += size;
+    drop(batch);
+
+    // Bundles are built in memory, since the output might not be seekable
+    let bundle =
+        match settings.format {
+            OutputFormat::Group => {
+                let mut group = group::Writer::new(Vec::new());
+                if let Some(comment) = &settings.comment {
+                    group.set_comment(comment);
+                }
+                for (_, data) in &appvars {
+                    group.add_var(data)?;
+                }
+                Some(group.close().map_err(|e| {
+                    format!("{}; write them as separate files with --format loose", e)
+                })?)
+            }
+            OutputFormat::Zip => {
+                let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+                // Entries get a fixed timestamp so the same appvars always make the same archive
+                let zip_options =
+                    zip::write::FileOptions::default().last_modified_time(zip::DateTime::default());
+                for (name, data) in &appvars {
+                    zip.start_file(format!("{}.{}", name, extension), zip_options)?;
+                    zip.write_all(data)?;
+                }
+                Some(zip.finish()?.into_inner())
+            }
+            OutputFormat::Loose | OutputFormat::Raw | OutputFormat::TiPython => None,
+        };
+    // Bundled formats write a single file named after the input, or to stdout
+    let bundle_extension = bundle_extension(settings.format);
+    let destination = match bundle_extension {
+        _ if is_stdio(out_dir) => "stdout".to_string(),
+        Some(extension) => bundle_path(image_file, out_dir, extension)
+            .display()
+            .to_string(),
+        None => out_dir.display().to_string(),
+    };
+
+    if settings.dry_run {
+        for (name, data) in &appvars {
+            info!("  {:8} {:>7} bytes", name, data.len());
+        }
+        let total = match &bundle {
+            Some(bundle) => bundle.len(),
+            None => appvars.iter().map(|(_, data)| data.len()).sum(),
+        };
+        info!(
+            "Would write {} appvars to {} ({} bytes)",
+            appvars.len(),
+            destination,
+            total
+        );
+        return Ok(Some(manifest));
+    }
+
+    match bundle {
+        Some(bundle) => {
+            info!("Writing {} appvars to {}", appvars.len(), destination);
+            if is_stdio(out_dir) {
+                let mut out = std::io::stdout().lock();
+                out.write_all(&bundle)?;
+                out.flush()?;
+            } else {
+                std::fs::write(&destination, bundle)?;
+            }
+        }
+        None => {
+            let names: Vec<&str> = appvars.iter().map(|(name, _)| name.as_str()).collect();
+            info!("Writing appvars to {}: {}", destination, names.join(" "));
+            for (name, data) in &appvars {
+                std::fs::write(out_dir.join(format!("{}.{}", name, extension)), data)?;
+            }
+        }
+    }
+
+    if bundle_extension.is_some() && !is_stdio(out_dir) {
+        manifest.output = Some(destination);
+    }
+    Ok(Some(manifest))
+}
+
+/// A palette shared by several images holds their colors after adjustments.
+#[test]
+fn shared_palette_is_generated_after_preparing() {
+    use hdpictureconverter::decode::Palette;
+
+    let dir = std::env::temp_dir().join(format!("hdpc-shared-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, shift) in [("a.png", 0), ("b.png", 5)] {
+        let pixels = image::RgbaImage::from_fn(40, 30, |x, y| {
+            image::Rgba([
+                (x * 6 + shift) as u8,
+                (y * 8) as u8,
+                ((x + y) * 3) as u8,
+                255,
+            ])
+        });
+        pixels.save(dir.join(name)).unwrap();
+    }
+
+    let (a, b) = (dir.join("a.png"), dir.join("b.png"));
+    let m = command().get_matches_from([
+        "cli".as_ref(),
+        a.as_os_str(),
+        "AA".as_ref(),
+        b.as_os_str(),
+        "BB".as_ref(),
+        "--shared-palette".as_ref(),
+        "--brightness=-1".as_ref(),
+        "-o".as_ref(),
+        dir.as_os_str(),
+    ]);
+    convert_command(&m).unwrap();
+
+    let file = std::fs::read(dir.join("a.8xg")).unwrap();
+    let vars = hdpictureconverter::group::read(&file).unwrap().vars;
+    std::fs::remove_dir_all(&dir).unwrap();
+    let palette = vars
+        .iter()
+        .find(|var| Palette::is_palette(&var.data))
+        .unwrap();
+    let colors = Palette::read(&palette.data).unwrap().colors;
+    assert!(
+        colors.iter().all(|c| (c.r, c.g, c.b) == (0, 0, 0)),
+        "{:?}",
+        colors
+    );
+}
+
+/// Var prefixes on the command line apply to the image before them.
+#[test]
+fn inputs_are_paired_with_prefixes() {
+    let inputs =
+        |args: &[&str]| parse_inputs(&args.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+
+    assert_eq!(
+        inputs(&["a.png", "AB", "b.png"]).unwrap(),
+        [
+            (PathBuf::from("a.png"), Some("AB".to_string())),
+            (PathBuf::from("b.png"), None)
+        ]
+    );
+    assert!(inputs(&["AB", "a.png"]).is_err());
+    assert!(inputs(&["a.png", "AB", "CD"]).is_err());
+    // Directories hold several images, so they can't have one prefix
+    let dir = std::env::temp_dir().to_string_lossy().into_owned();
+    assert!(inputs(&[&dir, "AB"]).is_err());
+}
+
+/// Derived var prefixes come from the file name if they can, and otherwise are the first
+/// unused pair of letters.
+#[test]
+fn var_prefixes_are_derived() {
+    let mut used = HashSet::new();
+    assert_eq!(
+        derive_var_prefix(Path::new("dir/my-photo.png"), &used).unwrap(),
+        "MY"
+    );
+    assert_eq!(
+        derive_var_prefix(Path::new("2024.png"), &used).unwrap(),
+        "AA"
+    );
+    assert_eq!(derive_var_prefix(Path::new("x1.png"), &used).unwrap(), "AA");
+
+    used.insert("MY".to_string());
+    used.insert("AA".to_string());
+    assert_eq!(
+        derive_var_prefix(Path::new("my-photo.png"), &used).unwrap(),
+        "AB"
+    );
+
+    let used: HashSet<String> = ('A'..='Z')
+        .flat_map(|a| ('A'..='Z').map(move |b| format!("{}{}", a, b)))
+        .collect();
+    assert!(derive_var_prefix(Path::new("my-photo.png"), &used).is_err());
+}
+
+/// Appvars that are skipped because their files exist don't clash with those files, and dry
+/// runs write nothing.
+#[test]
+fn existing_appvars_are_skipped() {
+    let dir = std::env::temp_dir().join(format!("hdpc-existing-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let image = dir.join("small.png");
+    image::RgbaImage::from_pixel(40, 30, image::Rgba([10, 20, 30, 255]))
+        .save(&image)
+        .unwrap();
+    let convert = |extra: &[&str]| {
+        let mut args = vec![
+            "cli".as_ref(),
+            image.as_os_str(),
+            "-o".as_ref(),
+            dir.as_os_str(),
+        ];
+        args.extend(extra.iter().map(std::ffi::OsStr::new));
+        convert_command(&command().get_matches_from(args))
+    };
+    let appvar = dir.join("SM000000.8xv");
+
+    convert(&["--format=loose", "--dry-run"]).unwrap();
+    assert!(!appvar.exists());
+    convert(&["--format=loose"]).unwrap();
+    assert!(appvar.exists());
+    assert!(convert(&["--format=loose"]).is_err());
+    convert(&["--format=loose", "--skip-existing", "--check-existing"]).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Options that can't be used together are refused, whether by the parser or once they're
+/// combined.
+#[test]
+fn conflicting_options_are_rejected() {
+    for args in [
+        &["cli", "a.png", "--force", "--skip-existing"][..],
+        &["cli", "a.png", "--watch", "--check-existing"],
+        &["cli", "a.png", "--direct-color", "--colors=16"],
+        &["cli", "--project=a.yaml", "--watch"],
+    ] {
+        let e = command().try_get_matches_from(args).unwrap_err();
+        assert_eq!(
+            e.kind(),
+            clap::error::ErrorKind::ArgumentConflict,
+            "{:?}",
+            args
+        );
+    }
+    command()
+        .try_get_matches_from(["cli", "a.png", "--jobs=2", "--deterministic"])
+        .unwrap();
+
+    for (args, message) in [
+        (
+            &["cli", "a.png", "--emit-viewer=asm", "--compression=deflate"][..],
+            "asm viewer only reads",
+        ),
+        (
+            &["cli", "a.png", "-o", "-", "--format=loose"],
+            "written to stdout",
+        ),
+        (
+            &["cli", "a.png", "--bit-depth=4", "--colors=32"],
+            "4-bit pixels",
+        ),
+        (
+            &["cli", "a.png", "--transparent-index=0"],
+            "reserved colors",
+        ),
+        (
+            &["cli", "a.png", "--format=ti-python", "--thumbnails"],
+            "ti-python images can't have thumbnails",
+        ),
+        (
+            &["cli", "a.png", "--format=ti-python", "--target=cg50"],
+            "doesn't apply to cg50",
+        ),
+        (
+            &["cli", "a.png", "--direct-color", "--tile-size=180x180"],
+            "zx0 can compress",
+        ),
+        (
+            &[
+                "cli",
+                "a.png",
+                "--direct-color",
+                "--tile-size=200x200",
+                "--compression=deflate",
+            ],
+            "an appvar can hold",
+        ),
+    ] {
+        let e = convert_command(&command().get_matches_from(args)).unwrap_err();
+        assert!(e.to_string().contains(message), "{:?}: {}", args, e);
+    }
+}
+
+/// The TI-83 Premium CE gets exactly the files written for the TI-84 Plus CE.
+#[test]
+fn premium_ce_output_matches_ce() {
+    let dir = std::env::temp_dir().join(format!("hdpc-83pce-{}", std::process::id()));
+    let image = dir.join("small.png");
+    std::fs::create_dir_all(&dir).unwrap();
+    image::RgbaImage::from_fn(100, 50, |x, y| {
+        image::Rgba([(x * 2) as u8, (y * 5) as u8, 128, 255])
+    })
+    .save(&image)
+    .unwrap();
+
+    let mut outputs = Vec::new();
+    for target in ["ce", "83pce"] {
+        let out_dir = dir.join(target);
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let m = command().get_matches_from([
+            "cli".as_ref(),
+            image.as_os_str(),
+            // imagequant can order its threads' results differently between runs
+            "--quantizer=median-cut".as_ref(),
+            "--format=loose".as_ref(),
+            format!("--target={}", target).as_ref(),
+            "-o".as_ref(),
+            out_dir.as_os_str(),
+        ]);
+        convert_command(&m).unwrap();
+        let mut files: Vec<(String, Vec<u8>)> = std::fs::read_dir(&out_dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().into_owned();
+                (name, std::fs::read(&path).unwrap())
+            })
+            .collect();
+        files.sort();
+        outputs.push(files);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(!outputs[0].is_empty());
+    assert_eq!(outputs[0], outputs[1]);
+}
+
+/// Direct color images convert with the largest tiles allowed for zx0 and without compression.
+#[test]
+fn largest_direct_color_tiles_are_converted() {
+    let dir = std::env::temp_dir().join(format!("hdpc-direct-{}", std::process::id()));
+    let image = dir.join("large.png");
+    std::fs::create_dir_all(&dir).unwrap();
+    image::RgbaImage::from_fn(255, 128, |x, y| {
+        image::Rgba([x as u8, (y * 2) as u8, (x ^ y) as u8, 255])
+    })
+    .save(&image)
+    .unwrap();
+
+    for (tile_size, compression, tiles) in [("128x64", "zx0", 4), ("255x128", "none", 1)] {
+        let out_dir = dir.join(compression);
+        std::fs::create_dir_all(&out_dir).unwrap();
+        let m = command().get_matches_from([
+            "cli".as_ref(),
+            image.as_os_str(),
+            "--direct-color".as_ref(),
+            format!("--tile-size={}", tile_size).as_ref(),
+            format!("--compression={}", compression).as_ref(),
+            "--format=loose".as_ref(),
+            "-o".as_ref(),
+            out_dir.as_os_str(),
+        ]);
+        convert_command(&m).unwrap();
+        assert_eq!(std::fs::read_dir(&out_dir).unwrap().count(), tiles);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,360 @@
+//! Conversions described by convimg YAML project files
+//!
+//! convimg is the CE toolchain's image converter, and its projects list `palettes`, `converts`
+//! of images against them and `outputs` that say how converts are written. Each convert becomes
+//! one conversion here, with its options and those of its palette and outputs turned into
+//! arguments ahead of the command line's, which take precedence over them:
+//!
+//! - A convert's images and tilesets' images are converted, relative to the project file, and
+//!   share one palette. Tilesets' `tile-width` and `tile-height` become `--tile-size`.
+//! - `transparent-color-index`, `compress`, `bpp`, `rotate`, `flip-x` and `flip-y` become the
+//!   matching options. zx7 isn't supported, so it's compressed with zx0 instead.
+//! - The `xlibc` palette becomes `--palette xlibc`. Other palettes are generated, their
+//!   `max-entries` becoming `--colors` and `fixed-entries` being reserved in order of index.
+//! - Outputs of type `c`, `asm` and `ice` add `--emit-c`, `--emit-inc` and `--emit-ice`, and
+//!   `bin` writes raw data. An output's `directory` becomes `--outdir`, relative to the project
+//!   file, and `archived: false` becomes `--no-archive`.
+//!
+//! Options convimg has that don't apply to HD Picture Viewer's appvars, like `style`, are
+//! ignored with a warning.
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+use serde_yaml::Value;
+
+/// Return a scalar's text, as written for numbers and booleans.
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Return the items of a sequence, or any other value as the only item.
+fn items(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Sequence(items) => items.iter().collect(),
+        Value::Null => Vec::new(),
+        other => vec![other],
+    }
+}
+
+/// Return a mapping's value for `key` as text.
+fn get_str(value: &Value, key: &str) -> Option<String> {
+    value.get(key).and_then(scalar)
+}
+
+/// Return the color of a fixed palette entry as `#RRGGBB`.
+fn fixed_color(entry: &Value) -> Option<(u32, String)> {
+    let color = entry.get("color").unwrap_or(entry);
+    let index = get_str(color, "index")?.parse().ok()?;
+    if let Some(hex) = get_str(color, "hex") {
+        return Some((index, format!("#{}", hex.trim_start_matches('#'))));
+    }
+    let channel = |name| get_str(color, name)?.parse::<u8>().ok();
+    let (r, g, b) = (channel("r")?, channel("g")?, channel("b")?);
+    Some((index, format!("#{:02x}{:02x}{:02x}", r, g, b)))
+}
+
+/// Return the arguments for a convimg palette.
+fn palette_args(
+    path: &Path,
+    name: &str,
+    palettes: &[&Value],
+    args: &mut Vec<OsString>,
+) -> Result<(), String> {
+    if name == "xlibc" {
+        args.push("--palette=xlibc".into());
+        return Ok(());
+    }
+    let Some(palette) = palettes
+        .iter()
+        .find(|p| get_str(p, "name").as_deref() == Some(name))
+    else {
+        warn!(
+            "{}: palette {} isn't built in or defined, so one is generated",
+            path.display(),
+            name
+        );
+        return Ok(());
+    };
+    if let Some(max) = get_str(palette, "max-entries") {
+        args.push(format!("--colors={}", max).into());
+    }
+    if let Some(entries) = palette.get("fixed-entries") {
+        let mut colors = items(entries)
+            .into_iter()
+            .map(|entry| {
+                fixed_color(entry).ok_or_else(|| {
+                    format!(
+                        "{}: fixed entries of palette {} need an index and color",
+                        path.display(),
+                        name
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        colors.sort();
+        if colors
+            .iter()
+            .enumerate()
+            .any(|(i, (index, _))| *index != i as u32)
+        {
+            warn!(
+                "{}: fixed entries of palette {} are reserved from index 0 in order",
+                path.display(),
+                name
+            );
+        }
+        let colors: Vec<String> = colors.into_iter().map(|(_, color)| color).collect();
+        if !colors.is_empty() {
+            args.push(format!("--reserve-colors={}", colors.join(",")).into());
+        }
+    }
+    Ok(())
+}
+
+/// Return the images listed by a value, relative to `base`.
+fn image_paths(base: &Path, value: &Value) -> Vec<PathBuf> {
+    items(value)
+        .into_iter()
+        .filter_map(scalar)
+        .map(|image| base.join(image))
+        .collect()
+}
+
+/// Return the arguments and images of a convert, without those of its outputs.
+fn convert_args(
+    path: &Path,
+    convert: &Value,
+    palettes: &[&Value],
+) -> Result<(Vec<OsString>, Vec<PathBuf>), String> {
+    let base = path.parent().unwrap_or(Path::new(""));
+    let mut args = Vec::new();
+    let mut images = Vec::new();
+    let Value::Mapping(entries) = convert else {
+        return Err(format!("{}: converts must be mappings", path.display()));
+    };
+    for (key, value) in entries {
+        let key = scalar(key).unwrap_or_default();
+        let text = scalar(value).unwrap_or_default();
+        let flag = matches!(text.as_str(), "true" | "yes");
+        match key.as_str() {
+            "name" => {}
+            "images" => images.extend(image_paths(base, value)),
+            "tilesets" => {
+                if let (Some(width), Some(height)) =
+                    (get_str(value, "tile-width"), get_str(value, "tile-height"))
+                {
+                    args.push(format!("--tile-size={}x{}", width, height).into());
+                }
+                if let Some(tilesets) = value.get("images") {
+                    images.extend(image_paths(base, tilesets));
+                }
+            }
+            "palette" => palette_args(path, &text, palettes, &mut args)?,
+            "transparent-color-index" => args.push(format!("--transparent-index={}", text).into()),
+            "compress" if text == "zx7" => {
+                warn!(
+                    "{}: zx7 isn't supported, so tiles are compressed with zx0",
+                    path.display()
+                );
+                args.push("--compression=zx0".into());
+            }
+            "compress" => args.push(format!("--compression={}", text).into()),
+            "bpp" => args.push(format!("--bit-depth={}", text).into()),
+            "rotate" if text == "0" => {}
+            "rotate" => args.push(format!("--rotate={}", text).into()),
+            "flip-x" if flag => args.push("--flip-h".into()),
+            "flip-y" if flag => args.push("--flip-v".into()),
+            "flip-x" | "flip-y" => {}
+            _ => warn!(
+                "{}: ignoring {}, which doesn't apply to appvars for HD Picture Viewer",
+                path.display(),
+                key
+            ),
+        }
+    }
+    if images.len() > 1 {
+        args.push("--shared-palette".into());
+    }
+    Ok((args, images))
+}
+
+/// Return the arguments of an output that lists a convert.
+fn output_args(path: &Path, output: &Value, args: &mut Vec<OsString>) {
+    let base = path.parent().unwrap_or(Path::new(""));
+    match get_str(output, "type").as_deref() {
+        Some("c") => args.push("--emit-c".into()),
+        Some("asm") => args.push("--emit-inc".into()),
+        Some("ice") => args.push("--emit-ice".into()),
+        Some("bin") => args.push("--format=raw".into()),
+        Some("appvar") => {}
+        other => warn!(
+            "{}: ignoring output of type {}",
+            path.display(),
+            other.unwrap_or("none")
+        ),
+    }
+    if let Some(directory) = get_str(output, "directory") {
+        let mut arg = OsString::from("--outdir=");
+        arg.push(base.join(directory));
+        args.push(arg);
+    }
+    if get_str(output, "archived").as_deref() == Some("false") {
+        args.push("--no-archive".into());
+    }
+}
+
+/// Return the arguments for each conversion in the project at `path`, given the arguments from
+/// the command line.
+///
+/// Images are named after their var prefix on the command line, so every image in the project
+/// gets a different one.
+pub fn conversions(path: &Path, args: Vec<OsString>) -> Result<Vec<Vec<OsString>>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+    let project: Value = serde_yaml::from_str(&text)
+        .map_err(|e| format!("Unable to parse {}: {}", path.display(), e))?;
+    let section = |name| project.get(name).map(items).unwrap_or_default();
+    let (palettes, outputs) = (section("palettes"), section("outputs"));
+
+    let mut used = HashSet::new();
+    let mut conversions = Vec::new();
+    for convert in section("converts") {
+        let name = get_str(convert, "name").unwrap_or_default();
+        let (mut options, images) = convert_args(path, convert, &palettes)?;
+        if images.is_empty() {
+            warn!("{}: convert {} has no images", path.display(), name);
+            continue;
+        }
+        for output in &outputs {
+            let listed = output.get("converts").map(items).unwrap_or_default();
+            if listed
+                .iter()
+                .any(|c| scalar(c).as_deref() == Some(name.as_str()))
+            {
+                output_args(path, output, &mut options);
+            }
+        }
+
+        let mut conversion = vec![args[0].clone()];
+        conversion.extend(options);
+        conversion.extend(args[1..].iter().cloned());
+        for image in images {
+            let prefix = super::derive_var_prefix(&image, &used)?;
+            used.insert(prefix.clone());
+            conversion.push(image.into());
+            conversion.push(prefix.into());
+        }
+        conversions.push(conversion);
+    }
+    if conversions.is_empty() {
+        return Err(format!("{} has no images to convert", path.display()));
+    }
+    Ok(conversions)
+}
+
+/// Each convert becomes a conversion with options from its palette and outputs, ahead of the
+/// command line's.
+#[test]
+fn converts_become_conversions() {
+    let dir = std::env::temp_dir().join(format!("hdpc-project-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("convimg.yaml");
+    std::fs::write(
+        &path,
+        r##"# A convimg project
+palettes:
+  - name: global_palette
+    max-entries: 128
+    fixed-entries:
+      - color: {index: 0, r: 255, g: 0, b: 128}   # transparent
+      - color: {index: 1, hex: "#ffffff"}
+    images: automatic
+
+converts:
+  - name: sprites
+    palette: global_palette
+    transparent-color-index: 0
+    style: rlet
+    images:
+      - oiram.png
+      - mushroom.png
+  - name: tileset
+    palette: xlibc
+    compress: zx7
+    tilesets:
+      tile-width: 16
+      tile-height: 16
+      images: [tiles.png]
+
+outputs:
+  - type: c
+    include-file: gfx.h
+    palettes:
+      - global_palette
+    converts:
+      - sprites
+  - type: appvar
+    name: demogfx
+    directory: out
+    converts: [sprites, tileset]
+  - type: bin
+    converts:
+      - tileset
+"##,
+    )
+    .unwrap();
+
+    let args = vec!["cli".into(), "--dither=none".into()];
+    let conversions = conversions(&path, args).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let outdir = format!("--outdir={}", dir.join("out").display());
+    let image = |name: &str| dir.join(name).into_os_string();
+    assert_eq!(
+        conversions,
+        [
+            vec![
+                "cli".into(),
+                "--colors=128".into(),
+                "--reserve-colors=#ff0080,#ffffff".into(),
+                "--transparent-index=0".into(),
+                "--shared-palette".into(),
+                "--emit-c".into(),
+                outdir.clone().into(),
+                "--dither=none".into(),
+                image("oiram.png"),
+                "OI".into(),
+                image("mushroom.png"),
+                "MU".into(),
+            ],
+            vec![
+                OsString::from("cli"),
+                "--palette=xlibc".into(),
+                "--compression=zx0".into(),
+                "--tile-size=16x16".into(),
+                outdir.into(),
+                "--format=raw".into(),
+                "--dither=none".into(),
+                image("tiles.png"),
+                "TI".into(),
+            ],
+        ]
+    );
+}
+
+/// Projects that aren't YAML are reported along with where parsing stopped.
+#[test]
+fn invalid_projects_are_reported() {
+    let path = std::env::temp_dir().join(format!("hdpc-invalid-{}.yaml", std::process::id()));
+    std::fs::write(&path, "converts:\n  - name: [sprites\n").unwrap();
+    let error = conversions(&path, vec!["cli".into()]).unwrap_err();
+    std::fs::remove_file(&path).unwrap();
+    assert!(error.starts_with("Unable to parse"), "{}", error);
+    assert!(error.contains("line 2"), "{}", error);
+}
@@ -0,0 +1,406 @@
+//! The `serve` subcommand, which converts images uploaded over HTTP
+//!
+//! Images are converted by `POST /convert` requests whose body is the image file, with options
+//! in the query string, and the response is the group file. For example:
+//!
+//! ```text
+//! curl --data-binary @photo.png -o photo.8xg 'http://localhost:8080/convert?name=photo.png'
+//! ```
+//!
+//! Only as much of HTTP/1.1 as that needs is understood, and each connection carries a single
+//! request. Requests are handled by a fixed number of threads, and the headers and body of each
+//! are limited in size, but put a proper web server in front of it to serve it beyond a trusted
+//! network.
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use clap::{Arg, ArgMatches, Command, ValueEnum};
+use log::{info, warn};
+
+use hdpictureconverter::{Compression, Image, QuantizeOptions, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use super::{derive_var_prefix, var_prefix_str, CompressionChoice, DitherChoice, QuantizerChoice};
+
+/// How long a client may take to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest request line or header line accepted, in bytes.
+const MAX_LINE_LEN: usize = 8 * 1024;
+/// Most bytes accepted in the request line and headers together.
+const MAX_HEADERS_LEN: usize = 32 * 1024;
+
+pub fn command() -> Command {
+    Command::new("serve")
+        .about("Convert images uploaded to a small HTTP server")
+        .long_about(
+            "Convert images uploaded to a small HTTP server. POST an image file to /convert \
+             and the response is a group file of its appvars. Options are given in the query \
+             string: name, prefix, quantizer, dither, colors and compression, each taking the \
+             same values as the option of the same name. Images are fit to the screen like \
+             the CLI does, unless fit-screen is 0, false or no.",
+        )
+        .args([
+            Arg::new("listen")
+                .long("listen")
+                .value_name("address:port")
+                .default_value("127.0.0.1:8080")
+                .value_parser(clap::value_parser!(SocketAddr))
+                .help("Address to listen for connections on"),
+            Arg::new("max_size")
+                .long("max-size")
+                .value_name("bytes")
+                .default_value("16777216")
+                .value_parser(clap::value_parser!(usize))
+                .help("Reject images larger than this"),
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .value_name("N")
+                .default_value("4")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("Handle this many requests at once"),
+        ])
+}
+
+/// A response to send to the client.
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    headers: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn error(status: &'static str, message: impl std::fmt::Display) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            headers: Vec::new(),
+            body: format!("{}\n", message).into_bytes(),
+        }
+    }
+
+    fn write(&self, stream: &mut impl Write) -> std::io::Result<()> {
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.status,
+            self.content_type,
+            self.body.len()
+        )?;
+        for header in &self.headers {
+            write!(stream, "{}\r\n", header)?;
+        }
+        stream.write_all(b"\r\n")?;
+        stream.write_all(&self.body)
+    }
+}
+
+/// Decode `%XX` escapes and `+` in part of a query string.
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        let hex = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (b, hex) {
+            (b'%', Some(decoded)) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+                continue;
+            }
+            (b'+', _) => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+        rest = tail;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Convert an uploaded image with the options in a query string.
+fn convert(query: &str, data: Vec<u8>) -> Result<(String, Vec<u8>), String> {
+    let mut name = "image".to_string();
+    let mut prefix = None;
+    let mut options = QuantizeOptions::default();
+    let mut compression = CompressionChoice(Compression::Zx0);
+    let mut fit_screen = true;
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode(value);
+        let invalid = |e: String| format!("invalid {}: {}", key, e);
+        match key {
+            "name" => name = value,
+            "prefix" => prefix = Some(var_prefix_str(&value).map_err(invalid)?),
+            "quantizer" => options.quantizer = QuantizerChoice::from_str(&value, true)?.0,
+            "dither" => options.dither = DitherChoice::from_str(&value, true)?.0,
+            "colors" => match value.parse::<u32>() {
+                Ok(colors @ 2..=256) => options.max_colors = colors,
+                _ => return Err(invalid(format!("{:?} is not from 2 to 256", value))),
+            },
+            "compression" => compression = CompressionChoice::from_str(&value, true)?,
+            "fit-screen" => match value.as_str() {
+                "" | "1" | "true" | "yes" => fit_screen = true,
+                "0" | "false" | "no" => fit_screen = false,
+                _ => return Err(invalid(format!("{:?} is not a yes or no", value))),
+            },
+            _ => return Err(format!("{} is not an option", key)),
+        }
+    }
+    let prefix = match prefix {
+        Some(prefix) => prefix,
+        None => derive_var_prefix(Path::new(&name), &HashSet::default())?,
+    };
+
+    let mut image = Image::new(Cursor::new(data), &name, &prefix).map_err(|e| e.to_string())?;
+    if fit_screen {
+        image.downscale_to_fit(SCREEN_WIDTH, SCREEN_HEIGHT);
+    }
+    let mut image = image.quantize_with(&options).map_err(|e| e.to_string())?;
+    image.set_compression(compression.0);
+    let group = image
+        .write_group(Cursor::new(Vec::new()))
+        .map_err(|e| e.to_string())?;
+    let file_name = Path::new(&name).with_extension("8xg");
+    let file_name = file_name.file_name().unwrap_or_default().to_string_lossy();
+    Ok((file_name.into_owned(), group.into_inner()))
+}
+
+/// Read a line of the request's headers, taking its length from `remaining`, or return `None`
+/// if it's too long.
+fn read_line(reader: &mut impl BufRead, remaining: &mut usize) -> std::io::Result<Option<String>> {
+    let limit = MAX_LINE_LEN.min(*remaining);
+    let mut line = Vec::new();
+    reader
+        .by_ref()
+        .take(limit as u64)
+        .read_until(b'\n', &mut line)?;
+    if line.len() == limit && !line.ends_with(b"\n") {
+        return Ok(None);
+    }
+    *remaining -= line.len();
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Read a request and return the response to it.
+fn respond(stream: impl Read, max_size: usize) -> std::io::Result<Response> {
+    let too_large = || {
+        Response::error(
+            "431 Request Header Fields Too Large",
+            "request headers are too large",
+        )
+    };
+    let mut reader = BufReader::new(stream);
+    let mut remaining = MAX_HEADERS_LEN;
+    let Some(request_line) = read_line(&mut reader, &mut remaining)? else {
+        return Ok(too_large());
+    };
+    let mut content_length = None;
+    loop {
+        let Some(header) = read_line(&mut reader, &mut remaining)? else {
+            return Ok(too_large());
+        };
+        if header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    info!("{} {}", method, target);
+    match (method, path) {
+        ("POST", "/convert") => {}
+        (_, "/convert") => return Ok(Response::error("405 Method Not Allowed", "use POST")),
+        ("GET", "/") => {
+            return Ok(Response::error(
+                "200 OK",
+                "POST an image to /convert to convert it to a group file",
+            ))
+        }
+        _ => return Ok(Response::error("404 Not Found", "not found")),
+    }
+
+    let Some(length) = content_length else {
+        return Ok(Response::error(
+            "411 Length Required",
+            "Content-Length is required",
+        ));
+    };
+    if length > max_size {
+        return Ok(Response::error(
+            "413 Content Too Large",
+            format!("images may be at most {} bytes", max_size),
+        ));
+    }
+    // The body is read as it arrives rather than into a buffer of the length the client claims
+    let mut data = Vec::new();
+    reader.take(length as u64).read_to_end(&mut data)?;
+    if data.len() != length {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+
+    Ok(match convert(query, data) {
+        Ok((file_name, group)) => Response {
+            status: "200 OK",
+            content_type: "application/octet-stream",
+            headers: vec![format!(
+                "Content-Disposition: attachment; filename=\"{}\"",
+                file_name.replace(['"', '\\'], "_")
+            )],
+            body: group,
+        },
+        Err(e) => Response::error("400 Bad Request", e),
+    })
+}
+
+pub fn run(m: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let address = m.get_one::<SocketAddr>("listen").unwrap();
+    let max_size = *m.get_one::<usize>("max_size").unwrap();
+    let jobs = *m.get_one::<u64>("jobs").unwrap() as usize;
+    let listener = TcpListener::bind(address)
+        .map_err(|e| format!("Unable to listen on {}: {}", address, e))?;
+    info!("Listening on http://{}", listener.local_addr()?);
+
+    // Connections wait in the listener's backlog while every worker is busy
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(0);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..jobs {
+        let receiver = Arc::clone(&receiver);
+        std::thread::spawn(move || loop {
+            let Ok(mut stream) = receiver.lock().unwrap().recv() else {
+                return;
+            };
+            let result = stream
+                .set_read_timeout(Some(READ_TIMEOUT))
+                .and_then(|_| respond(&stream, max_size))
+                .and_then(|response| response.write(&mut stream));
+            if let Err(e) = result {
+                warn!("Unable to respond to request: {}", e);
+            }
+        });
+    }
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => sender.send(stream)?,
+            Err(e) => warn!("Unable to accept connection: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// Escapes and `+` are decoded, and bad escapes are kept as they are.
+#[test]
+fn percent_decode_unescapes() {
+    assert_eq!(percent_decode("a+b%20c%2Fd"), "a b c/d");
+    assert_eq!(percent_decode("100%"), "100%");
+    assert_eq!(percent_decode("%zz%4"), "%zz%4");
+    assert_eq!(percent_decode("%C3%A9"), "\u{e9}");
+}
+
+/// Options in the query string are applied, and bad ones are rejected.
+#[test]
+fn query_options_are_checked() {
+    let mut png = Cursor::new(Vec::new());
+    image::RgbaImage::from_pixel(20, 10, image::Rgba([1, 2, 3, 255]))
+        .write_to(&mut png, image::ImageFormat::Png)
+        .unwrap();
+    let png = png.into_inner();
+
+    let (file_name, group) = convert(
+        "name=my+photo.png&prefix=XY&colors=16&dither=none&compression=rle",
+        png.clone(),
+    )
+    .unwrap();
+    assert_eq!(file_name, "my photo.8xg");
+    let contents = hdpictureconverter::group::read(&group).unwrap();
+    assert!(contents.vars.iter().any(|var| var.name == "HPXY0000"));
+
+    // Images larger than the screen are fit to it unless asked not to be, when they're too large
+    // for a group file
+    let mut photo = Cursor::new(Vec::new());
+    image::RgbaImage::from_fn(1000, 700, |x, y| image::Rgba([x as u8, y as u8, 128, 255]))
+        .write_to(&mut photo, image::ImageFormat::Png)
+        .unwrap();
+    let photo = photo.into_inner();
+    assert!(convert("name=big.png", photo.clone()).is_ok());
+    let e = convert("name=big.png&fit-screen=no&compression=none", photo).unwrap_err();
+    assert!(e.contains("too large for a group file"), "{}", e);
+
+    for query in [
+        "fit-screen=maybe",
+        "colors=1",
+        "colors=lots",
+        "prefix=x",
+        "dither=sometimes",
+        "compression=lz4",
+        "size=big",
+    ] {
+        assert!(convert(query, png.clone()).is_err(), "{}", query);
+    }
+}
+
+/// Requests get the response their method, path, headers and body call for.
+#[test]
+fn requests_are_answered() {
+    let status = |request: &[u8]| respond(request, 100).unwrap().status;
+    assert_eq!(status(b"GET / HTTP/1.1\r\n\r\n"), "200 OK");
+    assert_eq!(status(b"GET /other HTTP/1.1\r\n\r\n"), "404 Not Found");
+    assert_eq!(
+        status(b"GET /convert HTTP/1.1\r\n\r\n"),
+        "405 Method Not Allowed"
+    );
+    assert_eq!(
+        status(b"POST /convert HTTP/1.1\r\n\r\n"),
+        "411 Length Required"
+    );
+    assert_eq!(
+        status(b"POST /convert HTTP/1.1\r\nContent-Length: 101\r\n\r\n"),
+        "413 Content Too Large"
+    );
+    assert_eq!(
+        status(b"POST /convert HTTP/1.1\r\ncontent-length: 3\r\n\r\nabc"),
+        "400 Bad Request"
+    );
+
+    // A body shorter than claimed is an error rather than a truncated image
+    assert!(respond(
+        &b"POST /convert HTTP/1.1\r\nContent-Length: 9\r\n\r\nabc"[..],
+        100
+    )
+    .is_err());
+
+    // Overlong lines and too many headers are refused without reading them all
+    let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LEN));
+    assert_eq!(
+        status(long_line.as_bytes()),
+        "431 Request Header Fields Too Large"
+    );
+    let many_headers = format!(
+        "GET / HTTP/1.1\r\n{}\r\n",
+        "X-Filler: aaaaaaaaaaaaaaaa\r\n".repeat(MAX_HEADERS_LEN / 20)
+    );
+    assert_eq!(
+        status(many_headers.as_bytes()),
+        "431 Request Header Fields Too Large"
+    );
+
+    let mut written = Vec::new();
+    respond(&b"GET / HTTP/1.1\r\n\r\n"[..], 100)
+        .unwrap()
+        .write(&mut written)
+        .unwrap();
+    let written = String::from_utf8(written).unwrap();
+    assert!(written.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(written.contains("\r\nConnection: close\r\n"));
+}
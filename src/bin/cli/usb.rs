@@ -0,0 +1,490 @@
+//! Sending converted images to a calculator connected over USB
+//!
+//! The TI-84 Plus CE speaks TI's "DUSB" protocol over a pair of bulk endpoints. Raw packets of a
+//! few hundred bytes carry virtual packets of any length, each raw packet being acknowledged
+//! before the next is sent, and a variable is sent as a request to send followed by its
+//! contents, each of which the calculator acknowledges with a virtual packet of its own. This is
+//! the exchange libticalcs uses to send variables to the CE.
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{debug, info};
+use rusb::{Context, DeviceHandle, Direction, TransferType, UsbContext};
+
+use hdpictureconverter::group::{self, Var};
+
+/// USB vendor ID of Texas Instruments.
+const TI_VENDOR_ID: u16 = 0x0451;
+/// USB product ID of the TI-84 Plus CE and related calculators.
+const CE_PRODUCT_ID: u16 = 0xe008;
+/// How long to wait for each USB transfer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Raw packet types.
+const RAW_BUF_SIZE_REQ: u8 = 1;
+const RAW_BUF_SIZE_ALLOC: u8 = 2;
+const RAW_VIRT_DATA: u8 = 3;
+const RAW_VIRT_DATA_LAST: u8 = 4;
+const RAW_VIRT_DATA_ACK: u8 = 5;
+
+/// Virtual packet types.
+const VIRT_PING: u16 = 0x0001;
+const VIRT_RTS: u16 = 0x000b;
+const VIRT_VAR_CONTENTS: u16 = 0x000d;
+const VIRT_MODE_SET: u16 = 0x0012;
+const VIRT_DATA_ACK: u16 = 0xaa00;
+const VIRT_DELAY_ACK: u16 = 0xbb00;
+const VIRT_EOT: u16 = 0xdd00;
+const VIRT_ERROR: u16 = 0xee00;
+
+/// Variable attribute IDs.
+const ATTR_VAR_TYPE: u16 = 0x0002;
+const ATTR_ARCHIVED: u16 = 0x0003;
+const ATTR_VAR_VERSION: u16 = 0x0008;
+
+/// Length of a raw packet's header: its length and type.
+const RAW_HEADER_LEN: usize = 5;
+/// Length of a virtual packet's header: its length and type.
+const VIRT_HEADER_LEN: usize = 6;
+/// Largest raw packet payload to ask for, and to accept.
+const MAX_RAW_LEN: usize = 1024;
+/// The mode the calculator is put in to receive variables.
+const MODE_NORMAL: [u16; 5] = [3, 1, 0, 0, 0x07d0];
+
+/// A connection carrying bytes to and from the calculator.
+trait Link {
+    fn write(&mut self, data: &[u8]) -> Result<(), String>;
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), String>;
+}
+
+/// A calculator connected over USB.
+struct Usb {
+    handle: DeviceHandle<Context>,
+    endpoint_in: u8,
+    endpoint_out: u8,
+    /// Bytes read from the calculator that haven't been used yet.
+    received: VecDeque<u8>,
+}
+
+impl Usb {
+    /// Open the first connected calculator.
+    fn open() -> Result<Self, String> {
+        let usb_error = |e: rusb::Error| format!("Unable to open the calculator: {}", e);
+        // The global context panics if libusb can't start, such as without access to USB devices
+        let context = Context::new().map_err(|e| format!("Unable to use USB: {}", e))?;
+        let handle = context
+            .open_device_with_vid_pid(TI_VENDOR_ID, CE_PRODUCT_ID)
+            .ok_or("no TI-84 Plus CE is connected over USB")?;
+        let config = handle
+            .device()
+            .active_config_descriptor()
+            .map_err(usb_error)?;
+        let interface = config
+            .interfaces()
+            .next()
+            .and_then(|interface| interface.descriptors().next())
+            .ok_or("the calculator has no USB interface")?;
+        let endpoint = |direction| {
+            interface
+                .endpoint_descriptors()
+                .find(|e| e.direction() == direction && e.transfer_type() == TransferType::Bulk)
+                .map(|e| e.address())
+                .ok_or("the calculator has no bulk USB endpoints")
+        };
+        let (endpoint_in, endpoint_out) = (endpoint(Direction::In)?, endpoint(Direction::Out)?);
+
+        // Detaching kernel drivers isn't supported everywhere, and they're rarely attached
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle
+            .claim_interface(interface.interface_number())
+            .map_err(usb_error)?;
+        Ok(Usb {
+            handle,
+            endpoint_in,
+            endpoint_out,
+            received: VecDeque::new(),
+        })
+    }
+}
+
+impl Link for Usb {
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        let written = self
+            .handle
+            .write_bulk(self.endpoint_out, data, TIMEOUT)
+            .map_err(|e| format!("Unable to send to the calculator: {}", e))?;
+        if written != data.len() {
+            return Err("the calculator didn't accept a whole packet".into());
+        }
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        while self.received.len() < buf.len() {
+            let mut transfer = [0; 1024];
+            let len = self
+                .handle
+                .read_bulk(self.endpoint_in, &mut transfer, TIMEOUT)
+                .map_err(|e| format!("Unable to receive from the calculator: {}", e))?;
+            self.received.extend(&transfer[..len]);
+        }
+        let len = buf.len();
+        for (b, received) in buf.iter_mut().zip(self.received.drain(..len)) {
+            *b = received;
+        }
+        Ok(())
+    }
+}
+
+/// A DUSB session over some link.
+struct Dusb<L> {
+    link: L,
+    /// The largest raw packet payload the calculator accepts.
+    max_raw_len: usize,
+}
+
+impl<L: Link> Dusb<L> {
+    fn new(link: L) -> Self {
+        Dusb {
+            link,
+            max_raw_len: MAX_RAW_LEN,
+        }
+    }
+
+    fn send_raw(&mut self, ty: u8, data: &[u8]) -> Result<(), String> {
+        let mut packet = Vec::with_capacity(RAW_HEADER_LEN + data.len());
+        packet.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        packet.push(ty);
+        packet.extend_from_slice(data);
+        self.link.write(&packet)
+    }
+
+    fn recv_raw(&mut self) -> Result<(u8, Vec<u8>), String> {
+        let mut header = [0; RAW_HEADER_LEN];
+        self.link.read_exact(&mut header)?;
+        let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        if len > MAX_RAW_LEN {
+            return Err(format!("the calculator sent a {} byte packet", len));
+        }
+        let mut data = vec![0; len];
+        self.link.read_exact(&mut data)?;
+        Ok((header[4], data))
+    }
+
+    /// Agree on the largest raw packet with the calculator.
+    fn negotiate(&mut self) -> Result<(), String> {
+        self.send_raw(RAW_BUF_SIZE_REQ, &(MAX_RAW_LEN as u32).to_be_bytes())?;
+        match self.recv_raw()? {
+            (RAW_BUF_SIZE_ALLOC, size) if size.len() == 4 => {
+                let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+                self.max_raw_len = size.clamp(VIRT_HEADER_LEN + 1, MAX_RAW_LEN);
+                debug!("Calculator accepts {} byte packets", self.max_raw_len);
+                Ok(())
+            }
+            (ty, _) => Err(format!(
+                "the calculator answered a buffer size request with a type {} packet",
+                ty
+            )),
+        }
+    }
+
+    /// Wait for the calculator to acknowledge a raw packet, answering it if it asks to change
+    /// the buffer size first.
+    fn recv_ack(&mut self) -> Result<(), String> {
+        let (mut ty, mut data) = self.recv_raw()?;
+        if ty == RAW_BUF_SIZE_REQ && data.len() == 4 {
+            let size = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            self.send_raw(RAW_BUF_SIZE_ALLOC, &data)?;
+            self.max_raw_len = size.clamp(VIRT_HEADER_LEN + 1, MAX_RAW_LEN);
+            (ty, data) = self.recv_raw()?;
+        }
+        if ty != RAW_VIRT_DATA_ACK || data.first() != Some(&0xe0) {
+            return Err(format!(
+                "the calculator sent a type {} packet instead of an acknowledgement",
+                ty
+            ));
+        }
+        Ok(())
+    }
+
+    /// Send a virtual packet, split into as many raw packets as it takes.
+    fn send_virtual(&mut self, ty: u16, data: &[u8]) -> Result<(), String> {
+        let mut packet = Vec::with_capacity(VIRT_HEADER_LEN + data.len());
+        packet.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        packet.extend_from_slice(&ty.to_be_bytes());
+        packet.extend_from_slice(data);
+
+        let mut chunks = packet.chunks(self.max_raw_len).peekable();
+        while let Some(chunk) = chunks.next() {
+            let raw_type = if chunks.peek().is_some() {
+                RAW_VIRT_DATA
+            } else {
+                RAW_VIRT_DATA_LAST
+            };
+            self.send_raw(raw_type, chunk)?;
+            self.recv_ack()?;
+        }
+        Ok(())
+    }
+
+    /// Receive a virtual packet, acknowledging each raw packet of it.
+    fn recv_virtual(&mut self) -> Result<(u16, Vec<u8>), String> {
+        let mut packet = Vec::new();
+        loop {
+            let (ty, data) = self.recv_raw()?;
+            if ty != RAW_VIRT_DATA && ty != RAW_VIRT_DATA_LAST {
+                return Err(format!(
+                    "the calculator sent a type {} packet instead of data",
+                    ty
+                ));
+            }
+            packet.extend_from_slice(&data);
+            self.send_raw(RAW_VIRT_DATA_ACK, &[0xe0, 0x00])?;
+            if ty == RAW_VIRT_DATA_LAST {
+                break;
+            }
+        }
+        if packet.len() < VIRT_HEADER_LEN {
+            return Err("the calculator sent a truncated packet".into());
+        }
+        let len = u32::from_be_bytes([packet[0], packet[1], packet[2], packet[3]]) as usize;
+        let ty = u16::from_be_bytes([packet[4], packet[5]]);
+        let data = packet.split_off(VIRT_HEADER_LEN);
+        if data.len() != len {
+            return Err(format!(
+                "the calculator's packet has {} bytes but claims {}",
+                data.len(),
+                len
+            ));
+        }
+        Ok((ty, data))
+    }
+
+    /// Wait for a virtual packet of type `expected`, while the calculator asks for more time.
+    fn recv_expected(&mut self, expected: u16, what: &str) -> Result<(), String> {
+        loop {
+            match self.recv_virtual()? {
+                (ty, _) if ty == expected => return Ok(()),
+                (VIRT_DELAY_ACK, _) => continue,
+                (VIRT_ERROR, code) if code.len() >= 2 => {
+                    return Err(format!(
+                        "the calculator refused {} with error {:#06x}",
+                        what,
+                        u16::from_be_bytes([code[0], code[1]])
+                    ))
+                }
+                (ty, _) => {
+                    return Err(format!(
+                        "the calculator answered {} with a type {:#06x} packet",
+                        what, ty
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Prepare the calculator to receive variables.
+    fn set_mode(&mut self) -> Result<(), String> {
+        self.negotiate()?;
+        let mode: Vec<u8> = MODE_NORMAL.iter().flat_map(|x| x.to_be_bytes()).collect();
+        self.send_virtual(VIRT_PING, &mode)?;
+        self.recv_expected(VIRT_MODE_SET, "the connection")
+    }
+
+    fn send_var(&mut self, var: &Var) -> Result<(), String> {
+        let data = var.stored_data();
+        let mut rts = Vec::new();
+        rts.extend_from_slice(&(var.name.len() as u16).to_be_bytes());
+        rts.extend_from_slice(var.name.as_bytes());
+        rts.push(0);
+        rts.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        rts.push(1);
+        let attributes: [(u16, &[u8]); 3] = [
+            (ATTR_VAR_TYPE, &[0xf0, 0x07, 0x00, var.ty as u8]),
+            (ATTR_ARCHIVED, &[var.archived as u8]),
+            (ATTR_VAR_VERSION, &[0; 4]),
+        ];
+        rts.extend_from_slice(&(attributes.len() as u16).to_be_bytes());
+        for (id, value) in attributes {
+            rts.extend_from_slice(&id.to_be_bytes());
+            rts.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            rts.extend_from_slice(value);
+        }
+        self.send_virtual(VIRT_RTS, &rts)?;
+        self.recv_expected(VIRT_DATA_ACK, &var.name)?;
+
+        let mut contents = Vec::with_capacity(data.len() + 1);
+        contents.push(1);
+        contents.extend_from_slice(&data);
+        self.send_virtual(VIRT_VAR_CONTENTS, &contents)?;
+        self.recv_expected(VIRT_DATA_ACK, &var.name)?;
+        self.send_virtual(VIRT_EOT, &[])
+    }
+}
+
+/// Transfer the variables in files to the connected calculator.
+pub fn send(files: &[PathBuf]) -> Result<(), String> {
+    let mut vars = Vec::new();
+    for path in files {
+        let file = std::fs::read(path).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        let contents =
+            group::read(&file).map_err(|e| format!("Unable to read {:?}: {}", path, e))?;
+        vars.extend(contents.vars);
+    }
+    if vars.is_empty() {
+        return Ok(());
+    }
+
+    let mut dusb = Dusb::new(Usb::open()?);
+    dusb.set_mode()?;
+    info!("Sending {} variables to the calculator", vars.len());
+    for var in &vars {
+        debug!("Sending {}", var.name);
+        dusb.send_var(var)
+            .map_err(|e| format!("Unable to send {} to the calculator: {}", var.name, e))?;
+    }
+    Ok(())
+}
+
+/// A link to a pretend calculator, which answers with scripted bytes.
+#[cfg(test)]
+struct Script {
+    replies: VecDeque<u8>,
+    sent: Vec<Vec<u8>>,
+}
+
+#[cfg(test)]
+impl Link for &mut Script {
+    fn write(&mut self, data: &[u8]) -> Result<(), String> {
+        self.sent.push(data.to_vec());
+        Ok(())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        if self.replies.len() < buf.len() {
+            return Err("the script ran out".into());
+        }
+        let len = buf.len();
+        for (b, reply) in buf.iter_mut().zip(self.replies.drain(..len)) {
+            *b = reply;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn raw(ty: u8, data: &[u8]) -> Vec<u8> {
+    [&(data.len() as u32).to_be_bytes()[..], &[ty], data].concat()
+}
+
+/// Virtual packets are split across raw packets as large as the calculator allows, only the last
+/// of which is marked as such, and every one waits for an acknowledgement.
+#[test]
+fn virtual_packets_are_split() {
+    let ack = raw(RAW_VIRT_DATA_ACK, &[0xe0, 0x00]);
+    let mut script = Script {
+        replies: [
+            raw(RAW_BUF_SIZE_ALLOC, &[0, 0, 0, 10]),
+            ack.clone(),
+            ack.clone(),
+            ack,
+        ]
+        .concat()
+        .into(),
+        sent: Vec::new(),
+    };
+    let mut dusb = Dusb::new(&mut script);
+    dusb.negotiate().unwrap();
+    assert_eq!(dusb.max_raw_len, 10);
+    dusb.send_virtual(0x1234, b"abcdefghij").unwrap();
+    dusb.send_virtual(0x1234, b"").unwrap();
+
+    assert_eq!(
+        script.sent,
+        [
+            raw(RAW_BUF_SIZE_REQ, &[0, 0, 4, 0]),
+            raw(RAW_VIRT_DATA, b"\0\0\0\x0a\x12\x34abcd"),
+            raw(RAW_VIRT_DATA_LAST, b"efghij"),
+            raw(RAW_VIRT_DATA_LAST, b"\0\0\0\0\x12\x34"),
+        ]
+    );
+}
+
+/// Sending a variable asks to send it, with its type and where it's stored, then sends its
+/// contents once the calculator acknowledges that.
+#[test]
+fn variables_are_requested_then_sent() {
+    use tifiles::VariableType;
+
+    let ack = raw(RAW_VIRT_DATA_ACK, &[0xe0, 0x00]);
+    let data_ack = raw(RAW_VIRT_DATA_LAST, &[0, 0, 0, 2, 0xaa, 0x00, 0x00, 0x01]);
+    let delay = raw(RAW_VIRT_DATA_LAST, &[0, 0, 0, 4, 0xbb, 0x00, 0, 0, 0x01, 0]);
+    let mut script = Script {
+        replies: [
+            ack.clone(),
+            delay,
+            data_ack.clone(),
+            ack.clone(),
+            data_ack,
+            ack.clone(),
+        ]
+        .concat()
+        .into(),
+        sent: Vec::new(),
+    };
+    let var = Var {
+        name: "HPAB0000".into(),
+        ty: VariableType::AppVar,
+        archived: true,
+        data: b"pal".to_vec(),
+    };
+    Dusb::new(&mut script).send_var(&var).unwrap();
+
+    let rts = [
+        &[0, 8][..],
+        b"HPAB0000\0",
+        &[0, 0, 0, 5, 1],
+        &[0, 3],
+        &[0, 2, 0, 4, 0xf0, 0x07, 0x00, 0x15],
+        &[0, 3, 0, 1, 1],
+        &[0, 8, 0, 4, 0, 0, 0, 0],
+    ]
+    .concat();
+    let virt = |ty: u16, data: &[u8]| {
+        let header = [&(data.len() as u32).to_be_bytes()[..], &ty.to_be_bytes()].concat();
+        raw(RAW_VIRT_DATA_LAST, &[&header, data].concat())
+    };
+    let our_ack = raw(RAW_VIRT_DATA_ACK, &[0xe0, 0x00]);
+    assert_eq!(
+        script.sent,
+        [
+            virt(VIRT_RTS, &rts),
+            our_ack.clone(),
+            our_ack.clone(),
+            virt(VIRT_VAR_CONTENTS, b"\x01\x03\0pal"),
+            our_ack,
+            virt(VIRT_EOT, b""),
+        ]
+    );
+}
+
+/// Errors the calculator reports are passed on with their code.
+#[test]
+fn calculator_errors_are_reported() {
+    let error = raw(RAW_VIRT_DATA_LAST, &[0, 0, 0, 2, 0xee, 0x00, 0x00, 0x0c]);
+    let mut script = Script {
+        replies: [raw(RAW_VIRT_DATA_ACK, &[0xe0, 0x00]), error]
+            .concat()
+            .into(),
+        sent: Vec::new(),
+    };
+    let var = Var {
+        name: "AB000000".into(),
+        ty: tifiles::VariableType::AppVar,
+        archived: false,
+        data: Vec::new(),
+    };
+    let e = Dusb::new(&mut script).send_var(&var).unwrap_err();
+    assert!(e.contains("error 0x000c"), "{}", e);
+}
@@ -0,0 +1,174 @@
+//! The `verify` subcommand, which checks how closely converted images match their sources
+use std::path::PathBuf;
+
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use rgb::RGBA8 as RGBA;
+
+use hdpictureconverter::{decode, NameTemplate, Quality, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use super::{decode_options, dimensions, load_frames, ScaleModeChoice};
+
+fn non_negative(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(x) if x >= 0. => Ok(x),
+        Ok(x) => Err(format!("{} is negative", x)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+pub fn command() -> Command {
+    Command::new("verify")
+        .about("Check that converted images still look like their source")
+        .long_about(
+            "Check that converted images still look like their source, by decoding them from \
+             their appvars and measuring PSNR and SSIM against the source image. Exits with an \
+             error if either is below its minimum, so conversions can be checked automatically. \
+             Give the same resizing and background options the image was converted with.",
+        )
+        .args([
+            Arg::new("source")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The image that was converted"),
+            Arg::new("files")
+                .value_name("file")
+                .num_args(1..)
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Variable or group files containing the converted image"),
+            Arg::new("image")
+                .long("image")
+                .value_name("name")
+                .help("Name of the converted image to check, if the files contain several"),
+            Arg::new("min_psnr")
+                .long("min-psnr")
+                .value_name("dB")
+                .default_value("25")
+                .value_parser(non_negative)
+                .help("Fail if PSNR is below this"),
+            Arg::new("min_ssim")
+                .long("min-ssim")
+                .value_name("0.0-1.0")
+                .default_value("0.5")
+                .value_parser(non_negative)
+                .help("Fail if SSIM is below this, which dithering lowers"),
+            #[cfg(any(feature = "pdf", feature = "svg"))]
+            Arg::new("density")
+                .long("density")
+                .value_name("dpi")
+                .value_parser(super::density)
+                .help("The SVG drawing or PDF page was rasterized at this density"),
+            #[cfg(feature = "pdf")]
+            Arg::new("page")
+                .long("page")
+                .value_name("N")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .help("The page of the PDF document that was converted"),
+            Arg::new("no_fit_screen")
+                .long("no-fit-screen")
+                .action(ArgAction::SetTrue)
+                .help("The image was converted at full resolution, not shrunk to fit the screen"),
+            Arg::new("scale_mode")
+                .long("scale-mode")
+                .value_parser(clap::value_parser!(ScaleModeChoice))
+                .help("The image was resized to the --scale-to size this way"),
+            Arg::new("scale_to")
+                .long("scale-to")
+                .value_name("WxH")
+                .default_value("320x240")
+                .value_parser(dimensions)
+                .help("Size the image was resized to with --scale-mode"),
+            Arg::new("background")
+                .short('b')
+                .long("background")
+                .value_name("#RRGGBB")
+                .default_value("#000000")
+                .value_parser(hdpictureconverter::palette::parse_hex_color)
+                .help("Color transparent pixels were blended over"),
+            Arg::new("name_template")
+                .long("name-template")
+                .value_name("template")
+                .value_parser(NameTemplate::parse)
+                .help("How tile appvars are named, if not the default"),
+        ])
+}
+
+pub fn run(m: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let source: &PathBuf = m.get_one("source").unwrap();
+    let files: Vec<&PathBuf> = m.get_many::<PathBuf>("files").unwrap().collect();
+    let background = *m.get_one::<RGBA>("background").unwrap();
+    let min_psnr = *m.get_one::<f64>("min_psnr").unwrap();
+    let min_ssim = *m.get_one::<f64>("min_ssim").unwrap();
+
+    let vars = super::decode::read_vars(&files)?;
+    let mut images = decode::images(&vars, m.get_one::<NameTemplate>("name_template"))?;
+    let mut names: Vec<String> = images
+        .iter()
+        .map(|image| image.name.trim_end_matches('_').to_string())
+        .collect();
+    names.sort();
+    names.dedup();
+    let name = match m.get_one::<String>("image") {
+        Some(name) if names.contains(name) => name.clone(),
+        Some(name) => return Err(format!("there is no image named {}", name).into()),
+        None if names.len() == 1 => names.remove(0),
+        None if names.is_empty() => return Err("no palette appvars were found".into()),
+        None => {
+            return Err(format!(
+                "there are {} images, so choose one with --image: {}",
+                names.len(),
+                names.join(", ")
+            )
+            .into())
+        }
+    };
+    images.retain(|image| image.name.trim_end_matches('_') == name);
+    images.sort_by_key(|image| image.frame);
+
+    let frames = load_frames(source, "AA", &decode_options(m), false)?;
+    if frames.len() != images.len() {
+        return Err(format!(
+            "{:?} has {} frames, but {} has {}",
+            source,
+            frames.len(),
+            name,
+            images.len()
+        )
+        .into());
+    }
+
+    let mut passed = true;
+    for (frame, decoded) in frames.into_iter().zip(&images) {
+        let mut image = frame.image;
+        if !m.get_flag("no_fit_screen") && !m.contains_id("scale_mode") {
+            image.downscale_to_fit(SCREEN_WIDTH, SCREEN_HEIGHT);
+        }
+        if let Some(mode) = m.get_one::<ScaleModeChoice>("scale_mode") {
+            let (width, height) = *m.get_one::<(u32, u32)>("scale_to").unwrap();
+            image.resize(width, height, mode.0);
+        }
+        let Quality { psnr, ssim } = image.compare(&decoded.image, background)?;
+        let pass = psnr >= min_psnr && ssim >= min_ssim;
+        passed &= pass;
+        let what = if images.len() > 1 {
+            format!("{} frame {}", name, decoded.frame)
+        } else {
+            name.clone()
+        };
+        println!(
+            "{}: PSNR {:.2} dB, SSIM {:.4}: {}",
+            what,
+            psnr,
+            ssim,
+            if pass { "pass" } else { "FAIL" }
+        );
+    }
+    if !passed {
+        return Err(format!(
+            "{} doesn't match {:?} closely enough (minimum PSNR {} dB, SSIM {})",
+            name, source, min_psnr, min_ssim
+        )
+        .into());
+    }
+    Ok(())
+}
@@ -0,0 +1,314 @@
+//! Color spaces that palettes are generated and matched in, and measures of color difference
+//!
+//! Distances between sRGB colors don't match how different they look: greens are spread too far
+//! apart and dark colors too close together. [Oklab] is a perceptually uniform alternative.
+//!
+//! Palette generation works on RGBA pixel data, so quantizers use Oklab by being given pixels
+//! whose first three components are Oklab coordinates scaled to bytes, and turning the colors
+//! they generate back to sRGB.
+//!
+//! Matching pixels to palette colors can instead use a [`ColorMetric`]. Every metric but
+//! CIEDE2000 is a straight-line distance between coordinates in some space.
+//!
+//! [Oklab]: https://bottosson.github.io/posts/oklab/
+use std::borrow::Cow;
+
+use rgb::RGBA8 as RGBA;
+
+/// Color spaces in which palettes are generated and pixels are matched to palette colors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ColorSpace {
+    /// sRGB components as they're stored.
+    #[default]
+    Rgb,
+    /// Oklab, in which distances between colors match how different they look.
+    Oklab,
+}
+
+/// Measures of the difference between colors, for matching pixels to palette colors.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColorMetric {
+    /// Distance between sRGB components.
+    Rgb,
+    /// Distance between sRGB components weighted 2:4:3 for red, green and blue, roughly following
+    /// how sensitive eyes are to each.
+    WeightedRgb,
+    /// Distance in CIELAB, also known as CIE76.
+    Lab,
+    /// The CIEDE2000 color difference, which corrects CIELAB's remaining nonuniformity.
+    ///
+    /// It's much slower to compute than the others and can't be searched with SIMD.
+    Ciede2000,
+}
+
+/// How pixels are matched to palette colors: by distance in a color space, or by a metric.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Matching {
+    Space(ColorSpace),
+    Metric(ColorMetric),
+}
+
+impl Matching {
+    /// Return the coordinates of an sRGB color, whose components are from 0 to 255, which are
+    /// compared to find the nearest palette color.
+    ///
+    /// Colors are nearest by distance between coordinates, except with CIEDE2000 whose
+    /// coordinates are CIELAB.
+    pub(crate) fn coordinates(self, rgb: [f32; 3]) -> [f32; 3] {
+        match self {
+            Matching::Space(space) => space.coordinates(rgb),
+            Matching::Metric(ColorMetric::Rgb) => rgb,
+            Matching::Metric(ColorMetric::WeightedRgb) => [
+                rgb[0] * std::f32::consts::SQRT_2,
+                rgb[1] * 2.,
+                rgb[2] * 3f32.sqrt(),
+            ],
+            Matching::Metric(ColorMetric::Lab | ColorMetric::Ciede2000) => cielab(rgb),
+        }
+    }
+}
+
+/// Scale of Oklab coordinates when they're stored in bytes or compared with each other.
+///
+/// Lightness spans 0 to 1 and the other axes about -0.32 to 0.32 for sRGB colors, so this keeps
+/// every axis within a byte when the others are offset by half of one.
+const OKLAB_SCALE: f32 = 255.;
+
+impl ColorSpace {
+    /// Return the coordinates of an sRGB color, whose components are from 0 to 255, in this
+    /// space.
+    ///
+    /// Coordinates in either space are scaled so distances between colors are comparable.
+    pub(crate) fn coordinates(self, rgb: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorSpace::Rgb => rgb,
+            ColorSpace::Oklab => oklab(rgb).map(|c| c * OKLAB_SCALE),
+        }
+    }
+
+    /// Convert RGBA pixel data into this space, keeping alpha.
+    pub(crate) fn encode(self, pixels: &[u8]) -> Cow<'_, [u8]> {
+        match self {
+            ColorSpace::Rgb => Cow::Borrowed(pixels),
+            ColorSpace::Oklab => Cow::Owned(
+                pixels
+                    .chunks_exact(4)
+                    .flat_map(|p| {
+                        let [l, a, b] = self.coordinates([p[0] as f32, p[1] as f32, p[2] as f32]);
+                        let byte = |c: f32| c.round().clamp(0., 255.) as u8;
+                        [byte(l), byte(a + 128.), byte(b + 128.), p[3]]
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Convert a color produced from [`encode`](ColorSpace::encode)d pixels back to sRGB.
+    ///
+    /// Colors outside the sRGB gamut are clamped to it.
+    pub(crate) fn decode(self, color: RGBA) -> RGBA {
+        match self {
+            ColorSpace::Rgb => color,
+            ColorSpace::Oklab => {
+                let lab = [
+                    color.r as f32 / OKLAB_SCALE,
+                    (color.g as f32 - 128.) / OKLAB_SCALE,
+                    (color.b as f32 - 128.) / OKLAB_SCALE,
+                ];
+                let [r, g, b] = srgb(lab).map(|c| c.round().clamp(0., 255.) as u8);
+                RGBA::new(r, g, b, color.a)
+            }
+        }
+    }
+}
+
+fn srgb_to_linear(c: f32) -> f32 {
+    let c = c / 255.;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f32) -> f32 {
+    let c = if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.max(0.).powf(1. / 2.4) - 0.055
+    };
+    c * 255.
+}
+
+/// Convert an sRGB color with components from 0 to 255 to Oklab.
+fn oklab(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(srgb_to_linear);
+    let l = (0.41222147 * r + 0.53633254 * g + 0.051445995 * b).cbrt();
+    let m = (0.2119035 * r + 0.6806995 * g + 0.10739696 * b).cbrt();
+    let s = (0.08830246 * r + 0.28171884 * g + 0.6299787 * b).cbrt();
+    [
+        0.21045426 * l + 0.7936178 * m - 0.004072047 * s,
+        1.9779985 * l - 2.4285922 * m + 0.4505937 * s,
+        0.025904037 * l + 0.78277177 * m - 0.80867577 * s,
+    ]
+}
+
+/// Convert an sRGB color with components from 0 to 255 to CIELAB, relative to the D65 white point.
+fn cielab(rgb: [f32; 3]) -> [f32; 3] {
+    let [r, g, b] = rgb.map(srgb_to_linear);
+    // Relative to the white point, which is where these are all 1
+    let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+    let y = 0.2126729 * r + 0.7151522 * g + 0.072175 * b;
+    let z = (0.0193339 * r + 0.119192 * g + 0.9503041 * b) / 1.08883;
+
+    const DELTA: f32 = 6. / 29.;
+    let f = |t: f32| {
+        if t > DELTA * DELTA * DELTA {
+            t.cbrt()
+        } else {
+            t / (3. * DELTA * DELTA) + 4. / 29.
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116. * fy - 16., 500. * (fx - fy), 200. * (fy - fz)]
+}
+
+/// Return the CIEDE2000 difference between two CIELAB colors.
+///
+/// This follows Sharma, Wu and Dalal's "The CIEDE2000 Color-Difference Formula: Implementation
+/// Notes, Supplementary Test Data, and Mathematical Observations".
+pub(crate) fn ciede2000(lab1: [f32; 3], lab2: [f32; 3]) -> f32 {
+    use std::f32::consts::PI;
+
+    let [l1, a1, b1] = lab1;
+    let [l2, a2, b2] = lab2;
+    let pow7 = |x: f32| x.powi(7);
+
+    // Stretch a* to correct the blue region
+    let c_mean = ((a1.hypot(b1) + a2.hypot(b2)) / 2.).max(0.);
+    let g = 0.5 * (1. - (pow7(c_mean) / (pow7(c_mean) + pow7(25.))).sqrt());
+    let (a1, a2) = (a1 * (1. + g), a2 * (1. + g));
+    let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+    let hue = |a: f32, b: f32| {
+        if a == 0. && b == 0. {
+            0.
+        } else {
+            b.atan2(a).rem_euclid(2. * PI)
+        }
+    };
+    let (h1, h2) = (hue(a1, b1), hue(a2, b2));
+
+    let delta_l = l2 - l1;
+    let delta_c = c2 - c1;
+    let delta_h = if c1 * c2 == 0. {
+        0.
+    } else if (h2 - h1).abs() <= PI {
+        h2 - h1
+    } else if h2 - h1 > PI {
+        h2 - h1 - 2. * PI
+    } else {
+        h2 - h1 + 2. * PI
+    };
+    let delta_h = 2. * (c1 * c2).sqrt() * (delta_h / 2.).sin();
+
+    let l_mean = (l1 + l2) / 2.;
+    let c_mean = (c1 + c2) / 2.;
+    let h_mean = if c1 * c2 == 0. {
+        h1 + h2
+    } else if (h1 - h2).abs() <= PI {
+        (h1 + h2) / 2.
+    } else if h1 + h2 < 2. * PI {
+        (h1 + h2 + 2. * PI) / 2.
+    } else {
+        (h1 + h2 - 2. * PI) / 2.
+    };
+
+    let t = 1. - 0.17 * (h_mean - PI / 6.).cos()
+        + 0.24 * (2. * h_mean).cos()
+        + 0.32 * (3. * h_mean + PI / 30.).cos()
+        - 0.2 * (4. * h_mean - 63f32.to_radians()).cos();
+    // Rotate to correct the blue region's hue dependence
+    let delta_theta = 30f32.to_radians() * (-((h_mean.to_degrees() - 275.) / 25.).powi(2)).exp();
+    let r_c = 2. * (pow7(c_mean) / (pow7(c_mean) + pow7(25.))).sqrt();
+    let r_t = -(2. * delta_theta).sin() * r_c;
+    let s_l = 1. + 0.015 * (l_mean - 50.).powi(2) / (20. + (l_mean - 50.).powi(2)).sqrt();
+    let s_c = 1. + 0.045 * c_mean;
+    let s_h = 1. + 0.015 * c_mean * t;
+
+    let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_h / s_h);
+    (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
+/// Convert an Oklab color to sRGB with components from 0 to 255, which may be out of range.
+fn srgb(lab: [f32; 3]) -> [f32; 3] {
+    let [lightness, a, b] = lab;
+    let l = (lightness + 0.39633778 * a + 0.21580376 * b).powi(3);
+    let m = (lightness - 0.105561346 * a - 0.06385417 * b).powi(3);
+    let s = (lightness - 0.08948418 * a - 1.2914855 * b).powi(3);
+    [
+        4.0767417 * l - 3.3077116 * m + 0.23096994 * s,
+        -1.268438 * l + 2.6097574 * m - 0.34131938 * s,
+        -0.0041960863 * l - 0.7034186 * m + 1.7076147 * s,
+    ]
+    .map(linear_to_srgb)
+}
+
+/// Colors survive conversion to Oklab bytes and back closely, and gray has no chroma.
+#[test]
+fn oklab_round_trips() {
+    for rgb in [
+        [0, 0, 0],
+        [255, 255, 255],
+        [255, 0, 0],
+        [0, 255, 0],
+        [93, 55, 180],
+    ] {
+        let encoded = ColorSpace::Oklab
+            .encode(&[rgb[0], rgb[1], rgb[2], 255])
+            .into_owned();
+        let decoded = ColorSpace::Oklab.decode(RGBA::new(encoded[0], encoded[1], encoded[2], 255));
+        for (a, b) in [decoded.r, decoded.g, decoded.b].into_iter().zip(rgb) {
+            assert!(a.abs_diff(b) <= 6, "{:?} became {:?}", rgb, decoded);
+        }
+    }
+
+    let gray = ColorSpace::Oklab.coordinates([128., 128., 128.]);
+    assert!(gray[1].abs() < 0.5 && gray[2].abs() < 0.5, "{:?}", gray);
+}
+
+/// CIEDE2000 matches the published test data.
+#[test]
+fn ciede2000_matches_test_data() {
+    // Pairs from Sharma, Wu and Dalal's supplementary data with their differences
+    let pairs = [
+        ([50., 2.6772, -79.7751], [50., 0., -82.7485], 2.0425),
+        ([50., 0., 0.], [50., -1., 2.], 2.3669),
+        ([50., 2.5, 0.], [73., 25., -18.], 27.1492),
+        ([50., 2.5, 0.], [50., 3.2592, 0.335], 1.),
+        (
+            [60.2574, -34.0099, 36.2677],
+            [60.4626, -34.1751, 39.4387],
+            1.2644,
+        ),
+        (
+            [22.7233, 20.0904, -46.694],
+            [23.0331, 14.973, -42.5619],
+            2.0373,
+        ),
+        ([2.0776, 0.0795, -1.135], [0.9033, -0.0636, -0.5514], 0.9082),
+    ];
+    for (lab1, lab2, expected) in pairs {
+        let difference = ciede2000(lab1, lab2);
+        assert!(
+            (difference - expected).abs() < 1e-3,
+            "{:?} and {:?} differ by {} rather than {}",
+            lab1,
+            lab2,
+            difference,
+            expected
+        );
+        assert!((ciede2000(lab2, lab1) - difference).abs() < 1e-4);
+    }
+
+    assert_eq!(cielab([255., 255., 255.]).map(f32::round), [100., 0., 0.]);
+}
@@ -0,0 +1,314 @@
+//! Compression of tile pixel data
+//!
+//! HD Picture Viewer reads zx0-compressed tiles with a `HDPICCV4` signature, so that's what is
+//! written by default. Tiles using any other compression get a `HDPICTV1` signature instead,
+//! followed by the image name and a byte identifying the compression, for viewers that support
+//! them.
+use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+use std::str::FromStr;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+/// Signature of tiles in the format HD Picture Viewer reads, which are always zx0-compressed.
+pub(crate) const VIEWER_SIGNATURE: &str = "HDPICCV4";
+/// Signature of tiles that record their compression in a header byte.
+pub(crate) const TAGGED_SIGNATURE: &str = "HDPICTV1";
+/// Most bytes of pixel data in a tile to compress with zx0.
+///
+/// The zx0 compressor finds the optimal encoding, which takes time growing with the square of
+/// the data's length, and memory too once long runs like padding follow other pixels: 16 KiB
+/// takes a couple of seconds at most, but 64 KiB can take minutes and gigabytes.
+pub const MAX_ZX0_LEN: usize = 16 * 1024;
+
+/// How tile pixel data is compressed.
+///
+/// Each is named by its variant in lowercase, "zx0", "deflate", "rle" or "none", which it can
+/// be [parsed](FromStr) from.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    /// Stored as-is.
+    None,
+    /// [zx0](https://github.com/einar-saukas/ZX0), which is fast to decompress on the calculator.
+    #[default]
+    Zx0,
+    /// Raw DEFLATE, preceded by the uncompressed size as a 16-bit little-endian integer.
+    Deflate,
+    /// Run-length encoding as pairs of a count from 1 to 255 and the byte to repeat that many
+    /// times, which suits images with large areas of flat color.
+    Rle,
+}
+
+impl Compression {
+    /// Return the byte which identifies this compression in tagged tile headers.
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Zx0 => 1,
+            Compression::Deflate => 2,
+            Compression::Rle => 3,
+        }
+    }
+
+    /// Return the compression identified by a byte in a tagged tile header, if it's known.
+    pub fn from_id(id: u8) -> Option<Self> {
+        [
+            Compression::None,
+            Compression::Zx0,
+            Compression::Deflate,
+            Compression::Rle,
+        ]
+        .into_iter()
+        .find(|compression| compression.id() == id)
+    }
+
+    /// Compress tile data, failing if it's too large for this compression to record its size.
+    pub(crate) fn compress(self, data: &[u8]) -> IoResult<Vec<u8>> {
+        Ok(match self {
+            Compression::None => data.to_vec(),
+            Compression::Zx0 => zx0::compress(data),
+            Compression::Deflate => {
+                let size = u16::try_from(data.len()).map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "tile data of {} bytes is too large to deflate, which allows at most {}",
+                            data.len(),
+                            u16::MAX
+                        ),
+                    )
+                })?;
+                let mut encoder =
+                    DeflateEncoder::new(size.to_le_bytes().to_vec(), flate2::Compression::best());
+                encoder
+                    .write_all(data)
+                    .expect("writing to memory can't fail");
+                encoder.finish().expect("writing to memory can't fail")
+            }
+            Compression::Rle => {
+                let mut out = Vec::new();
+                for run in data.chunk_by(|a, b| a == b) {
+                    for chunk in run.chunks(u8::MAX as usize) {
+                        out.extend_from_slice(&[chunk.len() as u8, chunk[0]]);
+                    }
+                }
+                out
+            }
+        })
+    }
+
+    /// Reverse [`compress`](Compression::compress), failing if the data is malformed.
+    pub(crate) fn decompress(self, data: &[u8]) -> IoResult<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zx0 => zx0_decompress(data).ok_or_else(|| corrupt("zx0")),
+            Compression::Deflate => {
+                let size = data.get(..2).ok_or_else(|| corrupt("DEFLATE"))?;
+                let size = u16::from_le_bytes([size[0], size[1]]) as usize;
+                let mut inflated = Vec::with_capacity(size);
+                DeflateDecoder::new(&data[2..]).read_to_end(&mut inflated)?;
+                if inflated.len() != size {
+                    return Err(corrupt("DEFLATE"));
+                }
+                Ok(inflated)
+            }
+            Compression::Rle => {
+                if !data.len().is_multiple_of(2) {
+                    return Err(corrupt("run-length encoded"));
+                }
+                Ok(data
+                    .chunks_exact(2)
+                    .flat_map(|run| std::iter::repeat_n(run[1], run[0] as usize))
+                    .collect())
+            }
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "zx0" => Ok(Compression::Zx0),
+            "deflate" => Ok(Compression::Deflate),
+            "rle" => Ok(Compression::Rle),
+            "none" => Ok(Compression::None),
+            _ => Err(format!("{:?} isn't a kind of compression", s)),
+        }
+    }
+}
+
+fn corrupt(compression: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{} compressed data is corrupt", compression),
+    )
+}
+
+/// Reads the bits and bytes of zx0 data, which are interleaved.
+struct Zx0Reader<'a> {
+    data: &'a [u8],
+    next: usize,
+    bits: u8,
+    mask: u8,
+    /// Whether the next bit is the low bit of the last byte read, which offsets share with the
+    /// following length.
+    backtrack: bool,
+}
+
+impl Zx0Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.data.get(self.next)?;
+        self.next += 1;
+        Some(byte)
+    }
+
+    fn bit(&mut self) -> Option<bool> {
+        if self.backtrack {
+            self.backtrack = false;
+            return Some(self.data[self.next - 1] & 1 != 0);
+        }
+        self.mask >>= 1;
+        if self.mask == 0 {
+            self.mask = 0x80;
+            self.bits = self.byte()?;
+        }
+        Some(self.bits & self.mask != 0)
+    }
+
+    /// Read an Elias gamma code whose value bits are interlaced with its length bits, optionally
+    /// inverting the value bits.
+    fn gamma(&mut self, inverted: bool) -> Option<usize> {
+        let mut value = 1usize;
+        while !self.bit()? {
+            value = (value << 1) | (self.bit()? ^ inverted) as usize;
+            if value > 1 << 16 {
+                return None;
+            }
+        }
+        Some(value)
+    }
+}
+
+/// Decompress data in the current zx0 format, as written by [`zx0::compress`], returning `None`
+/// if it's malformed.
+fn zx0_decompress(data: &[u8]) -> Option<Vec<u8>> {
+    let mut input = Zx0Reader {
+        data,
+        next: 0,
+        bits: 0,
+        mask: 0,
+        backtrack: false,
+    };
+    let mut out: Vec<u8> = Vec::new();
+    let mut offset = 1;
+    let copy = |out: &mut Vec<u8>, offset: usize, length: usize| {
+        let start = out.len().checked_sub(offset)?;
+        for i in start..start + length {
+            out.push(out[i]);
+        }
+        Some(())
+    };
+
+    // Each block of literals is followed by a match, and every match by literals or another
+    // match at a new offset.
+    loop {
+        let length = input.gamma(false)?;
+        for _ in 0..length {
+            let byte = input.byte()?;
+            out.push(byte);
+        }
+
+        let mut new_offset = input.bit()?;
+        if !new_offset {
+            let length = input.gamma(false)?;
+            copy(&mut out, offset, length)?;
+            new_offset = input.bit()?;
+        }
+        while new_offset {
+            let high = input.gamma(true)?;
+            if high == 256 {
+                return Some(out);
+            }
+            offset = (high * 128).checked_sub(input.byte()? as usize >> 1)?;
+            input.backtrack = true;
+            let length = input.gamma(false)? + 1;
+            copy(&mut out, offset, length)?;
+            new_offset = input.bit()?;
+        }
+    }
+}
+
+/// Every compression decompresses back to the original data.
+#[test]
+fn compression_round_trips() {
+    let mut data: Vec<u8> = (0..6402).map(|i| (i / 100) as u8).collect();
+    // Pseudo-random bytes exercise literals and long offsets
+    let mut state = 1u32;
+    data.extend((0..3000).map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 24) as u8
+    }));
+    data.extend_from_within(100..2000);
+
+    for compression in [
+        Compression::None,
+        Compression::Zx0,
+        Compression::Deflate,
+        Compression::Rle,
+    ] {
+        let compressed = compression.compress(&data).unwrap();
+        assert_eq!(
+            compression.decompress(&compressed).unwrap(),
+            data,
+            "{:?}",
+            compression
+        );
+        assert_eq!(Compression::from_id(compression.id()), Some(compression));
+    }
+}
+
+/// Deflated data records its size and inflates back to the original.
+#[test]
+fn deflate_round_trips() {
+    let data: Vec<u8> = (0..6402).map(|i| (i / 100) as u8).collect();
+    let compressed = Compression::Deflate.compress(&data).unwrap();
+    assert_eq!(u16::from_le_bytes([compressed[0], compressed[1]]), 6402);
+
+    let mut inflated = Vec::new();
+    DeflateDecoder::new(&compressed[2..])
+        .read_to_end(&mut inflated)
+        .unwrap();
+    assert_eq!(inflated, data);
+}
+
+/// Runs longer than a count can hold are split.
+#[test]
+fn rle_splits_long_runs() {
+    let data = [[7; 300].as_slice(), &[1, 2, 2]].concat();
+    assert_eq!(
+        Compression::Rle.compress(&data).unwrap(),
+        [255, 7, 45, 7, 1, 1, 2, 2]
+    );
+}
+
+/// Data whose size doesn't fit in the 16-bit prefix is refused rather than truncated.
+#[test]
+fn deflate_size_must_fit() {
+    let err = Compression::Deflate
+        .compress(&vec![0; u16::MAX as usize + 1])
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(Compression::Deflate
+        .compress(&vec![0; u16::MAX as usize])
+        .is_ok());
+}
+
+/// Compressions are parsed from their names, which are the ones the bindings accept.
+#[test]
+fn compression_names_are_parsed() {
+    assert_eq!("deflate".parse(), Ok(Compression::Deflate));
+    assert_eq!("none".parse(), Ok(Compression::None));
+    assert!("lz4".parse::<Compression>().is_err());
+}
@@ -0,0 +1,513 @@
+//! Reading converted images back from their appvars
+//!
+//! A palette appvar records the image's name, var prefix and number of tiles, so with a
+//! [`NameTemplate`] the tile appvars can be found and put back together. The result is the
+//! quantized image as the calculator would show it, including padding added to fill whole tiles
+//! and with pixels enlarged for images shown with the LCD in a scaled mode.
+//! Palettes don't record which index is transparent, so decoded images are fully opaque.
+//! Direct color images have no palette appvar, so only their tiles can be read.
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result as IoResult};
+
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+use rgb::RGBA8 as RGBA;
+
+use crate::group::Var;
+use crate::{
+    compress, depth, direct, screen, BitDepth, Compression, LcdScale, NameTemplate, GRGB1555,
+};
+
+const PALETTE_SIGNATURE: &[u8] = b"HDPALV10";
+/// Signature of palettes for tiles with more than one pixel per byte.
+const PACKED_PALETTE_SIGNATURE: &[u8] = b"HDPALV11";
+const ANIMATION_SIGNATURE: &[u8] = b"HDANIMV1";
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Split the common header of palette and animation appvars from the data following it,
+/// returning the image name, var prefix, and number of tile columns and rows.
+fn read_header<'a>(
+    data: &'a [u8],
+    signature: &[u8],
+    what: &str,
+) -> IoResult<(String, String, u32, u32, &'a [u8])> {
+    let header = data
+        .get(..24)
+        .filter(|header| header.starts_with(signature))
+        .ok_or_else(|| invalid(format!("Not a {} appvar: signature is missing", what)))?;
+    let text = |range: std::ops::Range<usize>| String::from_utf8_lossy(&header[range]).into_owned();
+    let last_index = |range: std::ops::Range<usize>| {
+        text(range)
+            .parse::<u32>()
+            .map_err(|_| invalid(format!("{} appvar has an invalid tile count", what)))
+    };
+    Ok((
+        text(8..16),
+        text(16..18),
+        last_index(18..21)? + 1,
+        last_index(21..24)? + 1,
+        &data[24..],
+    ))
+}
+
+/// The contents of a palette appvar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    /// Name of the image, padded with underscores to 8 characters.
+    pub name: String,
+    pub var_prefix: String,
+    pub columns: u32,
+    pub rows: u32,
+    /// How many bits each pixel of the tiles takes.
+    pub bit_depth: BitDepth,
+    /// The LCD mode the image is shown in.
+    pub lcd_scale: LcdScale,
+    /// Colors as stored, reduced to the calculator's 16-bit color.
+    pub colors: Vec<RGBA>,
+}
+
+impl Palette {
+    /// Return whether appvar data is a palette.
+    pub fn is_palette(data: &[u8]) -> bool {
+        data.starts_with(PALETTE_SIGNATURE) || data.starts_with(PACKED_PALETTE_SIGNATURE)
+    }
+
+    /// Read the data of a palette appvar.
+    pub fn read(data: &[u8]) -> IoResult<Self> {
+        let (name, var_prefix, columns, rows, bit_depth, lcd_scale, colors) =
+            if data.starts_with(PACKED_PALETTE_SIGNATURE) {
+                let (name, var_prefix, columns, rows, rest) =
+                    read_header(data, PACKED_PALETTE_SIGNATURE, "palette")?;
+                let Some(&[bits, x, y]) = rest.get(..3) else {
+                    return Err(invalid("Palette appvar ends early".into()));
+                };
+                let depth = BitDepth::from_bits(bits)
+                    .ok_or_else(|| invalid(format!("Palette bit depth {} is unknown", bits)))?;
+                let scale = LcdScale::from_factors((x.into(), y.into()))
+                    .ok_or_else(|| invalid(format!("Palette LCD scale {}x{} is unknown", x, y)))?;
+                (name, var_prefix, columns, rows, depth, scale, &rest[3..])
+            } else {
+                let (name, var_prefix, columns, rows, colors) =
+                    read_header(data, PALETTE_SIGNATURE, "palette")?;
+                let (depth, scale) = (BitDepth::Eight, LcdScale::Full);
+                (name, var_prefix, columns, rows, depth, scale, colors)
+            };
+        if !colors.len().is_multiple_of(2) || colors.len() > 512 {
+            return Err(invalid(format!(
+                "Palette has {} bytes of colors, which isn't a whole number of at most 256",
+                colors.len()
+            )));
+        }
+        Ok(Palette {
+            name,
+            var_prefix,
+            columns,
+            rows,
+            bit_depth,
+            lcd_scale,
+            colors: colors
+                .chunks_exact(2)
+                .map(|c| RGBA::from(GRGB1555(u16::from_le_bytes([c[0], c[1]]))))
+                .collect(),
+        })
+    }
+}
+
+/// The contents of an animation appvar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Animation {
+    /// Name of the image, padded with underscores to 8 characters.
+    pub name: String,
+    pub var_prefix: String,
+    pub columns: u32,
+    pub rows: u32,
+    /// How long each frame is shown, in milliseconds.
+    pub delays_ms: Vec<u16>,
+    /// Whether every frame uses the first frame's palette.
+    pub shared_palette: bool,
+}
+
+impl Animation {
+    /// Return whether appvar data describes an animation.
+    pub fn is_animation(data: &[u8]) -> bool {
+        data.starts_with(ANIMATION_SIGNATURE)
+    }
+
+    /// Read the data of an animation appvar.
+    pub fn read(data: &[u8]) -> IoResult<Self> {
+        let (name, var_prefix, columns, rows, rest) =
+            read_header(data, ANIMATION_SIGNATURE, "animation")?;
+        let frames = rest
+            .get(..2)
+            .map(|count| u16::from_le_bytes([count[0], count[1]]) as usize);
+        let delays = frames.and_then(|frames| rest.get(3..3 + frames * 2));
+        let (Some(&flags), Some(delays)) = (rest.get(2), delays) else {
+            return Err(invalid("Animation appvar ends early".into()));
+        };
+        Ok(Animation {
+            name,
+            var_prefix,
+            columns,
+            rows,
+            delays_ms: delays
+                .chunks_exact(2)
+                .map(|d| u16::from_le_bytes([d[0], d[1]]))
+                .collect(),
+            shared_palette: flags & 1 != 0,
+        })
+    }
+}
+
+/// The contents of a tile appvar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tile {
+    /// Name of the image, padded with underscores to 8 characters.
+    pub name: String,
+    pub compression: Compression,
+    /// How many bits each palette index took in the appvar, which is [`BitDepth::Eight`] for
+    /// direct color tiles.
+    pub bit_depth: BitDepth,
+    pub width: u32,
+    pub height: u32,
+    /// Palette indices of each pixel, row by row, unpacked to one byte each.
+    ///
+    /// This is empty for direct color tiles.
+    pub pixels: Vec<u8>,
+    /// The color of each pixel, row by row, for direct color tiles, which have no palette.
+    pub colors: Option<Vec<RGBA>>,
+}
+
+impl Tile {
+    /// Return whether appvar data is a tile.
+    pub fn is_tile(data: &[u8]) -> bool {
+        data.starts_with(compress::VIEWER_SIGNATURE.as_bytes())
+            || data.starts_with(compress::TAGGED_SIGNATURE.as_bytes())
+            || data.starts_with(depth::PACKED_SIGNATURE.as_bytes())
+    }
+
+    /// Return the pixels of a tile appvar as stored, after its signature and the fields that
+    /// describe them.
+    ///
+    /// This is the tile's width and height followed by its pixels, compressed together with the
+    /// appvar's compression method.
+    pub fn payload(data: &[u8]) -> IoResult<&[u8]> {
+        let header_len = if data.starts_with(compress::VIEWER_SIGNATURE.as_bytes()) {
+            16
+        } else if data.starts_with(compress::TAGGED_SIGNATURE.as_bytes()) {
+            17
+        } else if data.starts_with(depth::PACKED_SIGNATURE.as_bytes()) {
+            18
+        } else {
+            return Err(invalid("Not a tile appvar: signature is missing".into()));
+        };
+        data.get(header_len..)
+            .ok_or_else(|| invalid("Tile appvar ends early".into()))
+    }
+
+    /// Read and decompress the data of a tile appvar.
+    pub fn read(data: &[u8]) -> IoResult<Self> {
+        if !Self::is_tile(data) || data.len() < 16 {
+            return Err(invalid("Not a tile appvar: signature is missing".into()));
+        }
+        let name = String::from_utf8_lossy(&data[8..16]).into_owned();
+        let compression = |id: u8| {
+            Compression::from_id(id)
+                .ok_or_else(|| invalid(format!("Tile compression {} is unknown", id)))
+        };
+        let ends_early = || invalid("Tile appvar ends early".into());
+        let (compression, bit_depth, compressed) =
+            if data.starts_with(compress::VIEWER_SIGNATURE.as_bytes()) {
+                (Compression::Zx0, BitDepth::Eight, &data[16..])
+            } else if data.starts_with(compress::TAGGED_SIGNATURE.as_bytes()) {
+                let id = *data.get(16).ok_or_else(ends_early)?;
+                (compression(id)?, BitDepth::Eight, &data[17..])
+            } else {
+                let (id, bits) = match data.get(16..18) {
+                    Some(&[id, bits]) => (id, bits),
+                    _ => return Err(ends_early()),
+                };
+                if bits == direct::DIRECT_COLOR_BITS {
+                    return Self::read_direct(name, compression(id)?, &data[18..]);
+                }
+                let depth = BitDepth::from_bits(bits)
+                    .ok_or_else(|| invalid(format!("Tile bit depth {} is unknown", bits)))?;
+                (compression(id)?, depth, &data[18..])
+            };
+
+        let data = compression.decompress(compressed)?;
+        let (width, height) = match *data.as_slice() {
+            [width, height, ..] => (width as usize, height as usize),
+            _ => (0, 0),
+        };
+        let row_len = bit_depth.packed_len(width);
+        if data.len() < 2 || data.len() != 2 + row_len * height {
+            return Err(invalid(
+                "Tile pixel data doesn't match its dimensions".into(),
+            ));
+        }
+        let pixels = data[2..]
+            .chunks(row_len.max(1))
+            .flat_map(|row| bit_depth.unpack(row, width))
+            .collect();
+        Ok(Tile {
+            name,
+            compression,
+            bit_depth,
+            width: width as u32,
+            height: height as u32,
+            pixels,
+            colors: None,
+        })
+    }
+
+    /// Read the compressed data of a direct color tile.
+    fn read_direct(name: String, compression: Compression, compressed: &[u8]) -> IoResult<Self> {
+        let data = compression.decompress(compressed)?;
+        let (width, height) = match *data.as_slice() {
+            [width, height, ..] => (width as usize, height as usize),
+            _ => (0, 0),
+        };
+        if data.len() < 2 || data.len() != 2 + width * height * 2 {
+            return Err(invalid(
+                "Tile pixel data doesn't match its dimensions".into(),
+            ));
+        }
+        let colors = data[2..]
+            .chunks_exact(2)
+            .map(|c| {
+                let [r, g, b, a] = screen::from_rgb565(u16::from_le_bytes([c[0], c[1]])).0;
+                RGBA::new(r, g, b, a)
+            })
+            .collect();
+        Ok(Tile {
+            name,
+            compression,
+            bit_depth: BitDepth::Eight,
+            width: width as u32,
+            height: height as u32,
+            pixels: Vec::new(),
+            colors: Some(colors),
+        })
+    }
+}
+
+/// An image rebuilt from its appvars.
+pub struct DecodedImage {
+    /// Name of the image, padded with underscores to 8 characters.
+    pub name: String,
+    pub var_prefix: String,
+    /// The animation frame this image is, which is 0 for still images.
+    pub frame: u32,
+    pub image: RgbaImage,
+}
+
+/// Return the template [`QuantizedImage`](crate::QuantizedImage) names tiles with by default:
+/// the one for frames if `vars` include an animation with `var_prefix`, or else the one for
+/// still images.
+pub fn default_template(vars: &[Var], var_prefix: &str) -> NameTemplate {
+    let animated = vars.iter().any(|var| {
+        Animation::is_animation(&var.data)
+            && Animation::read(&var.data).is_ok_and(|a| a.var_prefix == var_prefix)
+    });
+    if animated {
+        NameTemplate::default_for_frames()
+    } else {
+        NameTemplate::default()
+    }
+}
+
+/// Rebuild every image whose palette is among `vars`, finding its tiles by name.
+///
+/// Tiles are named by `template`, or by default the [`default_template`]. Every frame of
+/// animations that share a palette is rebuilt with the first frame's.
+pub fn images(vars: &[Var], template: Option<&NameTemplate>) -> IoResult<Vec<DecodedImage>> {
+    let by_name: HashMap<&str, &Var> = vars.iter().map(|var| (var.name.as_str(), var)).collect();
+    let mut palettes = Vec::new();
+    let mut animations = Vec::new();
+    for var in vars {
+        if Palette::is_palette(&var.data) {
+            let palette = Palette::read(&var.data)?;
+            // Frames after the first have their number at the end of the palette's name
+            let frame = var.name.get(4..).and_then(|n| n.parse().ok()).unwrap_or(0);
+            palettes.push((frame, palette));
+        } else if Animation::is_animation(&var.data) {
+            animations.push(Animation::read(&var.data)?);
+        }
+    }
+    for animation in animations.iter().filter(|a| a.shared_palette) {
+        let first = palettes
+            .iter()
+            .find(|(frame, p)| *frame == 0 && p.var_prefix == animation.var_prefix)
+            .map(|(_, palette)| palette.clone());
+        if let Some(first) = first {
+            let frames = animation.delays_ms.len() as u32;
+            palettes.extend((1..frames).map(|frame| (frame, first.clone())));
+        }
+    }
+
+    palettes
+        .into_iter()
+        .map(|(frame, palette)| {
+            let default_template = default_template(vars, &palette.var_prefix);
+            let template = template.unwrap_or(&default_template);
+            let mut image: Option<RgbaImage> = None;
+            for row in 0..palette.rows {
+                for column in 0..palette.columns {
+                    let name = template.render(&palette.var_prefix, frame, column, row);
+                    let var = by_name
+                        .get(name.as_str())
+                        .ok_or_else(|| invalid(format!("Tile appvar {} is missing", name)))?;
+                    let tile = Tile::read(&var.data)?;
+                    let image = image.get_or_insert_with(|| {
+                        RgbaImage::new(palette.columns * tile.width, palette.rows * tile.height)
+                    });
+                    if image.width() != palette.columns * tile.width
+                        || image.height() != palette.rows * tile.height
+                    {
+                        return Err(invalid(format!(
+                            "Tile appvar {} is a different size from the others",
+                            name
+                        )));
+                    }
+                    for (i, &index) in tile.pixels.iter().enumerate() {
+                        let color = palette.colors.get(index as usize).ok_or_else(|| {
+                            invalid(format!(
+                                "Tile appvar {} uses palette index {}, but there are only {} \
+                                 colors",
+                                name,
+                                index,
+                                palette.colors.len()
+                            ))
+                        })?;
+                        let (x, y) = (i as u32 % tile.width, i as u32 / tile.width);
+                        image.put_pixel(
+                            column * tile.width + x,
+                            row * tile.height + y,
+                            Rgba([color.r, color.g, color.b, 255]),
+                        );
+                    }
+                }
+            }
+            // Scaled images are shown larger than they're stored
+            let mut image = image.unwrap_or_default();
+            let (x, y) = palette.lcd_scale.factors();
+            if (x, y) != (1, 1) {
+                image = imageops::resize(
+                    &image,
+                    image.width() * x,
+                    image.height() * y,
+                    FilterType::Nearest,
+                );
+            }
+            Ok(DecodedImage {
+                name: palette.name,
+                var_prefix: palette.var_prefix,
+                frame,
+                image,
+            })
+        })
+        .collect()
+}
+
+/// Decoding a converted image gives back its quantized pixels.
+#[test]
+fn images_round_trip() {
+    use std::io::Cursor;
+
+    let pixels = RgbaImage::from_fn(37, 21, |x, y| {
+        Rgba([(x * 7) as u8, (y * 12) as u8, ((x + y) * 5) as u8, 255])
+    });
+    let mut image = crate::Image::from_rgba(pixels, "test", "TS");
+    image.set_tile_size(16, 8);
+    let palette = crate::palette::xlibc();
+    let options = crate::QuantizeOptions {
+        dither: crate::Dither::None,
+        palette: Some(palette.clone()),
+        ..Default::default()
+    };
+    let mut quantized = image.quantize_with(&options).unwrap();
+    quantized.set_compression(Compression::Rle);
+
+    let group = quantized.write_group(Cursor::new(Vec::new())).unwrap();
+    let vars = crate::group::read(&group.into_inner()).unwrap().vars;
+    let decoded = images(&vars, None).unwrap();
+    assert_eq!(decoded.len(), 1);
+    assert_eq!(
+        (decoded[0].name.as_str(), decoded[0].frame),
+        ("test____", 0)
+    );
+    assert_eq!(decoded[0].image.dimensions(), (48, 24));
+
+    // Every pixel is a palette color as the calculator shows it
+    let stored = Palette::read(&vars.last().unwrap().data).unwrap().colors;
+    let index = quantized.tiles().next().unwrap().rows().next().unwrap()[3];
+    let color = stored[index as usize];
+    assert_eq!(
+        decoded[0].image.get_pixel(3, 0),
+        &Rgba([color.r, color.g, color.b, 255])
+    );
+}
+
+/// Packed tiles decode to the same image as unpacked ones.
+#[test]
+fn packed_images_round_trip() {
+    use std::io::Cursor;
+
+    let pixels = RgbaImage::from_fn(21, 9, |x, y| Rgba([(x * 12) as u8, (y * 28) as u8, 0, 255]));
+    let mut image = crate::Image::from_rgba(pixels, "test", "TS");
+    image.set_tile_size(7, 9);
+    let options = crate::QuantizeOptions {
+        max_colors: 16,
+        ..Default::default()
+    };
+    let mut quantized = image.quantize_with(&options).unwrap();
+    let decode = |quantized: &crate::QuantizedImage| {
+        let group = quantized.write_group(Cursor::new(Vec::new())).unwrap();
+        let vars = crate::group::read(&group.into_inner()).unwrap().vars;
+        let palette = Palette::read(&vars.last().unwrap().data).unwrap();
+        let image = images(&vars, None).unwrap().remove(0).image;
+        (palette.bit_depth, image)
+    };
+    let (depth, unpacked) = decode(&quantized);
+    assert_eq!(depth, BitDepth::Eight);
+
+    quantized.set_bit_depth(BitDepth::Four).unwrap();
+    let (depth, packed) = decode(&quantized);
+    assert_eq!(depth, BitDepth::Four);
+    assert_eq!(packed, unpacked);
+
+    // Images for a scaled LCD are enlarged to match
+    quantized.set_lcd_scale(LcdScale::HalfWidth);
+    let (_, scaled) = decode(&quantized);
+    assert_eq!(scaled.dimensions(), (42, 9));
+    assert_eq!(scaled.get_pixel(5, 3), unpacked.get_pixel(2, 3));
+}
+
+/// A tile's payload is its dimensions and pixels, whatever its signature.
+#[test]
+fn tile_payload_follows_signature() {
+    use std::io::Cursor;
+
+    let pixels = RgbaImage::from_pixel(4, 2, Rgba([255, 0, 0, 255]));
+    let mut image = crate::Image::from_rgba(pixels, "test", "TS");
+    image.set_tile_size(4, 2);
+    let mut quantized = image.quantize();
+    for depth in [BitDepth::Eight, BitDepth::Four] {
+        quantized.set_compression(Compression::None);
+        quantized.set_bit_depth(depth).unwrap();
+        let tile = quantized.tiles().next().unwrap();
+        let file = tile.write_appvar(Cursor::new(Vec::new())).unwrap();
+        let var = crate::group::read(&file.into_inner())
+            .unwrap()
+            .vars
+            .remove(0);
+        let index = tile.rows().next().unwrap()[0];
+        let mut expected = vec![4, 2];
+        depth.pack(&[index; 8], &mut expected);
+        assert_eq!(Tile::payload(&var.data).unwrap(), expected);
+    }
+    assert!(Tile::payload(b"HDPICTV1test____").is_err());
+}
@@ -0,0 +1,111 @@
+//! Packing of tile pixels into fewer than eight bits each
+//!
+//! Images with few colors can store each pixel's palette index in fewer bits, several to a byte
+//! with the leftmost pixel in the most significant bits and each row starting on a new byte. HD
+//! Picture Viewer only reads one pixel per byte, so packed tiles get a `HDPICPV1` signature
+//! followed by the image name, a byte identifying the compression and the number of bits per
+//! pixel. Their palette appvars get a `HDPALV11` signature, with only as many colors as the pixels
+//! can index. That signature is followed by the usual header, then the number of bits per pixel
+//! and how many screen pixels each image pixel covers across and down, for images shown with the
+//! LCD in a scaled mode.
+
+/// Signature of tiles with more than one pixel per byte.
+pub(crate) const PACKED_SIGNATURE: &str = "HDPICPV1";
+
+/// How many bits each pixel of a tile takes.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum BitDepth {
+    /// One pixel per byte, which is what HD Picture Viewer reads.
+    #[default]
+    Eight,
+    /// Two pixels per byte, for up to 16 colors.
+    Four,
+    /// Four pixels per byte, for up to 4 colors.
+    Two,
+    /// Eight pixels per byte, for 2 colors, which suits scanned documents and line art.
+    One,
+}
+
+impl BitDepth {
+    /// Return the number of bits per pixel.
+    pub fn bits(self) -> u8 {
+        match self {
+            BitDepth::Eight => 8,
+            BitDepth::Four => 4,
+            BitDepth::Two => 2,
+            BitDepth::One => 1,
+        }
+    }
+
+    /// Return the depth with some number of bits per pixel, if it's supported.
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        [
+            BitDepth::Eight,
+            BitDepth::Four,
+            BitDepth::Two,
+            BitDepth::One,
+        ]
+        .into_iter()
+        .find(|depth| depth.bits() == bits)
+    }
+
+    /// Return the most colors pixels can index at this depth.
+    pub fn max_colors(self) -> usize {
+        1 << self.bits()
+    }
+
+    /// Return the number of bytes in a packed row of pixels.
+    pub fn packed_len(self, width: usize) -> usize {
+        (width * self.bits() as usize).div_ceil(8)
+    }
+
+    /// Append a row of palette indices to `out`, packed at this depth.
+    pub(crate) fn pack(self, row: &[u8], out: &mut Vec<u8>) {
+        let bits = self.bits() as usize;
+        for pixels in row.chunks(8 / bits) {
+            out.push(pixels.iter().enumerate().fold(0, |byte, (i, &index)| {
+                debug_assert!((index as usize) < self.max_colors());
+                byte | index << (8 - bits * (i + 1))
+            }));
+        }
+    }
+
+    /// Reverse [`pack`](BitDepth::pack) for a packed row of `width` pixels.
+    pub(crate) fn unpack(self, packed: &[u8], width: usize) -> Vec<u8> {
+        let bits = self.bits() as usize;
+        let mask = (self.max_colors() - 1) as u8;
+        (0..width)
+            .map(|x| {
+                let byte = packed[x * bits / 8];
+                byte >> (8 - bits * (x % (8 / bits) + 1)) & mask
+            })
+            .collect()
+    }
+}
+
+/// Pixels pack from the most significant bits, and rows pad to whole bytes.
+#[test]
+fn packing_round_trips() {
+    let row = [1, 15, 0, 7, 9];
+    let mut packed = Vec::new();
+    BitDepth::Four.pack(&row, &mut packed);
+    assert_eq!(packed, [0x1f, 0x07, 0x90]);
+    assert_eq!(BitDepth::Four.packed_len(row.len()), packed.len());
+    assert_eq!(BitDepth::Four.unpack(&packed, row.len()), row);
+
+    let row = [3, 0, 1, 2, 2];
+    let mut packed = Vec::new();
+    BitDepth::Two.pack(&row, &mut packed);
+    assert_eq!(packed, [0b11_00_01_10, 0b10_00_00_00]);
+    assert_eq!(BitDepth::Two.unpack(&packed, row.len()), row);
+
+    let row = [1, 0, 0, 1, 1, 1, 0, 1, 1];
+    let mut packed = Vec::new();
+    BitDepth::One.pack(&row, &mut packed);
+    assert_eq!(packed, [0b1001_1101, 0b1000_0000]);
+    assert_eq!(BitDepth::One.unpack(&packed, row.len()), row);
+
+    let mut packed = Vec::new();
+    BitDepth::Eight.pack(&row, &mut packed);
+    assert_eq!(packed, row);
+}
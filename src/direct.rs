@@ -0,0 +1,83 @@
+//! Tiles storing each pixel's color rather than a palette index
+//!
+//! Images converted this way skip quantization and have no palette appvar. Every pixel of their
+//! tiles is a little-endian RGB565 color, which takes twice the space of a palette index but shows
+//! exactly what the screen can. Their tiles have the packed signature described in [`BitDepth`]
+//! with 16 bits per pixel.
+use rgb::RGBA8 as RGBA;
+
+use crate::{BitDepth, Compression, Image, LcdScale, NameTemplate, QuantizedImage};
+
+/// Bits per pixel recorded in the header of direct color tiles.
+pub(crate) const DIRECT_COLOR_BITS: u8 = 16;
+/// Most bytes of data in an appvar, which is what tifiles will write in one.
+const MAX_APPVAR_LEN: usize = u16::MAX as usize - 17;
+/// Bytes of a direct color tile other than its pixels: the signature, name, compression and
+/// depth, then the tile's dimensions.
+const TILE_HEADER_LEN: usize = 8 + 8 + 2 + 2;
+/// Most pixels in a direct color tile, so that its two bytes a pixel fit in an appvar even when
+/// compression doesn't shrink them.
+pub const MAX_DIRECT_COLOR_PIXELS: usize = (MAX_APPVAR_LEN - TILE_HEADER_LEN) / 2;
+
+impl Image {
+    /// Convert the image to 16-bit colors without quantizing it.
+    ///
+    /// Partly transparent pixels are blended over the background color, which also fills padding
+    /// out to whole tiles. The result has no palette, so it can't be packed to another
+    /// [`BitDepth`] and has no palette appvar.
+    pub fn direct_color(self, background: RGBA) -> QuantizedImage {
+        let canvas = self.canvas(background);
+        let data = canvas
+            .pixels()
+            .flat_map(|&pixel| crate::screen::rgb565(pixel).to_le_bytes())
+            .collect();
+
+        QuantizedImage {
+            var_prefix: self.var_prefix,
+            name: self.name,
+            width: canvas.width(),
+            height: canvas.height(),
+            tile_size: self.tile_size,
+            compression: Compression::default(),
+            bit_depth: BitDepth::default(),
+            lcd_scale: LcdScale::default(),
+            direct_color: true,
+            archived: true,
+            comment: None,
+            name_template: NameTemplate::default(),
+            frame: 0,
+            palette: Vec::new(),
+            data,
+            first_row: 0,
+            transparent_index: None,
+        }
+    }
+}
+
+/// Direct color tiles hold every pixel's color, and there's no palette to write.
+#[test]
+fn direct_color_tiles() {
+    use image::{Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    let pixels = RgbaImage::from_fn(10, 4, |x, _| Rgba([x as u8 * 25, 0, 255, 255]));
+    let mut image = Image::from_rgba(pixels, "direct", "DR");
+    image.set_tile_size(5, 4);
+    let mut direct = image.direct_color(RGBA::new(0, 0, 0, 255));
+    direct.set_compression(Compression::Rle);
+    assert!(direct.is_direct_color());
+    assert!(direct.set_bit_depth(BitDepth::Four).is_err());
+    assert!(direct
+        .write_palette_appvar(Cursor::new(Vec::new()))
+        .is_err());
+
+    let group = direct.write_group(Cursor::new(Vec::new())).unwrap();
+    let vars = crate::group::read(&group.into_inner()).unwrap().vars;
+    assert_eq!(vars.len(), 2);
+    let tile = crate::decode::Tile::read(&vars[1].data).unwrap();
+    assert_eq!((tile.width, tile.height), (5, 4));
+    let colors = tile.colors.unwrap();
+    assert_eq!(colors.len(), 20);
+    // The second tile starts at x = 5, so its third pixel has red 175, kept to five bits
+    assert_eq!(colors[2], RGBA::new(172, 0, 255, 255));
+}
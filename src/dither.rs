@@ -0,0 +1,398 @@
+//! Dithering of images as they're mapped to a palette
+
+use image::RgbaImage;
+use rgb::RGBA8 as RGBA;
+
+use crate::color_space::Matching;
+use crate::nearest::PaletteSearch;
+
+/// Algorithms for dithering an image when mapping its pixels to the palette.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// Map each pixel to the nearest palette color.
+    None,
+    /// Floyd–Steinberg error diffusion.
+    ///
+    /// This is done by libimagequant when it also generates the palette.
+    #[default]
+    FloydSteinberg,
+    /// Atkinson error diffusion, which diffuses only part of the error for higher contrast.
+    Atkinson,
+    /// Three-row Sierra error diffusion.
+    Sierra,
+    /// Jarvis, Judice and Ninke error diffusion, which spreads error furthest.
+    JarvisJudiceNinke,
+    /// Ordered dithering with an 8x8 Bayer matrix.
+    ///
+    /// This gives a regular pattern and the result for any given pixel depends only on its
+    /// position and color, never on its neighbors.
+    Bayer,
+    /// Ordered dithering with a blue noise texture.
+    ///
+    /// Like [`Bayer`](Dither::Bayer) this is positional, but without any visible regular pattern.
+    BlueNoise,
+}
+
+impl Dither {
+    /// Return the kernel used by an error diffusion algorithm.
+    fn kernel(&self) -> Option<&'static Kernel> {
+        match self {
+            Dither::FloydSteinberg => Some(&FLOYD_STEINBERG),
+            Dither::Atkinson => Some(&ATKINSON),
+            Dither::Sierra => Some(&SIERRA),
+            Dither::JarvisJudiceNinke => Some(&JARVIS_JUDICE_NINKE),
+            Dither::None | Dither::Bayer | Dither::BlueNoise => None,
+        }
+    }
+
+    /// Return the threshold at a pixel position for ordered dithering, in `-0.5..0.5`.
+    fn threshold(&self, x: u32, y: u32) -> f32 {
+        match self {
+            Dither::Bayer => BAYER_8X8[y as usize % 8][x as usize % 8] as f32 / 64. - 0.5,
+            Dither::BlueNoise => {
+                let i =
+                    (y as usize % BLUE_NOISE_SIZE) * BLUE_NOISE_SIZE + x as usize % BLUE_NOISE_SIZE;
+                BLUE_NOISE[i] as f32 / 256. - 0.5
+            }
+            _ => unreachable!("{:?} is not an ordered dither", self),
+        }
+    }
+}
+
+/// An error diffusion kernel.
+///
+/// Each tap is a `(dx, dy, weight)` offset from the current pixel which receives `weight /
+/// divisor` of its quantization error. Pixels are visited left to right and top to bottom so
+/// taps must only point forward.
+struct Kernel {
+    taps: &'static [(i32, u32, u8)],
+    divisor: f32,
+}
+
+const FLOYD_STEINBERG: Kernel = Kernel {
+    taps: &[(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)],
+    divisor: 16.,
+};
+
+const ATKINSON: Kernel = Kernel {
+    taps: &[
+        (1, 0, 1),
+        (2, 0, 1),
+        (-1, 1, 1),
+        (0, 1, 1),
+        (1, 1, 1),
+        (0, 2, 1),
+    ],
+    divisor: 8.,
+};
+
+const SIERRA: Kernel = Kernel {
+    taps: &[
+        (1, 0, 5),
+        (2, 0, 3),
+        (-2, 1, 2),
+        (-1, 1, 4),
+        (0, 1, 5),
+        (1, 1, 4),
+        (2, 1, 2),
+        (-1, 2, 2),
+        (0, 2, 3),
+        (1, 2, 2),
+    ],
+    divisor: 32.,
+};
+
+const JARVIS_JUDICE_NINKE: Kernel = Kernel {
+    taps: &[
+        (1, 0, 7),
+        (2, 0, 5),
+        (-2, 1, 3),
+        (-1, 1, 5),
+        (0, 1, 7),
+        (1, 1, 5),
+        (2, 1, 3),
+        (-2, 2, 1),
+        (-1, 2, 3),
+        (0, 2, 5),
+        (1, 2, 3),
+        (2, 2, 1),
+    ],
+    divisor: 48.,
+};
+
+/// Thresholds for ordered dithering, each in `0..64`.
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Width and height of the blue noise texture.
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Thresholds for blue noise dithering, each in `0..256` and stored row-major.
+///
+/// This is generated with the void-and-cluster method by `generate-blue-noise.py`.
+static BLUE_NOISE: &[u8; BLUE_NOISE_SIZE * BLUE_NOISE_SIZE] = include_bytes!("blue_noise.bin");
+
+/// Map an image to a palette with the chosen dithering, returning palette indices.
+///
+/// `strength` scales the dithering from 0 (none) to 1 (full), and pixels are matched to the
+/// nearest palette colors by `matching`.
+pub(crate) fn remap(
+    image: &RgbaImage,
+    palette: &[RGBA],
+    dither: Dither,
+    strength: f32,
+    matching: Matching,
+) -> Vec<u8> {
+    let search = PaletteSearch::new(palette, matching);
+    match dither {
+        Dither::None => image
+            .pixels()
+            .map(|px| search.nearest([px[0] as f32, px[1] as f32, px[2] as f32]))
+            .collect(),
+        Dither::Bayer | Dither::BlueNoise => ordered(image, palette, &search, dither, strength),
+        _ => error_diffusion(image, palette, &search, dither.kernel().unwrap(), strength),
+    }
+}
+
+/// Map an image to a palette with ordered dithering, returning palette indices.
+///
+/// `strength` scales how far colors are perturbed, from 0 (not at all) to 1.
+fn ordered(
+    image: &RgbaImage,
+    palette: &[RGBA],
+    search: &PaletteSearch,
+    dither: Dither,
+    strength: f32,
+) -> Vec<u8> {
+    // Perturb colors by about the distance between palette entries, assuming they're spread
+    // evenly over the color cube.
+    let spread = strength * 255. / (palette.len() as f32).cbrt();
+
+    image
+        .enumerate_pixels()
+        .map(|(x, y, px)| {
+            let offset = dither.threshold(x, y) * spread;
+            search.nearest([
+                px[0] as f32 + offset,
+                px[1] as f32 + offset,
+                px[2] as f32 + offset,
+            ])
+        })
+        .collect()
+}
+
+/// Map an image to a palette with error diffusion, returning palette indices.
+///
+/// `strength` is the fraction of quantization error that gets diffused, from 0 to 1.
+fn error_diffusion(
+    image: &RgbaImage,
+    palette: &[RGBA],
+    search: &PaletteSearch,
+    kernel: &Kernel,
+    strength: f32,
+) -> Vec<u8> {
+    let width = image.width() as usize;
+    let height = image.height() as usize;
+    let mut work: Vec<[f32; 3]> = image
+        .pixels()
+        .map(|px| [px[0] as f32, px[1] as f32, px[2] as f32])
+        .collect();
+
+    let mut out = Vec::with_capacity(work.len());
+    for y in 0..height {
+        for x in 0..width {
+            // Clamp so accumulated error can't push colors beyond what the palette can reach
+            let wanted = work[y * width + x].map(|c| c.clamp(0., 255.));
+            let index = search.nearest(wanted);
+            let chosen = palette[index as usize];
+            let error = [
+                wanted[0] - chosen.r as f32,
+                wanted[1] - chosen.g as f32,
+                wanted[2] - chosen.b as f32,
+            ];
+
+            for &(dx, dy, weight) in kernel.taps {
+                let nx = x as i32 + dx;
+                let ny = y + dy as usize;
+                if nx < 0 || nx as usize >= width || ny >= height {
+                    continue;
+                }
+
+                let fraction = strength * weight as f32 / kernel.divisor;
+                let target = &mut work[ny * width + nx as usize];
+                for (c, e) in target.iter_mut().zip(error) {
+                    *c += e * fraction;
+                }
+            }
+            out.push(index);
+        }
+    }
+    out
+}
+
+/// Dither lightness values from 0 (black) to 1 (white) to one bit each, returning whether each
+/// pixel is dark.
+///
+/// Undithered pixels are dark when darker than `threshold`, which error diffusion and ordered
+/// dithering then move for each pixel. Error diffusion runs along alternate rows in opposite
+/// directions, since the diagonal streaks of always scanning one way stand out among the few
+/// pixels of a monochrome screen.
+pub(crate) fn one_bit(
+    lightness: &[f32],
+    width: usize,
+    dither: Dither,
+    threshold: f32,
+) -> Vec<bool> {
+    let Some(kernel) = dither.kernel() else {
+        return lightness
+            .iter()
+            .enumerate()
+            .map(|(i, &level)| match dither {
+                Dither::None => level < threshold,
+                _ => level + dither.threshold((i % width) as u32, (i / width) as u32) < threshold,
+            })
+            .collect();
+    };
+
+    let height = lightness.len() / width;
+    let mut work = lightness.to_vec();
+    let mut dark = vec![false; work.len()];
+    for y in 0..height {
+        let reversed = y % 2 == 1;
+        for i in 0..width {
+            let x = if reversed { width - 1 - i } else { i };
+            let wanted = work[y * width + x].clamp(0., 1.);
+            dark[y * width + x] = wanted < threshold;
+            let error = wanted - if wanted < threshold { 0. } else { 1. };
+
+            for &(dx, dy, weight) in kernel.taps {
+                let nx = x as i32 + if reversed { -dx } else { dx };
+                let ny = y + dy as usize;
+                if nx < 0 || nx as usize >= width || ny >= height {
+                    continue;
+                }
+                work[ny * width + nx as usize] += error * weight as f32 / kernel.divisor;
+            }
+        }
+    }
+    dark
+}
+
+/// Each error diffusion kernel dithers a flat gray to a roughly even mix of black and white.
+#[test]
+fn error_diffusion_mixes_evenly() {
+    let image = RgbaImage::from_pixel(32, 32, image::Rgba([128, 128, 128, 255]));
+    let palette = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255)];
+
+    for dither in [
+        Dither::FloydSteinberg,
+        Dither::Atkinson,
+        Dither::Sierra,
+        Dither::JarvisJudiceNinke,
+    ] {
+        let data = remap(
+            &image,
+            &palette,
+            dither,
+            1.,
+            Matching::Metric(crate::ColorMetric::Rgb),
+        );
+        let white = data.iter().filter(|&&i| i == 1).count() as f32 / data.len() as f32;
+        assert!(
+            (0.45..0.55).contains(&white),
+            "{:?} gave {} white",
+            dither,
+            white
+        );
+    }
+}
+
+/// Without any strength dithering does nothing, choosing the nearest color everywhere.
+#[test]
+fn zero_strength_does_not_dither() {
+    let image = RgbaImage::from_pixel(16, 16, image::Rgba([100, 100, 100, 255]));
+    let palette = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255)];
+
+    assert!(remap(
+        &image,
+        &palette,
+        Dither::Bayer,
+        0.,
+        Matching::Metric(crate::ColorMetric::Rgb)
+    )
+    .iter()
+    .all(|&i| i == 0));
+    assert!(remap(
+        &image,
+        &palette,
+        Dither::Sierra,
+        0.,
+        Matching::Metric(crate::ColorMetric::Rgb)
+    )
+    .iter()
+    .all(|&i| i == 0));
+}
+
+/// A flat gray halfway between the only two palette colors dithers to an even mix of both.
+#[test]
+fn ordered_dither_mixes_evenly() {
+    let image = RgbaImage::from_pixel(64, 64, image::Rgba([128, 128, 128, 255]));
+    let palette = [RGBA::new(0, 0, 0, 255), RGBA::new(255, 255, 255, 255)];
+
+    for dither in [Dither::Bayer, Dither::BlueNoise] {
+        let data = remap(
+            &image,
+            &palette,
+            dither,
+            1.,
+            Matching::Metric(crate::ColorMetric::Rgb),
+        );
+        let white = data.iter().filter(|&&i| i == 1).count();
+        assert_eq!(white, data.len() / 2, "{:?}", dither);
+    }
+}
+
+/// One-bit dithering of a flat gray darkens about its share of pixels with every algorithm, and
+/// the threshold alone decides undithered pixels.
+#[test]
+fn one_bit_matches_lightness() {
+    let lightness = vec![0.25; 96 * 64];
+    for dither in [
+        Dither::FloydSteinberg,
+        Dither::Atkinson,
+        Dither::Sierra,
+        Dither::JarvisJudiceNinke,
+        Dither::Bayer,
+        Dither::BlueNoise,
+    ] {
+        let dark = one_bit(&lightness, 96, dither, 0.5);
+        let share = dark.iter().filter(|&&dark| dark).count() as f32 / dark.len() as f32;
+        // Atkinson drops some error by design, so it strays further
+        let expected = if dither == Dither::Atkinson {
+            0.7..0.9
+        } else {
+            0.7..0.8
+        };
+        assert!(
+            expected.contains(&share),
+            "{:?} gave {} dark",
+            dither,
+            share
+        );
+    }
+
+    assert!(one_bit(&lightness, 96, Dither::None, 0.5)
+        .iter()
+        .all(|&dark| dark));
+    assert!(!one_bit(&lightness, 96, Dither::None, 0.2)
+        .iter()
+        .any(|&dark| dark));
+}
@@ -0,0 +1,189 @@
+//! C interface for embedding the converter in other tools
+//!
+//! With the `ffi` feature the library's cdylib exports these functions, declared for C in
+//! `include/hdpictureconverter.h`. A conversion reads an image file and returns the bytes of a
+//! group file holding its tile and palette appvars, in a buffer that must be released with
+//! [`hdpc_free`].
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+
+use crate::{check_var_prefix, Compression, Dither, Image, QuantizeOptions};
+
+/// Options for [`hdpc_convert`], all of which are the default when zeroed.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HdpcOptions {
+    /// Nonzero to map colors to the nearest in the palette without dithering.
+    pub no_dither: c_int,
+    /// 0 for zx0, 1 for DEFLATE, 2 for run-length encoding or 3 for none.
+    pub compression: c_int,
+    /// Nonzero to store the appvars in RAM rather than archive.
+    pub unarchived: c_int,
+}
+
+/// Bytes returned to the caller, which owns them until passing them to [`hdpc_free`].
+#[repr(C)]
+pub struct HdpcBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+pub const HDPC_OK: c_int = 0;
+/// An argument was null or invalid.
+pub const HDPC_INVALID_ARGUMENT: c_int = -1;
+/// The image couldn't be read or converted.
+pub const HDPC_CONVERSION_FAILED: c_int = -2;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn fail(code: c_int, message: impl ToString) -> c_int {
+    let message = CString::new(message.to_string().replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|error| *error.borrow_mut() = message);
+    code
+}
+
+/// Convert the image file at `path` to a group file, which is stored in `out_buf`.
+///
+/// `prefix` is the two-letter var prefix of the appvars, and `options` may be null for the
+/// defaults. Returns [`HDPC_OK`] on success or a negative error code, in which case
+/// [`hdpc_last_error`] describes what went wrong.
+///
+/// # Safety
+///
+/// `path` and `prefix` must be null or point to NUL-terminated strings, `options` must be null
+/// or point to an `HdpcOptions`, and `out_buf` must be null or point to writable memory for an
+/// `HdpcBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn hdpc_convert(
+    path: *const c_char,
+    prefix: *const c_char,
+    options: *const HdpcOptions,
+    out_buf: *mut HdpcBuffer,
+) -> c_int {
+    if path.is_null() || prefix.is_null() || out_buf.is_null() {
+        return fail(
+            HDPC_INVALID_ARGUMENT,
+            "path, prefix and out_buf must not be null",
+        );
+    }
+    let (Ok(path), Ok(prefix)) = (
+        CStr::from_ptr(path).to_str(),
+        CStr::from_ptr(prefix).to_str(),
+    ) else {
+        return fail(HDPC_INVALID_ARGUMENT, "path and prefix must be UTF-8");
+    };
+    if let Err(e) = check_var_prefix(prefix) {
+        return fail(HDPC_INVALID_ARGUMENT, e);
+    }
+    let options = options.as_ref().copied().unwrap_or_default();
+    let compression = match options.compression {
+        0 => Compression::Zx0,
+        1 => Compression::Deflate,
+        2 => Compression::Rle,
+        3 => Compression::None,
+        other => {
+            return fail(
+                HDPC_INVALID_ARGUMENT,
+                format!("compression {} is unknown", other),
+            )
+        }
+    };
+
+    match convert(Path::new(path), prefix, &options, compression) {
+        Ok(group) => {
+            let group = Box::into_raw(group.into_boxed_slice());
+            out_buf.write(HdpcBuffer {
+                data: group as *mut u8,
+                len: group.len(),
+            });
+            HDPC_OK
+        }
+        Err(e) => fail(HDPC_CONVERSION_FAILED, e),
+    }
+}
+
+fn convert(
+    path: &Path,
+    prefix: &str,
+    options: &HdpcOptions,
+    compression: Compression,
+) -> std::io::Result<Vec<u8>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let image = Image::new(BufReader::new(std::fs::File::open(path)?), &name, prefix)?;
+    let mut quantize = QuantizeOptions::default();
+    if options.no_dither != 0 {
+        quantize.dither = Dither::None;
+    }
+    let mut image = image.quantize_with(&quantize)?;
+    image.set_compression(compression);
+    image.set_archived(options.unarchived == 0);
+    Ok(image.write_group(Cursor::new(Vec::new()))?.into_inner())
+}
+
+/// Release a buffer returned by [`hdpc_convert`], leaving it empty.
+///
+/// # Safety
+///
+/// `buf` must be null or point to a buffer filled in by [`hdpc_convert`] or already freed.
+#[no_mangle]
+pub unsafe extern "C" fn hdpc_free(buf: *mut HdpcBuffer) {
+    let Some(buf) = buf.as_mut() else {
+        return;
+    };
+    if !buf.data.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+            buf.data, buf.len,
+        )));
+    }
+    buf.data = std::ptr::null_mut();
+    buf.len = 0;
+}
+
+/// Return a description of the last error on this thread, which is valid until the next call
+/// to [`hdpc_convert`] on it.
+#[no_mangle]
+pub extern "C" fn hdpc_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| error.borrow().as_ptr())
+}
+
+/// Converting through the C interface gives the same group file as converting directly.
+#[test]
+fn convert_matches_library() {
+    let path = std::env::temp_dir().join(format!("hdpc-ffi-{}.png", std::process::id()));
+    let pixels = image::RgbaImage::from_fn(37, 21, |x, y| {
+        image::Rgba([(x * 7) as u8, (y * 12) as u8, ((x + y) * 5) as u8, 255])
+    });
+    pixels.save(&path).unwrap();
+
+    let c_path = CString::new(path.to_str().unwrap()).unwrap();
+    let mut buf = HdpcBuffer {
+        data: std::ptr::null_mut(),
+        len: 0,
+    };
+    let options = HdpcOptions {
+        no_dither: 1,
+        ..Default::default()
+    };
+    let result = unsafe { hdpc_convert(c_path.as_ptr(), c"TS".as_ptr(), &options, &mut buf) };
+    assert_eq!(result, HDPC_OK);
+    let group = unsafe { std::slice::from_raw_parts(buf.data, buf.len) }.to_vec();
+    unsafe { hdpc_free(&mut buf) };
+    assert!(buf.data.is_null());
+
+    let expected = convert(&path, "TS", &options, Compression::Zx0).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(group, expected);
+
+    let result = unsafe { hdpc_convert(c_path.as_ptr(), c"T".as_ptr(), &options, &mut buf) };
+    assert_eq!(result, HDPC_INVALID_ARGUMENT);
+    let error = unsafe { CStr::from_ptr(hdpc_last_error()) };
+    assert!(
+        error.to_str().unwrap().contains("two letters"),
+        "{:?}",
+        error
+    );
+}
@@ -0,0 +1,362 @@
+//! TI group (.8xg) files
+//!
+//! A group file has the same layout as a single variable file (signature, comment, data section
+//! length, data section and checksum), except the data section contains the entries of any number
+//! of variables back to back. TI Connect CE and CEmu will send every contained variable when
+//! given such a file.
+//!
+//! Because an individual variable file's data section is exactly one such entry, groups are
+//! built here from complete variable files like those produced by [`tifiles::Writer`], and
+//! [`read`] reads both kinds of file the same way.
+use std::io::{Error, ErrorKind, Result as IoResult, Seek, SeekFrom, Write};
+
+use tifiles::VariableType;
+
+/// Signature which begins every file.
+const SIGNATURE: &[u8; 11] = b"**TI83F*\x1a\x0a\0";
+/// Size of the signature, comment and data section length preceding the data section.
+pub const HEADER_LEN: usize = 55;
+/// Size of the comment following the signature, which is padded with spaces.
+pub const COMMENT_LEN: usize = 42;
+
+/// Writes group files.
+///
+/// Variables are added with [`add_var`](Writer::add_var) and the group is emitted when
+/// [`close`](Writer::close) is called. Entries are buffered in memory because the data section
+/// length precedes them in the file.
+pub struct Writer<W: Write> {
+    out: W,
+    comment: String,
+    entries: Vec<u8>,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(out: W) -> Self {
+        Writer {
+            out,
+            comment: "Group file by hdpictureconverter".into(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Replace the file's comment, which must be ASCII and at most [`COMMENT_LEN`] bytes.
+    pub fn set_comment(&mut self, comment: &str) {
+        assert_valid_comment(comment);
+        self.comment = comment.into();
+    }
+
+    /// Append the variable contained in a complete variable file to the group.
+    pub fn add_var(&mut self, file: &[u8]) -> IoResult<()> {
+        self.entries.extend_from_slice(var_entry(file)?);
+        Ok(())
+    }
+
+    /// Write the group out, returning the underlying writer.
+    ///
+    /// This fails without writing anything if the entries don't fit in the 16-bit data section
+    /// length, since TI Connect CE and the calculator reject groups whose length is wrong.
+    pub fn close(mut self) -> IoResult<W> {
+        let len = u16::try_from(self.entries.len()).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "variables of {} bytes are too large for a group file, which holds at most {}",
+                    self.entries.len(),
+                    u16::MAX
+                ),
+            )
+        })?;
+        self.out.write_all(SIGNATURE)?;
+        write!(self.out, "{:1$}", self.comment, COMMENT_LEN)?;
+        self.out.write_all(&len.to_le_bytes())?;
+        self.out.write_all(&self.entries)?;
+        self.out.write_all(&checksum(&self.entries).to_le_bytes())?;
+        Ok(self.out)
+    }
+}
+
+/// A variable read from a variable or group file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Var {
+    pub name: String,
+    pub ty: VariableType,
+    pub archived: bool,
+    /// The variable's contents, without the length that begins some types' data.
+    pub data: Vec<u8>,
+}
+
+impl Var {
+    /// Return the variable's contents as the calculator stores them, which begin with their
+    /// length for some types.
+    pub fn stored_data(&self) -> Vec<u8> {
+        let mut stored = Vec::with_capacity(self.data.len() + 2);
+        if has_length(self.ty) {
+            stored.extend_from_slice(&(self.data.len() as u16).to_le_bytes());
+        }
+        stored.extend_from_slice(&self.data);
+        stored
+    }
+}
+
+/// The variables in a variable or group file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contents {
+    pub comment: String,
+    pub vars: Vec<Var>,
+    /// Whether the file's checksum matches its data.
+    pub checksum_valid: bool,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Read every variable in a variable or group file.
+///
+/// Entries are read up to the checksum at the end of the file rather than as far as the data
+/// section length says, so that large groups from tools which let that wrap can still be read.
+/// [`Writer::close`] refuses to write such groups instead.
+pub fn read(file: &[u8]) -> IoResult<Contents> {
+    if file.len() < HEADER_LEN + 2 || &file[..SIGNATURE.len()] != SIGNATURE {
+        return Err(invalid(
+            "Not a TI variable file: signature is missing".into(),
+        ));
+    }
+    let comment = &file[SIGNATURE.len()..SIGNATURE.len() + COMMENT_LEN];
+    let comment = String::from_utf8_lossy(comment)
+        .trim_end_matches([' ', '\0'])
+        .to_string();
+    let (entries, file_checksum) = file[HEADER_LEN..].split_at(file.len() - HEADER_LEN - 2);
+
+    let mut vars = Vec::new();
+    let mut rest = entries;
+    while !rest.is_empty() {
+        let (var, len) = read_entry(rest)?;
+        vars.push(var);
+        rest = &rest[len..];
+    }
+    Ok(Contents {
+        comment,
+        vars,
+        checksum_valid: checksum(entries).to_le_bytes() == file_checksum,
+    })
+}
+
+/// Return whether variables of a type begin with the length of the rest of their data, like
+/// appvars do.
+fn has_length(ty: VariableType) -> bool {
+    matches!(
+        ty,
+        VariableType::AppVar
+            | VariableType::Program
+            | VariableType::ProtectedProgram
+            | VariableType::Picture
+    )
+}
+
+/// Read the variable entry at the start of `entries`, returning it and the entry's length.
+fn read_entry(entries: &[u8]) -> IoResult<(Var, usize)> {
+    let u16_at = |i: usize| {
+        entries
+            .get(i..i + 2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]) as usize)
+            .ok_or_else(|| invalid("Variable entry ends early".into()))
+    };
+    let header_len = u16_at(0)?;
+    if ![11, 13].contains(&header_len) {
+        return Err(invalid(format!(
+            "Variable header reports length {}, which is unrecognized",
+            header_len
+        )));
+    }
+    let data_len = u16_at(2)?;
+    if u16_at(header_len + 2)? != data_len {
+        return Err(invalid("Variable data length fields disagree".into()));
+    }
+    let data_start = header_len + 4;
+    let data = entries
+        .get(data_start..data_start + data_len)
+        .ok_or_else(|| invalid("Variable entry ends early".into()))?;
+
+    let ty = VariableType::try_from(entries[4])
+        .map_err(|_| invalid(format!("Variable type {:#x} is not recognized", entries[4])))?;
+    let name = String::from_utf8_lossy(&entries[5..13])
+        .trim_end_matches('\0')
+        .to_string();
+    let archived = header_len == 13 && entries[14] & 0x80 != 0;
+    let data = if has_length(ty) {
+        if data.len() < 2 || u16::from_le_bytes([data[0], data[1]]) as usize != data.len() - 2 {
+            return Err(invalid(format!("Variable {} has the wrong length", name)));
+        }
+        &data[2..]
+    } else {
+        data
+    };
+
+    let var = Var {
+        name,
+        ty,
+        archived,
+        data: data.to_vec(),
+    };
+    Ok((var, data_start + data_len))
+}
+
+pub(crate) fn assert_valid_comment(comment: &str) {
+    assert!(
+        comment.is_ascii() && comment.len() <= COMMENT_LEN,
+        "file comment must be ASCII and at most {} bytes",
+        COMMENT_LEN
+    );
+}
+
+/// Replace the comment of the variable file that begins at `start` in `out`, leaving `out`
+/// positioned where it was.
+pub(crate) fn replace_comment<W: Write + Seek>(
+    out: &mut W,
+    start: u64,
+    comment: &str,
+) -> IoResult<()> {
+    let end = out.stream_position()?;
+    out.seek(SeekFrom::Start(start + SIGNATURE.len() as u64))?;
+    write!(out, "{:1$}", comment, COMMENT_LEN)?;
+    out.seek(SeekFrom::Start(end))?;
+    Ok(())
+}
+
+/// Return the data section of a single variable file, which is that variable's group entry.
+fn var_entry(file: &[u8]) -> IoResult<&[u8]> {
+    if file.len() < HEADER_LEN + 2 || &file[..SIGNATURE.len()] != SIGNATURE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Not a TI variable file: signature is missing",
+        ));
+    }
+
+    let data_len = u16::from_le_bytes([file[HEADER_LEN - 2], file[HEADER_LEN - 1]]) as usize;
+    if file.len() != HEADER_LEN + data_len + 2 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "Variable file data section should be {} bytes but file is {} bytes long",
+                data_len,
+                file.len()
+            ),
+        ));
+    }
+
+    Ok(&file[HEADER_LEN..HEADER_LEN + data_len])
+}
+
+/// Compute the file checksum over a data section: the low 16 bits of the sum of all bytes.
+fn checksum(data: &[u8]) -> u16 {
+    data.iter()
+        .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16))
+}
+
+/// A group of two variables contains both entries and a checksum over both of them.
+#[test]
+fn group_concatenates_entries() {
+    use std::io::Cursor;
+    use tifiles::VariableType;
+
+    let var = |name: &str, data: &[u8]| {
+        let mut w = tifiles::Writer::new(Cursor::new(Vec::new()), VariableType::AppVar, name, true)
+            .unwrap();
+        w.write_all(data).unwrap();
+        w.close().unwrap().into_inner()
+    };
+    let a = var("A", b"one");
+    let b = var("B", b"two!");
+
+    let mut group = Writer::new(Vec::new());
+    group.add_var(&a).unwrap();
+    group.add_var(&b).unwrap();
+    let group = group.close().unwrap();
+
+    let entries = [var_entry(&a).unwrap(), var_entry(&b).unwrap()].concat();
+    assert_eq!(&group[..11], SIGNATURE);
+    assert_eq!(
+        u16::from_le_bytes([group[53], group[54]]) as usize,
+        entries.len()
+    );
+    assert_eq!(&group[HEADER_LEN..group.len() - 2], &*entries);
+
+    // A single variable's checksum covers its data section, so it's the sum of the two
+    let file_checksum = |f: &[u8]| u16::from_le_bytes([f[f.len() - 2], f[f.len() - 1]]);
+    assert_eq!(
+        file_checksum(&group),
+        file_checksum(&a).wrapping_add(file_checksum(&b))
+    );
+}
+
+/// Groups can be as large as their 16-bit data section length can say, and no larger.
+#[test]
+fn group_length_must_fit() {
+    use std::io::Cursor;
+    use tifiles::VariableType;
+
+    let var = |len: usize| {
+        let mut w =
+            tifiles::Writer::new(Cursor::new(Vec::new()), VariableType::AppVar, "A", true).unwrap();
+        w.write_all(&vec![0; len]).unwrap();
+        w.close().unwrap().into_inner()
+    };
+    // Variables can't be that large by themselves, so it takes two
+    let overhead = var_entry(&var(0)).unwrap().len();
+    let first = var(40000);
+    let rest = u16::MAX as usize - var_entry(&first).unwrap().len() - overhead;
+
+    let mut group = Writer::new(Vec::new());
+    group.add_var(&first).unwrap();
+    group.add_var(&var(rest)).unwrap();
+    let group = group.close().unwrap();
+    assert_eq!(u16::from_le_bytes([group[53], group[54]]), u16::MAX);
+
+    let mut group = Writer::new(Vec::new());
+    group.add_var(&first).unwrap();
+    group.add_var(&var(rest + 1)).unwrap();
+    let e = group.close().unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+}
+
+/// Reading a group returns each variable it was built from.
+#[test]
+fn read_returns_each_var() {
+    use std::io::Cursor;
+
+    let var = |name: &str, data: &[u8], archived| {
+        let mut w = tifiles::Writer::new(
+            Cursor::new(Vec::new()),
+            VariableType::AppVar,
+            name,
+            archived,
+        )
+        .unwrap();
+        w.write_all(data).unwrap();
+        w.close().unwrap().into_inner()
+    };
+    let mut group = Writer::new(Vec::new());
+    group.set_comment("two vars");
+    group.add_var(&var("ONE", b"one", true)).unwrap();
+    group.add_var(&var("TWO", b"two!", false)).unwrap();
+    let mut group = group.close().unwrap();
+
+    let contents = read(&group).unwrap();
+    assert_eq!(contents.comment, "two vars");
+    assert!(contents.checksum_valid);
+    let vars: Vec<_> = contents
+        .vars
+        .iter()
+        .map(|v| (v.name.as_str(), v.archived, v.data.as_slice()))
+        .collect();
+    assert_eq!(
+        vars,
+        [("ONE", true, &b"one"[..]), ("TWO", false, &b"two!"[..])]
+    );
+    // The calculator stores appvars with their length first
+    assert_eq!(contents.vars[0].stored_data(), b"\x03\0one");
+
+    *group.last_mut().unwrap() ^= 1;
+    assert!(!read(&group).unwrap().checksum_valid);
+}
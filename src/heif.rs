@@ -0,0 +1,41 @@
+//! Decoding HEIF images, like the HEIC photos iPhones take
+//!
+//! The image crate can't decode HEIF, so its primary image is decoded with libheif instead,
+//! with the rotation and cropping the file specifies already applied.
+use std::io::{Error, ErrorKind, Result as IoResult};
+
+use image::RgbaImage;
+use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+fn decode_error(e: impl std::fmt::Display) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Unable to decode image: {}", e),
+    )
+}
+
+/// Decode the primary image of a HEIF file.
+pub(crate) fn decode(data: &[u8]) -> IoResult<RgbaImage> {
+    let heif = LibHeif::new();
+    let context = HeifContext::read_from_bytes(data).map_err(decode_error)?;
+    let handle = context.primary_image_handle().map_err(decode_error)?;
+    let image = heif
+        .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(decode_error)?;
+    let plane = image
+        .planes()
+        .interleaved
+        .ok_or_else(|| decode_error("libheif gave no RGBA pixels"))?;
+
+    // Rows may be padded past the end of their pixels
+    let row_len = plane.width as usize * 4;
+    let pixels = plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| &row[..row_len])
+        .copied()
+        .collect();
+    RgbaImage::from_raw(plane.width, plane.height, pixels)
+        .ok_or_else(|| decode_error("libheif gave too few pixels"))
+}
@@ -0,0 +1,150 @@
+//! Index appvars listing the images in a slideshow
+//!
+//! A viewer showing several images can read an index to list them without knowing their names in
+//! advance. It has a `HDIDXV10` signature and the number of images as a 16-bit little-endian
+//! integer, followed for each image by its 8-character name, 2-character var prefix, width and
+//! height in pixels as 16-bit little-endian integers, and the number of columns and rows of tiles
+//! as a byte each.
+use std::io::{Error, ErrorKind, Result as IoResult, Seek, Write};
+
+use tifiles::VariableType;
+
+use crate::QuantizedImage;
+
+const SIGNATURE: &[u8] = b"HDIDXV10";
+/// Bytes each image takes in an index.
+const ENTRY_LEN: usize = 8 + 2 + 2 + 2 + 1 + 1;
+
+/// Name of the index appvar unless another is chosen.
+pub const DEFAULT_NAME: &str = "HDINDEX";
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// One image listed in an index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Name of the image, padded with underscores to 8 characters.
+    pub name: String,
+    pub var_prefix: String,
+    /// Width of the image in pixels, including padding to fill whole tiles.
+    pub width: u16,
+    /// Height of the image in pixels, including padding to fill whole tiles.
+    pub height: u16,
+    pub columns: u8,
+    pub rows: u8,
+}
+
+impl Entry {
+    /// Describe a converted image.
+    pub fn new(image: &QuantizedImage) -> Self {
+        Entry {
+            name: image.name.clone(),
+            var_prefix: image.var_prefix.clone(),
+            width: image.width() as u16,
+            height: image.height() as u16,
+            columns: image.width_tiles() as u8,
+            rows: image.height_tiles() as u8,
+        }
+    }
+}
+
+/// The images listed in an index appvar, in the order a viewer shows them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Index {
+    pub entries: Vec<Entry>,
+}
+
+impl Index {
+    /// Return whether appvar data is an index.
+    pub fn is_index(data: &[u8]) -> bool {
+        data.starts_with(SIGNATURE)
+    }
+
+    /// Read the data of an index appvar.
+    pub fn read(data: &[u8]) -> IoResult<Self> {
+        if !Self::is_index(data) || data.len() < 10 {
+            return Err(invalid("Not an index appvar: signature is missing".into()));
+        }
+        let count = u16::from_le_bytes([data[8], data[9]]) as usize;
+        let entries = data[10..]
+            .get(..count * ENTRY_LEN)
+            .ok_or_else(|| invalid("Index appvar ends early".into()))?;
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        Ok(Index {
+            entries: entries
+                .chunks_exact(ENTRY_LEN)
+                .map(|entry| Entry {
+                    name: text(&entry[..8]),
+                    var_prefix: text(&entry[8..10]),
+                    width: u16::from_le_bytes([entry[10], entry[11]]),
+                    height: u16::from_le_bytes([entry[12], entry[13]]),
+                    columns: entry[14],
+                    rows: entry[15],
+                })
+                .collect(),
+        })
+    }
+
+    /// Add an image to the end of the index, replacing any already listed with its var prefix,
+    /// since their appvars would have the same names.
+    pub fn add(&mut self, entry: Entry) {
+        self.entries
+            .retain(|existing| existing.var_prefix != entry.var_prefix);
+        self.entries.push(entry);
+    }
+
+    /// Write the index as an appvar file named `name`.
+    ///
+    /// This fails if there are more images than an index can list.
+    pub fn write<W: Write + Seek>(&self, out: W, name: &str, archived: bool) -> IoResult<W> {
+        let count = u16::try_from(self.entries.len()).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "an index can list at most {} images, not {}",
+                    u16::MAX,
+                    self.entries.len()
+                ),
+            )
+        })?;
+        let mut writer = tifiles::Writer::new(out, VariableType::AppVar, name, archived)?;
+        writer.write_all(SIGNATURE)?;
+        writer.write_all(&count.to_le_bytes())?;
+        for entry in &self.entries {
+            write!(writer, "{:8}{:2}", entry.name, entry.var_prefix)?;
+            writer.write_all(&entry.width.to_le_bytes())?;
+            writer.write_all(&entry.height.to_le_bytes())?;
+            writer.write_all(&[entry.columns, entry.rows])?;
+        }
+        writer.close()
+    }
+}
+
+/// Indexes read back as written, and adding an image again replaces it.
+#[test]
+fn index_round_trips() {
+    use crate::group;
+    use image::RgbaImage;
+    use std::io::Cursor;
+
+    let first = crate::Image::from_rgba(RgbaImage::new(100, 50), "first", "FI").quantize();
+    let second = crate::Image::from_rgba(RgbaImage::new(80, 80), "second", "SE").quantize();
+    let mut index = Index::default();
+    index.add(Entry::new(&first));
+    index.add(Entry::new(&second));
+    index.add(Entry::new(&first));
+    assert_eq!(index.entries.len(), 2);
+    assert_eq!(index.entries[1].var_prefix, "FI");
+    assert_eq!((index.entries[1].width, index.entries[1].columns), (160, 2));
+
+    let file = index
+        .write(Cursor::new(Vec::new()), DEFAULT_NAME, true)
+        .unwrap()
+        .into_inner();
+    let var = group::read(&file).unwrap().vars.remove(0);
+    assert_eq!(var.name, DEFAULT_NAME);
+    assert!(Index::is_index(&var.data));
+    assert_eq!(Index::read(&var.data).unwrap(), index);
+}
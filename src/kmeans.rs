@@ -0,0 +1,117 @@
+//! Refining palettes with k-means clustering
+//!
+//! Each pass maps every pixel to its nearest palette color, then moves each color to the average
+//! of the pixels mapped to it. Passes repeat until the palette stops changing or the pass limit is
+//! reached, so a palette gets no worse for the pixels it's refined against.
+use std::convert::Infallible;
+
+use rgb::RGBA8 as RGBA;
+
+use crate::color_space::Matching;
+use crate::nearest::PaletteSearch;
+
+/// One pass of refinement, accumulating the pixels nearest each palette color.
+pub(crate) struct Pass {
+    palette: Vec<RGBA>,
+    search: PaletteSearch,
+    sums: Vec<[u64; 3]>,
+    counts: Vec<u64>,
+}
+
+impl Pass {
+    /// Start a pass matching pixels to the nearest palette colors by `matching`.
+    pub(crate) fn new(palette: Vec<RGBA>, matching: Matching) -> Self {
+        Pass {
+            search: PaletteSearch::new(&palette, matching),
+            sums: vec![[0; 3]; palette.len()],
+            counts: vec![0; palette.len()],
+            palette,
+        }
+    }
+
+    /// Assign each pixel of RGBA pixel data to its nearest palette color, ignoring alpha.
+    pub(crate) fn add(&mut self, pixels: &[u8]) {
+        for p in pixels.chunks_exact(4) {
+            let index = self.search.nearest([p[0] as f32, p[1] as f32, p[2] as f32]) as usize;
+            for (sum, &component) in self.sums[index].iter_mut().zip(&p[..3]) {
+                *sum += component as u64;
+            }
+            self.counts[index] += 1;
+        }
+    }
+
+    /// Return the refined palette, and whether any color moved.
+    ///
+    /// Colors that no pixel was nearest stay where they were.
+    pub(crate) fn finish(self) -> (Vec<RGBA>, bool) {
+        let mut changed = false;
+        let palette = self
+            .palette
+            .iter()
+            .zip(self.sums.iter().zip(&self.counts))
+            .map(|(&color, (sum, &count))| {
+                if count == 0 {
+                    return color;
+                }
+                let [r, g, b] = sum.map(|sum| ((sum + count / 2) / count) as u8);
+                let refined = RGBA::new(r, g, b, color.a);
+                changed |= refined != color;
+                refined
+            })
+            .collect();
+        (palette, changed)
+    }
+}
+
+/// Refine a palette against RGBA pixel data with up to `passes` passes.
+pub(crate) fn refine(
+    palette: Vec<RGBA>,
+    pixels: &[u8],
+    passes: u32,
+    matching: Matching,
+) -> Vec<RGBA> {
+    let Ok(palette) = refine_with(palette, passes, matching, |pass| {
+        pass.add(pixels);
+        Ok::<_, Infallible>(())
+    });
+    palette
+}
+
+/// Refine a palette with up to `passes` passes, where `add` adds every pixel to each pass.
+pub(crate) fn refine_with<E>(
+    mut palette: Vec<RGBA>,
+    passes: u32,
+    matching: Matching,
+    mut add: impl FnMut(&mut Pass) -> Result<(), E>,
+) -> Result<Vec<RGBA>, E> {
+    for _ in 0..passes {
+        let mut pass = Pass::new(palette, matching);
+        add(&mut pass)?;
+        let changed;
+        (palette, changed) = pass.finish();
+        if !changed {
+            break;
+        }
+    }
+    Ok(palette)
+}
+
+/// Refinement moves badly placed colors to the clusters of pixels near them.
+#[test]
+fn refine_moves_to_clusters() {
+    let pixels: Vec<u8> = [[10, 10, 10], [20, 20, 20], [200, 0, 0], [220, 0, 0]]
+        .iter()
+        .flat_map(|&[r, g, b]| [r, g, b, 255])
+        .collect();
+    let palette = vec![RGBA::new(0, 0, 0, 255), RGBA::new(255, 0, 0, 255)];
+
+    assert_eq!(
+        refine(
+            palette,
+            &pixels,
+            10,
+            Matching::Metric(crate::ColorMetric::Rgb)
+        ),
+        [RGBA::new(15, 15, 15, 255), RGBA::new(210, 0, 0, 255)]
+    );
+}
@@ -1,67 +1,371 @@
-use std::io::{BufRead, Write};
-use std::io::{Result as IoResult, Seek};
+use std::io::{BufRead, Cursor, Read, Write};
+use std::io::{Result as IoResult, Seek, SeekFrom};
 use std::iter::repeat;
 
 use image::{Rgba, RgbaImage};
-use imagequant::RGBA;
-use rgb::FromSlice;
+use rgb::RGBA8 as RGBA;
 use tifiles::VariableType;
 
+mod adjust;
+mod animation;
+mod color_space;
+mod compress;
+pub mod decode;
+mod depth;
+mod direct;
+mod dither;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod group;
+#[cfg(feature = "heif")]
+mod heif;
+pub mod index;
+mod kmeans;
+pub mod metadata;
+mod metrics;
+mod naming;
+mod nearest;
+#[cfg(feature = "node")]
+pub mod node;
+mod numworks;
+pub mod palette;
+#[cfg(feature = "pdf")]
+mod pdf;
+mod picture;
+#[cfg(feature = "python")]
+mod py;
+mod python;
+mod quantizer;
+pub mod screen;
+mod source;
+mod stream;
+#[cfg(feature = "svg")]
+mod svg;
+pub mod thumbnail;
+mod ti_python;
+mod transform;
+pub mod viewer;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use animation::Frame;
+pub use color_space::{ColorMetric, ColorSpace};
+pub use compress::{Compression, MAX_ZX0_LEN};
+pub use depth::BitDepth;
+pub use direct::MAX_DIRECT_COLOR_PIXELS;
+pub use dither::Dither;
+pub use metrics::Quality;
+pub use naming::{check_var_prefix, NameTemplate, TileName};
+pub use numworks::{NUMWORKS_HEIGHT, NUMWORKS_WIDTH};
+pub use picture::{Picture, PictureOptions, PICTURE_HEIGHT, PICTURE_WIDTH};
+pub use quantizer::Quantizer;
+pub use stream::StreamingImage;
+pub use ti_python::{TI_PYTHON_HEIGHT, TI_PYTHON_WIDTH};
+pub use transform::{LcdScale, Rotation, ScaleMode, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Options controlling how an [`Image`] is quantized.
+#[derive(Debug, Clone)]
+pub struct QuantizeOptions {
+    /// The algorithm that generates the palette, unless `palette` is specified.
+    pub quantizer: Quantizer,
+    /// The minimum and target quality of a generated palette, from 0 to 100.
+    ///
+    /// Quantizing fails if the minimum can't be reached with `max_colors` colors, while a lower
+    /// target makes libimagequant stop refining the palette sooner. Only libimagequant uses this.
+    pub quality: (u8, u8),
+    /// How much quality libimagequant trades for speed, from 1 (slowest) to 10 (fastest).
+    pub speed: u8,
+    /// The most k-means passes to refine a generated palette with, or 0 not to.
+    ///
+    /// Each pass moves every color to the average of the pixels nearest it, which takes about
+    /// as long as mapping the image to the palette. This improves photos in particular.
+    pub refine_passes: u32,
+    /// The color space in which palettes are generated and pixels are matched to them.
+    ///
+    /// libimagequant always generates palettes in its own perceptual space, and only maps pixels
+    /// to them itself in sRGB.
+    pub color_space: ColorSpace,
+    /// How differences between colors are measured when matching pixels to the palette.
+    ///
+    /// Without one, colors are matched by distance in `color_space`.
+    pub color_metric: Option<ColorMetric>,
+    /// How pixels are dithered when mapped to the palette.
+    pub dither: Dither,
+    /// How strongly to dither, from 0 (not at all) to 1 (fully).
+    ///
+    /// Weaker dithering is less noisy and makes tiles compress better.
+    pub dither_strength: f32,
+    /// The largest number of colors the palette may contain, from 2 to 256.
+    ///
+    /// Smaller palettes compress better and leave room for other colors on the calculator.
+    /// This has no effect if `palette` is specified.
+    pub max_colors: u32,
+    /// A fixed palette to map the image to, instead of generating one.
+    ///
+    /// Colors keep their order, so pixels with a given palette index in the output are the color
+    /// at that index here. See [`palette::read`] to load one from a file.
+    pub palette: Option<Vec<RGBA>>,
+    /// Colors to place at the start of the palette, which the image is never mapped to.
+    ///
+    /// These reserve palette entries for other uses like user interface elements, and count
+    /// toward `max_colors`.
+    pub reserved_colors: Vec<RGBA>,
+    /// The index of a reserved color to map fully transparent pixels to.
+    ///
+    /// Padding added to make the image a whole number of tiles is also transparent. Without this,
+    /// transparent pixels are the background color.
+    pub transparent_index: Option<u8>,
+    /// The color that partly transparent pixels are blended over, which also fills padding.
+    pub background: RGBA,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> Self {
+        QuantizeOptions {
+            quantizer: Quantizer::default(),
+            quality: (0, 100),
+            speed: 4,
+            refine_passes: 0,
+            color_space: ColorSpace::default(),
+            color_metric: None,
+            dither: Dither::default(),
+            dither_strength: 1.,
+            max_colors: 256,
+            palette: None,
+            reserved_colors: Vec::new(),
+            transparent_index: None,
+            background: RGBA::new(0, 0, 0, 255),
+        }
+    }
+}
+
+impl QuantizeOptions {
+    fn matching(&self) -> color_space::Matching {
+        match self.color_metric {
+            Some(metric) => color_space::Matching::Metric(metric),
+            None => color_space::Matching::Space(self.color_space),
+        }
+    }
+}
+
+/// Options controlling how image files are decoded to an [`Image`].
+#[derive(Debug, Default, Clone)]
+pub struct DecodeOptions {
+    /// The density to rasterize vector images and document pages at, in dots per inch.
+    ///
+    /// Without one, they're rasterized to fit the calculator screen.
+    pub density: Option<f32>,
+    /// The page of documents to convert, counting from 0 for the first.
+    pub page: usize,
+}
+
+/// File extensions of images in formats that are decoded without the image crate, when their
+/// features are enabled.
+pub const OTHER_EXTENSIONS: &[&str] = &[
+    #[cfg(feature = "heif")]
+    "heic",
+    #[cfg(feature = "heif")]
+    "heif",
+    #[cfg(feature = "pdf")]
+    "pdf",
+    #[cfg(feature = "svg")]
+    "svg",
+];
+
+/// Major brands of the HEIF container that iPhone photos and other HEIC images use.
+const HEIF_BRANDS: [&[u8]; 8] = [
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"mif1", b"msf1",
+];
+
+/// Formats of images that the image crate can't decode, which people commonly try to convert.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum OtherFormat {
+    Heif,
+    Avif,
+    Pdf,
+    Svg,
+}
+
+impl OtherFormat {
+    fn name(self) -> &'static str {
+        match self {
+            OtherFormat::Heif => "HEIF/HEIC",
+            OtherFormat::Avif => "AVIF",
+            OtherFormat::Pdf => "PDF",
+            OtherFormat::Svg => "SVG",
+        }
+    }
+}
+
+/// Return the format of a file starting with `header`, if it's one the image crate can't decode.
+fn other_format(header: &[u8]) -> Option<OtherFormat> {
+    if header.len() >= 12 && &header[4..8] == b"ftyp" && HEIF_BRANDS.contains(&&header[8..12]) {
+        return Some(OtherFormat::Heif);
+    }
+    if cfg!(not(feature = "avif"))
+        && header.len() >= 12
+        && &header[4..8] == b"ftyp"
+        && matches!(&header[8..12], b"avif" | b"avis")
+    {
+        return Some(OtherFormat::Avif);
+    }
+    if header.starts_with(b"%PDF-") {
+        return Some(OtherFormat::Pdf);
+    }
+    let text = header.strip_prefix(b"\xef\xbb\xbf").unwrap_or(header);
+    let text = &text[text.iter().take_while(|c| c.is_ascii_whitespace()).count()..];
+    let is_xml = [&b"<?xml"[..], b"<!DOCTYPE svg", b"<svg"]
+        .iter()
+        .any(|start| text.starts_with(start));
+    if is_xml && text.windows(4).any(|w| w == b"<svg") {
+        return Some(OtherFormat::Svg);
+    }
+    None
+}
+
+/// Return the format of `data` if it's one the image crate can't decode, leaving it positioned
+/// where it was.
+fn sniff_other_format<R: BufRead + Seek>(data: &mut R) -> IoResult<Option<OtherFormat>> {
+    let start = data.stream_position()?;
+    let mut header = Vec::with_capacity(256);
+    data.by_ref().take(256).read_to_end(&mut header)?;
+    data.seek(SeekFrom::Start(start))?;
+    Ok(other_format(&header))
+}
+
+fn unsupported(format: OtherFormat, why: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!(
+            "Unable to decode image: {} images {}, so convert it to PNG first",
+            format.name(),
+            why
+        ),
+    )
+}
+
+/// Decode `data` if it's an image the image crate can't decode, leaving it positioned where it
+/// was otherwise.
+///
+/// Images in formats that aren't supported at all, or whose features aren't enabled, fail with
+/// an explanation. Without this, images like HEIF, SVG and PDF documents fail to decode because
+/// their format can't be guessed.
+#[cfg_attr(not(any(feature = "pdf", feature = "svg")), allow(unused_variables))]
+fn decode_other<R: BufRead + Seek>(
+    data: &mut R,
+    options: &DecodeOptions,
+) -> IoResult<Option<RgbaImage>> {
+    match sniff_other_format(data)? {
+        None => Ok(None),
+        #[cfg(feature = "heif")]
+        Some(OtherFormat::Heif) => {
+            let mut heif = Vec::new();
+            data.read_to_end(&mut heif)?;
+            heif::decode(&heif).map(Some)
+        }
+        #[cfg(feature = "pdf")]
+        Some(OtherFormat::Pdf) => {
+            let mut pdf = Vec::new();
+            data.read_to_end(&mut pdf)?;
+            pdf::rasterize(&pdf, options.page, options.density).map(Some)
+        }
+        #[cfg(feature = "svg")]
+        Some(OtherFormat::Svg) => {
+            let mut svg = Vec::new();
+            data.read_to_end(&mut svg)?;
+            svg::rasterize(&svg, options.density).map(Some)
+        }
+        Some(format) => Err(unsupported(format, "aren't supported")),
+    }
+}
+
+/// Fail with an explanation if `data` is an image the image crate can't decode, which can't be
+/// streamed even if it can be decoded whole, leaving it positioned where it was.
+fn reject_other<R: BufRead + Seek>(data: &mut R) -> IoResult<()> {
+    match sniff_other_format(data)? {
+        None => Ok(()),
+        Some(format) => Err(unsupported(format, "can't be streamed")),
+    }
+}
+
+fn assert_valid_tile_size(width: u32, height: u32) {
+    assert!(
+        (1..=255).contains(&width) && (1..=255).contains(&height),
+        "tile size {}x{} must be between 1 and 255 pixels",
+        width,
+        height
+    );
+}
+
+#[derive(Clone)]
 pub struct Image {
+    /// The image as loaded, which may be any size and have transparent pixels.
     input: RgbaImage,
     var_prefix: String,
     name: String,
-}
-
-/// Compute the integer ceiling of `lhs / rhs`
-fn div_ceil(lhs: u32, rhs: u32) -> u32 {
-    (lhs + rhs - 1) / rhs
+    /// Width and height of each tile.
+    tile_size: (u32, u32),
 }
 
 impl Image {
-    const TILE_SIZE: u32 = 80;
+    /// Width and height of tiles unless [`set_tile_size`](Image::set_tile_size) is used, which is
+    /// what HD Picture Viewer expects.
+    pub const DEFAULT_TILE_SIZE: u32 = 80;
 
     pub fn new<R: BufRead + Seek>(data: R, name: &str, var_prefix: &str) -> IoResult<Self> {
+        Self::new_with(data, name, var_prefix, &DecodeOptions::default())
+    }
+
+    /// Decode an image file as specified by `options`.
+    pub fn new_with<R: BufRead + Seek>(
+        mut data: R,
+        name: &str,
+        var_prefix: &str,
+        options: &DecodeOptions,
+    ) -> IoResult<Self> {
+        if let Some(image) = decode_other(&mut data, options)? {
+            return Ok(Self::from_rgba(image, name, var_prefix));
+        }
         let loaded_image = match image::io::Reader::new(data).with_guessed_format()?.decode() {
             Ok(i) => i,
             Err(e) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    format!("Unable to decode image: {}", e),
-                ));
+                return Err(std::io::Error::other(format!(
+                    "Unable to decode image: {}",
+                    e
+                )));
             }
         };
 
-        // Generate a black image that's rounded to a multiple of TILE_SIZE
-        let mut image = image::ImageBuffer::from_pixel(
-            div_ceil(loaded_image.width(), Self::TILE_SIZE) * Self::TILE_SIZE,
-            div_ceil(loaded_image.height(), Self::TILE_SIZE) * Self::TILE_SIZE,
-            Rgba([0u8, 0, 0, 255]),
-        );
-
-        // Paste the loaded image onto the black canvas, which also blends down so we know
-        // we're fully opaque.
-        image::imageops::overlay(&mut image, &loaded_image, 0, 0);
-        assert_eq!(
-            image.height() % Self::TILE_SIZE,
-            0,
-            "Image height {} isn't a multiple of tile size",
-            image.height()
-        );
-        assert_eq!(
-            image.width() % Self::TILE_SIZE,
-            0,
-            "Image width {} isn't a multiple of tile size",
-            image.width()
-        );
+        Ok(Self::from_rgba(loaded_image.into_rgba8(), name, var_prefix))
+    }
 
+    /// Wrap an image that's already been decoded.
+    pub fn from_rgba(image: RgbaImage, name: &str, var_prefix: &str) -> Self {
         assert_eq!(var_prefix.len(), 2);
 
-        Ok(Image {
+        Image {
             input: image,
             name: Self::generate_calc_name(name),
             var_prefix: var_prefix.to_string(),
-        })
+            tile_size: (Self::DEFAULT_TILE_SIZE, Self::DEFAULT_TILE_SIZE),
+        }
+    }
+
+    /// Return the width and height of the image in pixels, before padding to fill whole tiles.
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.input.dimensions()
+    }
+
+    /// Set the two letters that start the names of the image's appvars.
+    pub fn set_var_prefix(&mut self, var_prefix: &str) {
+        assert_eq!(var_prefix.len(), 2);
+        self.var_prefix = var_prefix.to_string();
+    }
+
+    /// Set the dimensions of the tiles the image is split into, each from 1 to 255 pixels.
+    pub fn set_tile_size(&mut self, width: u32, height: u32) {
+        assert_valid_tile_size(width, height);
+        self.tile_size = (width, height);
     }
 
     /// Transform a string into one safe to use as a calculator variable name.
@@ -81,34 +385,273 @@ impl Image {
             .collect()
     }
 
-    /// Compute the palette for the loaded image.
+    /// Return the image padded to a whole number of tiles and made fully opaque.
+    fn canvas(&self, background: RGBA) -> RgbaImage {
+        // Generate a background-colored image that's rounded to a multiple of the tile size
+        let (tile_width, tile_height) = self.tile_size;
+        let mut canvas = image::ImageBuffer::from_pixel(
+            self.input.width().div_ceil(tile_width) * tile_width,
+            self.input.height().div_ceil(tile_height) * tile_height,
+            Rgba([background.r, background.g, background.b, 255]),
+        );
+
+        // Paste the loaded image onto the opaque canvas, which also blends down. Blending can
+        // round alpha slightly below opaque, so force it.
+        image::imageops::overlay(&mut canvas, &self.input, 0, 0);
+        for pixel in canvas.pixels_mut() {
+            pixel[3] = 255;
+        }
+        canvas
+    }
+
+    /// Return whether the pixel at a position on the canvas is fully transparent, which includes
+    /// padding outside the loaded image.
+    fn is_transparent(&self, x: u32, y: u32) -> bool {
+        x >= self.input.width() || y >= self.input.height() || self.input.get_pixel(x, y)[3] == 0
+    }
+
+    /// Compute the palette for the loaded image with default options.
     ///
     /// This stage can take a long time at high quality settings.
     pub fn quantize(self) -> QuantizedImage {
-        let attrs = imagequant::Attributes::new();
-        let bitmap = (&*self.input).as_rgba();
-        let mut image = attrs
-            .new_image_borrowed(
-                bitmap,
-                self.input.width() as usize,
-                self.input.height() as usize,
-                0.,
-            )
-            .expect("failed to construct imagequant image");
+        self.quantize_with(&QuantizeOptions::default())
+            .expect("default options have no minimum quality")
+    }
 
-        let mut result = attrs
-            .quantize(&mut image)
-            .expect("failed to quantize image");
-        let (palette, data) = result.remapped(&mut image).expect("failed to remap image");
+    /// Compute the palette for the loaded image and map the image to it.
+    ///
+    /// If the options specify a palette, it is used as-is instead. This fails if the generated
+    /// palette can't reach the minimum [`quality`](QuantizeOptions::quality).
+    pub fn quantize_with(self, options: &QuantizeOptions) -> IoResult<QuantizedImage> {
+        let canvas = self.canvas(options.background);
+        let (palette, data) = match &options.palette {
+            Some(palette) => {
+                let data = Self::remap(&canvas, palette, options);
+                (palette.clone(), data)
+            }
+            None => Self::generate_palette(&canvas, options)?,
+        };
+
+        // Reserved colors go first, which shifts every index the image uses
+        let reserved = &options.reserved_colors;
+        assert!(
+            reserved.len() + palette.len() <= palette::MAX_COLORS,
+            "palette has too many colors to reserve {}",
+            reserved.len()
+        );
+        let palette = reserved.iter().chain(&palette).copied().collect();
+        let mut data: Vec<u8> = data.into_iter().map(|i| i + reserved.len() as u8).collect();
+
+        if let Some(index) = options.transparent_index {
+            assert!(
+                (index as usize) < reserved.len(),
+                "transparent index {} isn't a reserved color",
+                index
+            );
+            for (i, pixel) in data.iter_mut().enumerate() {
+                let (x, y) = (i as u32 % canvas.width(), i as u32 / canvas.width());
+                if self.is_transparent(x, y) {
+                    *pixel = index;
+                }
+            }
+        }
 
-        QuantizedImage {
+        Ok(QuantizedImage {
             var_prefix: self.var_prefix,
             name: self.name,
-            width: self.input.width(),
-            height: self.input.height(),
+            width: canvas.width(),
+            height: canvas.height(),
+            tile_size: self.tile_size,
+            compression: Compression::default(),
+            bit_depth: BitDepth::default(),
+            lcd_scale: LcdScale::default(),
+            direct_color: false,
+            archived: true,
+            comment: None,
+            name_template: NameTemplate::default(),
+            frame: 0,
             palette,
             data,
-        }
+            first_row: 0,
+            transparent_index: options.transparent_index,
+        })
+    }
+}
+
+impl Image {
+    /// Generate one palette suited to all of the given images.
+    ///
+    /// Passing it to [`quantize_with`](Image::quantize_with) for each image makes them all share
+    /// the same colors. The palette leaves room for any reserved colors, but doesn't include them
+    /// since `quantize_with` adds them.
+    pub fn shared_palette<'a, I: IntoIterator<Item = &'a Image>>(
+        images: I,
+        options: &QuantizeOptions,
+    ) -> IoResult<Vec<RGBA>> {
+        let images: Vec<&Image> = images.into_iter().collect();
+        let palette = match options.quantizer {
+            #[cfg(feature = "imagequant")]
+            Quantizer::ImageQuant => {
+                let attrs = Self::quantizer_attributes(options);
+                let mut histogram = imagequant::Histogram::new(&attrs);
+                for image in &images {
+                    let canvas = image.canvas(options.background);
+                    histogram
+                        .add_image(&attrs, &mut Self::quantizer_image(&canvas, &attrs))
+                        .expect("failed to add image to histogram");
+                }
+
+                histogram
+                    .quantize(&attrs)
+                    .map_err(quantizer::imagequant_error)?
+                    .palette_vec()
+            }
+            Quantizer::NeuQuant => {
+                let pixels: Vec<u8> = images
+                    .iter()
+                    .flat_map(|image| image.canvas(options.background).into_raw())
+                    .collect();
+                quantizer::neuquant_palette(
+                    &pixels,
+                    Self::palette_size(options),
+                    options.color_space,
+                )
+            }
+            Quantizer::MedianCut | Quantizer::Octree => {
+                let mut histogram =
+                    quantizer::Histogram::new(options.quantizer, options.color_space);
+                for image in &images {
+                    histogram.add(&image.canvas(options.background));
+                }
+                histogram.palette(Self::palette_size(options))
+            }
+        };
+
+        kmeans::refine_with(palette, options.refine_passes, options.matching(), |pass| {
+            for image in &images {
+                pass.add(&image.canvas(options.background));
+            }
+            Ok(())
+        })
+    }
+
+    /// Return the number of colors to generate, which leaves room for reserved colors.
+    fn palette_size(options: &QuantizeOptions) -> u32 {
+        let size = options.max_colors - options.reserved_colors.len() as u32;
+        assert!(
+            (2..=256).contains(&size),
+            "palette size should be between 2 and 256 after reserving colors"
+        );
+        size
+    }
+
+    #[cfg(feature = "imagequant")]
+    fn quantizer_attributes(options: &QuantizeOptions) -> imagequant::Attributes {
+        let mut attrs = imagequant::Attributes::new();
+        attrs
+            .set_max_colors(Self::palette_size(options))
+            .expect("palette size should be between 2 and 256 after reserving colors");
+        attrs
+            .set_quality(options.quality.0, options.quality.1)
+            .expect("quality should be at most 100, with the minimum no more than the target");
+        attrs
+            .set_speed(options.speed as i32)
+            .expect("speed should be between 1 and 10");
+        attrs
+    }
+
+    #[cfg(feature = "imagequant")]
+    fn quantizer_image<'a>(
+        canvas: &'a RgbaImage,
+        attrs: &imagequant::Attributes,
+    ) -> imagequant::Image<'a> {
+        use rgb::FromSlice;
+
+        attrs
+            .new_image_borrowed(
+                (**canvas).as_rgba(),
+                canvas.width() as usize,
+                canvas.height() as usize,
+                0.,
+            )
+            .expect("failed to construct imagequant image")
+    }
+
+    /// Map a canvas to a palette as the options say, returning palette indices.
+    fn remap(canvas: &RgbaImage, palette: &[RGBA], options: &QuantizeOptions) -> Vec<u8> {
+        dither::remap(
+            canvas,
+            palette,
+            options.dither,
+            options.dither_strength,
+            options.matching(),
+        )
+    }
+
+    /// Generate a palette with the chosen quantizer, returning it and the remapped image.
+    fn generate_palette(
+        canvas: &RgbaImage,
+        options: &QuantizeOptions,
+    ) -> IoResult<(Vec<RGBA>, Vec<u8>)> {
+        let palette = match options.quantizer {
+            #[cfg(feature = "imagequant")]
+            Quantizer::ImageQuant => return Self::generate_palette_imagequant(canvas, options),
+            Quantizer::NeuQuant => quantizer::neuquant_palette(
+                canvas.as_raw(),
+                Self::palette_size(options),
+                options.color_space,
+            ),
+            Quantizer::MedianCut | Quantizer::Octree => {
+                let mut histogram =
+                    quantizer::Histogram::new(options.quantizer, options.color_space);
+                histogram.add(canvas);
+                histogram.palette(Self::palette_size(options))
+            }
+        };
+        let palette = kmeans::refine(palette, canvas, options.refine_passes, options.matching());
+        let data = Self::remap(canvas, &palette, options);
+        Ok((palette, data))
+    }
+
+    /// Generate a palette with libimagequant, returning it and the remapped image.
+    #[cfg(feature = "imagequant")]
+    fn generate_palette_imagequant(
+        canvas: &RgbaImage,
+        options: &QuantizeOptions,
+    ) -> IoResult<(Vec<RGBA>, Vec<u8>)> {
+        let attrs = Self::quantizer_attributes(options);
+        let mut image = Self::quantizer_image(canvas, &attrs);
+
+        let mut result = attrs
+            .quantize(&mut image)
+            .map_err(quantizer::imagequant_error)?;
+        Ok(match options.dither {
+            // libimagequant does these itself, which also allows it to refine the palette
+            Dither::None | Dither::FloydSteinberg
+                if options.refine_passes == 0
+                    && options.color_space == ColorSpace::Rgb
+                    && options.color_metric.is_none() =>
+            {
+                result
+                    .set_dithering_level(if options.dither == Dither::None {
+                        0.
+                    } else {
+                        options.dither_strength
+                    })
+                    .expect("dithering strength should be between 0 and 1");
+                result.remapped(&mut image).expect("failed to remap image")
+            }
+            _ => {
+                let palette = kmeans::refine(
+                    result.palette_vec(),
+                    canvas,
+                    options.refine_passes,
+                    options.matching(),
+                );
+                let data = Self::remap(canvas, &palette, options);
+                (palette, data)
+            }
+        })
     }
 }
 
@@ -117,8 +660,25 @@ pub struct QuantizedImage {
     name: String,
     width: u32,
     height: u32,
+    tile_size: (u32, u32),
+    compression: Compression,
+    bit_depth: BitDepth,
+    lcd_scale: LcdScale,
+    /// Whether `data` holds a 16-bit color for each pixel instead of a palette index, from
+    /// [`Image::direct_color`].
+    direct_color: bool,
+    archived: bool,
+    comment: Option<String>,
+    name_template: NameTemplate,
+    /// The animation frame this image is, which is 0 for still images.
+    frame: u32,
     palette: Vec<RGBA>,
+    /// Pixels of whole tile rows, starting at `first_row`.
     data: Vec<u8>,
+    /// The tile row that `data` begins with, which is only nonzero for images converted in
+    /// bands by [`StreamingImage`].
+    first_row: u32,
+    transparent_index: Option<u8>,
 }
 
 impl QuantizedImage {
@@ -126,42 +686,280 @@ impl QuantizedImage {
     pub fn tiles(&self) -> Tiles<'_> {
         Tiles {
             image: self,
-            next: (0, 0),
+            next: (0, self.first_row),
         }
     }
 
+    /// Return the tile row following the last one with pixel data.
+    fn end_row(&self) -> u32 {
+        let row_len = self.width * self.tile_size.1 * self.bytes_per_pixel();
+        self.first_row + (self.data.len() / row_len as usize) as u32
+    }
+
+    /// Return how many bytes of `data` each pixel takes.
+    fn bytes_per_pixel(&self) -> u32 {
+        if self.direct_color {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Return whether pixels store their color directly, without a palette.
+    pub fn is_direct_color(&self) -> bool {
+        self.direct_color
+    }
+
+    /// Set how tile pixel data is compressed.
+    ///
+    /// HD Picture Viewer only reads the default zx0 compression; other choices write tiles with
+    /// a different signature and a header byte identifying the compression.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Set how many bits each pixel of the tiles takes.
+    ///
+    /// HD Picture Viewer only reads the default of eight; other depths write tiles and palette
+    /// appvars with different signatures. This fails if the palette has more colors than pixels
+    /// at the depth can index.
+    pub fn set_bit_depth(&mut self, depth: BitDepth) -> IoResult<()> {
+        if self.direct_color && depth != BitDepth::Eight {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "direct color images can't be packed to fewer bits per pixel",
+            ));
+        }
+        if self.palette.len() > depth.max_colors() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "the palette has {} colors, but {}-bit pixels can only use {}",
+                    self.palette.len(),
+                    depth.bits(),
+                    depth.max_colors()
+                ),
+            ));
+        }
+        self.bit_depth = depth;
+        Ok(())
+    }
+
+    /// Set the LCD mode the image is meant to be shown in, which is recorded in its palette
+    /// appvar for viewers to switch to.
+    ///
+    /// HD Picture Viewer only shows images at full resolution; others have a palette appvar with
+    /// a different signature. The image should already have been shrunk with
+    /// [`Image::scale_for_lcd`].
+    pub fn set_lcd_scale(&mut self, scale: LcdScale) {
+        self.lcd_scale = scale;
+    }
+
+    /// Set whether appvars are marked to be stored in archive, which they are by default.
+    ///
+    /// Link software sends archived variables to flash, and most images are too large to fit in
+    /// RAM anyway.
+    pub fn set_archived(&mut self, archived: bool) {
+        self.archived = archived;
+    }
+
+    /// Set how tile appvars are named.
+    ///
+    /// HD Picture Viewer only finds tiles with the default names, so other templates are for other
+    /// viewers. This fails if any tile's name would be too long or the same as another's.
+    pub fn set_name_template(&mut self, template: NameTemplate) -> IoResult<()> {
+        self.check_names(&template, self.frame)?;
+        self.name_template = template;
+        Ok(())
+    }
+
+    /// Set which frame of an animation this image is, which is part of its palette appvar's name
+    /// and of its tiles' if the name template includes one.
+    ///
+    /// This fails if any tile's name would be too long with the frame number.
+    pub fn set_frame(&mut self, frame: u32) -> IoResult<()> {
+        if frame > 9999 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "animations may have at most 10000 frames",
+            ));
+        }
+        self.check_names(&self.name_template, frame)?;
+        self.frame = frame;
+        Ok(())
+    }
+
+    /// Return the frame of an animation this image is.
+    pub fn frame(&self) -> u32 {
+        self.frame
+    }
+
+    /// Check that the names of every tile in a frame are valid and distinct.
+    fn check_names(&self, template: &NameTemplate, frame: u32) -> IoResult<()> {
+        let mut names = std::collections::HashSet::new();
+        for y in 0..self.height_tiles() {
+            for x in 0..self.width_tiles() {
+                let name = template.render(&self.var_prefix, frame, x, y);
+                let problem = if name.len() > NameTemplate::MAX_LEN {
+                    "is longer than 8 characters"
+                } else if !name.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                    "doesn't begin with a letter"
+                } else if !names.insert(name.clone()) {
+                    "is used by more than one tile"
+                } else {
+                    continue;
+                };
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Tile appvar name {:?} {}", name, problem),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the comment in the header of every appvar file, which must be ASCII and at most
+    /// [`group::COMMENT_LEN`] bytes.
+    ///
+    /// Without this, files have the comment written by [`tifiles::Writer`].
+    pub fn set_comment(&mut self, comment: &str) {
+        group::assert_valid_comment(comment);
+        self.comment = Some(comment.into());
+    }
+
+    /// Finish writing a variable file that began at `start`, applying any custom comment.
+    fn finish_var<W: Write + Seek>(&self, writer: tifiles::Writer<W>, start: u64) -> IoResult<W> {
+        let mut out = writer.close()?;
+        if let Some(comment) = &self.comment {
+            group::replace_comment(&mut out, start, comment)?;
+        }
+        Ok(out)
+    }
+
+    /// Return the palette index of transparent pixels, if there are any.
+    pub fn transparent_index(&self) -> Option<u8> {
+        self.transparent_index
+    }
+
+    /// Return the width of the image in pixels, including any padding to fill whole tiles.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Return the height of the image in pixels, including any padding to fill whole tiles.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Return the width and height of each tile.
+    pub fn tile_size(&self) -> (u32, u32) {
+        self.tile_size
+    }
+
     pub fn width_tiles(&self) -> u32 {
-        self.width / Image::TILE_SIZE
+        self.width / self.tile_size.0
     }
 
     pub fn height_tiles(&self) -> u32 {
-        self.height / Image::TILE_SIZE
+        self.height / self.tile_size.1
+    }
+
+    /// Return the colors of the palette as the calculator shows them, reduced to its 16-bit
+    /// color. Direct color images have no palette, so this is empty for them.
+    pub fn stored_palette(&self) -> Vec<RGBA> {
+        if self.direct_color {
+            return Vec::new();
+        }
+        self.palette
+            .iter()
+            .map(|color| RGBA::from(GRGB1555::from(color)))
+            .collect()
     }
 
     pub fn palette_appvar_name(&self) -> String {
-        format!("HP{:2}0000", self.var_prefix)
+        format!("HP{:2}{:04}", self.var_prefix, self.frame)
     }
 
-    pub fn write_palette_appvar<W: Write + Seek>(&self, out: W) -> IoResult<W> {
-        let mut writer =
-            tifiles::Writer::new(out, VariableType::AppVar, &self.palette_appvar_name(), true)?;
+    pub fn write_palette_appvar<W: Write + Seek>(&self, mut out: W) -> IoResult<W> {
+        if self.direct_color {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "direct color images have no palette",
+            ));
+        }
+        let start = out.stream_position()?;
+        let mut writer = tifiles::Writer::new(
+            out,
+            VariableType::AppVar,
+            &self.palette_appvar_name(),
+            self.archived,
+        )?;
 
         // Header: signature, 8-character image name, 2-character var prefix
         // and index of last image tile.
+        let extended = self.bit_depth != BitDepth::Eight || self.lcd_scale != LcdScale::Full;
         write!(
             writer,
-            "HDPALV10{:8}{:2}{:03}{:03}",
+            "{}{:8}{:2}{:03}{:03}",
+            if extended { "HDPALV11" } else { "HDPALV10" },
             self.name,
             self.var_prefix,
             self.width_tiles() - 1,
             self.height_tiles() - 1,
         )?;
+        // Then the depth, which tiles also record, and how the LCD scales pixels
+        if extended {
+            let (x, y) = self.lcd_scale.factors();
+            writer.write_all(&[self.bit_depth.bits(), x as u8, y as u8])?;
+        }
 
         // Palette data follows directly, little-endian RGB565
         for swatch in &self.palette {
             writer.write_all(&GRGB1555::from(swatch).to_le_bytes())?;
         }
-        writer.close()
+        self.finish_var(writer, start)
+    }
+
+    /// Write every tile appvar followed by the palette appvar, if there is one, into a single
+    /// group file.
+    ///
+    /// Any comment set with [`set_comment`](QuantizedImage::set_comment) applies to the group.
+    pub fn write_group<W: Write>(&self, out: W) -> IoResult<W> {
+        let mut group = group::Writer::new(out);
+        if let Some(comment) = &self.comment {
+            group.set_comment(comment);
+        }
+        for tile in self.tiles() {
+            group.add_var(&tile.write_appvar(Cursor::new(Vec::new()))?.into_inner())?;
+        }
+        if self.direct_color {
+            return group.close();
+        }
+        group.add_var(
+            &self
+                .write_palette_appvar(Cursor::new(Vec::new()))?
+                .into_inner(),
+        )?;
+        group.close()
+    }
+
+    /// Write every tile appvar followed by the palette appvar, if there is one, as separate 8xv
+    /// files, returning the name of each appvar with its file.
+    pub fn write_appvars(&self) -> IoResult<Vec<(String, Vec<u8>)>> {
+        let mut appvars = self
+            .tiles()
+            .map(|tile| {
+                let file = tile.write_appvar(Cursor::new(Vec::new()))?.into_inner();
+                Ok((tile.appvar_name().to_string(), file))
+            })
+            .collect::<IoResult<Vec<_>>>()?;
+        if !self.direct_color {
+            let file = self
+                .write_palette_appvar(Cursor::new(Vec::new()))?
+                .into_inner();
+            appvars.push((self.palette_appvar_name(), file));
+        }
+        Ok(appvars)
     }
 }
 
@@ -191,38 +989,81 @@ impl<'a> Iterator for TileRows<'a> {
     type Item = &'a [u8];
 
     fn next(&mut self) -> Option<&'a [u8]> {
-        if self.y >= Image::TILE_SIZE {
+        let (tile_width, tile_height) = self.tile.image.tile_size;
+        if self.y >= tile_height {
             return None;
         }
 
-        let row_start = (self.tile.index.1 * Image::TILE_SIZE + self.y) * self.tile.image.width;
-        let row_offset = self.tile.index.0 * Image::TILE_SIZE;
+        let band_row = self.tile.index.1 - self.tile.image.first_row;
+        let pixel_len = self.tile.image.bytes_per_pixel();
+        let row_start = (band_row * tile_height + self.y) * self.tile.image.width * pixel_len;
+        let row_offset = self.tile.index.0 * tile_width * pixel_len;
         self.y += 1;
 
         let row_base = (row_start + row_offset) as usize;
-        Some(&self.tile.image.data[row_base..row_base + Image::TILE_SIZE as usize])
+        Some(&self.tile.image.data[row_base..row_base + (tile_width * pixel_len) as usize])
     }
 }
 
 impl<'a> Tile<'a> {
+    /// Return an iterator over rows of the tile's pixels, which are palette indices or, for
+    /// direct color images, two bytes of color each.
     pub fn rows(&'a self) -> TileRows<'a> {
         TileRows { tile: self, y: 0 }
     }
 
-    pub fn write_appvar<W: Write + Seek>(&self, out: W) -> IoResult<W> {
-        let mut appvar = tifiles::Writer::new(out, VariableType::AppVar, &self.appvar_name, true)?;
+    pub fn write_appvar<W: Write + Seek>(&self, mut out: W) -> IoResult<W> {
+        let start = out.stream_position()?;
+        let mut appvar = tifiles::Writer::new(
+            out,
+            VariableType::AppVar,
+            &self.appvar_name,
+            self.image.archived,
+        )?;
         // Image data buffer so we can compress it
-        let mut imgbuf =
-            Vec::with_capacity(Image::TILE_SIZE as usize * Image::TILE_SIZE as usize + 2);
+        let (tile_width, tile_height) = self.image.tile_size;
+        let depth = self.image.bit_depth;
+        let row_len = if self.image.direct_color {
+            tile_width as usize * 2
+        } else {
+            depth.packed_len(tile_width as usize)
+        };
+        let mut imgbuf = Vec::with_capacity(row_len * tile_height as usize + 2);
 
-        // Image signature (not compressed)
-        write!(appvar, "HDPICCV4{:8}", &self.image.name)?;
+        // Image signature (not compressed), with the compression and depth if they aren't implied
+        let compression = self.image.compression;
+        if self.image.direct_color {
+            write!(appvar, "{}{:8}", depth::PACKED_SIGNATURE, &self.image.name)?;
+            appvar.write_all(&[compression.id(), direct::DIRECT_COLOR_BITS])?;
+        } else if depth != BitDepth::Eight {
+            write!(appvar, "{}{:8}", depth::PACKED_SIGNATURE, &self.image.name)?;
+            appvar.write_all(&[compression.id(), depth.bits()])?;
+        } else if compression == Compression::Zx0 {
+            write!(
+                appvar,
+                "{}{:8}",
+                compress::VIEWER_SIGNATURE,
+                &self.image.name
+            )?;
+        } else {
+            write!(
+                appvar,
+                "{}{:8}",
+                compress::TAGGED_SIGNATURE,
+                &self.image.name
+            )?;
+            appvar.write_all(&[compression.id()])?;
+        }
         // Image dimensions, always the tile size
-        imgbuf.write_all(&[Image::TILE_SIZE as u8, Image::TILE_SIZE as u8])?;
+        imgbuf.write_all(&[tile_width as u8, tile_height as u8])?;
 
-        // Paletteized pixel data follows, row-major one byte per pixel
+        // Pixel data follows, row-major and either colors or palette indices packed to the depth
         for row in self.rows() {
-            imgbuf.write_all(row)?;
+            if self.image.direct_color {
+                imgbuf.extend_from_slice(row);
+            } else {
+                depth.pack(row, &mut imgbuf);
+            }
         }
         debug_assert_eq!(
             imgbuf.capacity(),
@@ -230,10 +1071,21 @@ impl<'a> Tile<'a> {
             "Initial buffer capacity was wrong"
         );
 
-        // Then compress and write the compressed data
-        let compressed = zx0::compress(&imgbuf);
-        appvar.write_all(&compressed)?;
-        appvar.close()
+        // Then compress and write the compressed data, refusing to spend minutes on a large zx0 tile
+        if compression == Compression::Zx0 && imgbuf.len() - 2 > MAX_ZX0_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{}x{} tiles hold {} bytes of pixels, more than the {} zx0 can compress in reasonable time",
+                    tile_width,
+                    tile_height,
+                    imgbuf.len() - 2,
+                    MAX_ZX0_LEN
+                ),
+            ));
+        }
+        appvar.write_all(&compression.compress(&imgbuf)?)?;
+        self.image.finish_var(appvar, start)
     }
 
     pub fn index(&self) -> (u32, u32) {
@@ -250,7 +1102,7 @@ impl<'a> Iterator for Tiles<'a> {
 
     fn next(&mut self) -> Option<Tile<'a>> {
         let (x, y) = self.next;
-        if y == self.image.height_tiles() {
+        if y == self.image.end_row() {
             None
         } else {
             self.next.0 += 1;
@@ -260,8 +1112,13 @@ impl<'a> Iterator for Tiles<'a> {
 
             Some(Tile {
                 index: (x, y),
-                appvar_name: format!("{:2}{:03}{:03}", self.image.var_prefix, x, y),
-                image: &self.image,
+                appvar_name: self.image.name_template.render(
+                    &self.image.var_prefix,
+                    self.image.frame,
+                    x,
+                    y,
+                ),
+                image: self.image,
             })
         }
     }
@@ -294,6 +1151,24 @@ impl From<&RGBA> for GRGB1555 {
     }
 }
 
+/// Convert to 24-bit RGB, which round-trips colors converted from it.
+impl From<GRGB1555> for RGBA {
+    fn from(color: GRGB1555) -> RGBA {
+        let color = color.0;
+        let r = (color >> 10) & 0b1_1111;
+        let g = ((color >> 4) & 0b11_1110) | (color >> 15);
+        let b = color & 0b1_1111;
+        let expand = |value: u16, max: u16| (value as f32 / max as f32 * 255.0).round() as u8;
+
+        RGBA::new(
+            expand(r, 0b1_1111),
+            expand(g, 0b11_1111),
+            expand(b, 0b1_1111),
+            0xff,
+        )
+    }
+}
+
 impl std::ops::Deref for GRGB1555 {
     type Target = u16;
 
@@ -304,9 +1179,314 @@ impl std::ops::Deref for GRGB1555 {
 
 #[test]
 fn rgb_conversion_is_correct() {
-    assert_eq!(GRGB1555::from(&RGBA::new(0xff, 0xff, 0xff, 0xff)), GRGB1555(0xFFFF));
-    assert_eq!(GRGB1555::from(&RGBA::new(0xff, 0, 0, 0xff)), GRGB1555(0x7c00));
-    assert_eq!(GRGB1555::from(&RGBA::new(0, 0xff, 0, 0xff)), GRGB1555(0x83e0));
-    assert_eq!(GRGB1555::from(&RGBA::new(0, 0, 0xff, 0xff)), GRGB1555(0x001f));
-    assert_eq!(GRGB1555::from(&RGBA::new(0x5d, 0x37, 0x2c, 0xff)), GRGB1555(0x2CE5));
+    assert_eq!(
+        GRGB1555::from(&RGBA::new(0xff, 0xff, 0xff, 0xff)),
+        GRGB1555(0xFFFF)
+    );
+    assert_eq!(
+        GRGB1555::from(&RGBA::new(0xff, 0, 0, 0xff)),
+        GRGB1555(0x7c00)
+    );
+    assert_eq!(
+        GRGB1555::from(&RGBA::new(0, 0xff, 0, 0xff)),
+        GRGB1555(0x83e0)
+    );
+    assert_eq!(
+        GRGB1555::from(&RGBA::new(0, 0, 0xff, 0xff)),
+        GRGB1555(0x001f)
+    );
+    assert_eq!(
+        GRGB1555::from(&RGBA::new(0x5d, 0x37, 0x2c, 0xff)),
+        GRGB1555(0x2CE5)
+    );
+
+    for color in [0xFFFF, 0x7c00, 0x83e0, 0x001f, 0x2CE5] {
+        let rgb = RGBA::from(GRGB1555(color));
+        assert_eq!(GRGB1555::from(&rgb), GRGB1555(color));
+    }
+}
+
+/// A shared palette covers the colors of every image.
+#[test]
+fn shared_palette_covers_all_images() {
+    let solid = |color: [u8; 4]| {
+        Image::from_rgba(
+            RgbaImage::from_pixel(
+                Image::DEFAULT_TILE_SIZE,
+                Image::DEFAULT_TILE_SIZE,
+                Rgba(color),
+            ),
+            "SOLID",
+            "AA",
+        )
+    };
+    let images = [solid([255, 0, 0, 255]), solid([0, 0, 255, 255])];
+
+    let palette = Image::shared_palette(&images, &QuantizeOptions::default()).unwrap();
+    assert!(palette.contains(&RGBA::new(255, 0, 0, 255)));
+    assert!(palette.contains(&RGBA::new(0, 0, 255, 255)));
+}
+
+/// Quantizing fails rather than producing a palette below the minimum quality.
+#[cfg(feature = "imagequant")]
+#[test]
+fn minimum_quality_is_enforced() {
+    let gradient = || {
+        let input = RgbaImage::from_fn(64, 64, |x, _| Rgba([x as u8 * 4, 0, 128, 255]));
+        Image::from_rgba(input, "GRADIENT", "AA")
+    };
+    let options = QuantizeOptions {
+        max_colors: 2,
+        quality: (90, 100),
+        ..Default::default()
+    };
+    assert!(gradient().quantize_with(&options).is_err());
+    assert!(Image::shared_palette(&[gradient()], &options).is_err());
+
+    let options = QuantizeOptions {
+        max_colors: 256,
+        ..options
+    };
+    assert!(gradient().quantize_with(&options).is_ok());
+}
+
+/// Reserved colors come first in the palette and pixels never use them.
+#[test]
+fn reserved_colors_are_not_used() {
+    let image = Image::from_rgba(
+        RgbaImage::from_pixel(
+            Image::DEFAULT_TILE_SIZE,
+            Image::DEFAULT_TILE_SIZE,
+            Rgba([255, 0, 0, 255]),
+        ),
+        "SOLID",
+        "AA",
+    );
+    let reserved = vec![RGBA::new(255, 0, 0, 255), RGBA::new(0, 0, 0, 255)];
+    let quantized = image
+        .quantize_with(&QuantizeOptions {
+            reserved_colors: reserved.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+
+    assert_eq!(quantized.palette[..2], reserved[..]);
+    assert!(quantized.data.iter().all(|&i| i >= 2));
+}
+
+/// Transparent pixels and padding use the transparent index, and nothing else does.
+#[test]
+fn transparent_pixels_use_transparent_index() {
+    let mut input = RgbaImage::from_pixel(60, 80, Rgba([255, 0, 0, 255]));
+    input.put_pixel(0, 0, Rgba([255, 0, 0, 0]));
+    let image = Image::from_rgba(input, "SPRITE", "AA");
+    let quantized = image
+        .quantize_with(&QuantizeOptions {
+            reserved_colors: vec![RGBA::new(255, 0, 255, 255)],
+            transparent_index: Some(0),
+            ..Default::default()
+        })
+        .unwrap();
+
+    assert_eq!(quantized.transparent_index(), Some(0));
+    for (i, &pixel) in quantized.data.iter().enumerate() {
+        let (x, y) = (i % 80, i / 80);
+        assert_eq!(
+            pixel == 0,
+            (x, y) == (0, 0) || x >= 60,
+            "pixel at {:?}",
+            (x, y)
+        );
+    }
+}
+
+/// Translucent pixels are blended over the background, which also fills padding.
+#[test]
+fn canvas_blends_over_background() {
+    let image = Image::from_rgba(
+        RgbaImage::from_pixel(1, 1, Rgba([255, 255, 255, 128])),
+        "BLEND",
+        "AA",
+    );
+    let canvas = image.canvas(RGBA::new(0, 0, 255, 255));
+
+    assert_eq!(
+        canvas.dimensions(),
+        (Image::DEFAULT_TILE_SIZE, Image::DEFAULT_TILE_SIZE)
+    );
+    let blended = canvas.get_pixel(0, 0).0;
+    assert!(blended[0].abs_diff(128) <= 1 && blended[1].abs_diff(128) <= 1);
+    assert_eq!(blended[2..], [255, 255]);
+    assert_eq!(canvas.get_pixel(1, 1).0, [0, 0, 255, 255]);
+}
+
+/// Images split into non-square tiles have rows of the tile's width.
+#[test]
+fn custom_tile_size() {
+    let mut image = Image::from_rgba(RgbaImage::new(250, 30), "STRIPS", "AA");
+    image.set_tile_size(160, 20);
+    let quantized = image.quantize();
+
+    assert_eq!((quantized.width_tiles(), quantized.height_tiles()), (2, 2));
+    let tile = quantized.tiles().nth(3).unwrap();
+    assert_eq!(tile.index(), (1, 1));
+    assert_eq!(tile.rows().count(), 20);
+    assert!(tile.rows().all(|row| row.len() == 160));
+}
+
+/// Tiles without the default compression record theirs after the signature and name.
+#[test]
+fn tagged_compression_header() {
+    let mut quantized = Image::from_rgba(RgbaImage::new(80, 80), "PLAIN", "AA").quantize();
+    quantized.set_compression(Compression::None);
+    let tile = quantized.tiles().next().unwrap();
+    let appvar = tile
+        .write_appvar(Cursor::new(Vec::new()))
+        .unwrap()
+        .into_inner();
+
+    // The variable's data follows the file header, variable header and 2-byte data length
+    let data = &appvar[group::HEADER_LEN + 19..appvar.len() - 2];
+    assert_eq!(&data[..16], b"HDPICTV1PLAIN___");
+    assert_eq!(data[16], Compression::None.id());
+    assert_eq!(&data[17..19], &[80, 80]);
+    assert_eq!(data.len(), 19 + 80 * 80);
+}
+
+/// Packed tiles record their depth after the compression, and so do their palettes.
+#[test]
+fn packed_tile_header() {
+    let mut image = Image::from_rgba(RgbaImage::new(75, 80), "PACKED", "AA");
+    image.set_tile_size(75, 80);
+    let mut quantized = image.quantize();
+    quantized.set_bit_depth(BitDepth::Four).unwrap();
+    quantized.set_compression(Compression::None);
+    let tile = quantized.tiles().next().unwrap();
+    let appvar = tile
+        .write_appvar(Cursor::new(Vec::new()))
+        .unwrap()
+        .into_inner();
+
+    let data = &appvar[group::HEADER_LEN + 19..appvar.len() - 2];
+    assert_eq!(&data[..16], b"HDPICPV1PACKED__");
+    assert_eq!(data[16..18], [Compression::None.id(), 4]);
+    assert_eq!(&data[18..20], &[75, 80]);
+    assert_eq!(data.len(), 20 + 38 * 80);
+
+    let palette = quantized
+        .write_palette_appvar(Cursor::new(Vec::new()))
+        .unwrap()
+        .into_inner();
+    let data = &palette[group::HEADER_LEN + 19..palette.len() - 2];
+    assert_eq!(&data[..8], b"HDPALV11");
+    assert_eq!(data[24..27], [4, 1, 1]);
+
+    // Palettes with too many colors can't be packed
+    let options = QuantizeOptions {
+        palette: Some(palette::xlibc()),
+        ..Default::default()
+    };
+    let mut quantized = Image::from_rgba(RgbaImage::new(80, 80), "FULL", "AA")
+        .quantize_with(&options)
+        .unwrap();
+    assert!(quantized.set_bit_depth(BitDepth::Four).is_err());
+}
+
+/// Appvars written separately are the same variables as a group file holds, in the same order.
+#[test]
+fn separate_appvars_match_group() {
+    let quantized = Image::from_rgba(RgbaImage::new(100, 50), "SPLIT", "AA").quantize();
+    let appvars = quantized.write_appvars().unwrap();
+    let names: Vec<_> = appvars.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["AA000000", "AA001000", "HPAA0000"]);
+
+    let group = group::read(&quantized.write_group(Vec::new()).unwrap()).unwrap();
+    for ((name, file), var) in appvars.iter().zip(group.vars) {
+        let file = group::read(file).unwrap().vars.remove(0);
+        assert_eq!((&file.name, &file.data), (name, &var.data));
+    }
+}
+
+/// Custom comments replace the default in every file without disturbing anything else.
+#[test]
+fn custom_comment() {
+    let mut quantized = Image::from_rgba(RgbaImage::new(80, 80), "COMMENT", "AA").quantize();
+    let default = quantized
+        .write_palette_appvar(Cursor::new(Vec::new()))
+        .unwrap()
+        .into_inner();
+
+    quantized.set_comment("From test.png");
+    let mut out = Cursor::new(b"prefix".to_vec());
+    out.set_position(6);
+    let custom = quantized.write_palette_appvar(out).unwrap().into_inner();
+
+    assert_eq!(&custom[..6], b"prefix");
+    let custom = &custom[6..];
+    assert_eq!(
+        &custom[11..53],
+        format!("{:42}", "From test.png").as_bytes()
+    );
+    assert_eq!(custom[..11], default[..11]);
+    assert_eq!(custom[53..], default[53..]);
+}
+
+/// Name templates that would make unusable names are refused.
+#[test]
+fn name_template_validation() {
+    let mut quantized = Image::from_rgba(RgbaImage::new(160, 80), "NAMES", "AA").quantize();
+    let template = |s| NameTemplate::parse(s).unwrap();
+
+    assert!(quantized
+        .set_name_template(template("{prefix}{row}"))
+        .is_err());
+    assert!(quantized
+        .set_name_template(template("{prefix}{col:07}"))
+        .is_err());
+    assert!(quantized
+        .set_name_template(template("{col}{prefix}"))
+        .is_err());
+
+    quantized
+        .set_name_template(template("{prefix}R{row}C{col}"))
+        .unwrap();
+    let names: Vec<String> = quantized
+        .tiles()
+        .map(|t| t.appvar_name().to_string())
+        .collect();
+    assert_eq!(names, ["AAR0C0", "AAR0C1"]);
+}
+
+/// Unsupported images are refused with an explanation, rather than as an unknown format.
+#[test]
+fn unsupported_formats_are_explained() {
+    let mut heic = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic".to_vec();
+    heic.resize(64, 0);
+    assert_eq!(other_format(&heic), Some(OtherFormat::Heif));
+    if cfg!(not(feature = "heif")) {
+        let e = Image::new(Cursor::new(heic), "PHOTO", "PH").err().unwrap();
+        assert!(e.to_string().contains("HEIF"), "{}", e);
+    }
+
+    assert_eq!(
+        other_format(b"<?xml version=\"1.0\"?>\n<svg xmlns=\"http://www.w3.org/2000/svg\">"),
+        Some(OtherFormat::Svg)
+    );
+    assert_eq!(
+        other_format(b"  <svg width=\"10\"/>"),
+        Some(OtherFormat::Svg)
+    );
+    assert_eq!(other_format(b"<?xml version=\"1.0\"?><palette/>"), None);
+    assert_eq!(other_format(b"%PDF-1.7\n%\xe2\xe3"), Some(OtherFormat::Pdf));
+    let avif = other_format(b"\0\0\0\x1cftypavif\0\0\0\0avifmif1");
+    assert_eq!(avif, (!cfg!(feature = "avif")).then_some(OtherFormat::Avif));
+
+    let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"8\" height=\"8\"/>";
+    let image = Image::new(Cursor::new(svg), "LOGO", "LO");
+    if cfg!(feature = "svg") {
+        assert_eq!(image.unwrap().dimensions(), (240, 240));
+    } else {
+        let e = image.err().unwrap();
+        assert!(e.to_string().contains("SVG"), "{}", e);
+    }
 }
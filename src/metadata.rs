@@ -0,0 +1,138 @@
+//! Metadata appvars describing the geometry and encoding of an image
+//!
+//! Viewers can read the metadata appvar to size and decode an image without having to rely on
+//! the defaults of HD Picture Viewer. It has a `HDMETAV1` signature, the 8-character image name
+//! and 2-character var prefix, the width and height in pixels as 16-bit little-endian integers,
+//! then a byte each for the width and height of tiles, the number of columns and rows of tiles,
+//! the number of bits per pixel (16 for direct color images) and the
+//! [`id`](Compression::id) of the tiles' compression.
+use std::io::{Error, ErrorKind, Result as IoResult, Seek, Write};
+
+use tifiles::VariableType;
+
+use crate::{direct, Compression, QuantizedImage};
+
+const SIGNATURE: &[u8] = b"HDMETAV1";
+const LEN: usize = 8 + 8 + 2 + 2 + 2 + 6;
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl QuantizedImage {
+    /// Return the name of the appvar holding the image's metadata.
+    pub fn metadata_appvar_name(&self) -> String {
+        format!("HM{:2}0000", self.var_prefix)
+    }
+
+    /// Write the appvar describing the image's size, tiles and encoding.
+    pub fn write_metadata_appvar<W: Write + Seek>(&self, mut out: W) -> IoResult<W> {
+        let start = out.stream_position()?;
+        let mut writer = tifiles::Writer::new(
+            out,
+            VariableType::AppVar,
+            &self.metadata_appvar_name(),
+            self.archived,
+        )?;
+        writer.write_all(SIGNATURE)?;
+        write!(writer, "{:8}{:2}", self.name, self.var_prefix)?;
+        writer.write_all(&(self.width() as u16).to_le_bytes())?;
+        writer.write_all(&(self.height() as u16).to_le_bytes())?;
+        let (tile_width, tile_height) = self.tile_size();
+        let bits = if self.direct_color {
+            direct::DIRECT_COLOR_BITS
+        } else {
+            self.bit_depth.bits()
+        };
+        writer.write_all(&[
+            tile_width as u8,
+            tile_height as u8,
+            self.width_tiles() as u8,
+            self.height_tiles() as u8,
+            bits,
+            self.compression.id(),
+        ])?;
+        self.finish_var(writer, start)
+    }
+}
+
+/// The contents of a metadata appvar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// Name of the image, padded with underscores to 8 characters.
+    pub name: String,
+    pub var_prefix: String,
+    /// Width of the image in pixels, including padding to fill whole tiles.
+    pub width: u16,
+    /// Height of the image in pixels, including padding to fill whole tiles.
+    pub height: u16,
+    pub tile_width: u8,
+    pub tile_height: u8,
+    pub columns: u8,
+    pub rows: u8,
+    /// Bits per pixel of the tiles, which is 16 for direct color images.
+    pub bits: u8,
+    pub compression: Compression,
+}
+
+impl Metadata {
+    /// Return whether appvar data is metadata.
+    pub fn is_metadata(data: &[u8]) -> bool {
+        data.starts_with(SIGNATURE)
+    }
+
+    /// Read the data of a metadata appvar.
+    pub fn read(data: &[u8]) -> IoResult<Self> {
+        if !Self::is_metadata(data) {
+            return Err(invalid(
+                "Not a metadata appvar: signature is missing".into(),
+            ));
+        }
+        let data = data
+            .get(..LEN)
+            .ok_or_else(|| invalid("Metadata appvar ends early".into()))?;
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        let compression = Compression::from_id(data[27])
+            .ok_or_else(|| invalid(format!("Compression method {} is unknown", data[27])))?;
+        Ok(Metadata {
+            name: text(&data[8..16]),
+            var_prefix: text(&data[16..18]),
+            width: u16::from_le_bytes([data[18], data[19]]),
+            height: u16::from_le_bytes([data[20], data[21]]),
+            tile_width: data[22],
+            tile_height: data[23],
+            columns: data[24],
+            rows: data[25],
+            bits: data[26],
+            compression,
+        })
+    }
+}
+
+/// Metadata records the geometry and encoding of the tiles.
+#[test]
+fn metadata_round_trips() {
+    use crate::{group, BitDepth, Image};
+    use image::RgbaImage;
+    use std::io::Cursor;
+
+    let mut image = Image::from_rgba(RgbaImage::new(100, 50), "meta", "ME");
+    image.set_tile_size(40, 30);
+    let mut quantized = image.quantize();
+    quantized.set_compression(Compression::Rle);
+    quantized.set_bit_depth(BitDepth::Two).unwrap();
+    let file = quantized
+        .write_metadata_appvar(Cursor::new(Vec::new()))
+        .unwrap()
+        .into_inner();
+    let var = group::read(&file).unwrap().vars.remove(0);
+    assert_eq!(var.name, "HMME0000");
+
+    let metadata = Metadata::read(&var.data).unwrap();
+    assert_eq!(metadata.name, "meta____");
+    assert_eq!((metadata.width, metadata.height), (120, 60));
+    assert_eq!((metadata.tile_width, metadata.tile_height), (40, 30));
+    assert_eq!((metadata.columns, metadata.rows), (3, 2));
+    assert_eq!((metadata.bits, metadata.compression), (2, Compression::Rle));
+    assert!(Metadata::read(&var.data[..20]).is_err());
+}
@@ -0,0 +1,144 @@
+//! Measuring how closely a converted image matches its source
+//!
+//! PSNR compares every color channel of every pixel, while SSIM compares the brightness, contrast
+//! and structure of 8x8 windows of luma, which tracks what people notice more closely. Both are
+//! affected by reducing colors to the calculator's 16-bit color, so even a perfect palette doesn't
+//! score a perfect match.
+use std::io::{Error, ErrorKind, Result as IoResult};
+
+use image::{GenericImageView, RgbaImage};
+use rgb::RGBA8 as RGBA;
+
+use crate::Image;
+
+/// Size of the square windows SSIM is computed over.
+const SSIM_WINDOW: u32 = 8;
+
+/// How closely one image matches another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quality {
+    /// Peak signal-to-noise ratio in decibels, which is infinite for identical images.
+    pub psnr: f64,
+    /// Mean structural similarity, from -1 up to 1 for identical images.
+    pub ssim: f64,
+}
+
+impl Quality {
+    /// Measure how closely `b` matches `a`, which must have the same dimensions. Alpha is
+    /// ignored.
+    pub fn measure(a: &RgbaImage, b: &RgbaImage) -> Self {
+        assert_eq!(a.dimensions(), b.dimensions());
+        Quality {
+            psnr: psnr(a, b),
+            ssim: ssim(a, b),
+        }
+    }
+}
+
+fn psnr(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    let squared_error: f64 = a
+        .pixels()
+        .zip(b.pixels())
+        .flat_map(|(a, b)| (0..3).map(move |c| (a[c] as f64 - b[c] as f64).powi(2)))
+        .sum();
+    let mse = squared_error / (a.pixels().len().max(1) * 3) as f64;
+    10. * (255f64.powi(2) / mse).log10()
+}
+
+fn luma(image: &RgbaImage) -> Vec<f64> {
+    image
+        .pixels()
+        .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+        .collect()
+}
+
+/// Return the mean SSIM of windows overlapping by half, or of the whole image if it's smaller
+/// than a window.
+fn ssim(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+    const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+
+    let (width, height) = a.dimensions();
+    let (la, lb) = (luma(a), luma(b));
+    let (window_width, window_height) = (SSIM_WINDOW.min(width), SSIM_WINDOW.min(height));
+    let starts = |size: u32, window: u32| (0..=size - window).step_by(SSIM_WINDOW as usize / 2);
+
+    let (mut total, mut windows) = (0., 0);
+    for y in starts(height, window_height) {
+        for x in starts(width, window_width) {
+            let pixels: Vec<(f64, f64)> = (y..y + window_height)
+                .flat_map(|y| (x..x + window_width).map(move |x| (y * width + x) as usize))
+                .map(|i| (la[i], lb[i]))
+                .collect();
+            let n = pixels.len() as f64;
+            let mean_a = pixels.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_b = pixels.iter().map(|p| p.1).sum::<f64>() / n;
+            let (mut var_a, mut var_b, mut covar) = (0., 0., 0.);
+            for (pa, pb) in &pixels {
+                var_a += (pa - mean_a).powi(2) / n;
+                var_b += (pb - mean_b).powi(2) / n;
+                covar += (pa - mean_a) * (pb - mean_b) / n;
+            }
+            total += (2. * mean_a * mean_b + C1) * (2. * covar + C2)
+                / ((mean_a.powi(2) + mean_b.powi(2) + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    total / windows.max(1) as f64
+}
+
+impl Image {
+    /// Measure how closely a decoded conversion of this image matches it.
+    ///
+    /// Like converting, the image is blended over `background`. Only the top left of `decoded`
+    /// is compared, leaving out any padding added to fill whole tiles.
+    pub fn compare(&self, decoded: &RgbaImage, background: RGBA) -> IoResult<Quality> {
+        let (width, height) = self.dimensions();
+        if decoded.width() < width || decoded.height() < height {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "the decoded image is {}x{}, which is smaller than the {}x{} source",
+                    decoded.width(),
+                    decoded.height(),
+                    width,
+                    height
+                ),
+            ));
+        }
+        let source = self.canvas(background);
+        Ok(Quality::measure(
+            &source.view(0, 0, width, height).to_image(),
+            &decoded.view(0, 0, width, height).to_image(),
+        ))
+    }
+}
+
+/// Identical images match perfectly, and noise lowers both scores.
+#[test]
+fn quality_falls_with_noise() {
+    use image::Rgba;
+
+    let image = RgbaImage::from_fn(37, 21, |x, y| {
+        Rgba([(x * 7) as u8, (y * 12) as u8, ((x + y) * 5) as u8, 255])
+    });
+    let same = Quality::measure(&image, &image);
+    assert_eq!(same.psnr, f64::INFINITY);
+    assert!((same.ssim - 1.).abs() < 1e-9);
+
+    let mut noisy = image.clone();
+    for (i, pixel) in noisy.pixels_mut().enumerate() {
+        let noise = if i % 2 == 0 { 20 } else { 0 };
+        pixel[1] = pixel[1].saturating_add(noise);
+    }
+    let quality = Quality::measure(&image, &noisy);
+    assert!(quality.psnr > 20. && quality.psnr < 40., "{:?}", quality);
+    assert!(quality.ssim > 0.5 && quality.ssim < 0.99, "{:?}", quality);
+
+    // Padding beyond the source isn't compared
+    let mut padded = RgbaImage::new(48, 24);
+    image::imageops::replace(&mut padded, &image, 0, 0);
+    let source = Image::from_rgba(image, "test", "TS");
+    let compared = source.compare(&padded, RGBA::new(0, 0, 0, 255)).unwrap();
+    assert_eq!(compared.psnr, f64::INFINITY);
+}
@@ -0,0 +1,209 @@
+//! Templates for naming tile appvars
+use std::fmt::Write;
+
+/// A pattern for tile appvar names, like `{prefix}{col:03}{row:03}`.
+///
+/// Placeholders are surrounded by braces: `{prefix}` is the image's var prefix, `{col}` and
+/// `{row}` are a tile's zero-based column and row, and `{frame}` is the zero-based number of the
+/// animation frame it's part of. Numbers may be given a width to zero-pad them to, as in
+/// `{row:02}`. Anything outside braces is copied to every name, and must be letters or
+/// digits.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Prefix,
+    Column(usize),
+    Row(usize),
+    Frame(usize),
+}
+
+/// Where a tile is, as recorded in its name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileName {
+    pub var_prefix: String,
+    pub frame: u32,
+    pub column: u32,
+    pub row: u32,
+}
+
+/// Names are what HD Picture Viewer looks for: the prefix, column and row.
+impl Default for NameTemplate {
+    fn default() -> Self {
+        NameTemplate {
+            parts: vec![Part::Prefix, Part::Column(3), Part::Row(3)],
+        }
+    }
+}
+
+impl NameTemplate {
+    /// The most characters a calculator variable name may have.
+    pub const MAX_LEN: usize = 8;
+
+    /// The template used for frames of animations unless another is chosen,
+    /// `{prefix}{frame:02}{col:02}{row:02}`.
+    pub fn default_for_frames() -> Self {
+        NameTemplate {
+            parts: vec![Part::Prefix, Part::Frame(2), Part::Column(2), Part::Row(2)],
+        }
+    }
+
+    /// Return whether names include the frame number, which frames of an animation need to be
+    /// told apart.
+    pub fn has_frame(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Frame(_)))
+    }
+
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            let Some(placeholder) = rest.strip_prefix('{') else {
+                let end = rest.find('{').unwrap_or(rest.len());
+                let literal = &rest[..end];
+                if let Some(c) = literal.chars().find(|c| !c.is_ascii_alphanumeric()) {
+                    return Err(format!("{:?} can't be used in a variable name", c));
+                }
+                parts.push(Part::Literal(literal.into()));
+                rest = &rest[end..];
+                continue;
+            };
+
+            let Some((placeholder, after)) = placeholder.split_once('}') else {
+                return Err(format!("{:?} is missing a closing brace", template));
+            };
+            let (field, width) = match placeholder.split_once(':') {
+                Some((field, width)) => match width.parse::<usize>() {
+                    Ok(width) => (field, width),
+                    Err(_) => return Err(format!("{:?} is not a width", width)),
+                },
+                None => (placeholder, 0),
+            };
+            parts.push(match field {
+                "prefix" => Part::Prefix,
+                "col" => Part::Column(width),
+                "row" => Part::Row(width),
+                "frame" => Part::Frame(width),
+                _ => {
+                    return Err(format!(
+                        "{{{}}} is not one of {{prefix}}, {{col}}, {{row}} or {{frame}}",
+                        field
+                    ))
+                }
+            });
+            rest = after;
+        }
+
+        Ok(NameTemplate { parts })
+    }
+
+    /// Return the name of the tile in a column and row of a frame of an image with the given
+    /// prefix.
+    pub fn render(&self, prefix: &str, frame: u32, column: u32, row: u32) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => name.push_str(s),
+                Part::Prefix => name.push_str(prefix),
+                Part::Column(width) => write!(name, "{:01$}", column, width).unwrap(),
+                Part::Row(width) => write!(name, "{:01$}", row, width).unwrap(),
+                Part::Frame(width) => write!(name, "{:01$}", frame, width).unwrap(),
+            }
+        }
+        name
+    }
+
+    /// Find which tile has a name, if it's one this template could render.
+    ///
+    /// Numbers without a width are read as far as their digits go. Parts that a template
+    /// doesn't include are 0.
+    pub fn locate(&self, name: &str) -> Option<TileName> {
+        let mut tile = TileName {
+            var_prefix: String::new(),
+            frame: 0,
+            column: 0,
+            row: 0,
+        };
+        let mut rest = name;
+        for part in &self.parts {
+            let (value, width) = match part {
+                Part::Literal(s) => {
+                    rest = rest.strip_prefix(s.as_str())?;
+                    continue;
+                }
+                Part::Prefix => {
+                    tile.var_prefix = rest.get(..2)?.into();
+                    rest = &rest[2..];
+                    continue;
+                }
+                Part::Column(width) => (&mut tile.column, *width),
+                Part::Row(width) => (&mut tile.row, *width),
+                Part::Frame(width) => (&mut tile.frame, *width),
+            };
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let len = if width == 0 { digits } else { width };
+            if len == 0 || len > digits {
+                return None;
+            }
+            *value = rest[..len].parse().ok()?;
+            rest = &rest[len..];
+        }
+        rest.is_empty().then_some(tile)
+    }
+}
+
+/// Check that a var prefix is two ASCII letters, which tile and palette appvar names start with.
+pub fn check_var_prefix(var_prefix: &str) -> Result<(), String> {
+    if var_prefix.len() != 2 || !var_prefix.bytes().all(|b| b.is_ascii_alphabetic()) {
+        return Err(format!("var prefix {:?} isn't two letters", var_prefix));
+    }
+    Ok(())
+}
+
+/// Templates are parsed into parts that render into names, and bad templates are refused.
+#[test]
+fn parse_and_render() {
+    let template = NameTemplate::parse("{prefix}{row:02}X{col}").unwrap();
+    assert_eq!(template.render("AB", 0, 12, 3), "AB03X12");
+    assert!(!template.has_frame());
+    let frames = NameTemplate::parse("{prefix}{frame:02}{col:02}{row:02}").unwrap();
+    assert_eq!(frames, NameTemplate::default_for_frames());
+    assert_eq!(frames.render("AB", 7, 1, 2), "AB070102");
+    assert!(frames.has_frame());
+    assert_eq!(
+        frames.locate("AB070102"),
+        Some(TileName {
+            var_prefix: "AB".into(),
+            frame: 7,
+            column: 1,
+            row: 2
+        })
+    );
+    assert_eq!(
+        template.locate("AB03X12").map(|t| (t.column, t.row)),
+        Some((12, 3))
+    );
+    assert_eq!(NameTemplate::default().locate("HPAB0000"), None);
+    assert_eq!(
+        NameTemplate::parse("{prefix}{col:03}{row:03}").unwrap(),
+        NameTemplate::default()
+    );
+
+    assert!(NameTemplate::parse("{prefix}_{row}").is_err());
+    assert!(NameTemplate::parse("{prefix}{row").is_err());
+    assert!(NameTemplate::parse("{prefix}{row:x}").is_err());
+    assert!(NameTemplate::parse("{name}").is_err());
+}
+
+/// Only two ASCII letters make a var prefix.
+#[test]
+fn var_prefixes_are_checked() {
+    assert!(check_var_prefix("Ab").is_ok());
+    for prefix in ["", "A", "ABC", "A1", "É"] {
+        assert!(check_var_prefix(prefix).is_err(), "{:?}", prefix);
+    }
+}
@@ -0,0 +1,212 @@
+//! Finding the palette entry nearest to a color
+//!
+//! This is done for every pixel when mapping an image to a fixed palette, so palette components
+//! are laid out in separate arrays to compare a pixel against several entries at once with SIMD
+//! instructions where the target has them (SSE2 on x86-64, NEON on AArch64). CIEDE2000 isn't a
+//! distance between coordinates, so it compares entries one at a time.
+use rgb::RGBA8 as RGBA;
+
+use crate::color_space::{self, ColorMetric, Matching};
+
+/// Number of palette entries compared at once.
+const LANES: usize = 4;
+
+/// A palette prepared for finding the nearest entry to colors.
+pub(crate) struct PaletteSearch {
+    matching: Matching,
+    len: usize,
+    // Coordinates of each entry in that space, padded to a multiple of LANES with entries that
+    // are infinitely far from every color.
+    r: Vec<f32>,
+    g: Vec<f32>,
+    b: Vec<f32>,
+}
+
+impl PaletteSearch {
+    /// Prepare to search a palette for the entries nearest colors by `matching`.
+    pub(crate) fn new(palette: &[RGBA], matching: Matching) -> Self {
+        assert!(!palette.is_empty(), "palette should not be empty");
+        assert!(palette.len() <= 256, "palette indices must fit in a byte");
+
+        let padded = palette.len().div_ceil(LANES) * LANES;
+        let coordinates: Vec<[f32; 3]> = palette
+            .iter()
+            .map(|c| matching.coordinates([c.r as f32, c.g as f32, c.b as f32]))
+            .collect();
+        let component = |i: usize| {
+            let mut values: Vec<f32> = coordinates.iter().map(|c| c[i]).collect();
+            values.resize(padded, f32::INFINITY);
+            values
+        };
+        PaletteSearch {
+            matching,
+            len: palette.len(),
+            r: component(0),
+            g: component(1),
+            b: component(2),
+        }
+    }
+
+    /// Return the index of the palette entry closest to an RGB color, preferring the earliest of
+    /// equally close entries.
+    pub(crate) fn nearest(&self, rgb: [f32; 3]) -> u8 {
+        let rgb = self.matching.coordinates(rgb);
+        if self.matching == Matching::Metric(ColorMetric::Ciede2000) {
+            return self.nearest_ciede2000(rgb);
+        }
+        #[cfg(target_arch = "x86_64")]
+        // SAFETY: SSE2 is part of the x86-64 baseline, so it's always available.
+        return unsafe { self.nearest_sse2(rgb) };
+        #[cfg(target_arch = "aarch64")]
+        // SAFETY: NEON is part of the AArch64 baseline, so it's always available.
+        return unsafe { self.nearest_neon(rgb) };
+        #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+        return self.nearest_scalar(rgb);
+    }
+
+    #[cfg_attr(any(target_arch = "x86_64", target_arch = "aarch64"), allow(dead_code))]
+    fn nearest_scalar(&self, rgb: [f32; 3]) -> u8 {
+        let mut best = (f32::INFINITY, 0);
+        for i in 0..self.r.len() {
+            let dr = self.r[i] - rgb[0];
+            let dg = self.g[i] - rgb[1];
+            let db = self.b[i] - rgb[2];
+            let distance = dr * dr + dg * dg + db * db;
+            if distance < best.0 {
+                best = (distance, i);
+            }
+        }
+        best.1 as u8
+    }
+
+    fn nearest_ciede2000(&self, lab: [f32; 3]) -> u8 {
+        let mut best = (f32::INFINITY, 0);
+        for i in 0..self.len {
+            let difference = color_space::ciede2000(lab, [self.r[i], self.g[i], self.b[i]]);
+            if difference < best.0 {
+                best = (difference, i);
+            }
+        }
+        best.1 as u8
+    }
+
+    /// Pick the best of the closest entries found by each lane, which are the earliest of equally
+    /// close entries within that lane.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn best_lane(distances: [f32; LANES], indices: [u32; LANES]) -> u8 {
+        let mut best = (distances[0], indices[0]);
+        for lane in 1..LANES {
+            let candidate = (distances[lane], indices[lane]);
+            if candidate.0 < best.0 || (candidate.0 == best.0 && candidate.1 < best.1) {
+                best = candidate;
+            }
+        }
+        best.1 as u8
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[target_feature(enable = "sse2")]
+    unsafe fn nearest_sse2(&self, rgb: [f32; 3]) -> u8 {
+        use std::arch::x86_64::*;
+
+        let (r, g, b) = (
+            _mm_set1_ps(rgb[0]),
+            _mm_set1_ps(rgb[1]),
+            _mm_set1_ps(rgb[2]),
+        );
+        let mut best_distance = _mm_set1_ps(f32::INFINITY);
+        let mut best_index = _mm_setzero_si128();
+        let mut index = _mm_set_epi32(3, 2, 1, 0);
+        let step = _mm_set1_epi32(LANES as i32);
+
+        for i in (0..self.r.len()).step_by(LANES) {
+            let dr = _mm_sub_ps(_mm_loadu_ps(self.r.as_ptr().add(i)), r);
+            let dg = _mm_sub_ps(_mm_loadu_ps(self.g.as_ptr().add(i)), g);
+            let db = _mm_sub_ps(_mm_loadu_ps(self.b.as_ptr().add(i)), b);
+            let distance = _mm_add_ps(
+                _mm_add_ps(_mm_mul_ps(dr, dr), _mm_mul_ps(dg, dg)),
+                _mm_mul_ps(db, db),
+            );
+
+            // SSE2 has no blend, so select lanes with masks
+            let closer = _mm_cmplt_ps(distance, best_distance);
+            best_distance = _mm_min_ps(distance, best_distance);
+            let closer = _mm_castps_si128(closer);
+            best_index = _mm_or_si128(
+                _mm_and_si128(closer, index),
+                _mm_andnot_si128(closer, best_index),
+            );
+            index = _mm_add_epi32(index, step);
+        }
+
+        let mut distances = [0.; LANES];
+        let mut indices = [0; LANES];
+        _mm_storeu_ps(distances.as_mut_ptr(), best_distance);
+        _mm_storeu_si128(indices.as_mut_ptr() as *mut __m128i, best_index);
+        Self::best_lane(distances, indices)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[target_feature(enable = "neon")]
+    unsafe fn nearest_neon(&self, rgb: [f32; 3]) -> u8 {
+        use std::arch::aarch64::*;
+
+        let (r, g, b) = (
+            vdupq_n_f32(rgb[0]),
+            vdupq_n_f32(rgb[1]),
+            vdupq_n_f32(rgb[2]),
+        );
+        let mut best_distance = vdupq_n_f32(f32::INFINITY);
+        let mut best_index = vdupq_n_u32(0);
+        let mut index = vld1q_u32([0, 1, 2, 3].as_ptr());
+        let step = vdupq_n_u32(LANES as u32);
+
+        for i in (0..self.r.len()).step_by(LANES) {
+            let dr = vsubq_f32(vld1q_f32(self.r.as_ptr().add(i)), r);
+            let dg = vsubq_f32(vld1q_f32(self.g.as_ptr().add(i)), g);
+            let db = vsubq_f32(vld1q_f32(self.b.as_ptr().add(i)), b);
+            let distance = vaddq_f32(
+                vaddq_f32(vmulq_f32(dr, dr), vmulq_f32(dg, dg)),
+                vmulq_f32(db, db),
+            );
+
+            let closer = vcltq_f32(distance, best_distance);
+            best_distance = vbslq_f32(closer, distance, best_distance);
+            best_index = vbslq_u32(closer, index, best_index);
+            index = vaddq_u32(index, step);
+        }
+
+        let mut distances = [0.; LANES];
+        let mut indices = [0; LANES];
+        vst1q_f32(distances.as_mut_ptr(), best_distance);
+        vst1q_u32(indices.as_mut_ptr(), best_index);
+        Self::best_lane(distances, indices)
+    }
+}
+
+/// The SIMD search finds the same entries as a plain search, including among equally close ones.
+#[test]
+fn simd_matches_scalar() {
+    // Duplicate entries make ties, and an odd count exercises the padding
+    let palette: Vec<RGBA> = (0..37u8)
+        .map(|i| {
+            let i = i % 29;
+            RGBA::new(
+                i.wrapping_mul(97),
+                i.wrapping_mul(57),
+                i.wrapping_mul(31),
+                255,
+            )
+        })
+        .collect();
+    let search = PaletteSearch::new(&palette, Matching::Metric(ColorMetric::Rgb));
+
+    for r in (0..=255).step_by(15) {
+        for g in (0..=255).step_by(15) {
+            for b in (0..=255).step_by(15) {
+                let rgb = [r as f32, g as f32, b as f32];
+                assert_eq!(search.nearest(rgb), search.nearest_scalar(rgb), "{:?}", rgb);
+            }
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! Node.js bindings for converting images in-process
+//!
+//! Building the library with the `node` feature makes its shared object a Node-API addon, which
+//! Node and Electron can `require` once it's renamed to end in `.node`. Conversions return the
+//! appvars as buffers rather than writing files.
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::path::Path;
+
+use napi::bindgen_prelude::Buffer;
+use napi::{Error, Result, Status};
+use napi_derive::napi;
+
+use crate::{check_var_prefix, Compression, Dither, Image, QuantizeOptions};
+
+/// Options for [`convert`], each of which is the default conversion's if it isn't given.
+#[napi(object)]
+#[derive(Default)]
+pub struct ConvertOptions {
+    /// False to map colors to the nearest in the palette without dithering.
+    pub dither: Option<bool>,
+    /// The name of a [`Compression`], zx0 by default.
+    pub compression: Option<String>,
+    /// The largest number of colors in the palette, from 2 to 256.
+    pub max_colors: Option<u32>,
+    /// False to store the appvars in RAM rather than archive.
+    pub archived: Option<bool>,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(Status::InvalidArg, message)
+}
+
+/// Convert the image file at `path` to appvars, returning an object from the name of each
+/// appvar to the bytes of its 8xv file.
+///
+/// `prefix` must be two letters.
+#[napi]
+pub fn convert(
+    path: String,
+    prefix: String,
+    options: Option<ConvertOptions>,
+) -> Result<BTreeMap<String, Buffer>> {
+    check_var_prefix(&prefix).map_err(invalid)?;
+    let options = options.unwrap_or_default();
+    let compression = match options.compression.as_deref() {
+        Some(compression) => compression.parse().map_err(invalid)?,
+        None => Compression::Zx0,
+    };
+    let mut quantize = QuantizeOptions::default();
+    if let Some(max_colors) = options.max_colors {
+        if !(2..=256).contains(&max_colors) {
+            return Err(invalid(format!(
+                "maxColors must be from 2 to 256, not {}",
+                max_colors
+            )));
+        }
+        quantize.max_colors = max_colors;
+    }
+    if options.dither == Some(false) {
+        quantize.dither = Dither::None;
+    }
+
+    let path = Path::new(&path);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let appvars = std::fs::File::open(path)
+        .and_then(|file| Image::new(BufReader::new(file), &name, &prefix))
+        .and_then(|image| image.quantize_with(&quantize))
+        .and_then(|mut image| {
+            image.set_compression(compression);
+            image.set_archived(options.archived != Some(false));
+            image.write_appvars()
+        })
+        .map_err(|e| Error::from_reason(e.to_string()))?;
+    Ok(appvars
+        .into_iter()
+        .map(|(name, file)| (name, file.into()))
+        .collect())
+}
@@ -0,0 +1,74 @@
+//! Python scripts that draw images on NumWorks calculators
+//!
+//! NumWorks calculators run Python scripts but have no image variables, so the image is embedded
+//! in a script that draws it with the `kandinsky` module.
+use std::io::{Result as IoResult, Write};
+
+use crate::python::Dialect;
+use crate::{Image, QuantizeOptions};
+
+/// Width of the area Python scripts draw on, which is the whole screen.
+pub const NUMWORKS_WIDTH: u32 = 320;
+/// Height of the area Python scripts draw on, which is the screen below its title bar.
+pub const NUMWORKS_HEIGHT: u32 = 222;
+
+const KANDINSKY: Dialect = Dialect {
+    import: "from kandinsky import fill_rect",
+    draw_run: "      fill_rect(x,y,n,1,P[D[i]])\n",
+    before: "",
+    after: "",
+};
+
+impl Image {
+    /// Write a Python script that draws the image on a NumWorks calculator.
+    ///
+    /// Images larger than the drawing area shrink to fit and are centered on the background
+    /// color. This fails if the generated palette can't reach the minimum
+    /// [`quality`](QuantizeOptions::quality); fewer colors make shorter scripts.
+    pub fn write_numworks_script<W: Write>(self, options: &QuantizeOptions, out: W) -> IoResult<W> {
+        self.write_python_script(options, (NUMWORKS_WIDTH, NUMWORKS_HEIGHT), &KANDINSKY, out)
+    }
+}
+
+/// Scripts store each row as runs of palette indices.
+#[test]
+fn numworks_script_encodes_runs() {
+    use crate::python::LINE_LEN;
+    use image::{Rgba, RgbaImage};
+
+    // A red image with a blue left half, exactly the size of the drawing area
+    let image = RgbaImage::from_fn(NUMWORKS_WIDTH, NUMWORKS_HEIGHT, |x, _| {
+        if x < NUMWORKS_WIDTH / 2 {
+            Rgba([0, 0, 255, 255])
+        } else {
+            Rgba([255, 0, 0, 255])
+        }
+    });
+    let options = QuantizeOptions {
+        max_colors: 2,
+        ..Default::default()
+    };
+    let script = Image::from_rgba(image, "test", "TS")
+        .write_numworks_script(&options, Vec::new())
+        .unwrap();
+    let script = String::from_utf8(script).unwrap();
+
+    assert!(script.starts_with("# test converted by hdpictureconverter\n"));
+    assert!(
+        script.contains("P=((0,0,255),(255,0,0),)") || script.contains("P=((255,0,0),(0,0,255),)")
+    );
+    // Each row is two runs of 160 pixels
+    let runs: Vec<&str> = script
+        .lines()
+        .filter(|line| line.starts_with("b\""))
+        .collect();
+    assert_eq!(
+        runs.len(),
+        (NUMWORKS_HEIGHT as usize * 4).div_ceil(LINE_LEN)
+    );
+    assert!(
+        runs[0].starts_with("b\"\\x00\\xa0\\x01\\xa0")
+            || runs[0].starts_with("b\"\\x01\\xa0\\x00\\xa0")
+    );
+    assert!(script.ends_with("draw()\n"));
+}
@@ -0,0 +1,283 @@
+//! Reading and writing palettes as files
+//!
+//! Three formats are read, detected from their contents:
+//!
+//!  * GIMP palettes (`.gpl`), beginning with a `GIMP Palette` line.
+//!  * JASC palettes (`.pal`) as written by Paint Shop Pro, beginning with a `JASC-PAL` line.
+//!  * Plain lists of hex colors like `#ff8000` or `ff8000`, separated by whitespace or commas.
+//!    Anything following a `;` on a line is ignored.
+//!
+//! Palettes can be written as GIMP palettes, or as convimg YAML so projects built with the CE
+//! toolchain can convert other images against the same colors.
+use std::io::{BufRead, Error, ErrorKind, Result as IoResult, Write};
+
+use rgb::RGBA8 as RGBA;
+
+/// The most colors a palette may contain, since pixels are stored as one byte each.
+pub const MAX_COLORS: usize = 256;
+
+fn invalid(line: usize, message: String) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("line {}: {}", line + 1, message),
+    )
+}
+
+/// Read a palette in any supported format, with colors in the order they appear.
+pub fn read<R: BufRead>(input: R) -> IoResult<Vec<RGBA>> {
+    let lines = input.lines().collect::<IoResult<Vec<String>>>()?;
+    let header = lines.first().map(|l| l.trim());
+
+    let colors = match header {
+        Some("GIMP Palette") => read_gpl(&lines)?,
+        Some("JASC-PAL") => read_jasc(&lines)?,
+        _ => read_hex(&lines)?,
+    };
+
+    if colors.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "palette contains no colors",
+        ));
+    }
+    if colors.len() > MAX_COLORS {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "palette contains {} colors but may not have more than {}",
+                colors.len(),
+                MAX_COLORS
+            ),
+        ));
+    }
+    Ok(colors)
+}
+
+/// Parse whitespace-separated decimal red, green and blue components, ignoring anything after.
+fn parse_decimal_rgb(line_number: usize, line: &str) -> IoResult<RGBA> {
+    let mut components = line.split_whitespace().map(|c| {
+        c.parse::<u8>().map_err(|e| {
+            invalid(
+                line_number,
+                format!("{:?} is not a color component: {}", c, e),
+            )
+        })
+    });
+    let mut next = || {
+        components.next().unwrap_or_else(|| {
+            Err(invalid(
+                line_number,
+                "expected three color components".into(),
+            ))
+        })
+    };
+
+    Ok(RGBA::new(next()?, next()?, next()?, 255))
+}
+
+fn read_gpl(lines: &[String]) -> IoResult<Vec<RGBA>> {
+    let mut colors = Vec::new();
+    for (i, line) in lines.iter().enumerate().skip(1) {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("Name:")
+            || line.starts_with("Columns:")
+        {
+            continue;
+        }
+        colors.push(parse_decimal_rgb(i, line)?);
+    }
+    Ok(colors)
+}
+
+fn read_jasc(lines: &[String]) -> IoResult<Vec<RGBA>> {
+    // Header is the signature, a version and the number of colors
+    let count = match lines.get(2).map(|l| l.trim().parse::<usize>()) {
+        Some(Ok(n)) => n,
+        _ => return Err(invalid(2, "expected the number of colors".into())),
+    };
+
+    let colors = lines
+        .iter()
+        .enumerate()
+        .skip(3)
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| parse_decimal_rgb(i, line))
+        .collect::<IoResult<Vec<RGBA>>>()?;
+    if colors.len() != count {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "palette header says it has {} colors but contains {}",
+                count,
+                colors.len()
+            ),
+        ));
+    }
+    Ok(colors)
+}
+
+/// Write colors as a GIMP palette named `name`.
+pub fn write_gpl<W: Write>(colors: &[RGBA], name: &str, mut out: W) -> IoResult<W> {
+    writeln!(out, "GIMP Palette\nName: {}\nColumns: 16\n#", name)?;
+    for (i, color) in colors.iter().enumerate() {
+        writeln!(
+            out,
+            "{:3} {:3} {:3}\tIndex {}",
+            color.r, color.g, color.b, i
+        )?;
+    }
+    Ok(out)
+}
+
+/// Write colors as a convimg project that defines a palette named `name`.
+///
+/// Every color is a fixed entry at its index, so converts against the palette map to exactly
+/// these colors in this order.
+pub fn write_convimg<W: Write>(colors: &[RGBA], name: &str, mut out: W) -> IoResult<W> {
+    writeln!(out, "palettes:\n  - name: {}", name)?;
+    writeln!(out, "    max-entries: {}\n    fixed-entries:", colors.len())?;
+    for (i, color) in colors.iter().enumerate() {
+        writeln!(
+            out,
+            "      - color: {{index: {}, r: {}, g: {}, b: {}}}",
+            i, color.r, color.g, color.b
+        )?;
+    }
+    Ok(out)
+}
+
+/// The default palette of the graphx (xlibc) library, which is active unless a program loads
+/// another one.
+///
+/// Entry `i` is the RGB565 color with both bytes equal to `i`, so images mapped to this palette
+/// can be drawn without shipping a palette at all.
+pub fn xlibc() -> Vec<RGBA> {
+    let scale = |x: u32, max: u32| ((x * 255 + max / 2) / max) as u8;
+    (0..MAX_COLORS as u32)
+        .map(|i| {
+            let rgb565 = (i << 8) | i;
+            RGBA::new(
+                scale(rgb565 >> 11, 31),
+                scale((rgb565 >> 5) & 0x3f, 63),
+                scale(rgb565 & 0x1f, 31),
+                255,
+            )
+        })
+        .collect()
+}
+
+/// The most shades of gray the calculator can display, limited by its 5-bit red and blue.
+pub const MAX_GRAYS: usize = 32;
+
+/// Evenly spaced shades of gray from black to white, which must number between 2 and
+/// [`MAX_GRAYS`].
+///
+/// Every shade is exactly representable on the calculator.
+pub fn gray_ramp(levels: usize) -> Vec<RGBA> {
+    assert!(
+        (2..=MAX_GRAYS).contains(&levels),
+        "gray ramp must have between 2 and {} levels",
+        MAX_GRAYS
+    );
+    (0..levels)
+        .map(|i| {
+            // Round to the nearest 5-bit level so no two shades become the same color
+            let level = (i * 31 + (levels - 1) / 2) / (levels - 1);
+            let gray = ((level * 255 + 15) / 31) as u8;
+            RGBA::new(gray, gray, gray, 255)
+        })
+        .collect()
+}
+
+/// Parse a color written as six hex digits, optionally prefixed by `#` or `0x`.
+pub fn parse_hex_color(s: &str) -> Result<RGBA, String> {
+    let digits = s
+        .strip_prefix('#')
+        .or_else(|| s.strip_prefix("0x"))
+        .unwrap_or(s);
+    if digits.len() != 6 {
+        return Err(format!("{:?} is not a six-digit hex color", s));
+    }
+
+    match u32::from_str_radix(digits, 16) {
+        Ok(x) => Ok(RGBA::new((x >> 16) as u8, (x >> 8) as u8, x as u8, 255)),
+        Err(_) => Err(format!("{:?} is not a six-digit hex color", s)),
+    }
+}
+
+fn read_hex(lines: &[String]) -> IoResult<Vec<RGBA>> {
+    let mut colors = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let line = line.split(';').next().unwrap();
+        for token in line.split(|c: char| c.is_whitespace() || c == ',') {
+            if !token.is_empty() {
+                colors.push(parse_hex_color(token).map_err(|e| invalid(i, e))?);
+            }
+        }
+    }
+    Ok(colors)
+}
+
+/// Each format reads the same colors in order.
+#[test]
+fn reads_all_formats() {
+    let expected = vec![
+        RGBA::new(0, 0, 0, 255),
+        RGBA::new(255, 128, 0, 255),
+        RGBA::new(18, 52, 86, 255),
+    ];
+
+    let gpl = "GIMP Palette\nName: Test\nColumns: 3\n# comment\n  0   0   0\tBlack\n\
+               255 128   0 Orange\n 18  52  86\n";
+    assert_eq!(read(gpl.as_bytes()).unwrap(), expected);
+
+    let jasc = "JASC-PAL\r\n0100\r\n3\r\n0 0 0\r\n255 128 0\r\n18 52 86\r\n";
+    assert_eq!(read(jasc.as_bytes()).unwrap(), expected);
+
+    let hex = "#000000, 0xff8000 ; orange\n123456\n";
+    assert_eq!(read(hex.as_bytes()).unwrap(), expected);
+}
+
+/// Written GIMP palettes read back as the same colors.
+#[test]
+fn gpl_round_trips() {
+    let colors = vec![RGBA::new(0, 0, 0, 255), RGBA::new(255, 128, 8, 255)];
+    let gpl = write_gpl(&colors, "test", Vec::new()).unwrap();
+    assert!(gpl.starts_with(b"GIMP Palette\nName: test\n"));
+    assert_eq!(read(gpl.as_slice()).unwrap(), colors);
+
+    let yaml = String::from_utf8(write_convimg(&colors, "test", Vec::new()).unwrap()).unwrap();
+    assert!(yaml.starts_with("palettes:\n  - name: test\n    max-entries: 2\n"));
+    assert!(yaml.ends_with("      - color: {index: 1, r: 255, g: 128, b: 8}\n"));
+}
+
+/// Every xlibc entry survives conversion to the calculator's color format unchanged.
+#[test]
+fn xlibc_is_exact() {
+    let palette = xlibc();
+    assert_eq!(palette.len(), MAX_COLORS);
+
+    for (i, color) in palette.iter().enumerate() {
+        let rgb565 = ((i << 8) | i) as u16;
+        let (r, g, b) = (rgb565 >> 11, (rgb565 >> 5) & 0x3f, rgb565 & 0x1f);
+        let expected = ((g & 1) << 15) | (r << 10) | ((g & 0x3e) << 4) | b;
+        assert_eq!(*crate::GRGB1555::from(color), expected, "entry {}", i);
+    }
+}
+
+/// Gray ramps span black to white without repeating any calculator color.
+#[test]
+fn gray_ramp_is_distinct() {
+    for levels in 2..=MAX_GRAYS {
+        let ramp = gray_ramp(levels);
+        assert_eq!(ramp.len(), levels);
+        assert_eq!(ramp[0], RGBA::new(0, 0, 0, 255));
+        assert_eq!(ramp[levels - 1], RGBA::new(255, 255, 255, 255));
+
+        let colors: std::collections::HashSet<u16> =
+            ramp.iter().map(|c| *crate::GRGB1555::from(c)).collect();
+        assert_eq!(colors.len(), levels, "{} levels", levels);
+    }
+}
@@ -0,0 +1,81 @@
+//! Rasterizing pages of PDF documents
+//!
+//! Pages are rendered with pdfium, which isn't linked but loaded when the first document is
+//! decoded, from the directory of the running program or else the system's libraries.
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::sync::OnceLock;
+
+use image::RgbaImage;
+use pdfium_render::prelude::{PdfPageIndex, PdfRenderConfig, Pdfium};
+
+use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Density of the coordinates pages are measured in, in points per inch.
+const POINTS_PER_INCH: f32 = 72.;
+
+fn decode_error(e: impl std::fmt::Display) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("Unable to decode image: {}", e),
+    )
+}
+
+/// Return pdfium, loading it the first time.
+fn pdfium() -> IoResult<&'static Pdfium> {
+    static PDFIUM: OnceLock<Result<Pdfium, String>> = OnceLock::new();
+    PDFIUM
+        .get_or_init(|| {
+            let beside_program = std::env::current_exe().ok().and_then(|program| {
+                program
+                    .parent()
+                    .map(Pdfium::pdfium_platform_library_name_at_path)
+            });
+            let bindings = match beside_program.map(Pdfium::bind_to_library) {
+                Some(Ok(bindings)) => Ok(bindings),
+                _ => Pdfium::bind_to_system_library(),
+            };
+            bindings
+                .map(Pdfium::new)
+                .map_err(|e| format!("Unable to load pdfium to decode PDF documents ({:?})", e))
+        })
+        .as_ref()
+        .map_err(|e| Error::other(e.clone()))
+}
+
+/// Rasterize the page of a PDF document with index `page`, counting from 0, at `density` dots per
+/// inch or to fit the calculator screen if that isn't given.
+pub(crate) fn rasterize(data: &[u8], page: usize, density: Option<f32>) -> IoResult<RgbaImage> {
+    let document = pdfium()?
+        .load_pdf_from_byte_slice(data, None)
+        .map_err(decode_error)?;
+    let pages = document.pages();
+    let count = pages.len();
+    let index = PdfPageIndex::try_from(page)
+        .ok()
+        .filter(|&index| index < count)
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "There's no page {} in the document, which has {}",
+                    page + 1,
+                    count
+                ),
+            )
+        })?;
+    let page = pages.get(index).map_err(decode_error)?;
+
+    let scale = match density {
+        Some(dpi) => dpi / POINTS_PER_INCH,
+        None => f32::min(
+            SCREEN_WIDTH as f32 / page.width().value,
+            SCREEN_HEIGHT as f32 / page.height().value,
+        ),
+    };
+    let config = PdfRenderConfig::new().scale_page_by_factor(scale);
+    let image = page
+        .render_with_config(&config)
+        .and_then(|bitmap| bitmap.as_image())
+        .map_err(decode_error)?;
+    Ok(image.into_rgba8())
+}
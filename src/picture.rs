@@ -0,0 +1,171 @@
+//! Monochrome picture variables for the TI-83 Plus and TI-84 Plus
+//!
+//! These calculators have a 96x64 pixel black and white screen, and their picture variables
+//! store every pixel of it except the bottom row as one bit, eight to a byte with the leftmost
+//! in the most significant bit.
+use std::io::{Result as IoResult, Seek, Write};
+
+use rgb::RGBA8 as RGBA;
+use tifiles::VariableType;
+
+use crate::{dither, group, Dither, Image};
+
+/// Width of a picture variable in pixels.
+pub const PICTURE_WIDTH: u32 = 96;
+/// Height of a picture variable in pixels, one less than the screen.
+pub const PICTURE_HEIGHT: u32 = 63;
+
+/// Options controlling how an [`Image`] is converted to a [`Picture`].
+///
+/// These are separate from the [`QuantizeOptions`](crate::QuantizeOptions) for color images,
+/// since dithering to one bit at such a low resolution calls for different choices.
+#[derive(Debug, Clone)]
+pub struct PictureOptions {
+    /// How pixels are dithered to black and white.
+    ///
+    /// The default, Atkinson dithering, diffuses only part of the error, which keeps the high
+    /// contrast that small monochrome images need to be legible.
+    pub dither: Dither,
+    /// The lightness from 0 (black) to 1 (white) below which undithered pixels are dark.
+    ///
+    /// Lower thresholds make images lighter overall.
+    pub threshold: f32,
+    /// The color that partly transparent pixels are blended over, which also fills space around
+    /// images smaller than a picture.
+    pub background: RGBA,
+}
+
+impl Default for PictureOptions {
+    fn default() -> Self {
+        PictureOptions {
+            dither: Dither::Atkinson,
+            threshold: 0.5,
+            background: RGBA::new(255, 255, 255, 255),
+        }
+    }
+}
+
+/// An image converted to a monochrome picture variable.
+pub struct Picture {
+    /// Which of `Pic0` to `Pic9` the picture is stored as.
+    number: u8,
+    /// Whether each pixel is dark, row-major.
+    pixels: Vec<bool>,
+    archived: bool,
+    comment: Option<String>,
+}
+
+impl Image {
+    /// Convert the image to a black and white picture stored as `Pic0` to `Pic9`.
+    ///
+    /// Images larger than a picture shrink to fit and are centered on the background color.
+    pub fn to_picture(self, options: &PictureOptions, number: u8) -> Picture {
+        assert!(number <= 9, "picture number {} is not from 0 to 9", number);
+        let canvas = self.to_screen(PICTURE_WIDTH, PICTURE_HEIGHT, options.background);
+        let lightness: Vec<f32> = canvas
+            .pixels()
+            .map(|p| (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) / 255.)
+            .collect();
+        Picture {
+            number,
+            pixels: dither::one_bit(
+                &lightness,
+                PICTURE_WIDTH as usize,
+                options.dither,
+                options.threshold,
+            ),
+            archived: false,
+            comment: None,
+        }
+    }
+}
+
+impl Picture {
+    /// Return which of `Pic0` to `Pic9` the picture is stored as.
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Return the name of the picture variable as shown on the calculator, like `Pic1`.
+    pub fn var_name(&self) -> String {
+        format!("Pic{}", self.number)
+    }
+
+    /// Return whether the pixel at a position is dark.
+    pub fn is_dark(&self, x: u32, y: u32) -> bool {
+        self.pixels[(y * PICTURE_WIDTH + x) as usize]
+    }
+
+    /// Set whether the variable is stored in archive rather than RAM, which it isn't by default
+    /// since pictures must be in RAM to be recalled.
+    pub fn set_archived(&mut self, archived: bool) {
+        self.archived = archived;
+    }
+
+    /// Set the comment in the header of the variable file, which must be ASCII and at most
+    /// [`group::COMMENT_LEN`] bytes.
+    pub fn set_comment(&mut self, comment: &str) {
+        group::assert_valid_comment(comment);
+        self.comment = Some(comment.into());
+    }
+
+    /// Write the picture as an 8xi variable file.
+    pub fn write<W: Write + Seek>(&self, mut out: W) -> IoResult<W> {
+        let start = out.stream_position()?;
+        // Picture names are tokens: 0x60 followed by 0 for Pic1 through 8 for Pic9, then 9 for
+        // Pic0. The writer copies each character of the name as a byte.
+        let token = (self.number + 9) % 10;
+        let name: String = ['\u{60}', char::from(token)].into_iter().collect();
+        let mut writer = tifiles::Writer::new(out, VariableType::Picture, &name, self.archived)?;
+
+        for row in self.pixels.chunks(PICTURE_WIDTH as usize) {
+            let bytes: Vec<u8> = row
+                .chunks(8)
+                .map(|bits| {
+                    bits.iter()
+                        .enumerate()
+                        .fold(0, |byte, (i, &dark)| byte | (dark as u8) << (7 - i))
+                })
+                .collect();
+            writer.write_all(&bytes)?;
+        }
+
+        let mut out = writer.close()?;
+        if let Some(comment) = &self.comment {
+            group::replace_comment(&mut out, start, comment)?;
+        }
+        Ok(out)
+    }
+}
+
+/// Pictures are fixed size with bits packed from the left, and dark pixels are set.
+#[test]
+fn picture_packs_pixels() {
+    use image::{Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    // A white image with a black column at the left edge, taller than a picture
+    let image = RgbaImage::from_fn(96, 126, |x, _| {
+        let level = if x < 2 { 0 } else { 255 };
+        Rgba([level, level, level, 255])
+    });
+    let options = PictureOptions {
+        dither: Dither::None,
+        ..Default::default()
+    };
+    let picture = Image::from_rgba(image, "test", "TS").to_picture(&options, 0);
+    assert_eq!(picture.var_name(), "Pic0");
+    // Halving the width centers the image, so the column lands at x = 24
+    assert!(picture.is_dark(24, 10));
+    assert!(!picture.is_dark(0, 10));
+    assert!(!picture.is_dark(30, 10));
+
+    let file = picture.write(Cursor::new(Vec::new())).unwrap().into_inner();
+    let contents = group::read(&file).unwrap();
+    let var = &contents.vars[0];
+    assert_eq!(var.ty, VariableType::Picture);
+    assert_eq!(var.name.as_bytes(), [0x60, 0x09]);
+    assert_eq!(var.data.len(), 756);
+    assert_eq!(var.data[10 * 12 + 3], 0b1000_0000);
+    assert_eq!(var.data[10 * 12 + 2], 0);
+}
@@ -0,0 +1,120 @@
+//! Python bindings for converting images in scripts
+//!
+//! Building the library with the `python` feature, such as with
+//! `maturin build --features python`, makes its shared object a Python extension module named
+//! `hdpictureconverter`. Conversions return the appvars themselves rather than writing files, so
+//! asset pipelines can put them wherever they like.
+use std::io::BufReader;
+use std::path::PathBuf;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::{check_var_prefix, Compression, Dither, Image, QuantizeOptions};
+
+/// Convert the image file at `path` to appvars, returning a dict from the name of each appvar to
+/// the bytes of its 8xv file.
+///
+/// `prefix` must be two letters. Unless `dither` is false, colors are dithered like the default
+/// conversion, `compression` is one of "zx0" (the default), "deflate", "rle" or "none", and the
+/// palette has at most `max_colors` colors.
+#[pyfunction]
+#[pyo3(signature = (path, prefix, *, dither = true, compression = "zx0", max_colors = 256, archived = true))]
+fn convert<'py>(
+    py: Python<'py>,
+    path: PathBuf,
+    prefix: &str,
+    dither: bool,
+    compression: &str,
+    max_colors: u32,
+    archived: bool,
+) -> PyResult<Bound<'py, PyDict>> {
+    check_var_prefix(prefix).map_err(PyValueError::new_err)?;
+    let compression: Compression = compression.parse().map_err(PyValueError::new_err)?;
+    if !(2..=256).contains(&max_colors) {
+        return Err(PyValueError::new_err(format!(
+            "max_colors must be from 2 to 256, not {}",
+            max_colors
+        )));
+    }
+    let mut options = QuantizeOptions {
+        max_colors,
+        ..Default::default()
+    };
+    if !dither {
+        options.dither = Dither::None;
+    }
+
+    // Other Python threads can run while the image is converted
+    let appvars = py.detach(|| convert_appvars(path, prefix, &options, compression, archived))?;
+    let dict = PyDict::new(py);
+    for (name, file) in appvars {
+        dict.set_item(name, PyBytes::new(py, &file))?;
+    }
+    Ok(dict)
+}
+
+fn convert_appvars(
+    path: PathBuf,
+    prefix: &str,
+    options: &QuantizeOptions,
+    compression: Compression,
+    archived: bool,
+) -> std::io::Result<Vec<(String, Vec<u8>)>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let image = Image::new(BufReader::new(std::fs::File::open(&path)?), &name, prefix)?;
+    let mut image = image.quantize_with(options)?;
+    image.set_compression(compression);
+    image.set_archived(archived);
+    image.write_appvars()
+}
+
+#[pymodule]
+fn hdpictureconverter(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_function(wrap_pyfunction!(convert, module)?)
+}
+
+/// Converting from Python gives each appvar of the image, and bad options raise exceptions.
+#[test]
+fn convert_returns_appvars() {
+    let path = std::env::temp_dir().join(format!("hdpc-py-{}.png", std::process::id()));
+    image::RgbaImage::from_fn(100, 50, |x, y| {
+        image::Rgba([(x * 2) as u8, (y * 5) as u8, 128, 255])
+    })
+    .save(&path)
+    .unwrap();
+
+    Python::initialize();
+    Python::attach(|py| {
+        let module = PyModule::new(py, "hdpictureconverter").unwrap();
+        hdpictureconverter(&module).unwrap();
+        let locals = PyDict::new(py);
+        locals.set_item("hdpc", module).unwrap();
+        locals.set_item("path", path.to_str().unwrap()).unwrap();
+        py.run(
+            cr#"
+appvars = hdpc.convert(path, "TS", dither=False, max_colors=16)
+assert sorted(appvars) == ["HPTS0000", "TS000000", "TS001000"], appvars
+assert all(data.startswith(b"**TI83F*") for data in appvars.values())
+for kwargs in [{"compression": "lz4"}, {"max_colors": 1}]:
+    try:
+        hdpc.convert(path, "TS", **kwargs)
+    except ValueError:
+        pass
+    else:
+        raise AssertionError(kwargs)
+try:
+    hdpc.convert(path + ".missing", "TS")
+except FileNotFoundError:
+    pass
+else:
+    raise AssertionError("missing file converted")
+"#,
+            None,
+            Some(&locals),
+        )
+        .unwrap();
+    });
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,105 @@
+//! Python scripts that draw images on calculators without image variables
+//!
+//! The image is embedded in a script that draws it with the calculator's graphics module. Pixels
+//! are mapped to a palette and stored as runs of one color along each row, since every pixel at
+//! full depth wouldn't fit in the calculator's storage.
+use std::io::{Result as IoResult, Write};
+
+use crate::{Image, QuantizeOptions};
+
+/// Bytes of run data on each line of the script.
+pub(crate) const LINE_LEN: usize = 48;
+
+/// The parts of a script that depend on the calculator's graphics module.
+pub(crate) struct Dialect {
+    /// Line importing the functions the script draws with.
+    pub import: &'static str,
+    /// Statements drawing `n` pixels of color `P[D[i]]` from `x`, `y`, indented to go in the
+    /// drawing loop.
+    pub draw_run: &'static str,
+    /// Statements before drawing.
+    pub before: &'static str,
+    /// Statements after drawing.
+    pub after: &'static str,
+}
+
+impl Image {
+    /// Write a Python script that draws the image centered in a `width` by `height` area.
+    pub(crate) fn write_python_script<W: Write>(
+        self,
+        options: &QuantizeOptions,
+        (width, height): (u32, u32),
+        dialect: &Dialect,
+        mut out: W,
+    ) -> IoResult<W> {
+        let name = self.name.trim_end_matches('_').to_string();
+        // Tiles must divide the canvas exactly or it'd be padded past the screen
+        let canvas = Image {
+            input: self.to_screen(width, height, options.background),
+            name: String::new(),
+            var_prefix: String::new(),
+            tile_size: (width / 2, height),
+        };
+        let quantized = canvas.quantize_with(options)?;
+
+        // Runs are pairs of a palette index and a length from 1 to 255, and don't cross rows
+        let mut runs = Vec::new();
+        for row in quantized.data.chunks(width as usize) {
+            let mut pixels = row.iter().peekable();
+            while let Some(&index) = pixels.next() {
+                let mut len = 1;
+                while len < 255 && pixels.next_if_eq(&&index).is_some() {
+                    len += 1;
+                }
+                runs.extend_from_slice(&[index, len]);
+            }
+        }
+
+        writeln!(out, "# {} converted by hdpictureconverter", name)?;
+        writeln!(out, "{}", dialect.import)?;
+        writeln!(out, "W,H={},{}", width, height)?;
+        write!(out, "P=(")?;
+        for color in &quantized.palette {
+            write!(out, "({},{},{}),", color.r, color.g, color.b)?;
+        }
+        writeln!(out, ")")?;
+        writeln!(out, "D=(")?;
+        for line in runs.chunks(LINE_LEN) {
+            writeln!(out, "b\"{}\"", escape_bytes(line))?;
+        }
+        writeln!(out, ")")?;
+        out.write_all(
+            b"def draw():\n\
+              \x20 i=0\n\
+              \x20 for y in range(H):\n\
+              \x20   x=0\n\
+              \x20   while x<W:\n\
+              \x20     n=D[i+1]\n",
+        )?;
+        out.write_all(dialect.draw_run.as_bytes())?;
+        out.write_all(
+            b"      x+=n\n\
+              \x20     i+=2\n",
+        )?;
+        write!(out, "{}draw()\n{}", dialect.before, dialect.after)?;
+        Ok(out)
+    }
+}
+
+/// Return the contents of a Python bytes literal for some bytes.
+fn escape_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&b| match b {
+            b'"' | b'\\' => format!("\\{}", b as char),
+            0x20..=0x7e => (b as char).to_string(),
+            _ => format!("\\x{:02x}", b),
+        })
+        .collect()
+}
+
+/// Bytes that can't appear as themselves in a literal are escaped.
+#[test]
+fn bytes_are_escaped() {
+    assert_eq!(escape_bytes(b"a\"\\\n\xff "), "a\\\"\\\\\\x0a\\xff ");
+}
@@ -0,0 +1,447 @@
+//! Choice of algorithm for generating palettes
+
+use std::collections::HashMap;
+#[cfg(feature = "imagequant")]
+use std::io::Error;
+
+use color_quant::NeuQuant;
+use rgb::RGBA8 as RGBA;
+
+use crate::ColorSpace;
+
+/// Algorithms for generating a palette suited to an image.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Quantizer {
+    /// libimagequant, which generally gives the best palettes.
+    ///
+    /// This is only available with the `imagequant` feature.
+    #[cfg(feature = "imagequant")]
+    #[cfg_attr(feature = "imagequant", default)]
+    ImageQuant,
+    /// NeuQuant, which trains a small neural network on a sample of the pixels.
+    ///
+    /// It's usually faster than libimagequant but gives slightly worse palettes, particularly
+    /// with few colors.
+    #[cfg_attr(not(feature = "imagequant"), default)]
+    NeuQuant,
+    /// Median cut, which repeatedly splits the colors of the image in half.
+    ///
+    /// It's the fastest, and works well for images with few distinct colors like screenshots, but
+    /// gives banding in smooth gradients.
+    MedianCut,
+    /// An octree of colors, whose smallest branches are merged until few enough remain.
+    ///
+    /// It needs little memory however many colors the image has, and handles gradients better
+    /// than median cut.
+    Octree,
+}
+
+/// Fraction of pixels NeuQuant learns from, from 1 (every pixel) to 30 (one in 30).
+const NEUQUANT_SAMPLE_FACTOR: i32 = 10;
+
+/// Generate a palette of `colors` entries from RGBA pixel data with NeuQuant, learning in
+/// `space`.
+pub(crate) fn neuquant_palette(pixels: &[u8], colors: u32, space: ColorSpace) -> Vec<RGBA> {
+    NeuQuant::new(
+        NEUQUANT_SAMPLE_FACTOR,
+        colors as usize,
+        &space.encode(pixels),
+    )
+    .color_map_rgba()
+    .chunks_exact(4)
+    .map(|c| space.decode(RGBA::new(c[0], c[1], c[2], 255)))
+    .collect()
+}
+
+/// A histogram of the colors of one or more images, from which median cut generates a palette.
+#[derive(Default)]
+pub(crate) struct MedianCut {
+    counts: HashMap<[u8; 3], u32>,
+}
+
+impl MedianCut {
+    /// Count the colors of RGBA pixel data, ignoring alpha.
+    pub(crate) fn add(&mut self, pixels: &[u8]) {
+        for p in pixels.chunks_exact(4) {
+            *self.counts.entry([p[0], p[1], p[2]]).or_default() += 1;
+        }
+    }
+
+    /// Generate a palette of at most `colors` entries.
+    ///
+    /// Starting from a box holding every color, the box spanning the widest range of any one
+    /// component is split at the median pixel along that component until there are enough boxes.
+    /// Each box then contributes the average of its pixels.
+    pub(crate) fn palette(self, colors: u32) -> Vec<RGBA> {
+        // Sorting makes the palette the same every time, whatever order the map iterates in
+        let mut counts: Vec<_> = self.counts.into_iter().collect();
+        counts.sort_unstable();
+        let mut boxes = Vec::new();
+        if !counts.is_empty() {
+            boxes.push(ColorBox::new(counts));
+        }
+
+        while boxes.len() < colors as usize {
+            let Some(i) = (0..boxes.len())
+                .filter(|&i| boxes[i].range > 0)
+                .max_by_key(|&i| boxes[i].range)
+            else {
+                // Every box is a single color
+                break;
+            };
+
+            let ColorBox {
+                mut colors, axis, ..
+            } = boxes.swap_remove(i);
+            colors.sort_unstable_by_key(|(color, _)| color[axis]);
+            let total: u64 = colors.iter().map(|&(_, count)| count as u64).sum();
+            let mut seen = 0;
+            let median = colors
+                .iter()
+                .position(|&(_, count)| {
+                    seen += count as u64;
+                    seen * 2 >= total
+                })
+                .unwrap();
+            // Both halves must keep at least one color
+            let upper = colors.split_off((median + 1).min(colors.len() - 1));
+            boxes.push(ColorBox::new(colors));
+            boxes.push(ColorBox::new(upper));
+        }
+
+        boxes.iter().map(|b| average(&b.colors)).collect()
+    }
+}
+
+/// Distinct colors and their pixel counts, with the component whose values span the widest range.
+struct ColorBox {
+    colors: Vec<([u8; 3], u32)>,
+    axis: usize,
+    range: u8,
+}
+
+impl ColorBox {
+    fn new(colors: Vec<([u8; 3], u32)>) -> Self {
+        let (axis, range) = (0..3)
+            .map(|axis| {
+                let values = colors.iter().map(|(color, _)| color[axis]);
+                (axis, values.clone().max().unwrap() - values.min().unwrap())
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap();
+        ColorBox {
+            colors,
+            axis,
+            range,
+        }
+    }
+}
+
+/// Return the average color of the pixels in a box.
+fn average(colors: &[([u8; 3], u32)]) -> RGBA {
+    let mut sums = [0u64; 3];
+    let mut total = 0u64;
+    for &(color, count) in colors {
+        for (sum, component) in sums.iter_mut().zip(color) {
+            *sum += component as u64 * count as u64;
+        }
+        total += count as u64;
+    }
+    let [r, g, b] = sums.map(|sum| ((sum + total / 2) / total) as u8);
+    RGBA::new(r, g, b, 255)
+}
+
+/// Most leaves an octree may have while colors are added, beyond which its deepest level is merged.
+const OCTREE_MAX_LEAVES: usize = 1 << 14;
+
+/// A node of an octree, holding the total of every pixel that reached it.
+#[derive(Default)]
+struct Node {
+    sum: [u64; 3],
+    count: u64,
+    /// Indices of child nodes selected by one bit of each component, or 0 for none.
+    children: [u32; 8],
+    leaf: bool,
+}
+
+/// An octree of the colors of one or more images, from which a palette is generated.
+///
+/// Each level down the tree selects a child by the next most significant bit of each component,
+/// so leaves at the bottom are exact colors. Merging a node's children into it makes it a leaf
+/// standing for every color under it.
+pub(crate) struct Octree {
+    nodes: Vec<Node>,
+    /// Indices of nodes freed by merging, to reuse.
+    free: Vec<u32>,
+    /// Indices of the nodes at each depth which aren't leaves.
+    reducible: [Vec<u32>; 8],
+    leaves: usize,
+    /// Depth of the leaves, which decreases when a whole level is merged.
+    leaf_depth: usize,
+}
+
+impl Default for Octree {
+    fn default() -> Self {
+        let mut reducible: [Vec<u32>; 8] = Default::default();
+        reducible[0].push(0);
+        Octree {
+            nodes: vec![Node::default()],
+            free: Vec::new(),
+            reducible,
+            leaves: 0,
+            leaf_depth: 8,
+        }
+    }
+}
+
+impl Octree {
+    /// Count the colors of RGBA pixel data, ignoring alpha.
+    pub(crate) fn add(&mut self, pixels: &[u8]) {
+        for p in pixels.chunks_exact(4) {
+            let (mut node, mut depth) = (0, 0);
+            loop {
+                let n = &mut self.nodes[node];
+                n.count += 1;
+                for (sum, &component) in n.sum.iter_mut().zip(&p[..3]) {
+                    *sum += component as u64;
+                }
+                if n.leaf {
+                    break;
+                }
+
+                let shift = 7 - depth;
+                let bit = |component: u8| ((component >> shift) & 1) as usize;
+                let child = (bit(p[0]) << 2) | (bit(p[1]) << 1) | bit(p[2]);
+                if n.children[child] == 0 {
+                    let index = self.allocate(depth + 1);
+                    self.nodes[node].children[child] = index;
+                }
+                node = self.nodes[node].children[child] as usize;
+                depth += 1;
+            }
+
+            if self.leaves > OCTREE_MAX_LEAVES {
+                let depth = (0..8)
+                    .rev()
+                    .find(|&depth| !self.reducible[depth].is_empty())
+                    .unwrap();
+                for node in std::mem::take(&mut self.reducible[depth]) {
+                    self.merge(node);
+                }
+                self.leaf_depth = depth;
+            }
+        }
+    }
+
+    /// Add a node at `depth`, returning its index.
+    fn allocate(&mut self, depth: usize) -> u32 {
+        let leaf = depth == self.leaf_depth;
+        let node = Node {
+            leaf,
+            ..Default::default()
+        };
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index as usize] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() as u32 - 1
+            }
+        };
+
+        if leaf {
+            self.leaves += 1;
+        } else {
+            self.reducible[depth].push(index);
+        }
+        index
+    }
+
+    /// Merge the children of a node whose children are all leaves into it.
+    fn merge(&mut self, node: u32) {
+        let n = &mut self.nodes[node as usize];
+        n.leaf = true;
+        for child in std::mem::take(&mut n.children) {
+            if child != 0 {
+                self.free.push(child);
+                self.leaves -= 1;
+            }
+        }
+        self.leaves += 1;
+    }
+
+    /// Generate a palette of at most `colors` entries.
+    ///
+    /// The nodes with the fewest pixels at the deepest level are merged first, then the next
+    /// level up, until there are few enough leaves. Each leaf contributes the average of its
+    /// pixels.
+    pub(crate) fn palette(mut self, colors: u32) -> Vec<RGBA> {
+        for depth in (0..8).rev() {
+            if self.leaves <= colors as usize {
+                break;
+            }
+            let mut nodes = std::mem::take(&mut self.reducible[depth]);
+            nodes.sort_unstable_by_key(|&node| std::cmp::Reverse(self.nodes[node as usize].count));
+            while self.leaves > colors as usize {
+                let Some(node) = nodes.pop() else {
+                    break;
+                };
+                self.merge(node);
+            }
+        }
+
+        let mut palette = Vec::with_capacity(self.leaves);
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            let n = &self.nodes[node as usize];
+            if n.leaf {
+                let [r, g, b] = n.sum.map(|sum| ((sum + n.count / 2) / n.count) as u8);
+                palette.push(RGBA::new(r, g, b, 255));
+            } else {
+                stack.extend(n.children.iter().filter(|&&child| child != 0));
+            }
+        }
+        palette
+    }
+}
+
+/// The colors of one or more images, counted for whichever of median cut or an octree will
+/// generate their palette.
+pub(crate) struct Histogram {
+    space: ColorSpace,
+    counts: Counts,
+}
+
+enum Counts {
+    MedianCut(MedianCut),
+    Octree(Box<Octree>),
+}
+
+impl Histogram {
+    /// Start counting colors in `space`.
+    pub(crate) fn new(quantizer: Quantizer, space: ColorSpace) -> Self {
+        let counts = match quantizer {
+            Quantizer::MedianCut => Counts::MedianCut(MedianCut::default()),
+            Quantizer::Octree => Counts::Octree(Box::default()),
+            _ => unreachable!("{:?} doesn't generate palettes from a histogram", quantizer),
+        };
+        Histogram { space, counts }
+    }
+
+    /// Count the colors of RGBA pixel data, ignoring alpha.
+    pub(crate) fn add(&mut self, pixels: &[u8]) {
+        let pixels = self.space.encode(pixels);
+        match &mut self.counts {
+            Counts::MedianCut(h) => h.add(&pixels),
+            Counts::Octree(h) => h.add(&pixels),
+        }
+    }
+
+    /// Generate a palette of at most `colors` entries.
+    pub(crate) fn palette(self, colors: u32) -> Vec<RGBA> {
+        let palette = match self.counts {
+            Counts::MedianCut(h) => h.palette(colors),
+            Counts::Octree(h) => h.palette(colors),
+        };
+        palette.into_iter().map(|c| self.space.decode(c)).collect()
+    }
+}
+
+/// Describe a libimagequant failure, which is usually the palette falling short of the minimum
+/// quality.
+#[cfg(feature = "imagequant")]
+pub(crate) fn imagequant_error(e: imagequant::Error) -> Error {
+    match e {
+        imagequant::Error::QualityTooLow => Error::other(
+            "palette would be below the minimum quality; allow more colors or lower it",
+        ),
+        e => Error::other(format!("Unable to quantize image: {}", e)),
+    }
+}
+
+/// NeuQuant finds every color of an image with only a few of them.
+#[test]
+fn neuquant_finds_flat_colors() {
+    let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+    let pixels: Vec<u8> = (0..4096)
+        .flat_map(|i| {
+            let [r, g, b] = colors[i % colors.len()];
+            [r, g, b, 255]
+        })
+        .collect();
+
+    let palette = neuquant_palette(&pixels, 16, ColorSpace::Rgb);
+    assert_eq!(palette.len(), 16);
+    for [r, g, b] in colors {
+        assert!(
+            palette
+                .iter()
+                .any(|c| c.r.abs_diff(r) <= 8 && c.g.abs_diff(g) <= 8 && c.b.abs_diff(b) <= 8),
+            "no palette entry near {:?} in {:?}",
+            (r, g, b),
+            palette
+        );
+    }
+}
+
+/// Median cut keeps every color exactly when there are no more than the palette holds, and
+/// splits the widest range first when there are more.
+#[test]
+fn median_cut_splits_widest_range() {
+    let mut histogram = MedianCut::default();
+    histogram.add(&[255, 0, 0, 255, 0, 0, 255, 255, 0, 0, 255, 255]);
+    let mut palette = histogram.palette(16);
+    palette.sort_unstable_by_key(|c| (c.r, c.g, c.b));
+    assert_eq!(
+        palette,
+        [RGBA::new(0, 0, 255, 255), RGBA::new(255, 0, 0, 255)]
+    );
+
+    // Red spans 0 to 200 and green only 0 to 10, so red is split first
+    let mut histogram = MedianCut::default();
+    for color in [[0, 0, 0], [0, 10, 0], [200, 0, 0], [200, 10, 0]] {
+        histogram.add(&[color[0], color[1], color[2], 255]);
+    }
+    let mut palette = histogram.palette(2);
+    palette.sort_unstable_by_key(|c| (c.r, c.g, c.b));
+    assert_eq!(
+        palette,
+        [RGBA::new(0, 5, 0, 255), RGBA::new(200, 5, 0, 255)]
+    );
+}
+
+/// An octree keeps every color exactly when there are few, and stays small when there are many.
+#[test]
+fn octree_bounds_colors() {
+    let mut octree = Octree::default();
+    octree.add(&[255, 0, 0, 255, 0, 0, 255, 255, 0, 0, 255, 255, 1, 2, 3, 255]);
+    let mut palette = octree.palette(16);
+    palette.sort_unstable_by_key(|c| (c.r, c.g, c.b));
+    assert_eq!(
+        palette,
+        [
+            RGBA::new(0, 0, 255, 255),
+            RGBA::new(1, 2, 3, 255),
+            RGBA::new(255, 0, 0, 255)
+        ]
+    );
+
+    // Every color in a 64x64x64 cube, far more than the octree keeps while adding
+    let mut octree = Octree::default();
+    for r in 0..64 {
+        let pixels: Vec<u8> = (0..64 * 64)
+            .flat_map(|gb| [r * 4, (gb / 64) as u8 * 4, (gb % 64) as u8 * 4, 255])
+            .collect();
+        octree.add(&pixels);
+    }
+    assert!(octree.leaves <= OCTREE_MAX_LEAVES);
+    assert!(octree.nodes.len() <= 8 * OCTREE_MAX_LEAVES);
+    let palette = octree.palette(256);
+    assert!(
+        (200..=256).contains(&palette.len()),
+        "{} colors",
+        palette.len()
+    );
+}
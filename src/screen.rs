@@ -0,0 +1,116 @@
+//! Encodings of full color images for calculators that don't use a palette
+//!
+//! These calculators have 16-bit screens with five bits of red, six of green and five of blue in
+//! each pixel, so images are stored at that depth rather than mapped to a palette.
+use image::{Rgba, RgbaImage};
+
+/// Width of the Casio fx-CG50 screen in pixels.
+pub const CG50_WIDTH: u32 = 384;
+/// Height of the Casio fx-CG50 screen in pixels.
+pub const CG50_HEIGHT: u32 = 216;
+
+/// Return a pixel reduced to 16-bit color, with red in the high bits.
+pub fn rgb565(pixel: Rgba<u8>) -> u16 {
+    let [r, g, b, _] = pixel.0;
+    (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3
+}
+
+/// Return the color of a 16-bit pixel, scaling each channel back to the full range.
+pub fn from_rgb565(color: u16) -> Rgba<u8> {
+    let expand = |value: u16, bits: u32| (value * 255 / ((1 << bits) - 1)) as u8;
+    Rgba([
+        expand(color >> 11, 5),
+        expand(color >> 5 & 0x3f, 6),
+        expand(color & 0x1f, 5),
+        255,
+    ])
+}
+
+/// Encode an image as raw 16-bit pixels, row-major and little-endian with no header.
+///
+/// This is the layout of the TI-Nspire CX's framebuffer, so programs can copy it straight to
+/// the screen.
+pub fn raw_rgb565(image: &RgbaImage) -> Vec<u8> {
+    image
+        .pixels()
+        .flat_map(|&pixel| rgb565(pixel).to_le_bytes())
+        .collect()
+}
+
+/// Encode an image as a BMP file with 16-bit pixels.
+pub fn bmp(image: &RgbaImage) -> Vec<u8> {
+    const HEADER_LEN: u32 = 14 + 40 + 12;
+    // Rows are padded to a multiple of four bytes
+    let row_len = (image.width() * 2).div_ceil(4) * 4;
+    let data_len = row_len * image.height();
+
+    let mut file = Vec::with_capacity((HEADER_LEN + data_len) as usize);
+    // File header: signature, file size, reserved and offset of the pixels
+    file.extend_from_slice(b"BM");
+    for field in [HEADER_LEN + data_len, 0, HEADER_LEN] {
+        file.extend_from_slice(&field.to_le_bytes());
+    }
+    // Info header, with a positive height for rows from the bottom up and bit field compression
+    // so the masks that follow describe the pixels
+    file.extend_from_slice(&40u32.to_le_bytes());
+    file.extend_from_slice(&(image.width() as i32).to_le_bytes());
+    file.extend_from_slice(&(image.height() as i32).to_le_bytes());
+    file.extend_from_slice(&1u16.to_le_bytes());
+    file.extend_from_slice(&16u16.to_le_bytes());
+    for field in [3, data_len, 2835, 2835, 0, 0, 0xf800, 0x07e0, 0x001f] {
+        file.extend_from_slice(&field.to_le_bytes());
+    }
+
+    for row in image.rows().rev() {
+        let start = file.len();
+        for pixel in row {
+            file.extend_from_slice(&rgb565(*pixel).to_le_bytes());
+        }
+        file.resize(start + row_len as usize, 0);
+    }
+    file
+}
+
+/// Each channel keeps its most significant bits.
+#[test]
+fn rgb565_truncates_channels() {
+    assert_eq!(rgb565(Rgba([255, 255, 255, 255])), 0xffff);
+    assert_eq!(rgb565(Rgba([0xf8, 0, 0, 255])), 0xf800);
+    assert_eq!(rgb565(Rgba([0, 0xfc, 0, 255])), 0x07e0);
+    assert_eq!(rgb565(Rgba([7, 3, 0xff, 255])), 0x001f);
+    assert_eq!(from_rgb565(0xffff), Rgba([255, 255, 255, 255]));
+    assert_eq!(from_rgb565(0x07e0), Rgba([0, 255, 0, 255]));
+}
+
+/// Raw pixels have no header or padding.
+#[test]
+fn raw_rgb565_is_row_major() {
+    let image = RgbaImage::from_fn(2, 2, |x, y| Rgba([x as u8 * 0xff, y as u8 * 0xff, 0, 255]));
+    assert_eq!(
+        raw_rgb565(&image),
+        [0x00, 0x00, 0x00, 0xf8, 0xe0, 0x07, 0xe0, 0xff]
+    );
+}
+
+/// BMP files decode to the image, at 16-bit precision and with odd widths padded.
+#[test]
+fn bmp_round_trips() {
+    let image = RgbaImage::from_fn(5, 3, |x, y| Rgba([x as u8 * 60, y as u8 * 100, 200, 255]));
+    let file = bmp(&image);
+    assert_eq!(file.len(), 66 + 12 * 3);
+
+    let decoded = image::load_from_memory_with_format(&file, image::ImageFormat::Bmp)
+        .unwrap()
+        .into_rgba8();
+    assert_eq!(decoded.dimensions(), (5, 3));
+    for (original, decoded) in image.pixels().zip(decoded.pixels()) {
+        for c in 0..3 {
+            assert!(
+                original[c].abs_diff(decoded[c]) < 8,
+                "{:?} {:?}",
+                original,
+                decoded
+            );
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Source code describing converted images, for programs that show them
+//!
+//! Programs built on the PC or the calculator can include this to know an image's palette and
+//! the names of its appvars without copying them out by hand, whether they're written in ICE, in
+//! C with the CE toolchain or in assembly.
+use std::io::{Result as IoResult, Write};
+
+use crate::{QuantizedImage, GRGB1555};
+
+impl QuantizedImage {
+    /// Return the names of every tile appvar, row by row.
+    fn tile_names(&self) -> Vec<String> {
+        (0..self.height_tiles())
+            .flat_map(|row| {
+                (0..self.width_tiles()).map(move |column| {
+                    self.name_template
+                        .render(&self.var_prefix, self.frame, column, row)
+                })
+            })
+            .collect()
+    }
+
+    /// Return a description of the image for the top of generated source.
+    fn source_summary(&self) -> String {
+        format!(
+            "{} ({}), converted by hdpictureconverter\n\
+             {}x{} pixels in {}x{} tiles of {}x{}, compressed with {:?}",
+            self.name.trim_end_matches('_'),
+            self.var_prefix,
+            self.width(),
+            self.height(),
+            self.width_tiles(),
+            self.height_tiles(),
+            self.tile_size.0,
+            self.tile_size.1,
+            self.compression
+        )
+    }
+
+    /// Return the palette in the calculator's 16-bit format.
+    fn palette_words(&self) -> Vec<u16> {
+        self.palette
+            .iter()
+            .map(|color| *GRGB1555::from(color))
+            .collect()
+    }
+
+    /// Write source for ICE programs that stores the image's geometry, palette and appvar names
+    /// in variables.
+    ///
+    /// The source is text to tokenize into a program and include with `prgm`. `W` and `H` are
+    /// the size in pixels, `C` and `R` the number of columns and rows of tiles and `X` and `Y`
+    /// the size of each tile. `P` points to the palette in the format the LCD reads and `N` to
+    /// the palette appvar's name, and `T` to the names of every tile appvar, row by row and
+    /// padded with spaces to 8 characters each. Direct color images have no palette, so they
+    /// have neither `P` nor `N`.
+    pub fn write_ice_source<W: Write>(&self, mut out: W) -> IoResult<W> {
+        for line in self.source_summary().lines() {
+            writeln!(out, "// {}", line)?;
+        }
+        writeln!(out, "{}→W:{}→H", self.width(), self.height())?;
+        writeln!(out, "{}→C:{}→R", self.width_tiles(), self.height_tiles())?;
+        writeln!(out, "{}→X:{}→Y", self.tile_size.0, self.tile_size.1)?;
+        if !self.direct_color {
+            let colors: Vec<String> = self
+                .palette_words()
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            writeln!(out, "Data(2,{})→P", colors.join(","))?;
+            writeln!(out, "\"{}\"→N", self.palette_appvar_name())?;
+        }
+        let names: String = self
+            .tile_names()
+            .iter()
+            .map(|name| format!("{:8}", name))
+            .collect();
+        writeln!(out, "\"{}\"→T", names)?;
+        Ok(out)
+    }
+
+    /// Return the prefix of identifiers in C source, which is the image's name in lowercase.
+    fn c_identifier(&self) -> String {
+        self.name.trim_end_matches('_').to_ascii_lowercase()
+    }
+
+    /// Write a C header declaring the image's geometry, palette and tiles, in the style of
+    /// convimg.
+    ///
+    /// Every identifier starts with the image's name in lowercase, so headers for several images
+    /// can be included together. The palette is in the format graphx's `gfx_SetPalette` takes,
+    /// and each tile has the name to open with fileioc and its position in the image. Direct
+    /// color images have no palette, so they don't declare one.
+    pub fn write_c_header<W: Write>(&self, mut out: W) -> IoResult<W> {
+        let id = self.c_identifier();
+        let guard = format!("{}_H", id.to_ascii_uppercase());
+        let tiles = self.width_tiles() * self.height_tiles();
+        writeln!(out, "/*")?;
+        for line in self.source_summary().lines() {
+            writeln!(out, " * {}", line)?;
+        }
+        writeln!(out, " */")?;
+        writeln!(out, "#ifndef {}\n#define {}\n", guard, guard)?;
+        writeln!(out, "#include <stdint.h>\n")?;
+        writeln!(out, "#define {}_width {}", id, self.width())?;
+        writeln!(out, "#define {}_height {}", id, self.height())?;
+        writeln!(out, "#define {}_tile_width {}", id, self.tile_size.0)?;
+        writeln!(out, "#define {}_tile_height {}", id, self.tile_size.1)?;
+        writeln!(out, "#define {}_columns {}", id, self.width_tiles())?;
+        writeln!(out, "#define {}_rows {}", id, self.height_tiles())?;
+        writeln!(out, "#define {}_tile_count {}", id, tiles)?;
+        if !self.direct_color {
+            writeln!(
+                out,
+                "#define {}_palette_appvar \"{}\"",
+                id,
+                self.palette_appvar_name()
+            )?;
+            if let Some(index) = self.transparent_index() {
+                writeln!(out, "#define {}_transparent_index {}", id, index)?;
+            }
+            writeln!(
+                out,
+                "#define sizeof_{}_palette {}",
+                id,
+                self.palette.len() * 2
+            )?;
+            writeln!(
+                out,
+                "extern const uint16_t {}_palette[{}];",
+                id,
+                self.palette.len()
+            )?;
+        }
+        // Names are 8 characters and a terminator, to open with ti_Open as they are
+        writeln!(out, "\ntypedef struct {{")?;
+        writeln!(out, "    char name[9];\n    uint16_t x;\n    uint16_t y;")?;
+        writeln!(out, "}} {}_tile_t;\n", id)?;
+        writeln!(out, "extern const {}_tile_t {}_tiles[{}];", id, id, tiles)?;
+        writeln!(out, "\n#endif")?;
+        Ok(out)
+    }
+
+    /// Write C source defining what [`write_c_header`](Self::write_c_header) declares, which
+    /// includes the header as `header_name`.
+    pub fn write_c_source<W: Write>(&self, header_name: &str, mut out: W) -> IoResult<W> {
+        let id = self.c_identifier();
+        writeln!(out, "#include \"{}\"\n", header_name)?;
+        if !self.direct_color {
+            let words = self.palette_words();
+            writeln!(out, "const uint16_t {}_palette[{}] = {{", id, words.len())?;
+            for line in words.chunks(8) {
+                let line: Vec<String> = line.iter().map(|w| format!("0x{:04x},", w)).collect();
+                writeln!(out, "    {}", line.join(" "))?;
+            }
+            writeln!(out, "}};\n")?;
+        }
+        let names = self.tile_names();
+        writeln!(
+            out,
+            "const {}_tile_t {}_tiles[{}] = {{",
+            id,
+            id,
+            names.len()
+        )?;
+        let (tile_width, tile_height) = self.tile_size;
+        for (i, name) in names.iter().enumerate() {
+            let (column, row) = (i as u32 % self.width_tiles(), i as u32 / self.width_tiles());
+            writeln!(
+                out,
+                "    {{ \"{}\", {}, {} }},",
+                name,
+                column * tile_width,
+                row * tile_height
+            )?;
+        }
+        writeln!(out, "}};")?;
+        Ok(out)
+    }
+
+    /// Write an assembly include with equates for the image's geometry and palette, and the
+    /// names of its appvars.
+    ///
+    /// Equates and labels start with the image's name in lowercase, and use syntax both fasmg and
+    /// spasm-ng assemble. Each name is 10 bytes with the appvar type in front and a terminator,
+    /// ready to copy to OP1 and look up: the palette appvar's after `_palette_appvar` and the
+    /// tiles' after `_tiles`, row by row. Direct color images have no palette, so they have no
+    /// equates or name for it.
+    pub fn write_asm_include<W: Write>(&self, mut out: W) -> IoResult<W> {
+        let id = self.c_identifier();
+        let var = |name: &str| format!("\tdb $15,\"{}\",0", name);
+        for line in self.source_summary().lines() {
+            writeln!(out, "; {}", line)?;
+        }
+        writeln!(out, "{}_width = {}", id, self.width())?;
+        writeln!(out, "{}_height = {}", id, self.height())?;
+        writeln!(out, "{}_tile_width = {}", id, self.tile_size.0)?;
+        writeln!(out, "{}_tile_height = {}", id, self.tile_size.1)?;
+        writeln!(out, "{}_columns = {}", id, self.width_tiles())?;
+        writeln!(out, "{}_rows = {}", id, self.height_tiles())?;
+        writeln!(
+            out,
+            "{}_tile_count = {}",
+            id,
+            self.width_tiles() * self.height_tiles()
+        )?;
+        if !self.direct_color {
+            writeln!(out, "{}_palette_length = {}", id, self.palette.len())?;
+            if let Some(index) = self.transparent_index() {
+                writeln!(out, "{}_transparent_index = {}", id, index)?;
+            }
+            writeln!(out, "{}_palette_appvar:", id)?;
+            writeln!(out, "{}", var(&self.palette_appvar_name()))?;
+        }
+        writeln!(out, "{}_tiles:", id)?;
+        for name in self.tile_names() {
+            writeln!(out, "{}", var(&name))?;
+        }
+        Ok(out)
+    }
+}
+
+/// ICE source defines the palette and lists every tile.
+#[test]
+fn ice_source_lists_tiles() {
+    use image::{Rgba, RgbaImage};
+
+    let pixels = RgbaImage::from_fn(100, 50, |x, _| {
+        Rgba([if x < 50 { 0 } else { 255 }, 0, 0, 255])
+    });
+    let mut image = crate::Image::from_rgba(pixels, "ice", "IC");
+    image.set_tile_size(50, 50);
+    let source = image.quantize().write_ice_source(Vec::new()).unwrap();
+    let source = String::from_utf8(source).unwrap();
+
+    assert!(source.starts_with("// ice (IC), converted by hdpictureconverter\n"));
+    assert!(source.contains("\n100→W:50→H\n2→C:1→R\n50→X:50→Y\n"));
+    assert!(source.contains("Data(2,0,31744)→P\n") || source.contains("Data(2,31744,0)→P\n"));
+    assert!(source.contains("\n\"HPIC0000\"→N\n"));
+    assert!(source.ends_with("\n\"IC000000IC001000\"→T\n"));
+}
+
+/// C source defines the tiles and palette the header declares.
+#[test]
+fn c_source_declares_tiles() {
+    use image::{Rgba, RgbaImage};
+
+    let pixels = RgbaImage::from_pixel(100, 50, Rgba([255, 0, 0, 255]));
+    let mut image = crate::Image::from_rgba(pixels, "Sprite", "SP");
+    image.set_tile_size(50, 50);
+    let quantized = image.quantize();
+    let header = quantized.write_c_header(Vec::new()).unwrap();
+    let header = String::from_utf8(header).unwrap();
+    let source = quantized.write_c_source("sprite.h", Vec::new()).unwrap();
+    let source = String::from_utf8(source).unwrap();
+
+    assert!(header.contains("#ifndef SPRITE_H\n#define SPRITE_H\n"));
+    assert!(header.contains("#define sprite_width 100\n#define sprite_height 50\n"));
+    assert!(header.contains("#define sprite_palette_appvar \"HPSP0000\"\n"));
+    assert!(header.contains("extern const sprite_tile_t sprite_tiles[2];\n"));
+    assert!(source.starts_with("#include \"sprite.h\"\n"));
+    assert!(source.contains("const uint16_t sprite_palette["));
+    assert!(source.ends_with("    { \"SP000000\", 0, 0 },\n    { \"SP001000\", 50, 0 },\n};\n"));
+}
+
+/// Assembly includes define equates and list every tile's name.
+#[test]
+fn asm_include_lists_tiles() {
+    use image::{Rgba, RgbaImage};
+
+    let pixels = RgbaImage::from_pixel(100, 50, Rgba([255, 0, 0, 255]));
+    let mut image = crate::Image::from_rgba(pixels, "Sprite", "SP");
+    image.set_tile_size(50, 50);
+    let include = image.quantize().write_asm_include(Vec::new()).unwrap();
+    let include = String::from_utf8(include).unwrap();
+
+    assert!(include.starts_with("; Sprite (SP), converted by hdpictureconverter\n"));
+    assert!(include.contains("\nsprite_columns = 2\nsprite_rows = 1\nsprite_tile_count = 2\n"));
+    assert!(include.contains("\nsprite_palette_appvar:\n\tdb $15,\"HPSP0000\",0\n"));
+    assert!(
+        include.ends_with("\nsprite_tiles:\n\tdb $15,\"SP000000\",0\n\tdb $15,\"SP001000\",0\n")
+    );
+}
@@ -0,0 +1,300 @@
+//! Converting images a band of tile rows at a time
+//!
+//! Converting an [`Image`] holds several copies of it in memory at once, which is too much for
+//! very large images. A [`StreamingImage`] instead reads its input twice: first to build a
+//! palette from a histogram of every band of rows, then to map each band to that palette. Only one
+//! band is expanded and quantized at a time.
+//!
+//! PNGs are decoded a row at a time. Other formats, and interlaced PNGs, are decoded whole but
+//! kept in their own pixel format, which is usually smaller than what [`Image`] converts them to.
+use std::io::{BufRead, Error, ErrorKind, Result as IoResult, Seek};
+
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use rgb::RGBA8 as RGBA;
+
+use crate::{kmeans, quantizer, Image, QuantizeOptions, QuantizedImage, Quantizer};
+
+/// The most pixels NeuQuant learns from when generating a palette.
+const NEUQUANT_SAMPLE_PIXELS: usize = 1 << 20;
+
+fn decode_error(e: impl std::fmt::Display) -> Error {
+    Error::other(format!("Unable to decode image: {}", e))
+}
+
+/// An image that is converted one band of tile rows at a time, bounding how much memory it needs.
+///
+/// The input is read more than once, calling `open` to start reading it from the beginning each
+/// time.
+pub struct StreamingImage<F> {
+    open: F,
+    var_prefix: String,
+    name: String,
+    width: u32,
+    height: u32,
+    tile_size: (u32, u32),
+}
+
+/// Decoder producing the rows of an image in order.
+enum Rows<R: BufRead + Seek> {
+    Png {
+        reader: Box<png::Reader<R>>,
+        color: png::ColorType,
+    },
+    Decoded {
+        image: DynamicImage,
+        next: u32,
+    },
+}
+
+impl<R: BufRead + Seek> Rows<R> {
+    /// Return the next `count` rows of an image `width` pixels wide.
+    fn next(&mut self, width: u32, count: u32) -> IoResult<RgbaImage> {
+        let pixels = match self {
+            Rows::Png { reader, color } => {
+                let mut pixels = Vec::with_capacity(width as usize * count as usize * 4);
+                for _ in 0..count {
+                    let row = reader
+                        .next_row()
+                        .map_err(decode_error)?
+                        .ok_or_else(|| decode_error("image data ends early"))?;
+                    let row = row.data();
+                    match color {
+                        png::ColorType::Grayscale => {
+                            pixels.extend(row.iter().flat_map(|&l| [l, l, l, 255]))
+                        }
+                        png::ColorType::GrayscaleAlpha => pixels
+                            .extend(row.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]])),
+                        png::ColorType::Rgb => {
+                            pixels.extend(row.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]))
+                        }
+                        png::ColorType::Rgba => pixels.extend_from_slice(row),
+                        // Palettes are expanded by the decoder
+                        png::ColorType::Indexed => unreachable!(),
+                    }
+                }
+                pixels
+            }
+            Rows::Decoded { image, next } => {
+                let band = image.crop_imm(0, *next, width, count).into_rgba8();
+                *next += count;
+                band.into_raw()
+            }
+        };
+        Ok(RgbaImage::from_raw(width, count, pixels).expect("band should be the right size"))
+    }
+}
+
+impl<R, F> StreamingImage<F>
+where
+    R: BufRead + Seek,
+    F: FnMut() -> IoResult<R>,
+{
+    /// Prepare to convert the image read by `open`, which is only read far enough to find its
+    /// dimensions.
+    pub fn new(mut open: F, name: &str, var_prefix: &str) -> IoResult<Self> {
+        assert_eq!(var_prefix.len(), 2);
+
+        let mut data = open()?;
+        crate::reject_other(&mut data)?;
+        let (width, height) = image::io::Reader::new(data)
+            .with_guessed_format()?
+            .into_dimensions()
+            .map_err(decode_error)?;
+        Ok(StreamingImage {
+            open,
+            var_prefix: var_prefix.to_string(),
+            name: Image::generate_calc_name(name),
+            width,
+            height,
+            tile_size: (Image::DEFAULT_TILE_SIZE, Image::DEFAULT_TILE_SIZE),
+        })
+    }
+
+    /// Return the width and height of the image in pixels.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Set the dimensions of the tiles the image is split into, like
+    /// [`Image::set_tile_size`].
+    pub fn set_tile_size(&mut self, width: u32, height: u32) {
+        crate::assert_valid_tile_size(width, height);
+        self.tile_size = (width, height);
+    }
+
+    fn rows(&mut self) -> IoResult<Rows<R>> {
+        let reader = image::io::Reader::new((self.open)()?).with_guessed_format()?;
+        if reader.format() == Some(ImageFormat::Png) {
+            let mut decoder = png::Decoder::new(reader.into_inner());
+            decoder.set_transformations(png::Transformations::normalize_to_color8());
+            let reader = decoder.read_info().map_err(decode_error)?;
+            // Interlaced images are stored in passes over the whole image, so rows only become
+            // available in order once it's all been read.
+            if !reader.info().interlaced {
+                let (color, _) = reader.output_color_type();
+                return Ok(Rows::Png {
+                    reader: Box::new(reader),
+                    color,
+                });
+            }
+        }
+
+        let image = image::io::Reader::new((self.open)()?)
+            .with_guessed_format()?
+            .decode()
+            .map_err(decode_error)?;
+        if image.width() != self.width || image.height() != self.height {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "image changed size since it was opened",
+            ));
+        }
+        Ok(Rows::Decoded { image, next: 0 })
+    }
+
+    /// Call `f` with the index and pixels of each tile row in turn, from the top.
+    fn for_each_band(&mut self, mut f: impl FnMut(u32, Image) -> IoResult<()>) -> IoResult<()> {
+        let mut rows = self.rows()?;
+        let tile_height = self.tile_size.1;
+        for (row, y) in (0..self.height).step_by(tile_height as usize).enumerate() {
+            let band = Image {
+                input: rows.next(self.width, tile_height.min(self.height - y))?,
+                var_prefix: self.var_prefix.clone(),
+                name: self.name.clone(),
+                tile_size: self.tile_size,
+            };
+            f(row as u32, band)?;
+        }
+        Ok(())
+    }
+
+    /// Generate a palette for the image from every band.
+    ///
+    /// Like [`Image::shared_palette`], this leaves room for any reserved colors without including
+    /// them. libimagequant, median cut and octrees count every pixel, while NeuQuant learns
+    /// from a sample of about a million of them. Each refinement pass reads the image again.
+    pub fn palette(&mut self, options: &QuantizeOptions) -> IoResult<Vec<RGBA>> {
+        let palette = self.generate_palette(options)?;
+        kmeans::refine_with(palette, options.refine_passes, options.matching(), |pass| {
+            self.for_each_band(|_, band| {
+                pass.add(&band.canvas(options.background));
+                Ok(())
+            })
+        })
+    }
+
+    fn generate_palette(&mut self, options: &QuantizeOptions) -> IoResult<Vec<RGBA>> {
+        match options.quantizer {
+            #[cfg(feature = "imagequant")]
+            Quantizer::ImageQuant => {
+                let attrs = Image::quantizer_attributes(options);
+                let mut histogram = imagequant::Histogram::new(&attrs);
+                self.for_each_band(|_, band| {
+                    let canvas = band.canvas(options.background);
+                    histogram
+                        .add_image(&attrs, &mut Image::quantizer_image(&canvas, &attrs))
+                        .expect("failed to add image to histogram");
+                    Ok(())
+                })?;
+
+                Ok(histogram
+                    .quantize(&attrs)
+                    .map_err(quantizer::imagequant_error)?
+                    .palette_vec())
+            }
+            Quantizer::NeuQuant => {
+                let pixels = self.width as usize * self.height as usize;
+                let stride = pixels.div_ceil(NEUQUANT_SAMPLE_PIXELS);
+                let mut sample = Vec::with_capacity(pixels / stride * 4);
+                self.for_each_band(|_, band| {
+                    let canvas = band.canvas(options.background);
+                    sample.extend(canvas.pixels().step_by(stride).flat_map(|p| p.0));
+                    Ok(())
+                })?;
+                Ok(quantizer::neuquant_palette(
+                    &sample,
+                    Image::palette_size(options),
+                    options.color_space,
+                ))
+            }
+            Quantizer::MedianCut | Quantizer::Octree => {
+                let mut histogram =
+                    quantizer::Histogram::new(options.quantizer, options.color_space);
+                self.for_each_band(|_, band| {
+                    histogram.add(&band.canvas(options.background));
+                    Ok(())
+                })?;
+                Ok(histogram.palette(Image::palette_size(options)))
+            }
+        }
+    }
+
+    /// Quantize the image one tile row at a time, calling `f` with each in turn from the top.
+    ///
+    /// Each band is a [`QuantizedImage`] with the dimensions of the whole image, but whose
+    /// [`tiles`](QuantizedImage::tiles) are only those in that row. Any of them can write the
+    /// palette appvar.
+    ///
+    /// Unless the options include a palette, one is first generated with
+    /// [`palette`](StreamingImage::palette). Error diffusion dithering starts afresh in each band.
+    pub fn quantize_bands(
+        &mut self,
+        options: &QuantizeOptions,
+        mut f: impl FnMut(QuantizedImage) -> IoResult<()>,
+    ) -> IoResult<()> {
+        let mut options = options.clone();
+        if options.palette.is_none() {
+            options.palette = Some(self.palette(&options)?);
+        }
+
+        let height = self.height.div_ceil(self.tile_size.1) * self.tile_size.1;
+        self.for_each_band(|row, band| {
+            let mut band = band.quantize_with(&options)?;
+            band.height = height;
+            band.first_row = row;
+            f(band)
+        })
+    }
+}
+
+/// Converting in bands makes the same tiles as converting all at once.
+#[test]
+fn bands_match_whole_image() {
+    use std::io::Cursor;
+
+    let pixels = RgbaImage::from_fn(37, 21, |x, y| {
+        image::Rgba([(x * 7) as u8, (y * 12) as u8, ((x + y) * 5) as u8, 255])
+    });
+    let mut png = Vec::new();
+    DynamicImage::ImageRgba8(pixels.clone())
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .unwrap();
+
+    let mut whole = Image::from_rgba(pixels, "test", "TS");
+    whole.set_tile_size(16, 8);
+    let options = QuantizeOptions {
+        dither: crate::Dither::None,
+        palette: Some(crate::palette::xlibc()),
+        ..Default::default()
+    };
+    let whole = whole.quantize_with(&options).unwrap();
+    let appvars = |image: &QuantizedImage| {
+        image
+            .tiles()
+            .map(|tile| tile.write_appvar(Cursor::new(Vec::new())).unwrap())
+            .map(Cursor::into_inner)
+            .collect::<Vec<_>>()
+    };
+
+    let mut streaming = StreamingImage::new(|| Ok(Cursor::new(&png)), "test", "TS").unwrap();
+    streaming.set_tile_size(16, 8);
+    let mut banded = Vec::new();
+    streaming
+        .quantize_bands(&options, |band| {
+            assert_eq!((band.width_tiles(), band.height_tiles()), (3, 3));
+            banded.extend(appvars(&band));
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(banded, appvars(&whole));
+}
@@ -0,0 +1,115 @@
+//! Rasterizing SVG drawings
+//!
+//! Vector images have no pixels of their own, so they're drawn at the size they're converted
+//! at, which is sharper than shrinking a drawing already rasterized at another size.
+use std::io::{Error, ErrorKind, Result as IoResult};
+
+use image::RgbaImage;
+use resvg::{tiny_skia, usvg};
+
+use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Density at which SVG lengths are given in pixels, which is the size a drawing is meant to be
+/// shown at.
+const CSS_DPI: f32 = 96.;
+
+/// Fonts to draw each generic font family with, in order of preference.
+///
+/// fontdb's defaults only include the first of each, which usually only come with Windows and
+/// macOS, so text in those families would otherwise be missing elsewhere.
+const SERIF: &[&str] = &[
+    "Times New Roman",
+    "DejaVu Serif",
+    "Liberation Serif",
+    "Noto Serif",
+];
+const SANS_SERIF: &[&str] = &["Arial", "DejaVu Sans", "Liberation Sans", "Noto Sans"];
+const MONOSPACE: &[&str] = &[
+    "Courier New",
+    "DejaVu Sans Mono",
+    "Liberation Mono",
+    "Noto Mono",
+];
+
+/// Return the first of `families` that's installed.
+fn installed<'a>(fonts: &usvg::fontdb::Database, families: &[&'a str]) -> Option<&'a str> {
+    families.iter().copied().find(|family| {
+        fonts
+            .faces()
+            .any(|face| face.families.iter().any(|(name, _)| name == family))
+    })
+}
+
+/// Rasterize an SVG drawing at `density` dots per inch, or to fit the calculator screen if that
+/// isn't given.
+pub(crate) fn rasterize(data: &[u8], density: Option<f32>) -> IoResult<RgbaImage> {
+    let mut options = usvg::Options::default();
+    let fonts = options.fontdb_mut();
+    fonts.load_system_fonts();
+    if let Some(family) = installed(fonts, SANS_SERIF) {
+        fonts.set_sans_serif_family(family);
+    }
+    if let Some(family) = installed(fonts, MONOSPACE) {
+        fonts.set_monospace_family(family);
+    }
+    if let Some(family) = installed(fonts, SERIF) {
+        fonts.set_serif_family(family);
+        // Text without a font family is drawn in this
+        options.font_family = family.to_string();
+    }
+    let tree = usvg::Tree::from_data(data, &options).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("Unable to decode image: {}", e),
+        )
+    })?;
+
+    let size = tree.size();
+    let scale = match density {
+        Some(dpi) => dpi / CSS_DPI,
+        None => f32::min(
+            SCREEN_WIDTH as f32 / size.width(),
+            SCREEN_HEIGHT as f32 / size.height(),
+        ),
+    };
+    let width = ((size.width() * scale).round() as u32).max(1);
+    let height = ((size.height() * scale).round() as u32).max(1);
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Unable to rasterize SVG at {}x{} pixels", width, height),
+        )
+    })?;
+    resvg::render(
+        &tree,
+        tiny_skia::Transform::from_scale(scale, scale),
+        &mut pixmap.as_mut(),
+    );
+
+    // tiny-skia draws with premultiplied alpha
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    Ok(RgbaImage::from_raw(width, height, pixels).unwrap())
+}
+
+/// Drawings fill the screen unless a density is given.
+#[test]
+fn drawings_are_rasterized_to_fit() {
+    let svg = br##"<svg xmlns="http://www.w3.org/2000/svg" width="40" height="20">
+        <rect width="20" height="20" fill="#ff0000"/>
+    </svg>"##;
+
+    let image = rasterize(svg, None).unwrap();
+    assert_eq!(image.dimensions(), (320, 160));
+    assert_eq!(image.get_pixel(10, 80).0, [255, 0, 0, 255]);
+    assert_eq!(image.get_pixel(310, 80).0[3], 0);
+
+    let image = rasterize(svg, Some(192.)).unwrap();
+    assert_eq!(image.dimensions(), (80, 40));
+}
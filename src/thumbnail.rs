@@ -0,0 +1,161 @@
+//! Small previews of images for galleries on the calculator
+//!
+//! A gallery can show a thumbnail without reading and decompressing every tile of the image. Each
+//! is a single appvar with a `HDTHMBV1` signature, the 8-character image name and 2-character var
+//! prefix, the width and height in pixels as a byte each and the number of colors as a 16-bit
+//! little-endian integer. The palette follows in the same format as palette appvars, then a
+//! palette index for each pixel, one per byte, row-major and uncompressed.
+use std::io::{Error, ErrorKind, Result as IoResult, Seek, Write};
+
+use rgb::RGBA8 as RGBA;
+use tifiles::VariableType;
+
+use crate::{Image, QuantizeOptions, GRGB1555};
+
+const SIGNATURE: &[u8] = b"HDTHMBV1";
+/// Bytes before the palette.
+const HEADER_LEN: usize = 8 + 8 + 2 + 1 + 1 + 2;
+
+/// Width of thumbnails in pixels.
+pub const THUMBNAIL_WIDTH: u32 = 80;
+/// Height of thumbnails in pixels.
+pub const THUMBNAIL_HEIGHT: u32 = 60;
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+impl Image {
+    /// Return the name of the appvar holding the image's thumbnail.
+    pub fn thumbnail_appvar_name(&self) -> String {
+        format!("HT{:2}0000", self.var_prefix)
+    }
+
+    /// Write a thumbnail of the image as an appvar.
+    ///
+    /// Images larger than a thumbnail shrink to fit and are centered on the background color, so
+    /// every thumbnail is the same size. This fails if the generated palette can't reach the
+    /// minimum [`quality`](QuantizeOptions::quality).
+    pub fn write_thumbnail_appvar<W: Write + Seek>(
+        &self,
+        options: &QuantizeOptions,
+        archived: bool,
+        out: W,
+    ) -> IoResult<W> {
+        let unnamed = |input| Image {
+            input,
+            name: String::new(),
+            var_prefix: String::new(),
+            tile_size: (THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT),
+        };
+        let canvas = unnamed(self.input.clone()).to_screen(
+            THUMBNAIL_WIDTH,
+            THUMBNAIL_HEIGHT,
+            options.background,
+        );
+        let quantized = unnamed(canvas).quantize_with(options)?;
+
+        let mut writer = tifiles::Writer::new(
+            out,
+            VariableType::AppVar,
+            &self.thumbnail_appvar_name(),
+            archived,
+        )?;
+        writer.write_all(SIGNATURE)?;
+        write!(writer, "{:8}{:2}", self.name, self.var_prefix)?;
+        writer.write_all(&[THUMBNAIL_WIDTH as u8, THUMBNAIL_HEIGHT as u8])?;
+        writer.write_all(&(quantized.palette.len() as u16).to_le_bytes())?;
+        for swatch in &quantized.palette {
+            writer.write_all(&GRGB1555::from(swatch).to_le_bytes())?;
+        }
+        writer.write_all(&quantized.data)?;
+        writer.close()
+    }
+}
+
+/// The contents of a thumbnail appvar.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    /// Name of the image, padded with underscores to 8 characters.
+    pub name: String,
+    pub var_prefix: String,
+    pub width: u32,
+    pub height: u32,
+    /// Colors as stored, reduced to the calculator's 16-bit color.
+    pub palette: Vec<RGBA>,
+    /// Palette index of each pixel, row-major.
+    pub pixels: Vec<u8>,
+}
+
+impl Thumbnail {
+    /// Return whether appvar data is a thumbnail.
+    pub fn is_thumbnail(data: &[u8]) -> bool {
+        data.starts_with(SIGNATURE)
+    }
+
+    /// Read the data of a thumbnail appvar.
+    pub fn read(data: &[u8]) -> IoResult<Self> {
+        let header = data
+            .get(..HEADER_LEN)
+            .filter(|header| Self::is_thumbnail(header))
+            .ok_or_else(|| invalid("Not a thumbnail appvar: signature is missing".into()))?;
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+        let (width, height) = (header[18] as u32, header[19] as u32);
+        let colors = u16::from_le_bytes([header[20], header[21]]) as usize;
+        let rest = &data[HEADER_LEN..];
+        let (palette, pixels) = (colors * 2 <= rest.len())
+            .then(|| rest.split_at(colors * 2))
+            .filter(|(_, pixels)| pixels.len() == (width * height) as usize)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "Thumbnail appvar should have {} colors and {}x{} pixels",
+                    colors, width, height
+                ))
+            })?;
+        Ok(Thumbnail {
+            name: text(&header[8..16]),
+            var_prefix: text(&header[16..18]),
+            width,
+            height,
+            palette: palette
+                .chunks_exact(2)
+                .map(|c| RGBA::from(GRGB1555(u16::from_le_bytes([c[0], c[1]]))))
+                .collect(),
+            pixels: pixels.to_vec(),
+        })
+    }
+}
+
+/// Thumbnails are letterboxed to a fixed size and read back as written.
+#[test]
+fn thumbnail_round_trips() {
+    use crate::group;
+    use image::{Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    // Twice as wide as a thumbnail's aspect ratio, so it has bars above and below
+    let pixels = RgbaImage::from_pixel(320, 120, Rgba([255, 0, 0, 255]));
+    let image = Image::from_rgba(pixels, "wide", "WI");
+    let options = QuantizeOptions {
+        background: RGBA::new(0, 0, 255, 255),
+        ..Default::default()
+    };
+    let file = image
+        .write_thumbnail_appvar(&options, true, Cursor::new(Vec::new()))
+        .unwrap()
+        .into_inner();
+    let var = group::read(&file).unwrap().vars.remove(0);
+    assert_eq!(var.name, "HTWI0000");
+
+    let thumbnail = Thumbnail::read(&var.data).unwrap();
+    assert_eq!(
+        (thumbnail.name.as_str(), thumbnail.var_prefix.as_str()),
+        ("wide____", "WI")
+    );
+    assert_eq!((thumbnail.width, thumbnail.height), (80, 60));
+    let color =
+        |x: u32, y: u32| thumbnail.palette[thumbnail.pixels[(y * 80 + x) as usize] as usize];
+    assert_eq!(color(40, 30), RGBA::new(255, 0, 0, 255));
+    assert_eq!(color(40, 2), RGBA::new(0, 0, 255, 255));
+    assert!(Thumbnail::read(&var.data[..30]).is_err());
+}
@@ -0,0 +1,95 @@
+//! Python scripts that draw images on the TI-84 Plus CE Python edition
+//!
+//! The CE's Python app can't read HD Picture Viewer's appvars, so the image is embedded in a
+//! script that draws it with the `ti_draw` module. Scripts are stored in appvars that start with
+//! a `PYCD` signature, which is how the Python app tells them from other appvars.
+use std::io::{Error, Result as IoResult, Seek, Write};
+
+use tifiles::VariableType;
+
+use crate::python::Dialect;
+use crate::{Image, QuantizeOptions};
+
+/// Width of the area `ti_draw` draws on, which is the whole screen.
+pub const TI_PYTHON_WIDTH: u32 = 320;
+/// Height of the area `ti_draw` draws on, which is the screen below the status bar.
+pub const TI_PYTHON_HEIGHT: u32 = 210;
+
+const SIGNATURE: &[u8] = b"PYCD\0";
+
+const TI_DRAW: Dialect = Dialect {
+    import: "from ti_draw import clear,set_color,fill_rect,show_draw",
+    draw_run: "      c=P[D[i]]\n      set_color(c[0],c[1],c[2])\n      fill_rect(x,y,n,1)\n",
+    before: "clear()\n",
+    after: "show_draw()\n",
+};
+
+impl Image {
+    /// Return the name of the appvar holding the image's Python script, which is its name in
+    /// capitals.
+    pub fn ti_python_appvar_name(&self) -> String {
+        self.name.trim_end_matches('_').to_ascii_uppercase()
+    }
+
+    /// Write a Python script that draws the image on the CE, until a key is pressed.
+    ///
+    /// Images larger than the drawing area shrink to fit and are centered on the background
+    /// color. This fails if the generated palette can't reach the minimum
+    /// [`quality`](QuantizeOptions::quality); fewer colors make shorter scripts.
+    pub fn write_ti_python_script<W: Write>(
+        self,
+        options: &QuantizeOptions,
+        out: W,
+    ) -> IoResult<W> {
+        self.write_python_script(options, (TI_PYTHON_WIDTH, TI_PYTHON_HEIGHT), &TI_DRAW, out)
+    }
+
+    /// Write the image's Python script as an appvar the Python app lists.
+    ///
+    /// This fails if the script is longer than an appvar can hold.
+    pub fn write_ti_python_appvar<W: Write + Seek>(
+        self,
+        options: &QuantizeOptions,
+        archived: bool,
+        out: W,
+    ) -> IoResult<W> {
+        let name = self.ti_python_appvar_name();
+        let script = self.write_ti_python_script(options, Vec::new())?;
+        let mut writer = tifiles::Writer::new(out, VariableType::AppVar, &name, archived)?;
+        writer.write_all(SIGNATURE)?;
+        writer.write_all(&script).map_err(|e| {
+            Error::new(
+                e.kind(),
+                format!(
+                    "the script is {} bytes, which is too long for an appvar; \
+                     fewer colors and no dithering make shorter scripts",
+                    script.len()
+                ),
+            )
+        })?;
+        writer.close()
+    }
+}
+
+/// Scripts are stored in appvars named after the image.
+#[test]
+fn ti_python_appvar_draws_with_ti_draw() {
+    use crate::group;
+    use image::{Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    let image = RgbaImage::from_pixel(64, 64, Rgba([255, 0, 0, 255]));
+    let file = Image::from_rgba(image, "snake", "SN")
+        .write_ti_python_appvar(&QuantizeOptions::default(), true, Cursor::new(Vec::new()))
+        .unwrap()
+        .into_inner();
+    let var = group::read(&file).unwrap().vars.remove(0);
+    assert_eq!(var.name, "SNAKE");
+
+    let script = var.data.strip_prefix(SIGNATURE).unwrap();
+    let script = String::from_utf8(script.to_vec()).unwrap();
+    assert!(script.starts_with("# snake converted by hdpictureconverter\nfrom ti_draw import"));
+    assert!(script.contains("\nW,H=320,210\n"));
+    assert!(script.contains("      fill_rect(x,y,n,1)\n"));
+    assert!(script.ends_with("clear()\ndraw()\nshow_draw()\n"));
+}
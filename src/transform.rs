@@ -0,0 +1,312 @@
+//! Geometric transformations applied to images before quantization
+use std::io::{Error, ErrorKind, Result as IoResult};
+
+use image::imageops::{self, FilterType};
+use image::{Rgba, RgbaImage};
+use rgb::RGBA8 as RGBA;
+
+use crate::Image;
+
+/// Width of the calculator screen in pixels.
+pub const SCREEN_WIDTH: u32 = 320;
+/// Height of the calculator screen in pixels.
+pub const SCREEN_HEIGHT: u32 = 240;
+
+/// How an image is made to match a different aspect ratio when resized.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ScaleMode {
+    /// Scale to fit entirely, with transparent bars filling the remaining space.
+    #[default]
+    Fit,
+    /// Scale to cover the whole area, cropping off whatever extends past the edges.
+    Fill,
+    /// Scale each dimension independently, distorting the image.
+    Stretch,
+}
+
+/// A scaled mode of the LCD, in which each image pixel covers several on the screen.
+///
+/// The CE's LCD can double pixels as it draws them, so images converted for a scaled mode need
+/// only half the pixels, and the tiles to store them, to fill the screen.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum LcdScale {
+    /// Every image pixel is one screen pixel.
+    #[default]
+    Full,
+    /// Pixels are doubled across, filling the screen with 160x240.
+    HalfWidth,
+    /// Pixels are doubled both ways, filling the screen with 160x120.
+    Half,
+}
+
+impl LcdScale {
+    /// Return how many screen pixels each image pixel covers horizontally and vertically.
+    pub fn factors(self) -> (u32, u32) {
+        match self {
+            LcdScale::Full => (1, 1),
+            LcdScale::HalfWidth => (2, 1),
+            LcdScale::Half => (2, 2),
+        }
+    }
+
+    /// Return the scale with the given factors, if it's supported.
+    pub fn from_factors(factors: (u32, u32)) -> Option<Self> {
+        [LcdScale::Full, LcdScale::HalfWidth, LcdScale::Half]
+            .into_iter()
+            .find(|scale| scale.factors() == factors)
+    }
+}
+
+/// Clockwise rotation by a multiple of 90 degrees.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Rotation {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+impl Image {
+    /// Rotate the image clockwise.
+    pub fn rotate(&mut self, rotation: Rotation) {
+        self.input = match rotation {
+            Rotation::Rotate90 => imageops::rotate90(&self.input),
+            Rotation::Rotate180 => imageops::rotate180(&self.input),
+            Rotation::Rotate270 => imageops::rotate270(&self.input),
+        };
+    }
+
+    /// Resize the image to exactly the given dimensions.
+    pub fn resize(&mut self, width: u32, height: u32, mode: ScaleMode) {
+        let (old_width, old_height) = self.input.dimensions();
+        let scale_x = width as f64 / old_width as f64;
+        let scale_y = height as f64 / old_height as f64;
+        let scaled_size = |scale: f64| {
+            (
+                ((old_width as f64 * scale).round() as u32).max(1),
+                ((old_height as f64 * scale).round() as u32).max(1),
+            )
+        };
+
+        self.input = match mode {
+            ScaleMode::Stretch => {
+                imageops::resize(&self.input, width, height, FilterType::Lanczos3)
+            }
+            ScaleMode::Fit => {
+                let (w, h) = scaled_size(scale_x.min(scale_y));
+                let (w, h) = (w.min(width), h.min(height));
+                let scaled = imageops::resize(&self.input, w, h, FilterType::Lanczos3);
+
+                // Centered on a transparent canvas, so the bars are the background color
+                let mut letterboxed = RgbaImage::new(width, height);
+                imageops::replace(
+                    &mut letterboxed,
+                    &scaled,
+                    ((width - w) / 2).into(),
+                    ((height - h) / 2).into(),
+                );
+                letterboxed
+            }
+            ScaleMode::Fill => {
+                let (w, h) = scaled_size(scale_x.max(scale_y));
+                let (w, h) = (w.max(width), h.max(height));
+                let scaled = imageops::resize(&self.input, w, h, FilterType::Lanczos3);
+                imageops::crop_imm(&scaled, (w - width) / 2, (h - height) / 2, width, height)
+                    .to_image()
+            }
+        };
+    }
+
+    /// Mirror the image left to right.
+    pub fn flip_horizontal(&mut self) {
+        imageops::flip_horizontal_in_place(&mut self.input);
+    }
+
+    /// Mirror the image top to bottom.
+    pub fn flip_vertical(&mut self) {
+        imageops::flip_vertical_in_place(&mut self.input);
+    }
+
+    /// Keep only the region of the image with its top left corner at (`x`, `y`) and the given
+    /// size, which must lie entirely within the image.
+    pub fn crop(&mut self, x: u32, y: u32, width: u32, height: u32) -> IoResult<()> {
+        let (image_width, image_height) = self.input.dimensions();
+        let fits = |start: u32, len: u32, limit: u32| {
+            len > 0 && start.checked_add(len).is_some_and(|end| end <= limit)
+        };
+        if !fits(x, width, image_width) || !fits(y, height, image_height) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Crop region {}x{} at {},{} is outside the {}x{} image",
+                    width, height, x, y, image_width, image_height
+                ),
+            ));
+        }
+
+        self.input = imageops::crop_imm(&self.input, x, y, width, height).to_image();
+        Ok(())
+    }
+
+    /// Shrink the image so that with the LCD in a scaled mode it's shown at its current size.
+    pub fn scale_for_lcd(&mut self, scale: LcdScale) {
+        let (x, y) = scale.factors();
+        let (width, height) = self.input.dimensions();
+        if (x, y) != (1, 1) {
+            self.input = imageops::resize(
+                &self.input,
+                width.div_ceil(x),
+                height.div_ceil(y),
+                FilterType::Triangle,
+            );
+        }
+    }
+
+    /// Shrink the image to fit within the given dimensions, preserving its aspect ratio.
+    ///
+    /// Images that already fit are unchanged, since enlarging them would only make more tiles.
+    pub fn downscale_to_fit(&mut self, max_width: u32, max_height: u32) {
+        let (width, height) = self.input.dimensions();
+        if width <= max_width && height <= max_height {
+            return;
+        }
+
+        // Scale by whichever dimension is furthest over, rounding so neither exceeds its limit
+        let scale = f64::min(
+            max_width as f64 / width as f64,
+            max_height as f64 / height as f64,
+        );
+        let new_width = ((width as f64 * scale).round() as u32).clamp(1, max_width);
+        let new_height = ((height as f64 * scale).round() as u32).clamp(1, max_height);
+        self.input = imageops::resize(&self.input, new_width, new_height, FilterType::Lanczos3);
+    }
+
+    /// Return the image as it would fill a screen of the given size, for calculators that show
+    /// images in full color rather than through a palette.
+    ///
+    /// Images larger than the screen shrink to fit and are centered on the background color,
+    /// which partly transparent pixels are also blended over.
+    pub fn to_screen(mut self, width: u32, height: u32, background: RGBA) -> RgbaImage {
+        self.downscale_to_fit(width, height);
+        let mut screen = RgbaImage::from_pixel(
+            width,
+            height,
+            Rgba([background.r, background.g, background.b, 255]),
+        );
+        let (image_width, image_height) = self.input.dimensions();
+        imageops::overlay(
+            &mut screen,
+            &self.input,
+            ((width - image_width) / 2).into(),
+            ((height - image_height) / 2).into(),
+        );
+        for pixel in screen.pixels_mut() {
+            pixel[3] = 255;
+        }
+        screen
+    }
+}
+
+/// Large images shrink to fit without distortion, and small ones are left alone.
+#[test]
+fn downscale_preserves_aspect_ratio() {
+    use image::RgbaImage;
+
+    let mut image = Image::from_rgba(RgbaImage::new(1000, 500), "BIG", "AA");
+    image.downscale_to_fit(SCREEN_WIDTH, SCREEN_HEIGHT);
+    assert_eq!(image.input.dimensions(), (320, 160));
+
+    image.downscale_to_fit(SCREEN_WIDTH, SCREEN_HEIGHT);
+    assert_eq!(image.input.dimensions(), (320, 160));
+}
+
+/// Images for a scaled LCD shrink by their factors, rounding up.
+#[test]
+fn lcd_scale_halves_dimensions() {
+    let mut image = Image::from_rgba(RgbaImage::new(321, 240), "HALF", "AA");
+    image.scale_for_lcd(LcdScale::HalfWidth);
+    assert_eq!(image.input.dimensions(), (161, 240));
+    image.scale_for_lcd(LcdScale::Full);
+    assert_eq!(image.input.dimensions(), (161, 240));
+    image.scale_for_lcd(LcdScale::Half);
+    assert_eq!(image.input.dimensions(), (81, 120));
+}
+
+/// Screens are filled exactly, with small images centered on the background.
+#[test]
+fn screen_centers_image() {
+    let image = Image::from_rgba(
+        RgbaImage::from_pixel(10, 4, Rgba([255, 0, 0, 128])),
+        "SMALL",
+        "AA",
+    );
+    let screen = image.to_screen(20, 8, RGBA::new(0, 0, 255, 255));
+    assert_eq!(screen.dimensions(), (20, 8));
+    assert_eq!(*screen.get_pixel(0, 0), Rgba([0, 0, 255, 255]));
+    let blended = screen.get_pixel(5, 2);
+    assert!(
+        blended[0] > 100 && blended[2] > 100 && blended[3] == 255,
+        "{:?}",
+        blended
+    );
+    assert_eq!(*screen.get_pixel(4, 2), Rgba([0, 0, 255, 255]));
+}
+
+/// Every scale mode produces the requested size, with letterboxing or cropping as appropriate.
+#[test]
+fn resize_modes() {
+    use image::Rgba;
+
+    let resized = |mode| {
+        let mut image = Image::from_rgba(
+            RgbaImage::from_pixel(200, 100, Rgba([255, 255, 255, 255])),
+            "WIDE",
+            "AA",
+        );
+        image.resize(100, 100, mode);
+        image.input
+    };
+
+    // Fit leaves transparent bars above and below
+    let fit = resized(ScaleMode::Fit);
+    assert_eq!(fit.dimensions(), (100, 100));
+    assert_eq!(fit.get_pixel(50, 10)[3], 0);
+    assert_eq!(fit.get_pixel(50, 50)[3], 255);
+
+    // Fill and stretch cover the whole image
+    for mode in [ScaleMode::Fill, ScaleMode::Stretch] {
+        let image = resized(mode);
+        assert_eq!(image.dimensions(), (100, 100));
+        assert!(image.pixels().all(|p| p[3] == 255), "{:?} left gaps", mode);
+    }
+}
+
+/// Cropping keeps the requested region and refuses regions outside the image.
+#[test]
+fn crop_region() {
+    use image::Rgba;
+
+    let mut input = RgbaImage::new(100, 50);
+    input.put_pixel(10, 20, Rgba([1, 2, 3, 255]));
+    let mut image = Image::from_rgba(input, "CROP", "AA");
+
+    assert!(image.crop(90, 0, 11, 10).is_err());
+    image.crop(10, 20, 90, 30).unwrap();
+    assert_eq!(image.input.dimensions(), (90, 30));
+    assert_eq!(image.input.get_pixel(0, 0).0, [1, 2, 3, 255]);
+}
+
+/// Quarter turns swap the image's dimensions and move corners clockwise.
+#[test]
+fn rotate_clockwise() {
+    use image::Rgba;
+
+    let mut input = RgbaImage::new(4, 2);
+    input.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+    let mut image = Image::from_rgba(input, "ROTATE", "AA");
+
+    image.rotate(Rotation::Rotate90);
+    assert_eq!(image.input.dimensions(), (2, 4));
+    assert_eq!(image.input.get_pixel(1, 0).0, [255, 0, 0, 255]);
+    image.rotate(Rotation::Rotate270);
+    assert_eq!(image.input.get_pixel(0, 0).0, [255, 0, 0, 255]);
+}
@@ -0,0 +1,568 @@
+//! Programs that show converted images on the calculator
+//!
+//! TI-BASIC can't read appvars, so the BASIC viewer is a launcher: it tells the user which image
+//! to choose and runs HD Picture Viewer, which finds the tiles through their palette appvar and
+//! draws them with the graphics libraries it loads through LibLoad.
+//!
+//! The assembly viewer shows one image by itself, with the names of its appvars and where each
+//! tile goes on the screen built in. It's written as eZ80 source for [fasmg] with the CE
+//! toolchain's include files, not as a finished program: nothing here can assemble it, so it has
+//! to be built before it's sent to the calculator. It reads tiles with 8-bit pixels that are
+//! uncompressed or compressed with zx0 or run-length encoding.
+//!
+//! [fasmg]: https://flatassembler.net/download.php
+use std::io::{Error, ErrorKind, Result as IoResult, Seek, Write};
+
+use tifiles::VariableType;
+
+use crate::index::Entry;
+use crate::{Compression, NameTemplate, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+/// Name of the HD Picture Viewer program that BASIC viewers run.
+pub const HD_PICTURE_VIEWER: &str = "HDPICV";
+
+/// TI-BASIC tokens used by the BASIC viewer.
+mod token {
+    pub const NEWLINE: u8 = 0x3f;
+    pub const CLR_HOME: u8 = 0xe1;
+    pub const DISP: u8 = 0xde;
+    pub const PAUSE: u8 = 0xd8;
+    pub const PRGM: u8 = 0x5f;
+    pub const QUOTE: u8 = 0x2a;
+    pub const COMMA: u8 = 0x2b;
+    pub const SPACE: u8 = 0x29;
+}
+
+/// Return the name of the program that shows an image, which is its name in capitals.
+pub fn program_name(entry: &Entry) -> String {
+    entry.name.trim_end_matches('_').to_ascii_uppercase()
+}
+
+/// Return the name of the program for an image, unless it would replace HD Picture Viewer.
+fn checked_program_name(entry: &Entry) -> IoResult<String> {
+    let name = program_name(entry);
+    if name == HD_PICTURE_VIEWER {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("a viewer can't be named {}, like HD Picture Viewer", name),
+        ));
+    }
+    Ok(name)
+}
+
+/// Append the tokens of text shown by a BASIC program, which may contain capital letters,
+/// digits and spaces.
+fn push_text(program: &mut Vec<u8>, text: &str) {
+    for c in text.chars() {
+        debug_assert!(c == ' ' || c.is_ascii_uppercase() || c.is_ascii_digit());
+        // Letters and digits are one-byte tokens with their ASCII values
+        program.push(if c == ' ' { token::SPACE } else { c as u8 });
+    }
+}
+
+/// Write a TI-BASIC launcher for an image, named by [`program_name`], which says to choose the
+/// image and runs HD Picture Viewer to show it.
+///
+/// This fails for images whose program would have the same name as the viewer.
+pub fn write_basic_viewer<W: Write + Seek>(entry: &Entry, archived: bool, out: W) -> IoResult<W> {
+    let name = checked_program_name(entry)?;
+
+    // Lines of the home screen are 26 characters wide, which fits every image name
+    let lines = [
+        format!("{} {}X{}", name, entry.width, entry.height),
+        "RUNNING HD PICTURE VIEWER".to_string(),
+        format!("CHOOSE {} TO VIEW", name),
+        "PRESS ENTER".to_string(),
+    ];
+    let mut program = vec![token::CLR_HOME, token::NEWLINE, token::DISP];
+    for (i, line) in lines.iter().enumerate() {
+        if i > 0 {
+            program.push(token::COMMA);
+        }
+        program.push(token::QUOTE);
+        push_text(&mut program, line);
+        program.push(token::QUOTE);
+    }
+    program.extend_from_slice(&[token::NEWLINE, token::PAUSE, token::NEWLINE, token::PRGM]);
+    push_text(&mut program, HD_PICTURE_VIEWER);
+    program.extend_from_slice(&[token::NEWLINE, token::CLR_HOME]);
+
+    let mut writer = tifiles::Writer::new(out, VariableType::Program, &name, archived)?;
+    writer.write_all(&program)?;
+    writer.close()
+}
+
+/// Code of the assembly viewer that finds the palette and shows the tiles listed after it.
+const ASM_MAIN: &str = "
+; The low byte of the LCD control register for 8-bit pixels indexing the palette, and for the
+; OS's usual 16-bit pixels
+LCD_8BPP := $27
+LCD_16BPP := $2d
+; Bytes of each entry in the list of tiles: the appvar's type and name, where on the screen it
+; goes, and how many of its columns and rows are on the screen
+TILE_ENTRY := 9 + 3 + 1 + 1
+BUFFER := ti.pixelShadow
+
+main:
+\tcall\tti.RunIndicOff
+\tpush\tix
+\tld\thl, palette_name
+\tcall\tfind_appvar
+\tjr\tc, .missing
+\t; Colors follow a 24-byte header, in the format the LCD palette uses
+\tpush\thl
+\tld\thl, -24
+\tadd\thl, bc
+\tpush\thl
+\tpop\tbc
+\tpop\thl
+\tld\tde, 24
+\tadd\thl, de
+\tld\tde, ti.mpLcdPalette
+\tldir
+\tld\ta, LCD_8BPP
+\tld\t(ti.mpLcdCtrl), a
+\tld\thl, ti.vRam
+\tld\t(hl), 0
+\tld\tde, ti.vRam + 1
+\tld\tbc, ti.lcdWidth * ti.lcdHeight - 1
+\tldir
+
+\tld\thl, tiles
+.tile:
+\tld\t(tile), hl
+\tld\ta, (hl)
+\tor\ta, a
+\tjr\tz, .wait
+\t; Tiles that are missing are left blank
+\tcall\tfind_appvar
+\tcall\tnc, draw_tile
+\tld\thl, (tile)
+\tld\tde, TILE_ENTRY
+\tadd\thl, de
+\tjr\t.tile
+
+.wait:
+\tcall\tti.GetCSC
+\tor\ta, a
+\tjr\tz, .wait
+\tld\ta, LCD_16BPP
+\tld\t(ti.mpLcdCtrl), a
+\tcall\tti.ClrScrn
+\tcall\tti.HomeUp
+\tcall\tti.DrawStatusBar
+\tpop\tix
+\tret
+
+.missing:
+\tcall\tti.HomeUp
+\tld\thl, missing_text
+\tcall\tti.PutS
+\tcall\tti.NewLine
+\tpop\tix
+\tret
+
+; Find the appvar whose type and name are at hl, returning a pointer to its data in hl and its
+; size in bc, or carry if it doesn't exist.
+find_appvar:
+\tcall\tti.Mov9ToOP1
+\tcall\tti.ChkFindSym
+\tret\tc
+\tcall\tti.ChkInRam
+\tex\tde, hl
+\tjr\tz, .found
+\t; Archived variables start with a header and their name
+\tld\tde, 9
+\tadd\thl, de
+\tld\te, (hl)
+\tadd\thl, de
+\tinc\thl
+.found:
+\tld\tbc, 0
+\tld\tc, (hl)
+\tinc\thl
+\tld\tb, (hl)
+\tinc\thl
+\tor\ta, a
+\tret
+
+; Copy the rows of the tile whose pixels are at hl to the screen, as far as its entry says.
+copy_tile:
+\tld\tix, (tile)
+\tld\tde, (ix + 9)
+\tld\ta, (ix + 13)
+.row:
+\tpush\thl
+\tpush\tde
+\tld\tbc, 0
+\tld\tc, (ix + 12)
+\tldir
+\tpop\thl
+\tld\tbc, ti.lcdWidth
+\tadd\thl, bc
+\tex\tde, hl
+\tpop\thl
+\tld\tbc, TILE_WIDTH
+\tadd\thl, bc
+\tdec\ta
+\tjr\tnz, .row
+\tret
+";
+
+/// Drawing of uncompressed tiles, which are read where they are.
+const ASM_DRAW_NONE: &str = "
+; Draw the tile whose appvar data is at hl.
+draw_tile:
+\tld\tde, TILE_HEADER + 2
+\tadd\thl, de
+\tjr\tcopy_tile
+";
+
+/// Drawing of zx0 tiles, with the decompressor from the zx0 repository.
+const ASM_DRAW_ZX0: &str = "
+; Draw the tile whose appvar data is at hl.
+draw_tile:
+\tld\tde, TILE_HEADER
+\tadd\thl, de
+\tld\tde, BUFFER
+\tcall\tdzx0_standard
+\tld\thl, BUFFER + 2
+\tjr\tcopy_tile
+
+; dzx0_standard by Einar Saukas and Urusergi, BSD-3-Clause licensed, with the initial offset
+; loaded as 24 bits so every offset is negative in ADL mode.
+; Decompress the zx0 data at hl to de.
+dzx0_standard:
+\tld\tbc, -1
+\tpush\tbc
+\tinc\tbc
+\tld\ta, $80
+.literals:
+\tcall\t.elias
+\tldir
+\tadd\ta, a
+\tjr\tc, .new_offset
+\tcall\t.elias
+.copy:
+\tex\t(sp), hl
+\tpush\thl
+\tadd\thl, de
+\tldir
+\tpop\thl
+\tex\t(sp), hl
+\tadd\ta, a
+\tjr\tnc, .literals
+.new_offset:
+\tpop\tbc
+\tld\tc, $fe
+\tcall\t.elias_loop
+\tinc\tc
+\tret\tz
+\tld\tb, c
+\tld\tc, (hl)
+\tinc\thl
+\trr\tb
+\trr\tc
+\tpush\tbc
+\tld\tbc, 1
+\tcall\tnc, .elias_backtrack
+\tinc\tbc
+\tjr\t.copy
+.elias:
+\tinc\tc
+.elias_loop:
+\tadd\ta, a
+\tjr\tnz, .elias_skip
+\tld\ta, (hl)
+\tinc\thl
+\trla
+.elias_skip:
+\tret\tc
+.elias_backtrack:
+\tadd\ta, a
+\trl\tc
+\trl\tb
+\tjr\t.elias_loop
+";
+
+/// Drawing of run-length encoded tiles.
+const ASM_DRAW_RLE: &str = "
+; Draw the tile whose appvar data is at hl, with size bc.
+draw_tile:
+\tpush\thl
+\tadd\thl, bc
+\tld\t(data_end), hl
+\tpop\thl
+\tld\tde, TILE_HEADER
+\tadd\thl, de
+\tld\tde, BUFFER
+\tcall\trle_decompress
+\tld\thl, BUFFER + 2
+\tjr\tcopy_tile
+
+; Decompress the runs at hl to de, up to (data_end). Each is a count from 1 to 255 and the byte
+; to repeat that many times.
+rle_decompress:
+\tld\tbc, 0
+.run:
+\tld\tc, (hl)
+\tinc\thl
+\tld\ta, (hl)
+\tinc\thl
+.repeat:
+\tld\t(de), a
+\tinc\tde
+\tdec\tc
+\tjr\tnz, .repeat
+\tpush\tde
+\tld\tde, (data_end)
+\tor\ta, a
+\tsbc\thl, de
+\tadd\thl, de
+\tpop\tde
+\tjr\tnz, .run
+\tret
+
+data_end:
+\tdl\t0
+";
+
+/// Write the line of assembly declaring the type and name of an appvar.
+fn write_asm_appvar_name<W: Write>(out: &mut W, name: &str) -> IoResult<()> {
+    write!(out, "\tdb\t$15, '{}'", name)?;
+    for _ in name.len()..NameTemplate::MAX_LEN {
+        write!(out, ", 0")?;
+    }
+    writeln!(out)
+}
+
+/// Write eZ80 assembly source for a program that shows an image by itself until a key is
+/// pressed, named by [`program_name`].
+///
+/// Tiles are named by `template`, and those of animations are the first frame's. The part of
+/// the image that fits on the screen is shown from its top left corner. This fails for
+/// DEFLATE-compressed tiles, which the viewer can't decompress.
+pub fn write_asm_viewer<W: Write>(
+    entry: &Entry,
+    template: &NameTemplate,
+    compression: Compression,
+    mut out: W,
+) -> IoResult<W> {
+    let name = checked_program_name(entry)?;
+    let (header_len, draw) = match compression {
+        Compression::None => (17, ASM_DRAW_NONE),
+        Compression::Zx0 => (16, ASM_DRAW_ZX0),
+        Compression::Rle => (17, ASM_DRAW_RLE),
+        Compression::Deflate => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the assembly viewer can't decompress DEFLATE tiles",
+            ))
+        }
+    };
+    let tile_width = entry.width as u32 / entry.columns as u32;
+    let tile_height = entry.height as u32 / entry.rows as u32;
+    let palette = format!("HP{:2}0000", entry.var_prefix);
+
+    writeln!(
+        out,
+        "; Viewer for {}, generated by hdpictureconverter",
+        name
+    )?;
+    writeln!(out, ";")?;
+    writeln!(
+        out,
+        "; Build it with fasmg and the CE toolchain's include files:"
+    )?;
+    writeln!(out, ";     fasmg {0}.asm {0}.8xp", name)?;
+    writeln!(
+        out,
+        "; The palette appvar {} and tile appvars must be on the calculator.",
+        palette
+    )?;
+    writeln!(out)?;
+    writeln!(out, "include 'include/ez80.inc'")?;
+    writeln!(out, "include 'include/tiformat.inc'")?;
+    writeln!(out, "format ti executable '{}'", name)?;
+    writeln!(out, "include 'include/ti84pceg.inc'")?;
+    writeln!(out)?;
+    writeln!(out, "; Tiles are {}x{} pixels", tile_width, tile_height)?;
+    writeln!(out, "TILE_WIDTH := {}", tile_width)?;
+    writeln!(out, "; Bytes before the tile's compressed data")?;
+    writeln!(out, "TILE_HEADER := {}", header_len)?;
+    out.write_all(ASM_MAIN.as_bytes())?;
+    out.write_all(draw.as_bytes())?;
+
+    writeln!(out)?;
+    writeln!(out, "palette_name:")?;
+    write_asm_appvar_name(&mut out, &palette)?;
+    writeln!(out, "missing_text:")?;
+    writeln!(out, "\tdb\t'{} is missing', 0", palette)?;
+    writeln!(out)?;
+    writeln!(out, "tiles:")?;
+    for row in 0..entry.rows as u32 {
+        let y = row * tile_height;
+        for column in 0..entry.columns as u32 {
+            let x = column * tile_width;
+            if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
+                continue;
+            }
+            write_asm_appvar_name(
+                &mut out,
+                &template.render(&entry.var_prefix, 0, column, row),
+            )?;
+            writeln!(out, "\tdl\tti.vRam + {}", y * SCREEN_WIDTH + x)?;
+            writeln!(
+                out,
+                "\tdb\t{}, {}",
+                tile_width.min(SCREEN_WIDTH - x),
+                tile_height.min(SCREEN_HEIGHT - y)
+            )?;
+        }
+    }
+    writeln!(out, "\tdb\t0")?;
+    writeln!(out, "tile:")?;
+    writeln!(out, "\tdl\t0")?;
+    Ok(out)
+}
+
+/// BASIC viewers are tokenized programs that run HD Picture Viewer.
+#[test]
+fn basic_viewer_runs_hd_picture_viewer() {
+    use crate::group;
+    use std::io::Cursor;
+
+    let entry = Entry {
+        name: "small___".into(),
+        var_prefix: "SM".into(),
+        width: 240,
+        height: 160,
+        columns: 3,
+        rows: 2,
+    };
+    let file = write_basic_viewer(&entry, false, Cursor::new(Vec::new()))
+        .unwrap()
+        .into_inner();
+    let var = group::read(&file).unwrap().vars.remove(0);
+    assert_eq!(var.ty, VariableType::Program);
+    assert_eq!(var.name, "SMALL");
+    assert!(var
+        .data
+        .starts_with(b"\xe1\x3f\xde\x2aSMALL\x29240X160\x2a\x2b\x2aRUNNING"));
+    assert!(var.data.ends_with(b"\x2a\x3f\xd8\x3f\x5fHDPICV\x3f\xe1"));
+
+    let viewer = Entry {
+        name: "hdpicv__".into(),
+        ..entry
+    };
+    assert!(write_basic_viewer(&viewer, false, Cursor::new(Vec::new())).is_err());
+}
+
+/// Assembly viewers list only the tiles on the screen, clipped to its edges.
+#[test]
+fn asm_viewer_lists_visible_tiles() {
+    let entry = Entry {
+        name: "wide____".into(),
+        var_prefix: "WI".into(),
+        width: 400,
+        height: 80,
+        columns: 5,
+        rows: 1,
+    };
+    let source = write_asm_viewer(
+        &entry,
+        &NameTemplate::default(),
+        Compression::Zx0,
+        Vec::new(),
+    )
+    .unwrap();
+    let source = String::from_utf8(source).unwrap();
+
+    assert!(source.contains("format ti executable 'WIDE'\n"));
+    assert!(source.contains("TILE_WIDTH := 80\n") && source.contains("TILE_HEADER := 16\n"));
+    assert!(source.contains("\tdb\t$15, 'HPWI0000'\n"));
+    assert!(source.contains("dzx0_standard:"));
+    assert!(!source.contains("rle_decompress:"));
+    // The fifth tile starts past the right edge of the screen
+    assert!(source.contains("\tdb\t$15, 'WI003000'\n\tdl\tti.vRam + 240\n\tdb\t80, 80\n"));
+    assert!(!source.contains("WI004000"));
+
+    assert!(write_asm_viewer(
+        &entry,
+        &NameTemplate::default(),
+        Compression::Deflate,
+        Vec::new()
+    )
+    .is_err());
+}
+
+/// Assembly viewers refer only to labels and constants they define or that the CE toolchain's
+/// include files do, whichever routine draws their tiles.
+#[test]
+fn asm_viewer_labels_are_defined() {
+    use std::collections::HashSet;
+
+    let entry = Entry {
+        name: "small___".into(),
+        var_prefix: "SM".into(),
+        width: 160,
+        height: 240,
+        columns: 2,
+        rows: 3,
+    };
+    const REGISTERS: &[&str] = &[
+        "a", "b", "c", "d", "e", "h", "l", "bc", "de", "hl", "ix", "sp", "z", "nz", "nc",
+    ];
+    for compression in [Compression::None, Compression::Zx0, Compression::Rle] {
+        let source =
+            write_asm_viewer(&entry, &NameTemplate::default(), compression, Vec::new()).unwrap();
+        let source = String::from_utf8(source).unwrap();
+
+        // Local labels start with a dot and belong to the label before them
+        let mut defined = HashSet::new();
+        let mut references = Vec::new();
+        let mut global = String::new();
+        for line in source.lines() {
+            let line = line.split(';').next().unwrap();
+            if let Some((constant, _)) = line.split_once(" := ") {
+                defined.insert(constant.to_string());
+            } else if let Some(label) = line.strip_suffix(':').filter(|_| !line.starts_with('\t')) {
+                if !label.starts_with('.') {
+                    global = label.to_string();
+                }
+                defined.insert(format!(
+                    "{}{}",
+                    if label.starts_with('.') { &global } else { "" },
+                    label
+                ));
+            } else if let Some((op, operands)) = line.trim_start().split_once('\t') {
+                if op == "db" || op == "include" {
+                    continue;
+                }
+                let words =
+                    operands.split(|c: char| !(c.is_ascii_alphanumeric() || "._$".contains(c)));
+                for word in words.filter(|word| !word.is_empty()) {
+                    let is_symbol = !word.starts_with(|c: char| c.is_ascii_digit() || c == '$')
+                        && !word.starts_with("ti.")
+                        && !REGISTERS.contains(&word);
+                    if is_symbol {
+                        let local = if word.starts_with('.') {
+                            global.as_str()
+                        } else {
+                            ""
+                        };
+                        references.push(format!("{}{}", local, word));
+                    }
+                }
+            }
+        }
+        for reference in references {
+            assert!(
+                defined.contains(&reference),
+                "{} isn't defined with {:?}",
+                reference,
+                compression
+            );
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! JavaScript bindings for converting images in the browser
+//!
+//! Building the library for `wasm32-unknown-unknown` with the `wasm` feature, such as with
+//! `wasm-pack build --features wasm`, exports these functions to JavaScript. Images are passed
+//! in as the bytes of a file and come back as the bytes of a group file, ready to be downloaded
+//! and sent to a calculator.
+use std::io::Cursor;
+
+use wasm_bindgen::prelude::*;
+
+use crate::{check_var_prefix, Compression, Dither, Image, QuantizeOptions};
+
+/// Convert an image file to a group file containing its tile and palette appvars.
+///
+/// `var_prefix` must be two letters. Unless `dither` is false, colors are dithered like the
+/// default conversion, and `compression` is the name of a [`Compression`], zx0 by default.
+#[wasm_bindgen]
+pub fn convert(
+    image_data: &[u8],
+    image_name: &str,
+    var_prefix: &str,
+    dither: Option<bool>,
+    compression: Option<String>,
+) -> Result<Box<[u8]>, String> {
+    check_var_prefix(var_prefix)?;
+    let compression = match compression {
+        Some(compression) => compression.parse()?,
+        None => Compression::Zx0,
+    };
+    let mut options = QuantizeOptions::default();
+    if dither == Some(false) {
+        options.dither = Dither::None;
+    }
+
+    let image =
+        Image::new(Cursor::new(image_data), image_name, var_prefix).map_err(|e| e.to_string())?;
+    let mut image = image.quantize_with(&options).map_err(|e| e.to_string())?;
+    image.set_compression(compression);
+    let group = image
+        .write_group(Cursor::new(Vec::new()))
+        .map_err(|e| e.to_string())?;
+    Ok(group.into_inner().into_boxed_slice())
+}
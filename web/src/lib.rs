@@ -1,7 +1,5 @@
 use hdpictureconverter::Image;
 use std::io::{Cursor, Write};
-use flate2::write::GzEncoder;
-use flate2::Compression;
 use wasm_bindgen::prelude::*;
 use zip::ZipWriter;
 
@@ -27,6 +25,7 @@ pub struct ProcessResponse {
 }
 
 #[wasm_bindgen]
+#[derive(Default)]
 pub struct Converter;
 
 #[wasm_bindgen]
@@ -94,24 +93,16 @@ impl Converter {
             // First write the appvar to a memory buffer, since we need to seek within it
             let var_data = tile.write_appvar(Cursor::new(Vec::new()))?.into_inner();
 
-            // Compress the appvar bytes to gzip and write as `.8xg` inside the zip
-            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-            encoder.write_all(&var_data)?;
-            let gz_bytes = encoder.finish()?;
-
-            zip.start_file(format!("{}.8xg", tile.appvar_name()), zip_options)?;
-            zip.write_all(&gz_bytes)?;
+            zip.start_file(format!("{}.8xv", tile.appvar_name()), zip_options)?;
+            zip.write_all(&var_data)?;
         }
 
         // Dump palette to appvar
         let palette_data = im
             .write_palette_appvar(Cursor::new(Vec::new()))?
             .into_inner();
-        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-        encoder.write_all(&palette_data)?;
-        let gz_palette = encoder.finish()?;
-        zip.start_file(format!("{}.8xg", im.palette_appvar_name()), zip_options)?;
-        zip.write_all(&gz_palette)?;
+        zip.start_file(format!("{}.8xv", im.palette_appvar_name()), zip_options)?;
+        zip.write_all(&palette_data)?;
 
         let zip_bytes = zip.finish()?.into_inner();
 